@@ -0,0 +1,178 @@
+//! `cat-file`: print objects, one at a time or in batches named on the
+//! input.
+
+use std::io::{BufRead, Write};
+
+use crate::format::{parse_format, quote_path, FormatPiece};
+use crate::object::{parse_tree, ObjectFormat};
+use crate::{Error, GitObject, ObjectType, Repository};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Which `cat-file` batch mode is reading objects from its input.
+pub enum BatchMode {
+    /// `--batch`: print the info line followed by the object contents.
+    Contents,
+    /// `--batch-check`: print the info line only.
+    Check,
+    /// `--batch-command`: every input line names the action to take.
+    Command,
+}
+
+enum BatchAtom {
+    ObjectName,
+    ObjectType,
+    ObjectSize,
+    ObjectSizeDisk,
+    DeltaBase,
+    Rest,
+}
+
+/// Pretty-print an object to `out` as `cat-file -p` does.
+pub fn pretty_print(
+    obj: &GitObject,
+    format: ObjectFormat,
+    out: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    match obj.kind {
+        ObjectType::Tree => {
+            for entry in parse_tree(&obj.content, format)? {
+                write!(out, "{:06o} {} {}\t", entry.mode, entry.kind(), entry.hash)?;
+                out.write_all(&quote_path(&entry.name))?;
+                writeln!(out)?;
+            }
+        }
+        ObjectType::Blob | ObjectType::Commit | ObjectType::Tag => out.write_all(&obj.content)?,
+    }
+    Ok(())
+}
+
+/// Answer the object names (or, in [`BatchMode::Command`], the commands)
+/// read from `input` on `out`, one info line per object laid out by `format`
+/// (`%(objectname) %(objecttype) %(objectsize)` when empty) and, when asked
+/// for, its contents. Without `buffer`, `out` is flushed after every object.
+pub fn batch(
+    repo: &Repository,
+    mode: BatchMode,
+    format: &str,
+    buffer: bool,
+    input: impl BufRead,
+    out: impl Write,
+) -> Result<(), anyhow::Error> {
+    let format = if format.is_empty() {
+        "%(objectname) %(objecttype) %(objectsize)"
+    } else {
+        format
+    };
+    let atoms = parse_format(format, |name| match name {
+        "objectname" => Some(BatchAtom::ObjectName),
+        "objecttype" => Some(BatchAtom::ObjectType),
+        "objectsize" => Some(BatchAtom::ObjectSize),
+        "objectsize:disk" => Some(BatchAtom::ObjectSizeDisk),
+        "deltabase" => Some(BatchAtom::DeltaBase),
+        "rest" => Some(BatchAtom::Rest),
+        _ => None,
+    })?;
+    let split_rest = atoms
+        .iter()
+        .any(|a| matches!(a, FormatPiece::Atom(BatchAtom::Rest)));
+
+    let mut out = out;
+    for line in input.lines() {
+        let line = line?;
+        let (input, contents) = match mode {
+            BatchMode::Contents => (line.as_str(), true),
+            BatchMode::Check => (line.as_str(), false),
+            BatchMode::Command => {
+                if let Some(input) = line.strip_prefix("contents ") {
+                    (input, true)
+                } else if let Some(input) = line.strip_prefix("info ") {
+                    (input, false)
+                } else if line == "flush" {
+                    if !buffer {
+                        anyhow::bail!("flush is only for --buffer mode");
+                    }
+                    out.flush()?;
+                    continue;
+                } else if line.is_empty() {
+                    anyhow::bail!("empty command in input");
+                } else {
+                    anyhow::bail!("unknown command: '{line}'");
+                }
+            }
+        };
+
+        let (name, rest) = if split_rest {
+            let input = input.trim_start();
+            input
+                .split_once(|c: char| c.is_ascii_whitespace())
+                .unwrap_or((input, ""))
+        } else {
+            (input, "")
+        };
+        let hash = match repo.resolve(name) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => {
+                writeln!(out, "{name} missing")?;
+                continue;
+            }
+            Err(err) => {
+                match err.downcast_ref::<Error>() {
+                    Some(Error::BadObjectName(_) | Error::MissingObject { .. }) => {
+                        writeln!(out, "Not a valid object name {name}")?
+                    }
+                    _ => writeln!(out, "{name} ambiguous")?,
+                }
+                continue;
+            }
+        };
+        // Only `--batch` prints contents; the checks need just the header.
+        let found = if contents {
+            repo.odb()
+                .open(&hash)
+                .map(|obj| (obj.kind, obj.size, Some(obj)))
+        } else {
+            repo.odb()
+                .header(&hash)
+                .map(|(kind, size)| (kind, size, None))
+        };
+        let (kind, size, obj) = match found {
+            Ok(found) => found,
+            Err(Error::MissingObject { .. }) => {
+                writeln!(out, "{name} missing")?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        for piece in &atoms {
+            let atom = match piece {
+                FormatPiece::Literal(s) => {
+                    out.write_all(s)?;
+                    continue;
+                }
+                FormatPiece::Atom(atom) => atom,
+            };
+            match atom {
+                BatchAtom::ObjectName => write!(out, "{hash}")?,
+                BatchAtom::ObjectType => write!(out, "{kind}")?,
+                BatchAtom::ObjectSize => write!(out, "{size}")?,
+                BatchAtom::ObjectSizeDisk => write!(out, "{}", repo.odb().disk_size(&hash)?)?,
+                BatchAtom::DeltaBase => match repo.odb().delta_base(&hash)? {
+                    Some(base) => write!(out, "{base}")?,
+                    None => write!(out, "{}", repo.odb().format().null_id())?,
+                },
+                BatchAtom::Rest => write!(out, "{rest}")?,
+            }
+        }
+        writeln!(out)?;
+        if let Some(obj) = obj {
+            obj.copy_to(&mut out)?;
+            writeln!(out)?;
+        }
+        if !buffer {
+            out.flush()?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
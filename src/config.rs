@@ -0,0 +1,352 @@
+//! A reader for git's `config` file format.
+//!
+//! Keys are stored as `section.key` or `section.subsection.key`, with the
+//! section and key lowercased and the subsection kept as written, so lookups
+//! follow git's case rules.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+
+#[derive(Default)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+/// Lowercase the section and variable name of `key`, leaving the subsection alone.
+fn normalize_key(key: &str) -> String {
+    match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) if first != last => format!(
+            "{}{}{}",
+            key[..first].to_ascii_lowercase(),
+            &key[first..last],
+            key[last..].to_ascii_lowercase()
+        ),
+        _ => key.to_ascii_lowercase(),
+    }
+}
+
+impl Config {
+    /// Load the user's global config followed by the repository config in
+    /// `git_dir`, so repository values take precedence.
+    pub fn load(git_dir: &Path) -> Result<Self, anyhow::Error> {
+        let mut config = Config::default();
+        if let Some(home) = std::env::var_os("HOME") {
+            config.read_file(&Path::new(&home).join(".gitconfig"))?;
+        }
+        config.read_file(&git_dir.join("config"))?;
+        Ok(config)
+    }
+
+    fn read_file(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+        if !path.is_file() {
+            return Ok(());
+        }
+        let content = std::fs::read_to_string(path).context(format!("read {:?}", path))?;
+        self.parse(&content).context(format!("parse {:?}", path))
+    }
+
+    fn parse(&mut self, content: &str) -> Result<(), anyhow::Error> {
+        let mut section = String::new();
+        let mut lines = content.lines().enumerate();
+        while let Some((n, line)) = lines.next() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let end = header
+                    .find(']')
+                    .ok_or_else(|| anyhow::anyhow!("line {}: unterminated section", n + 1))?;
+                section = section_name(&header[..end])
+                    .ok_or_else(|| anyhow::anyhow!("line {}: bad subsection", n + 1))?;
+                continue;
+            }
+            if section.is_empty() {
+                anyhow::bail!("line {}: variable outside of a section", n + 1);
+            }
+
+            let (name, raw) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value)),
+                None => (line.trim(), None),
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                anyhow::bail!("line {}: bad variable name {name:?}", n + 1);
+            }
+            let key = format!("{section}.{}", name.to_ascii_lowercase());
+            let Some(raw) = raw else {
+                // a bare variable name means "true"
+                self.entries.push((key, "true".to_string()));
+                continue;
+            };
+
+            let mut value = String::new();
+            let mut raw = raw.to_string();
+            loop {
+                if parse_value(&raw, &mut value)? {
+                    // line continuation
+                    let Some((_, next)) = lines.next() else {
+                        break;
+                    };
+                    raw = next.to_string();
+                    continue;
+                }
+                break;
+            }
+            self.entries.push((key, value));
+        }
+        Ok(())
+    }
+
+    /// The last value set for `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value set for `key`, in the order they were read, for keys
+    /// that can be given more than once.
+    pub fn get_all<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a str> + 'a {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .filter(move |(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every key that is set, normalized, in the order they were read. Keys
+    /// set more than once appear more than once.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(k, _)| k.as_str())
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, anyhow::Error> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        match value.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" => Ok(Some(true)),
+            "false" | "no" | "off" | "" => Ok(Some(false)),
+            v => match v.parse::<i64>() {
+                Ok(n) => Ok(Some(n != 0)),
+                Err(_) => anyhow::bail!("bad boolean config value '{value}' for '{key}'"),
+            },
+        }
+    }
+}
+
+/// The section a `[section "subsection"]` header (without its brackets)
+/// opens, in the form keys start with, or `None` if it is malformed.
+fn section_name(header: &str) -> Option<String> {
+    match header.split_once(' ') {
+        Some((name, sub)) => {
+            let sub = sub.trim().strip_prefix('"')?.strip_suffix('"')?;
+            let sub = sub.replace("\\\"", "\"").replace("\\\\", "\\");
+            Some(format!("{}.{}", name.to_ascii_lowercase(), sub))
+        }
+        // the deprecated `[section.subsection]` form is case-insensitive
+        None => Some(header.to_ascii_lowercase()),
+    }
+}
+
+/// Set `key` to `value` in the config file at `path`, as `git config <key>
+/// <value>` does: the last line setting it is rewritten, or the variable is
+/// added to the end of its section, or a new section is appended.
+pub fn set(path: &Path, key: &str, value: &str) -> Result<(), anyhow::Error> {
+    let key = normalize_key(key);
+    let (section, name) = key
+        .rsplit_once('.')
+        .ok_or_else(|| anyhow::anyhow!("key does not contain a section: {key}"))?;
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(format!("read {:?}", path)),
+    };
+    let mut lines = content.lines().map(str::to_string).collect::<Vec<_>>();
+    let line = format!("\t{name} = {}", quote_value(value));
+    let mut current = None;
+    let mut last_in_section = None;
+    let mut existing = None;
+    for (i, text) in lines.iter().enumerate() {
+        let text = text.trim_start();
+        if let Some(header) = text.strip_prefix('[') {
+            current = header
+                .find(']')
+                .and_then(|end| section_name(&header[..end]));
+            continue;
+        }
+        if current.as_deref() != Some(section) {
+            continue;
+        }
+        last_in_section = Some(i);
+        let var = text.split_once('=').map_or(text, |(var, _)| var).trim();
+        if var.eq_ignore_ascii_case(name) {
+            existing = Some(i);
+        }
+    }
+    match (existing, last_in_section) {
+        (Some(i), _) => lines[i] = line,
+        (None, Some(i)) => lines.insert(i + 1, line),
+        (None, None) => {
+            let (section, subsection) = match section.split_once('.') {
+                Some((section, sub)) => (section, Some(sub)),
+                None => (section, None),
+            };
+            return append_section(path, section, subsection, &[(name, value)]);
+        }
+    }
+    let mut content = lines.join("\n");
+    content.push('\n');
+    std::fs::write(path, content).context(format!("write {:?}", path))
+}
+
+/// Append a `[section "subsection"]` setting `vars` to the config file at
+/// `path`, as `git config` writes them, creating the file if need be.
+pub fn append_section(
+    path: &Path,
+    section: &str,
+    subsection: Option<&str>,
+    vars: &[(&str, &str)],
+) -> Result<(), anyhow::Error> {
+    let mut text = match subsection {
+        Some(sub) => format!(
+            "[{section} \"{}\"]\n",
+            sub.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        None => format!("[{section}]\n"),
+    };
+    for (name, value) in vars {
+        text += &format!("\t{name} = {}\n", quote_value(value));
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("open {:?}", path))?;
+    file.write_all(text.as_bytes())
+        .context(format!("write {:?}", path))
+}
+
+/// A value as it is written to a config file: escaped, and quoted when it
+/// would otherwise lose whitespace or read as a comment.
+fn quote_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    let needs_quotes = value.starts_with(char::is_whitespace)
+        || value.ends_with(char::is_whitespace)
+        || value.contains(['#', ';']);
+    if needs_quotes {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+/// Append the unquoted, unescaped value in `raw` to `value`, stopping at an
+/// inline comment. Returns whether the line ends with a continuation backslash.
+fn parse_value(raw: &str, value: &mut String) -> Result<bool, anyhow::Error> {
+    let mut quoted = false;
+    // whitespace is only kept once it is followed by something else
+    let mut pending_space = String::new();
+    let mut chars = raw.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted => break,
+            '\\' => match chars.next() {
+                None => return Ok(true),
+                Some(e) => {
+                    value.push_str(&pending_space);
+                    pending_space.clear();
+                    value.push(match e {
+                        'n' => '\n',
+                        't' => '\t',
+                        'b' => '\x08',
+                        '"' | '\\' => e,
+                        _ => anyhow::bail!("bad config value escape \\{e}"),
+                    });
+                }
+            },
+            c if c.is_whitespace() && !quoted => pending_space.push(c),
+            c => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(c);
+            }
+        }
+    }
+    if quoted {
+        anyhow::bail!("unterminated quote in config value");
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Config {
+        let mut config = Config::default();
+        config.parse(content).unwrap();
+        config
+    }
+
+    #[test]
+    fn parses_sections_and_values() {
+        let config = parse(
+            "# comment\n\
+             [Core]\n\
+             \tAutoCRLF = Input ; trailing comment\n\
+             \tbare\n\
+             [remote \"Origin\"]\n\
+             \turl = \"a b\"  # kept\n\
+             \tfetch = one\\\n\
+             two\n\
+             [core]\n\
+             \tautocrlf = false\n",
+        );
+        assert_eq!(config.get("core.autocrlf"), Some("false"));
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(true));
+        assert_eq!(config.get("remote.Origin.url"), Some("a b"));
+        assert_eq!(config.get("REMOTE.Origin.URL"), Some("a b"));
+        assert_eq!(config.get("remote.origin.url"), None);
+        assert_eq!(config.get("remote.Origin.fetch"), Some("onetwo"));
+    }
+
+    #[test]
+    fn parses_booleans() {
+        let config = parse("[a]\nb = yes\nc = off\nd = 0\ne =\nf = maybe\n");
+        assert_eq!(config.get_bool("a.b").unwrap(), Some(true));
+        assert_eq!(config.get_bool("a.c").unwrap(), Some(false));
+        assert_eq!(config.get_bool("a.d").unwrap(), Some(false));
+        assert_eq!(config.get_bool("a.e").unwrap(), Some(false));
+        assert!(config.get_bool("a.f").is_err());
+        assert_eq!(config.get_bool("a.g").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        for bad in [
+            "x = 1\n",
+            "[a\n",
+            "[a]\nb c = 1\n",
+            "[a]\nb = \"open\n",
+            "[a]\nb = \\q\n",
+        ] {
+            assert!(Config::default().parse(bad).is_err(), "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Content conversion when files enter the object store: the end-of-line
+//! normalisation chosen by `core.autocrlf` and the `text`/`eol` attributes.
+
+use std::path::Path;
+
+use crate::config::Config;
+
+/// The end-of-line conversion `.gitattributes` asks for on a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextAttr {
+    Text,
+    Binary,
+    Auto,
+}
+
+/// Match `name` against a gitattributes/gitignore style glob.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => {
+            if let Some(rest) = rest.strip_prefix(b"*") {
+                // `**` crosses directory boundaries
+                let rest = rest.strip_prefix(b"/").unwrap_or(rest);
+                (0..=name.len()).any(|i| glob_match(rest, &name[i..]))
+            } else {
+                (0..=name.len())
+                    .take_while(|i| *i == 0 || name[i - 1] != b'/')
+                    .any(|i| glob_match(rest, &name[i..]))
+            }
+        }
+        Some((b'?', rest)) => {
+            matches!(name.split_first(), Some((c, name)) if *c != b'/' && glob_match(rest, name))
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            name.first() == rest.first() && glob_match(&rest[1..], &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+/// The end-of-line rules for one repository.
+pub struct Filters<'a> {
+    /// `.gitattributes` files to consult, in increasing priority.
+    attribute_files: Vec<std::path::PathBuf>,
+    config: &'a Config,
+}
+
+impl<'a> Filters<'a> {
+    /// The rules of the repository with the given work tree and git dir.
+    pub fn new(work_tree: Option<&Path>, git_dir: &Path, config: &'a Config) -> Self {
+        let mut attribute_files = Vec::new();
+        if let Some(work_tree) = work_tree {
+            attribute_files.push(work_tree.join(".gitattributes"));
+        }
+        attribute_files.push(git_dir.join("info/attributes"));
+        Filters {
+            attribute_files,
+            config,
+        }
+    }
+
+    /// Look up the `text`/`binary`/`eol` attributes for `path` in the top-level
+    /// `.gitattributes` and `.git/info/attributes`; later lines win.
+    fn text_attr(&self, path: &str) -> Option<TextAttr> {
+        let path = path.trim_start_matches("./").as_bytes();
+        let basename = path.rsplit(|c| *c == b'/').next().unwrap_or(path);
+        let mut attr = None;
+        for file in &self.attribute_files {
+            let Ok(content) = std::fs::read(file) else {
+                continue;
+            };
+            for line in content.split(|c| *c == b'\n') {
+                let mut fields = line
+                    .split(|c| c.is_ascii_whitespace())
+                    .filter(|f| !f.is_empty());
+                let Some(pattern) = fields.next() else {
+                    continue;
+                };
+                if pattern.starts_with(b"#") {
+                    continue;
+                }
+                let matched = match pattern.strip_prefix(b"/") {
+                    Some(anchored) => glob_match(anchored, path),
+                    None if pattern.contains(&b'/') => glob_match(pattern, path),
+                    None => glob_match(pattern, basename),
+                };
+                if !matched {
+                    continue;
+                }
+                for field in fields {
+                    attr = match field {
+                        b"text" | b"eol=lf" | b"eol=crlf" => Some(TextAttr::Text),
+                        b"-text" | b"binary" => Some(TextAttr::Binary),
+                        b"text=auto" => Some(TextAttr::Auto),
+                        b"!text" => None,
+                        _ => attr,
+                    };
+                }
+            }
+        }
+        attr
+    }
+
+    /// The conversion that applies to `path`, if any.
+    pub fn eol_conversion(&self, path: &str) -> Result<Option<TextAttr>, anyhow::Error> {
+        let autocrlf = match self.config.get("core.autocrlf") {
+            Some(v) if v.eq_ignore_ascii_case("input") => true,
+            _ => self.config.get_bool("core.autocrlf")?.unwrap_or(false),
+        };
+        Ok(self.text_attr(path).or(autocrlf.then_some(TextAttr::Auto)))
+    }
+
+    /// Apply the "clean" end-of-line conversion git performs when content at
+    /// `path` enters the object store: CRLF becomes LF for text files when
+    /// `core.autocrlf` or the `text`/`eol` attributes ask for it.
+    pub fn convert_to_git(&self, path: &str, data: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
+        let convert = match self.eol_conversion(path)? {
+            None | Some(TextAttr::Binary) => false,
+            Some(TextAttr::Text) => true,
+            // auto-detected text: no NULs, and never touch files with lone CRs,
+            // since converting them would not round-trip
+            Some(TextAttr::Auto) => {
+                !data.contains(&b'\0')
+                    && data
+                        .iter()
+                        .enumerate()
+                        .all(|(i, c)| *c != b'\r' || data.get(i + 1) == Some(&b'\n'))
+            }
+        };
+        if !convert || !data.windows(2).any(|w| w == b"\r\n") {
+            return Ok(data);
+        }
+        let mut out = Vec::with_capacity(data.len());
+        for (i, c) in data.iter().enumerate() {
+            if *c == b'\r' && data.get(i + 1) == Some(&b'\n') {
+                continue;
+            }
+            out.push(*c);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_attribute_globs() {
+        assert!(glob_match(b"*.txt", b"a.txt"));
+        assert!(!glob_match(b"*.txt", b"dir/a.txt"));
+        assert!(glob_match(b"docs/**/*.md", b"docs/a/b/c.md"));
+        assert!(glob_match(b"docs/**/*.md", b"docs/c.md"));
+        assert!(glob_match(b"?.c", b"a.c"));
+        assert!(!glob_match(b"?.c", b"/.c"));
+        assert!(glob_match(b"\\*", b"*"));
+        assert!(!glob_match(b"\\*", b"a"));
+    }
+}
@@ -0,0 +1,275 @@
+//! Computing git deltas: the copy and insert instructions that rebuild one
+//! object from another, which [`pack::apply_delta`](crate::pack::apply_delta)
+//! applies.
+//!
+//! As in git's diff-delta, the base is indexed by the hash of each 16-byte
+//! block, and the target is scanned with a rolling hash of the same width,
+//! looking up every position for a block to start copying from.
+
+use std::cell::OnceCell;
+use std::collections::HashMap;
+
+/// The width of the blocks the base is indexed by, and the shortest match
+/// looked for.
+const BLOCK: usize = 16;
+
+/// How many base positions are kept for one hash; repetitive data would
+/// otherwise make lookups slow without finding better matches.
+const MAX_BUCKET: usize = 64;
+
+/// The most one copy instruction copies, as git writes them.
+const MAX_COPY: usize = 0x10000;
+
+/// The most one insert instruction inserts.
+const MAX_INSERT: usize = 0x7f;
+
+/// Multiplier of the rolling hash.
+const PRIME: u32 = 0x0100_0193;
+
+/// `PRIME` to the power `BLOCK`, to take the byte leaving the window out of
+/// the hash.
+const PRIME_POW: u32 = {
+    let mut pow = 1u32;
+    let mut i = 0;
+    while i < BLOCK {
+        pow = pow.wrapping_mul(PRIME);
+        i += 1;
+    }
+    pow
+};
+
+fn block_hash(block: &[u8]) -> u32 {
+    block
+        .iter()
+        .fold(0u32, |h, &c| h.wrapping_mul(PRIME).wrapping_add(c as u32))
+}
+
+/// A base object, indexed for computing deltas against it. The index is
+/// only built once a delta is first computed, since many bases never get
+/// that far.
+pub struct DeltaIndex {
+    base: Vec<u8>,
+    blocks: OnceCell<HashMap<u32, Vec<u32>>>,
+}
+
+impl DeltaIndex {
+    /// A base to index, which must be smaller than 4 GiB for copy offsets to
+    /// reach all of it.
+    pub fn new(base: Vec<u8>) -> Self {
+        DeltaIndex {
+            base,
+            blocks: OnceCell::new(),
+        }
+    }
+
+    fn blocks(&self) -> &HashMap<u32, Vec<u32>> {
+        self.blocks.get_or_init(|| {
+            let base = &self.base;
+            let mut blocks: HashMap<u32, Vec<u32>> = HashMap::new();
+            // later blocks are indexed first, so that the earliest ones are
+            // kept when a bucket fills up; copies from nearer the start
+            // encode shorter
+            let mut start = base.len() / BLOCK * BLOCK;
+            while start >= BLOCK {
+                start -= BLOCK;
+                let bucket = blocks
+                    .entry(block_hash(&base[start..start + BLOCK]))
+                    .or_default();
+                if bucket.len() == MAX_BUCKET {
+                    bucket.remove(0);
+                }
+                bucket.push(start as u32);
+            }
+            blocks
+        })
+    }
+
+    /// The base object.
+    pub fn base(&self) -> &[u8] {
+        &self.base
+    }
+
+    /// The longest match of `target` from `at` in the base, of at least
+    /// `BLOCK` bytes: its start in the base and its length.
+    fn find_match(&self, hash: u32, target: &[u8], at: usize) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        for &start in self.blocks().get(&hash)?.iter().rev() {
+            let start = start as usize;
+            let len = self.base[start..]
+                .iter()
+                .zip(&target[at..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len >= BLOCK && best.map_or(true, |(_, best_len)| len > best_len) {
+                best = Some((start, len));
+            }
+        }
+        best
+    }
+}
+
+/// Append `n` in the little-endian base-128 of delta headers.
+fn push_size(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Append insert instructions for `data`.
+fn push_insert(out: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_INSERT) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Append copy instructions for `len` bytes of the base from `start`.
+fn push_copy(out: &mut Vec<u8>, mut start: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY);
+        let op_at = out.len();
+        let mut op = 0x80u8;
+        for (i, byte) in (start as u32).to_le_bytes().into_iter().enumerate() {
+            if byte != 0 {
+                op |= 1 << i;
+                out.push(byte);
+            }
+        }
+        // a size of 0x10000 is written as no size bytes at all
+        if size != MAX_COPY {
+            for (i, byte) in (size as u32).to_le_bytes()[..3].iter().enumerate() {
+                if *byte != 0 {
+                    op |= 0x10 << i;
+                    out.push(*byte);
+                }
+            }
+        }
+        out.insert(op_at, op);
+        start += size;
+        len -= size;
+    }
+}
+
+/// A delta turning the indexed base into `target`, or `None` if it would be
+/// larger than `max_size` bytes (unlimited when 0).
+pub fn create_delta(index: &DeltaIndex, target: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let base = index.base();
+    let too_big = |len: usize| max_size != 0 && len > max_size;
+    let mut out = Vec::new();
+    push_size(&mut out, base.len());
+    push_size(&mut out, target.len());
+
+    // bytes from `insert_from` up to the scan position have no match yet
+    let mut insert_from = 0;
+    let mut at = 0;
+    let mut hash = None;
+    while at + BLOCK <= target.len() {
+        let h = match hash {
+            Some(h) => h,
+            None => block_hash(&target[at..at + BLOCK]),
+        };
+        match index.find_match(h, target, at) {
+            Some((start, len)) => {
+                // grow the match backwards over bytes not yet matched
+                let back = base[..start]
+                    .iter()
+                    .rev()
+                    .zip(target[insert_from..at].iter().rev())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                push_insert(&mut out, &target[insert_from..at - back]);
+                push_copy(&mut out, start - back, len + back);
+                at += len;
+                insert_from = at;
+                hash = None;
+            }
+            None => {
+                if at + BLOCK < target.len() {
+                    let leaving = (target[at] as u32).wrapping_mul(PRIME_POW);
+                    let entering = target[at + BLOCK] as u32;
+                    hash = Some(
+                        h.wrapping_mul(PRIME)
+                            .wrapping_add(entering)
+                            .wrapping_sub(leaving),
+                    );
+                }
+                at += 1;
+            }
+        }
+        // a pending insert costs at least its own length
+        if too_big(out.len() + (at - insert_from)) {
+            return None;
+        }
+    }
+    push_insert(&mut out, &target[insert_from..]);
+    (!too_big(out.len())).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::apply_delta;
+
+    /// Bytes that do not repeat, so that only real copies match.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let delta = create_delta(&DeltaIndex::new(base.to_vec()), target, 0)
+            .expect("an unlimited delta is always made");
+        assert_eq!(apply_delta(base, &delta).unwrap(), target);
+        delta
+    }
+
+    #[test]
+    fn copies_exactly_one_maximal_chunk() {
+        let base = noise(MAX_COPY + 100, 1);
+        let delta = round_trip(&base, &base[..MAX_COPY]);
+        // two sizes, then one copy with no offset and no size bytes
+        assert_eq!(&delta[delta.len() - 1..], [0x80]);
+    }
+
+    #[test]
+    fn splits_long_copies() {
+        let base = noise(3 * MAX_COPY + 5, 2);
+        let delta = round_trip(&base, &base);
+        assert!(delta.len() < 32);
+    }
+
+    #[test]
+    fn splits_long_inserts() {
+        let base = noise(1000, 3);
+        let mut target = noise(3 * MAX_INSERT + 1, 4);
+        target.extend_from_slice(&base[200..600]);
+        target.extend_from_slice(&noise(MAX_INSERT + 2, 5));
+        round_trip(&base, &target);
+    }
+
+    #[test]
+    fn empty_base() {
+        round_trip(b"", &noise(300, 6));
+        round_trip(b"", b"");
+    }
+
+    #[test]
+    fn empty_target() {
+        let delta = round_trip(&noise(300, 7), b"");
+        assert_eq!(delta, [0xac, 0x02, 0x00]);
+    }
+
+    #[test]
+    fn respects_max_size() {
+        let base = noise(1000, 8);
+        let target = noise(1000, 9);
+        assert!(create_delta(&DeltaIndex::new(base), &target, 100).is_none());
+    }
+}
@@ -0,0 +1,70 @@
+//! The errors the tool reports and the exit codes they map to.
+//!
+//! Code that can fail in a way callers may want to tell apart returns (or
+//! wraps, through `anyhow` context) one of these; `main` finds it in the
+//! error chain and exits with its code. The codes follow sysexits(3) and are
+//! stable. Errors that are not one of these exit with 128, as git's `die()`
+//! does, and usage errors keep clap's exit code 2.
+
+use std::path::PathBuf;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("object {oid} not found")]
+    MissingObject { oid: String },
+    #[error("not a valid object name {0}")]
+    BadObjectName(String),
+    #[error("corrupt object {oid} ({path:?}): {reason}")]
+    CorruptObject {
+        oid: String,
+        path: PathBuf,
+        reason: String,
+    },
+    #[error("hash mismatch for object {oid} ({path:?}): content hashes to {actual}")]
+    HashMismatch {
+        oid: String,
+        path: PathBuf,
+        actual: String,
+    },
+    #[error("SHA-1 appears to be part of a collision attack: {hash}")]
+    Sha1Collision { hash: String },
+    #[error("corrupt pack: {0}")]
+    CorruptPack(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("cannot update ref {name}: {reason}")]
+    RefConflict { name: String, reason: String },
+    #[error("cannot access {path:?}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // EX_NOINPUT
+            Error::MissingObject { .. } | Error::BadObjectName(_) => 66,
+            // EX_DATAERR
+            Error::CorruptObject { .. }
+            | Error::HashMismatch { .. }
+            | Error::Sha1Collision { .. }
+            | Error::CorruptPack(_) => 65,
+            // EX_PROTOCOL
+            Error::Protocol(_) => 76,
+            // EX_CANTCREAT
+            Error::RefConflict { .. } => 73,
+            // EX_IOERR
+            Error::Io { .. } => 74,
+        }
+    }
+}
+
+/// The exit code for `err`: that of the first [`Error`] in its chain, or 128.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .find_map(|e| e.downcast_ref::<Error>())
+        .map_or(128, Error::exit_code)
+}
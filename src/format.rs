@@ -0,0 +1,82 @@
+//! Output helpers shared by the commands that print objects: `--format`
+//! strings with `%(atom)`s, and paths quoted as git quotes them.
+
+/// A piece of a parsed `--format` string.
+pub enum FormatPiece<A> {
+    Literal(Vec<u8>),
+    Atom(A),
+}
+
+/// Split a `--format` string into literal bytes and `%(atom)`s, resolving each
+/// atom name with `atom`. `%%` and `%xNN` escapes are expanded in place.
+pub fn parse_format<A>(
+    format: &str,
+    atom: impl Fn(&str) -> Option<A>,
+) -> Result<Vec<FormatPiece<A>>, anyhow::Error> {
+    let mut pieces = Vec::new();
+    let mut literal = Vec::new();
+    let mut rest = format;
+    while let Some(i) = rest.find('%') {
+        literal.extend_from_slice(&rest.as_bytes()[..i]);
+        rest = &rest[i..];
+        if let Some(r) = rest.strip_prefix("%%") {
+            literal.push(b'%');
+            rest = r;
+            continue;
+        }
+        if let Some(byte) = rest
+            .strip_prefix("%x")
+            .and_then(|r| r.get(..2))
+            .and_then(|h| u8::from_str_radix(h, 16).ok())
+        {
+            literal.push(byte);
+            rest = &rest[4..];
+            continue;
+        }
+        let Some(end) = rest.strip_prefix("%(").and_then(|r| r.find(')')) else {
+            literal.push(b'%');
+            rest = &rest[1..];
+            continue;
+        };
+        let name = &rest[2..end + 2];
+        let atom = atom(name).ok_or_else(|| anyhow::anyhow!("unknown format element: {name}"))?;
+        if !literal.is_empty() {
+            pieces.push(FormatPiece::Literal(std::mem::take(&mut literal)));
+        }
+        pieces.push(FormatPiece::Atom(atom));
+        rest = &rest[end + 3..];
+    }
+    literal.extend_from_slice(rest.as_bytes());
+    if !literal.is_empty() {
+        pieces.push(FormatPiece::Literal(literal));
+    }
+    Ok(pieces)
+}
+
+/// Quote a path the way git does when `core.quotePath` is on: paths with
+/// control characters, quotes, backslashes or non-ASCII bytes are wrapped in
+/// double quotes with C-style escapes.
+pub fn quote_path(path: &[u8]) -> Vec<u8> {
+    let needs_quote = |c: u8| !(0x20..0x7f).contains(&c) || c == b'"' || c == b'\\';
+    if !path.iter().any(|c| needs_quote(*c)) {
+        return path.to_vec();
+    }
+    let mut quoted = vec![b'"'];
+    for &c in path {
+        match c {
+            b'\x07' => quoted.extend_from_slice(b"\\a"),
+            b'\x08' => quoted.extend_from_slice(b"\\b"),
+            b'\t' => quoted.extend_from_slice(b"\\t"),
+            b'\n' => quoted.extend_from_slice(b"\\n"),
+            b'\x0b' => quoted.extend_from_slice(b"\\v"),
+            b'\x0c' => quoted.extend_from_slice(b"\\f"),
+            b'\r' => quoted.extend_from_slice(b"\\r"),
+            b'"' => quoted.extend_from_slice(b"\\\""),
+            b'\\' => quoted.extend_from_slice(b"\\\\"),
+            c if needs_quote(c) => quoted.extend_from_slice(format!("\\{c:03o}").as_bytes()),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push(b'"');
+    quoted
+}
@@ -0,0 +1,578 @@
+//! `fsck`: verify every object in the repository, the refs pointing into it,
+//! and that everything reachable from those refs is present.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::object::{parse_tree, tree_entry_cmp, validate_object, ObjectFormat, ObjectType};
+use crate::odb::ObjectDatabase;
+use crate::pack::{self, Pack};
+use crate::repository::Repository;
+
+// exit status bits, as in git
+const ERROR_OBJECT: i32 = 0o1;
+const ERROR_REACHABLE: i32 = 0o2;
+const ERROR_PACK: i32 = 0o4;
+const ERROR_REFS: i32 = 0o10;
+
+pub struct FsckOptions {
+    /// Only check that reachable objects are present, not their content.
+    pub connectivity_only: bool,
+    /// Print every unreachable object, not just the dangling ones.
+    pub unreachable: bool,
+    /// Write dangling objects to `.git/lost-found`.
+    pub lost_found: bool,
+}
+
+/// An object that was found, with the objects it points at and the type each
+/// of them must have.
+struct Node {
+    kind: ObjectType,
+    links: Vec<(ObjectType, String)>,
+    /// Found in a pack from a promisor remote, which may lack what it points
+    /// at.
+    promisor: bool,
+}
+
+#[derive(Default)]
+struct Fsck {
+    format: ObjectFormat,
+    /// `core.deltaBaseCacheLimit`, for the packs checked.
+    cache_limit: usize,
+    objects: HashMap<String, Node>,
+    errors: i32,
+}
+
+impl Fsck {
+    fn error(&mut self, bit: i32, msg: impl std::fmt::Display) {
+        eprintln!("error: {msg}");
+        self.errors |= bit;
+    }
+
+    /// Check the content of an object and record it with its links.
+    fn add(&mut self, oid: &str, kind: ObjectType, content: &[u8], check: bool) {
+        if check {
+            for problem in check_object(kind, content, self.format) {
+                eprintln!("error in {kind} {oid}: {problem}");
+                self.errors |= ERROR_OBJECT;
+            }
+        }
+        let mut links = links(kind, content, self.format);
+        let mut seen = HashSet::new();
+        links.retain(|(_, target)| seen.insert(target.clone()));
+        self.objects.insert(
+            oid.to_string(),
+            Node {
+                kind,
+                links,
+                promisor: false,
+            },
+        );
+    }
+
+    fn loose_objects(
+        &mut self,
+        odb: &ObjectDatabase,
+        opts: &FsckOptions,
+    ) -> Result<(), anyhow::Error> {
+        let objects_dir = odb.dir();
+        let mut dirs = std::fs::read_dir(objects_dir)
+            .context(format!("read {:?}", objects_dir))?
+            .collect::<Result<Vec<_>, _>>()?;
+        dirs.sort_by_key(|d| d.file_name());
+        for dir in dirs {
+            let prefix = dir.file_name();
+            let Some(prefix) = prefix.to_str().filter(|p| is_hex(p, 2)) else {
+                continue;
+            };
+            for entry in std::fs::read_dir(dir.path())? {
+                let name = entry?.file_name();
+                let rest_len = self.format.hex_len() - 2;
+                let Some(rest) = name.to_str().filter(|n| is_hex(n, rest_len)) else {
+                    // leftovers from interrupted writes are not objects
+                    continue;
+                };
+                let oid = format!("{prefix}{rest}");
+                let obj = match odb.open(&oid) {
+                    Ok(obj) if opts.connectivity_only && obj.kind == ObjectType::Blob => {
+                        self.add(&oid, obj.kind, b"", false);
+                        continue;
+                    }
+                    Ok(obj) => obj.read_all(),
+                    Err(e) => Err(e),
+                };
+                match obj {
+                    Ok(obj) => self.add(&oid, obj.kind, &obj.content, !opts.connectivity_only),
+                    Err(e) => self.error(ERROR_OBJECT, e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn pack(&mut self, idx_path: &Path, opts: &FsckOptions) {
+        let pack = match Pack::open(idx_path, self.format, self.cache_limit) {
+            Ok(pack) => pack,
+            Err(e) => {
+                self.error(ERROR_PACK, format!("{e:#}"));
+                return;
+            }
+        };
+        let name = pack.path.display().to_string();
+        let promisor = idx_path.with_extension("promisor").is_file();
+        if !opts.connectivity_only {
+            if !pack.index.checksum_ok {
+                self.error(ERROR_PACK, format!("{name}: index checksum mismatch"));
+            }
+            match pack.checksum_ok() {
+                Ok(true) => {}
+                Ok(false) => self.error(ERROR_PACK, format!("{name}: pack checksum mismatch")),
+                Err(e) => self.error(ERROR_PACK, format!("{name}: {e}")),
+            }
+            if pack.index.pack_checksum != pack.trailer() {
+                self.error(ERROR_PACK, format!("{name}: index is for a different pack"));
+            }
+            if pack.object_count() as usize != pack.index.entries.len() {
+                self.error(
+                    ERROR_PACK,
+                    format!(
+                        "{name}: pack has {} objects but its index has {}",
+                        pack.object_count(),
+                        pack.index.entries.len()
+                    ),
+                );
+            }
+        }
+
+        let mut entries = pack.index.entries.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|e| e.offset);
+        for entry in entries {
+            if let (Some(crc), false) = (entry.crc32, opts.connectivity_only) {
+                let mut actual = flate2::Crc::new();
+                match pack.raw_entry(entry.offset) {
+                    Ok(raw) => actual.update(&raw),
+                    Err(e) => {
+                        self.error(ERROR_PACK, format!("{name}: {}: {e:#}", entry.oid));
+                        continue;
+                    }
+                }
+                if actual.sum() != crc {
+                    self.error(ERROR_PACK, format!("{name}: {}: CRC mismatch", entry.oid));
+                }
+            }
+            let (kind, content) = match pack.object_at(entry.offset) {
+                Ok(obj) => obj,
+                Err(e) => {
+                    self.error(ERROR_PACK, format!("{name}: {}: {e:#}", entry.oid));
+                    continue;
+                }
+            };
+            if !opts.connectivity_only {
+                let actual = match self.format.hash_object(&kind.to_string(), &content) {
+                    Ok(actual) => actual,
+                    Err(e) => {
+                        self.error(ERROR_PACK, format!("{name}: {}: {e}", entry.oid));
+                        continue;
+                    }
+                };
+                if actual != entry.oid {
+                    self.error(
+                        ERROR_PACK,
+                        format!("{name}: {} hashes to {actual}", entry.oid),
+                    );
+                    continue;
+                }
+            }
+            self.add(&entry.oid, kind, &content, !opts.connectivity_only);
+            if promisor {
+                if let Some(node) = self.objects.get_mut(&entry.oid) {
+                    node.promisor = true;
+                }
+            }
+        }
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Problems with an object's content, in the spirit of git's fsck messages.
+fn check_object(kind: ObjectType, content: &[u8], format: ObjectFormat) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Err(e) = validate_object(kind, content, format) {
+        problems.push(format!("{e:#}"));
+        return problems;
+    }
+    if kind != ObjectType::Tree {
+        return problems;
+    }
+    let Ok(entries) = parse_tree(content, format) else {
+        return problems;
+    };
+    for entry in &entries {
+        if !matches!(
+            entry.mode,
+            0o100644 | 0o100755 | 0o120000 | 0o040000 | 0o160000
+        ) {
+            problems.push(format!(
+                "badFilemode: {:?} has bad mode {:o}",
+                String::from_utf8_lossy(&entry.name),
+                entry.mode
+            ));
+        }
+        match entry.name.as_slice() {
+            b"." => problems.push("hasDot: contains '.'".to_string()),
+            b".." => problems.push("hasDotdot: contains '..'".to_string()),
+            name if name.eq_ignore_ascii_case(b".git") => {
+                problems.push("hasDotgit: contains '.git'".to_string())
+            }
+            _ => {}
+        }
+    }
+    // a file and a directory of the same name need not be next to each
+    // other, as in "a", "a.b", "a/": file names a later directory could
+    // repeat are kept on a stack, as git's verify_ordered does
+    let mut candidates: Vec<&[u8]> = Vec::new();
+    let mut duplicate = None;
+    for (i, entry) in entries.iter().enumerate() {
+        let name = entry.name.as_slice();
+        let is_tree = entry.kind() == ObjectType::Tree;
+        if let Some(prev) = i.checked_sub(1).map(|i| &entries[i]) {
+            if prev.name == entry.name {
+                duplicate.get_or_insert(name);
+            } else if tree_entry_cmp(&prev.name, prev.kind() == ObjectType::Tree, name, is_tree)
+                != std::cmp::Ordering::Less
+            {
+                problems.push("treeNotSorted: not properly sorted".to_string());
+            }
+        }
+        while let Some(&candidate) = candidates.last() {
+            if name == candidate && is_tree {
+                duplicate.get_or_insert(name);
+            } else if name.len() > candidate.len()
+                && name.starts_with(candidate)
+                && name[candidate.len()] < b'/'
+            {
+                // names in between, still sorting before the directory
+                break;
+            }
+            candidates.pop();
+        }
+        if !is_tree {
+            candidates.push(name);
+        }
+    }
+    if let Some(name) = duplicate {
+        problems.push(format!(
+            "duplicateEntries: contains duplicate file entries {:?}",
+            String::from_utf8_lossy(name)
+        ));
+    }
+    problems
+}
+
+/// The objects `content` refers to, with the type each must have.
+fn links(kind: ObjectType, content: &[u8], format: ObjectFormat) -> Vec<(ObjectType, String)> {
+    let header = |content: &[u8]| -> Vec<(Vec<u8>, String)> {
+        content
+            .split(|c| *c == b'\n')
+            .take_while(|l| !l.is_empty())
+            .filter_map(|l| {
+                let sp = l.iter().position(|c| *c == b' ')?;
+                Some((
+                    l[..sp].to_vec(),
+                    String::from_utf8_lossy(&l[sp + 1..]).to_string(),
+                ))
+            })
+            .collect()
+    };
+    match kind {
+        ObjectType::Blob => Vec::new(),
+        ObjectType::Tree => parse_tree(content, format)
+            .unwrap_or_default()
+            .into_iter()
+            // gitlinks point into another repository
+            .filter(|e| e.kind() != ObjectType::Commit)
+            .map(|e| (e.kind(), e.hash))
+            .collect(),
+        ObjectType::Commit => header(content)
+            .into_iter()
+            .filter_map(|(k, v)| match k.as_slice() {
+                b"tree" => Some((ObjectType::Tree, v)),
+                b"parent" => Some((ObjectType::Commit, v)),
+                _ => None,
+            })
+            .collect(),
+        ObjectType::Tag => {
+            let fields = header(content);
+            let target = fields.iter().find(|(k, _)| k == b"object");
+            let kind = fields
+                .iter()
+                .find(|(k, _)| k == b"type")
+                .and_then(|(_, v)| ObjectType::parse(v));
+            match (target, kind) {
+                (Some((_, oid)), Some(kind)) => vec![(kind, oid.clone())],
+                _ => Vec::new(),
+            }
+        }
+    }
+}
+
+/// Objects named in reflogs, which keep recently dropped commits alive.
+fn reflog_objects(git_dir: &Path, format: ObjectFormat) -> Result<Vec<String>, anyhow::Error> {
+    fn walk(dir: &Path, format: ObjectFormat, oids: &mut Vec<String>) -> Result<(), anyhow::Error> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), format, oids)?;
+                continue;
+            }
+            // each line is `<old> <new> <ident>\t<message>`
+            for line in std::fs::read_to_string(entry.path())?.lines() {
+                oids.extend(
+                    line.split(' ')
+                        .take(2)
+                        .filter(|oid| {
+                            format.is_hex_id(oid.as_bytes()) && !oid.bytes().all(|c| c == b'0')
+                        })
+                        .map(str::to_string),
+                );
+            }
+        }
+        Ok(())
+    }
+    let mut oids = Vec::new();
+    let logs = git_dir.join("logs");
+    if logs.is_dir() {
+        walk(&logs, format, &mut oids)?;
+    }
+    Ok(oids)
+}
+
+/// Check `repo`, printing what is wrong. Returns the exit status bits.
+pub fn fsck(repo: &Repository, opts: &FsckOptions) -> Result<i32, anyhow::Error> {
+    let mut fsck = Fsck {
+        format: repo.odb().format(),
+        cache_limit: repo.odb().delta_base_cache_limit(),
+        ..Fsck::default()
+    };
+    let git_dir = repo.git_dir();
+    fsck.loose_objects(repo.odb(), opts)?;
+    for idx in pack::index_files(repo.odb().dir())? {
+        fsck.pack(&idx, opts);
+    }
+
+    let mut roots = Vec::new();
+    let refs = repo.refs()?;
+    for (name, value) in &refs {
+        if value.starts_with("ref: ") {
+            // symbolic refs are checked through their target
+            continue;
+        }
+        match fsck.objects.get(value) {
+            None if fsck.format.is_hex_id(value.as_bytes()) => {
+                fsck.error(ERROR_REFS, format!("{name}: invalid sha1 pointer {value}"))
+            }
+            None => fsck.error(ERROR_REFS, format!("{name}: invalid sha1 pointer")),
+            Some(node) if name.starts_with("refs/heads/") && node.kind != ObjectType::Commit => {
+                fsck.error(ERROR_REFS, format!("{name}: not a commit"));
+                roots.push(value.clone());
+            }
+            Some(_) => roots.push(value.clone()),
+        }
+    }
+    let head = std::fs::read_to_string(git_dir.join("HEAD")).context("read HEAD")?;
+    let head = head.trim();
+    match head.strip_prefix("ref: ") {
+        Some(target) => {
+            if !refs.iter().any(|(name, _)| name == target) {
+                eprintln!("notice: HEAD points to an unborn branch ({target})");
+            }
+        }
+        None if fsck.objects.contains_key(head) => roots.push(head.to_string()),
+        None => fsck.error(ERROR_REFS, format!("HEAD: invalid sha1 pointer {head}")),
+    }
+    if refs.is_empty() {
+        eprintln!("notice: No default references");
+    }
+    // blobs staged in the index are not dangling even though no commit
+    // points at them yet
+    let staged = repo.index()?.entries.into_iter().map(|e| e.oid);
+    for oid in reflog_objects(git_dir, fsck.format)?
+        .into_iter()
+        .chain(staged)
+    {
+        if fsck.objects.contains_key(&oid) {
+            roots.push(oid);
+        }
+    }
+
+    let mut reachable = HashSet::new();
+    let mut missing = HashSet::new();
+    let mut stack = roots;
+    while let Some(oid) = stack.pop() {
+        if !reachable.insert(oid.clone()) {
+            continue;
+        }
+        let Some(node) = fsck.objects.get(&oid) else {
+            continue;
+        };
+        // the parents of a shallow commit were never fetched
+        let shallow = node.kind == ObjectType::Commit && repo.odb().is_shallow(&oid);
+        for (kind, target) in &node.links {
+            if shallow && *kind == ObjectType::Commit {
+                continue;
+            }
+            match fsck.objects.get(target) {
+                // the promisor remote has it for when it is needed
+                None if node.promisor => {}
+                None => {
+                    println!(
+                        "broken link from {:>7} {oid}\n              to {:>7} {target}",
+                        node.kind.to_string(),
+                        kind.to_string()
+                    );
+                    if missing.insert(target.clone()) {
+                        println!("missing {kind} {target}");
+                    }
+                    fsck.errors |= ERROR_REACHABLE;
+                }
+                Some(t) if t.kind != *kind => {
+                    eprintln!(
+                        "error: {} {oid}: {target} is a {}, not a {kind}",
+                        node.kind, t.kind
+                    );
+                    fsck.errors |= ERROR_OBJECT;
+                }
+                Some(_) => stack.push(target.clone()),
+            }
+        }
+    }
+
+    let referenced = fsck
+        .objects
+        .values()
+        .flat_map(|n| n.links.iter().map(|(_, oid)| oid.as_str()))
+        .collect::<HashSet<_>>();
+    let mut unreachable = fsck
+        .objects
+        .iter()
+        .filter(|(oid, _)| !reachable.contains(*oid))
+        .collect::<Vec<_>>();
+    unreachable.sort_by_key(|(oid, _)| oid.as_str());
+    for (oid, node) in unreachable {
+        let dangling = !referenced.contains(oid.as_str());
+        if opts.unreachable {
+            println!("unreachable {} {oid}", node.kind);
+        } else if dangling {
+            println!("dangling {} {oid}", node.kind);
+        }
+        if dangling && opts.lost_found {
+            lost_found(repo, oid, node.kind)?;
+        }
+    }
+    Ok(fsck.errors)
+}
+
+/// Save a dangling object under `.git/lost-found`: commits go to `commit/`,
+/// everything else to `other/`. Blobs are written out with their content,
+/// other objects as their id.
+fn lost_found(repo: &Repository, oid: &str, kind: ObjectType) -> Result<(), anyhow::Error> {
+    let dir = repo
+        .git_dir()
+        .join("lost-found")
+        .join(if kind == ObjectType::Commit {
+            "commit"
+        } else {
+            "other"
+        });
+    std::fs::create_dir_all(&dir)?;
+    let content = if kind == ObjectType::Blob {
+        repo.odb().read(oid)?.content
+    } else {
+        format!("{oid}\n").into_bytes()
+    };
+    std::fs::write(dir.join(oid), content).context(format!("write lost-found {oid}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tree holding `entries` of (mode, name), in the order given.
+    fn tree(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut tree = Vec::new();
+        for (i, (mode, name)) in entries.iter().enumerate() {
+            tree.extend_from_slice(format!("{mode} {name}\0").as_bytes());
+            tree.extend_from_slice(&[i as u8 + 1; 20]);
+        }
+        tree
+    }
+
+    fn problems(entries: &[(&str, &str)]) -> Vec<String> {
+        check_object(ObjectType::Tree, &tree(entries), ObjectFormat::Sha1)
+    }
+
+    #[test]
+    fn sorted_tree_is_clean() {
+        // a directory sorts as if its name ended in '/'
+        let entries = [("100644", "a.b"), ("40000", "a"), ("100755", "a0")];
+        assert_eq!(problems(&entries), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unsorted_tree() {
+        let entries = [("100644", "b"), ("100644", "a")];
+        assert_eq!(problems(&entries), ["treeNotSorted: not properly sorted"]);
+        let entries = [("40000", "a"), ("100644", "a.b")];
+        assert_eq!(problems(&entries), ["treeNotSorted: not properly sorted"]);
+    }
+
+    #[test]
+    fn duplicate_entries() {
+        let duplicate = ["duplicateEntries: contains duplicate file entries \"a\""];
+        let entries = [("100644", "a"), ("100644", "a")];
+        assert_eq!(problems(&entries), duplicate);
+        let entries = [("100644", "a"), ("40000", "a")];
+        assert_eq!(problems(&entries), duplicate);
+        // the file and the directory are apart, but still the same name
+        let entries = [("100644", "a"), ("100755", "a.b"), ("40000", "a")];
+        assert_eq!(problems(&entries), duplicate);
+        let entries = [
+            ("100644", "a"),
+            ("100644", "a!"),
+            ("100644", "a.b"),
+            ("40000", "a.b"),
+            ("40000", "a"),
+        ];
+        assert_eq!(
+            problems(&entries),
+            ["duplicateEntries: contains duplicate file entries \"a.b\""]
+        );
+    }
+
+    #[test]
+    fn bad_modes() {
+        let entries = [("100664", "a"), ("120000", "b"), ("160000", "c")];
+        assert_eq!(
+            problems(&entries),
+            ["badFilemode: \"a\" has bad mode 100664"]
+        );
+    }
+
+    #[test]
+    fn dot_names() {
+        let entries = [("100644", ".GIT"), ("40000", "..")];
+        assert_eq!(
+            problems(&entries),
+            [
+                "hasDotgit: contains '.git'",
+                "hasDotdot: contains '..'",
+                "treeNotSorted: not properly sorted"
+            ]
+        );
+    }
+}
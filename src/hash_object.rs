@@ -0,0 +1,105 @@
+//! `hash-object`: compute the id an object would have, and optionally write
+//! it to the object database.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::convert::{Filters, TextAttr};
+use crate::object::validate_object;
+use crate::odb::ObjectDatabase;
+use crate::repository::Repository;
+use crate::worktree;
+use crate::ObjectType;
+
+pub struct HashObjectOptions {
+    /// The type to hash the data as; any string at all with `literally`.
+    pub object_type: String,
+    /// Write the objects to the database too.
+    pub write: bool,
+    /// Skip checking that the data is a well-formed object of its type.
+    pub literally: bool,
+    /// Hash the data as it is, without the clean filters and line ending
+    /// conversion the attributes ask for.
+    pub no_filters: bool,
+}
+
+/// Hashes data, or files, as `opts` describes.
+pub struct ObjectHasher<'a> {
+    repo: Option<&'a Repository>,
+    odb: &'a ObjectDatabase,
+    filters: Option<Filters<'a>>,
+    kind: Option<ObjectType>,
+    opts: &'a HashObjectOptions,
+}
+
+impl<'a> ObjectHasher<'a> {
+    /// Hash into `odb`, which is `repo`'s unless there is no repository;
+    /// without one there are no attributes, so no filters either.
+    pub fn new(
+        repo: Option<&'a Repository>,
+        odb: &'a ObjectDatabase,
+        opts: &'a HashObjectOptions,
+    ) -> Result<Self, anyhow::Error> {
+        let kind = ObjectType::parse(&opts.object_type);
+        if kind.is_none() && !opts.literally {
+            anyhow::bail!("invalid object type \"{}\"", opts.object_type);
+        }
+        let filters = repo
+            .filter(|_| !opts.no_filters)
+            .map(|repo| Filters::new(repo.work_tree(), repo.git_dir(), repo.config()));
+        Ok(ObjectHasher {
+            repo,
+            odb,
+            filters,
+            kind,
+            opts,
+        })
+    }
+
+    /// `path` as attribute patterns match it: from the top of the work tree.
+    fn repo_path(&self, path: &str) -> String {
+        self.repo
+            .and_then(|repo| repo.work_tree_path(Path::new(path)))
+            .unwrap_or_else(|| path.to_string())
+    }
+
+    /// Hash `data`, filtered as a blob at `filter_path` would be if given.
+    pub fn hash(&self, data: Vec<u8>, filter_path: Option<&str>) -> Result<String, anyhow::Error> {
+        let data = match (filter_path, &self.filters) {
+            (Some(p), Some(filters)) if self.kind == Some(ObjectType::Blob) => {
+                filters.convert_to_git(&self.repo_path(p), data)?
+            }
+            _ => data,
+        };
+        if let (Some(kind), false) = (self.kind, self.opts.literally) {
+            validate_object(kind, &data, self.odb.format())?;
+        }
+        if self.opts.write {
+            self.odb.write(&self.opts.object_type, &data)
+        } else {
+            Ok(self
+                .odb
+                .format()
+                .hash_object(&self.opts.object_type, &data)?)
+        }
+    }
+
+    /// Hash the contents of `file`, filtered as a blob at `filter_path`.
+    pub fn hash_file(&self, file: &str, filter_path: &str) -> Result<String, anyhow::Error> {
+        // content that no filter touches can be streamed from disk
+        let unfiltered = match &self.filters {
+            Some(filters) => matches!(
+                filters.eol_conversion(&self.repo_path(filter_path))?,
+                None | Some(TextAttr::Binary)
+            ),
+            None => true,
+        };
+        if self.kind == Some(ObjectType::Blob) && unfiltered {
+            return worktree::hash_file(self.odb, Path::new(file), self.opts.write);
+        }
+        let data = fs::read(file).context(format!("read {file}"))?;
+        self.hash(data, Some(filter_path))
+    }
+}
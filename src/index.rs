@@ -0,0 +1,116 @@
+//! Reading the index (`.git/index`), see gitformat-index(5).
+
+use std::path::Path;
+
+use crate::object::ObjectFormat;
+
+/// A staged file.
+pub struct Entry {
+    pub mode: u32,
+    pub oid: String,
+    /// 0 normally; 1-3 for the sides of an unresolved merge conflict.
+    pub stage: u8,
+    pub path: Vec<u8>,
+}
+
+pub struct Index {
+    pub version: u32,
+    pub entries: Vec<Entry>,
+}
+
+impl Index {
+    /// Read the index at `path`; a missing file is an empty index.
+    pub fn read(path: &Path, format: ObjectFormat) -> Result<Self, anyhow::Error> {
+        match std::fs::read(path) {
+            Ok(data) => Self::parse(&data, format),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Index {
+                version: 2,
+                entries: Vec::new(),
+            }),
+            Err(e) => Err(anyhow::Error::new(e).context(format!("read {:?}", path))),
+        }
+    }
+
+    pub fn parse(data: &[u8], format: ObjectFormat) -> Result<Self, anyhow::Error> {
+        let hash_len = format.raw_len();
+        let truncated = || anyhow::anyhow!("index file is truncated");
+        let be32 = |at: usize| -> Option<u32> {
+            let b = data.get(at..at + 4)?;
+            Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let (Some(version), Some(count)) = (be32(4), be32(8)) else {
+            return Err(truncated());
+        };
+        if !data.starts_with(b"DIRC") {
+            anyhow::bail!("index file has a bad signature");
+        }
+        if !(2..=4).contains(&version) {
+            anyhow::bail!("unsupported index version {version}");
+        }
+        if data.len() < 12 + hash_len {
+            return Err(truncated());
+        }
+        let (body, trailer) = data.split_at(data.len() - hash_len);
+        // index.skipHash leaves the trailer zeroed
+        if trailer.iter().any(|c| *c != 0) && format.digest(body)? != trailer {
+            anyhow::bail!("index file checksum mismatch");
+        }
+
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut at = 12;
+        let mut prev_path = Vec::new();
+        for _ in 0..count {
+            // 40 bytes of stat data, the object id, then 16 bits of flags
+            let mut len = 40 + hash_len + 2;
+            let entry = body.get(at..at + len).ok_or_else(truncated)?;
+            let mode = be32(at + 24).ok_or_else(truncated)?;
+            let oid = hex::encode(&entry[40..40 + hash_len]);
+            let flags = u16::from_be_bytes([entry[len - 2], entry[len - 1]]);
+            if version >= 3 && flags & 0x4000 != 0 {
+                len += 2;
+            }
+            let path = if version == 4 {
+                // the path is stored as the number of bytes to drop from the
+                // previous path, then the new suffix
+                let mut i = at + len;
+                let mut c = *body.get(i).ok_or_else(truncated)?;
+                i += 1;
+                let mut strip = (c & 0x7f) as usize;
+                while c & 0x80 != 0 {
+                    c = *body.get(i).ok_or_else(truncated)?;
+                    i += 1;
+                    strip = ((strip + 1) << 7) | (c & 0x7f) as usize;
+                }
+                let keep = prev_path
+                    .len()
+                    .checked_sub(strip)
+                    .ok_or_else(|| anyhow::anyhow!("bad path compression in index"))?;
+                let suffix = body
+                    .get(i..)
+                    .and_then(|rest| rest.iter().position(|c| *c == 0))
+                    .ok_or_else(truncated)?;
+                let mut path = prev_path[..keep].to_vec();
+                path.extend_from_slice(&body[i..i + suffix]);
+                at = i + suffix + 1;
+                path
+            } else {
+                let name = body
+                    .get(at + len..)
+                    .and_then(|rest| rest.iter().position(|c| *c == 0))
+                    .ok_or_else(truncated)?;
+                let path = body[at + len..at + len + name].to_vec();
+                // entries are NUL-padded to a multiple of eight bytes
+                at += (len + name + 8) & !7;
+                path
+            };
+            prev_path.clone_from(&path);
+            entries.push(Entry {
+                mode,
+                oid,
+                stage: ((flags >> 12) & 3) as u8,
+                path,
+            });
+        }
+        Ok(Index { version, entries })
+    }
+}
@@ -0,0 +1,44 @@
+//! A small implementation of git, usable as a library.
+//!
+//! [`Repository`] is the entry point: it opens, discovers or creates a
+//! repository and gives access to its config, object database, refs and
+//! index. [`transport`] talks to remotes over the smart HTTP protocol, and
+//! [`remote`] builds fetch, clone and push on top of it.
+//!
+//! ```no_run
+//! use codecrafters_git::{ObjectType, Repository};
+//!
+//! let repo = Repository::discover(std::path::Path::new("."))?;
+//! if let Some(oid) = repo.resolve("HEAD")? {
+//!     let commit = repo.odb().read(&oid)?;
+//!     let tree = repo.odb().peel(commit, ObjectType::Tree)?;
+//!     println!("{} bytes of tree", tree.content.len());
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod cat_file;
+pub mod config;
+pub mod convert;
+pub mod delta;
+pub mod error;
+pub mod format;
+pub mod fsck;
+pub mod hash_object;
+pub mod index;
+pub mod ls_tree;
+pub mod object;
+pub mod odb;
+pub mod pack;
+pub mod progress;
+pub mod refs;
+pub mod remote;
+pub mod repository;
+pub mod revwalk;
+pub mod shallow;
+pub mod transport;
+pub mod worktree;
+
+pub use error::Error;
+pub use object::{GitObject, ObjectType};
+pub use repository::Repository;
@@ -0,0 +1,247 @@
+//! `ls-tree`: list the contents of a tree.
+
+use std::io::Write;
+
+use crate::format::{quote_path, FormatPiece};
+use crate::object::parse_tree;
+use crate::odb::ObjectDatabase;
+use crate::{GitObject, ObjectType};
+
+/// A `%(atom)` of an `ls-tree --format` string.
+pub enum LsTreeAtom {
+    ObjectMode,
+    ObjectType,
+    ObjectName,
+    ObjectSize,
+    ObjectSizePadded,
+    Path,
+}
+
+impl LsTreeAtom {
+    /// The atom called `name`, as [`parse_format`](crate::format::parse_format)
+    /// looks them up.
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "objectmode" => LsTreeAtom::ObjectMode,
+            "objecttype" => LsTreeAtom::ObjectType,
+            "objectname" => LsTreeAtom::ObjectName,
+            "objectsize" => LsTreeAtom::ObjectSize,
+            "objectsize:padded" => LsTreeAtom::ObjectSizePadded,
+            "path" => LsTreeAtom::Path,
+            _ => return None,
+        })
+    }
+}
+
+pub struct LsTreeOptions {
+    /// Descend into subtrees.
+    pub recursive: bool,
+    /// Show the trees descended into as well.
+    pub show_trees: bool,
+    /// Show trees only, not blobs.
+    pub trees_only: bool,
+    /// End lines with NUL and do not quote paths.
+    pub nul: bool,
+    pub format: Vec<FormatPiece<LsTreeAtom>>,
+    /// Only show these paths and what is below them; all when empty.
+    pub paths: Vec<Vec<u8>>,
+    /// Show paths relative to this directory rather than to the top.
+    pub prefix: Vec<u8>,
+}
+
+/// `path` is `spec` itself or lives below it. The empty spec is the top of
+/// the tree and matches everything.
+fn pathspec_matches(path: &[u8], spec: &[u8]) -> bool {
+    spec.is_empty()
+        || path.starts_with(spec)
+            && (path.len() == spec.len() || spec.ends_with(b"/") || path[spec.len()] == b'/')
+}
+
+/// `spec` names something strictly below the directory `path`.
+fn pathspec_below(path: &[u8], spec: &[u8]) -> bool {
+    spec.len() > path.len() && spec.starts_with(path) && spec[path.len()] == b'/'
+}
+
+/// `path` as seen from the directory `prefix`, both relative to the top.
+fn relative_path(path: &[u8], prefix: &[u8]) -> Vec<u8> {
+    if prefix.is_empty() {
+        return path.to_vec();
+    }
+    let prefix = prefix.split(|c| *c == b'/').collect::<Vec<_>>();
+    let parts = path.split(|c| *c == b'/').collect::<Vec<_>>();
+    let common = prefix
+        .iter()
+        .zip(&parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut out = b"../".repeat(prefix.len() - common);
+    out.extend_from_slice(&parts[common..].join(&b'/'));
+    out
+}
+
+/// List the entries of `tree` on `out` as `git ls-tree` does.
+pub fn ls_tree(
+    odb: &ObjectDatabase,
+    tree: &GitObject,
+    opts: &LsTreeOptions,
+    out: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    list(odb, tree, b"", opts, out)
+}
+
+/// List the entries of `tree`, found at `base`.
+fn list(
+    odb: &ObjectDatabase,
+    tree: &GitObject,
+    base: &[u8],
+    opts: &LsTreeOptions,
+    out: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    for entry in parse_tree(&tree.content, odb.format())? {
+        let mut path = base.to_vec();
+        path.extend_from_slice(&entry.name);
+        let matches =
+            opts.paths.is_empty() || opts.paths.iter().any(|s| pathspec_matches(&path, s));
+        let below = opts.paths.iter().any(|s| pathspec_below(&path, s));
+
+        let kind = entry.kind();
+        let recurse = kind == ObjectType::Tree && (below || (opts.recursive && matches));
+        let show = if recurse {
+            opts.show_trees
+        } else {
+            matches && (kind != ObjectType::Blob || !opts.trees_only)
+        };
+
+        if show {
+            for piece in &opts.format {
+                let atom = match piece {
+                    FormatPiece::Literal(s) => {
+                        out.write_all(s)?;
+                        continue;
+                    }
+                    FormatPiece::Atom(atom) => atom,
+                };
+                match atom {
+                    LsTreeAtom::ObjectMode => write!(out, "{:06o}", entry.mode)?,
+                    LsTreeAtom::ObjectType => write!(out, "{kind}")?,
+                    LsTreeAtom::ObjectName => write!(out, "{}", entry.hash)?,
+                    LsTreeAtom::ObjectSize | LsTreeAtom::ObjectSizePadded => {
+                        let size = if kind == ObjectType::Blob {
+                            odb.header(&entry.hash)?.1.to_string()
+                        } else {
+                            "-".to_string()
+                        };
+                        if matches!(atom, LsTreeAtom::ObjectSizePadded) {
+                            write!(out, "{size:>7}")?
+                        } else {
+                            write!(out, "{size}")?
+                        }
+                    }
+                    LsTreeAtom::Path => {
+                        let path = relative_path(&path, &opts.prefix);
+                        if opts.nul {
+                            out.write_all(&path)?
+                        } else {
+                            out.write_all(&quote_path(&path))?
+                        }
+                    }
+                }
+            }
+            out.write_all(if opts.nul { b"\0" } else { b"\n" })?;
+        }
+
+        if recurse {
+            path.push(b'/');
+            let subtree = odb.read(&entry.hash)?;
+            list(odb, &subtree, &path, opts, out)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::format::parse_format;
+    use crate::object::ObjectFormat;
+
+    fn tree(entries: &[(&str, &[u8], u8)]) -> GitObject {
+        let mut content = Vec::new();
+        for (mode, name, id) in entries {
+            content.extend_from_slice(format!("{mode} ").as_bytes());
+            content.extend_from_slice(name);
+            content.push(0);
+            content.extend_from_slice(&[*id; 20]);
+        }
+        GitObject {
+            kind: ObjectType::Tree,
+            content,
+        }
+    }
+
+    fn ls(tree: &GitObject, format: &str, trees_only: bool, paths: &[&str]) -> String {
+        let opts = LsTreeOptions {
+            recursive: false,
+            show_trees: false,
+            trees_only,
+            nul: false,
+            format: parse_format(format, LsTreeAtom::parse).unwrap(),
+            paths: paths.iter().map(|p| p.as_bytes().to_vec()).collect(),
+            prefix: Vec::new(),
+        };
+        // nothing is listed that needs an object to be read
+        let odb =
+            ObjectDatabase::new("/nonexistent", ObjectFormat::Sha1, &Config::default()).unwrap();
+        let mut out = Vec::new();
+        ls_tree(&odb, tree, &opts, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn ls_tree_types_come_from_modes() {
+        // none of these objects exist, and listing must not need them
+        let tree = tree(&[
+            ("100755", b"run", 1),
+            ("120000", b"link", 2),
+            ("160000", b"sub", 3),
+            ("40000", b"dir", 4),
+        ]);
+        let ids: Vec<String> = (1..=4u8).map(|id| hex::encode([id; 20])).collect();
+        assert_eq!(
+            ls(&tree, "%(objectmode) %(objecttype) %(objectname)%x09%(path)", false, &[]),
+            format!(
+                "100755 blob {}\trun\n120000 blob {}\tlink\n160000 commit {}\tsub\n040000 tree {}\tdir\n",
+                ids[0], ids[1], ids[2], ids[3]
+            )
+        );
+        assert_eq!(ls(&tree, "%(path)", true, &[]), "sub\ndir\n");
+    }
+
+    #[test]
+    fn ls_tree_filters_and_quotes_paths() {
+        let tree = tree(&[
+            ("100644", b"a\tb", 1),
+            ("100644", b"caf\xc3\xa9", 2),
+            ("100644", b"dir", 3),
+            ("100644", b"dirt", 4),
+        ]);
+        assert_eq!(
+            ls(&tree, "%(path)", false, &[]),
+            "\"a\\tb\"\n\"caf\\303\\251\"\ndir\ndirt\n"
+        );
+        assert_eq!(ls(&tree, "%(path)", false, &["dir"]), "dir\n");
+        assert_eq!(ls(&tree, "%(path)", false, &["dirt", "nope"]), "dirt\n");
+        assert!(pathspec_matches(b"dir/file", b"dir/"));
+        assert!(pathspec_below(b"dir", b"dir/file"));
+        assert!(!pathspec_below(b"dir", b"dirt/file"));
+    }
+
+    #[test]
+    fn paths_relative_to_prefix() {
+        assert_eq!(relative_path(b"a/b/c", b""), b"a/b/c");
+        assert_eq!(relative_path(b"a/b/c", b"a/b"), b"c");
+        assert_eq!(relative_path(b"a/x", b"a/b"), b"../x");
+        assert_eq!(relative_path(b"top", b"a/b"), b"../../top");
+    }
+}
@@ -1,47 +1,204 @@
-#[allow(unused_imports)]
-use sha1::{Digest, Sha1};
-use std::collections::{self, HashMap};
-use std::fs::{self};
-use std::{
-    io::{BufRead, Read, Write},
-    path::{Path, PathBuf},
-};
+use std::fs;
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use clap::Parser;
 use clap::Subcommand;
-use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use codecrafters_git::cat_file::{self, BatchMode};
+use codecrafters_git::config::Config;
+use codecrafters_git::format::parse_format;
+use codecrafters_git::hash_object::{HashObjectOptions, ObjectHasher};
+use codecrafters_git::ls_tree::{self, LsTreeAtom, LsTreeOptions};
+use codecrafters_git::object::ObjectFormat;
+use codecrafters_git::odb::{self, ObjectDatabase};
+use codecrafters_git::pack::{self, PackWriteOptions};
+use codecrafters_git::remote::{self, PushStatus, Refspec, Remote, UpdateStatus};
+use codecrafters_git::repository::{DiscoverOptions, InitOptions, SharedRepository};
+use codecrafters_git::revwalk;
+use codecrafters_git::transport::{
+    Deepen, FetchOptions, Lease, ObjectFilter, PushOptions, TagMode,
+};
+use codecrafters_git::{error, fsck, transport, worktree};
+use codecrafters_git::{Error, ObjectType, Repository};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// Run as if started in <path>; each -C is relative to the previous one
+    #[arg(short = 'C', value_name = "path")]
+    directories: Vec<PathBuf>,
+
+    /// Use this git directory instead of searching for one (overrides GIT_DIR)
+    #[arg(long, value_name = "path")]
+    git_dir: Option<PathBuf>,
+
+    /// Use this work tree (overrides GIT_WORK_TREE and core.worktree)
+    #[arg(long, value_name = "path")]
+    work_tree: Option<PathBuf>,
+
+    /// zlib compression level (-1 for the zlib default, 0-9) for every object
+    /// written, overriding core.compression, core.looseCompression and
+    /// pack.compression
+    #[arg(long, global = true, allow_negative_numbers = true)]
+    compression: Option<i32>,
+
+    /// Do not check that objects hash to their name when reading them
+    #[arg(long, global = true)]
+    no_verify_objects: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Init a git repo
-    Init,
+    /// Create an empty repository or reinitialize an existing one
+    Init {
+        /// Create the repository here instead of in the current directory
+        directory: Option<PathBuf>,
+
+        /// Create a bare repository
+        #[arg(long)]
+        bare: bool,
+
+        /// Point HEAD at this branch instead of init.defaultBranch or main
+        #[arg(short = 'b', long)]
+        initial_branch: Option<String>,
+
+        /// Copy files from this directory into the new repository
+        #[arg(long, value_name = "template-directory")]
+        template: Option<PathBuf>,
+
+        /// The hash function objects are named with: sha1 or sha256
+        #[arg(long, value_name = "format")]
+        object_format: Option<String>,
+
+        /// Share the repository with a group: umask (false), group (true),
+        /// all (world, everybody) or an octal file mode
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "group")]
+        shared: Option<String>,
+
+        /// Only print errors and warnings
+        #[arg(short, long)]
+        quiet: bool,
+    },
     /// Cat-File
     CatFile {
-        #[arg(short = 'p', group = "type")]
-        pretty_print: bool, // TODO:  exclusive with -e -t -s
+        /// Pretty-print the contents of <object> based on its type
+        #[arg(short = 'p', group = "mode")]
+        pretty_print: bool,
 
-        object: String,
+        /// Show the object type identified by <object>
+        #[arg(short = 't', group = "mode")]
+        show_type: bool,
+
+        /// Show the object size identified by <object>
+        #[arg(short = 's', group = "mode")]
+        show_size: bool,
+
+        /// Exit with zero status if <object> exists and is a valid object
+        #[arg(short = 'e', group = "mode")]
+        exists: bool,
+
+        /// Print object information and contents for each object read from stdin
+        #[arg(long, group = "mode", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+        batch: Option<String>,
+
+        /// Print object information for each object read from stdin
+        #[arg(long, group = "mode", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+        batch_check: Option<String>,
+
+        /// Read `contents <object>`, `info <object>` and `flush` commands from stdin
+        #[arg(long, group = "mode", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+        batch_command: Option<String>,
+
+        /// Only flush the batch output at the end of input (or on `flush`)
+        #[arg(long)]
+        buffer: bool,
+
+        /// `<object>`, or `<type> <object>` when no mode flag is given
+        #[arg(num_args = 0..=2)]
+        args: Vec<String>,
     },
     HashObject {
         #[arg(short = 'w')]
         write_object: bool,
 
-        filename: String,
+        /// Type of object to create
+        #[arg(short = 't', default_value = "blob")]
+        object_type: String,
+
+        /// Read the object from standard input instead of from a file
+        #[arg(long)]
+        stdin: bool,
+
+        /// Read file names from standard input, one per line
+        #[arg(long, conflicts_with_all = ["stdin", "path", "files"])]
+        stdin_paths: bool,
+
+        /// Allow any garbage to be hashed as an object of any type
+        #[arg(long)]
+        literally: bool,
+
+        /// Hash the object as if it were located at <path>, for choosing filters
+        #[arg(long)]
+        path: Option<String>,
+
+        /// Hash the contents as is, ignoring any input filter
+        #[arg(long, conflicts_with = "path")]
+        no_filters: bool,
+
+        files: Vec<String>,
     },
     LsTree {
-        #[arg(long = "name-only")]
+        /// Recurse into sub-trees
+        #[arg(short = 'r')]
+        recursive: bool,
+
+        /// Show tree entries even when going to recurse them
+        #[arg(short = 't')]
+        show_trees: bool,
+
+        /// Show only the named tree entries, not their children
+        #[arg(short = 'd')]
+        trees_only: bool,
+
+        /// Show the object size of blob entries
+        #[arg(short = 'l', long = "long", group = "output")]
+        long: bool,
+
+        /// Show only the path of each entry
+        #[arg(long = "name-only", visible_alias = "name-status", group = "output")]
         name_only: bool,
 
+        /// Show only the object name of each entry
+        #[arg(long = "object-only", group = "output")]
+        object_only: bool,
+
+        /// Show path names relative to the top of the work tree rather than to
+        /// the current directory
+        #[arg(long = "full-name")]
+        full_name: bool,
+
+        /// Do not limit the listing to the current directory; implies --full-name
+        #[arg(long = "full-tree")]
+        full_tree: bool,
+
+        /// \0 line termination on output and do not quote paths
+        #[arg(short = 'z')]
+        nul: bool,
+
+        /// Pretty-print each entry with %(objectmode), %(objecttype),
+        /// %(objectname), %(objectsize), %(objectsize:padded) and %(path)
+        #[arg(long, group = "output")]
+        format: Option<String>,
+
         object: String,
+
+        /// Only show entries matching these paths
+        paths: Vec<String>,
     },
     WriteTree,
     CommitTree {
@@ -53,240 +210,543 @@ enum Commands {
         #[arg(short = 'p')]
         parent: String,
     },
+    /// Copy a repository into a new directory, with an origin remote
     Clone {
         git_url: String,
-        dir: String,
+
+        /// Where to clone to; defaults to the last part of the URL
+        dir: Option<PathBuf>,
+
+        /// Do not report progress
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Report progress even when stderr is not a terminal
+        #[arg(long)]
+        progress: bool,
+
+        /// Check out this branch, or tag, instead of the remote's HEAD
+        #[arg(short, long)]
+        branch: Option<String>,
+
+        /// Only fetch the branch that is checked out
+        #[arg(long)]
+        single_branch: bool,
+
+        /// Do not fetch tags, now or on later fetches
+        #[arg(long)]
+        no_tags: bool,
+
+        /// Create a bare repository, with the remote's branches as its own
+        #[arg(long)]
+        bare: bool,
+
+        /// Create a bare repository holding all of the remote's refs as they
+        /// are; implies --bare
+        #[arg(long)]
+        mirror: bool,
+
+        /// Fetch the remote's whole history
+        #[arg(long, conflicts_with = "single_branch")]
+        no_single_branch: bool,
+
+        /// Make a shallow clone with this many commits of history; implies
+        /// --single-branch
+        #[arg(long)]
+        depth: Option<u32>,
+
+        /// Make a shallow clone of the commits newer than this date; implies
+        /// --single-branch
+        #[arg(long, value_name = "date")]
+        shallow_since: Option<String>,
+
+        /// Make a shallow clone leaving out the history of this remote branch
+        /// or tag; implies --single-branch
+        #[arg(long, value_name = "ref")]
+        shallow_exclude: Vec<String>,
+
+        /// Make a partial clone, leaving out objects until they are needed:
+        /// blob:none, blob:limit=<n>[kmg] or tree:<depth>
+        #[arg(long, value_name = "filter-spec")]
+        filter: Option<String>,
     },
-}
+    /// Download objects and refs from another repository
+    Fetch {
+        /// A configured remote or a URL; defaults to the current branch's
+        /// remote, or origin
+        remote: Option<String>,
 
-#[derive(Debug)]
-enum ObjectType {
-    Blob,
-    Tree,
-    Commit,
-}
+        /// Refs to fetch instead of the remote's configured ones, as
+        /// [+]<src>[:<dst>]
+        refspecs: Vec<String>,
 
-impl std::fmt::Display for ObjectType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = match self {
-            ObjectType::Blob => "blob",
-            ObjectType::Tree => "tree",
-            ObjectType::Commit => "commit",
-        };
-        write!(f, "{}", s)
-    }
-}
+        /// Do not report progress or updated refs
+        #[arg(short, long)]
+        quiet: bool,
 
-struct GitObject {
-    _size: usize,
-    kind: ObjectType,
-    content: Vec<u8>,
-}
+        /// Report progress even when stderr is not a terminal
+        #[arg(long)]
+        progress: bool,
 
-impl GitObject {
-    fn new(object: &String) -> Result<Self, anyhow::Error> {
-        let prefix = &object[..2];
-        let path = &object[2..];
-        let path = PathBuf::from(".git/objects").join(prefix).join(path);
-        let f = std::fs::File::open(&path).context(format!("read {:?} err", path))?;
-        let z = ZlibDecoder::new(f);
-        let mut reader = std::io::BufReader::new(z);
-        let mut buf = Vec::new();
-        let type_n = reader.read_until(' ' as u8, &mut buf)?;
-        assert!(type_n > 0, "we must have a type");
-        let kind = str::from_utf8(&buf).context("parse object type")?;
-        let kind = match kind {
-            "blob " => ObjectType::Blob,
-            "tree " => ObjectType::Tree,
-            "commit " => ObjectType::Commit,
-            _ => unreachable!("unsupport object type {}", kind),
-        };
-        buf.clear();
-        reader.read_until('\0' as u8, &mut buf)?;
-        let size_str = str::from_utf8(&buf[..buf.len() - 1]).context("convert size")?;
-        let size = size_str
-            .parse::<usize>()
-            .context(format!("num: {:?}", &size_str))?;
-        buf.clear();
-        let mut reader = reader.take(size as u64);
-        let content_len = reader.read_to_end(&mut buf)?;
-        assert_eq!(content_len, size as usize, "{object}");
-        Ok(GitObject {
-            _size: size,
-            kind,
-            content: buf,
-        })
-    }
+        /// Delete remote-tracking refs whose remote ref no longer exists
+        #[arg(short, long, overrides_with = "no_prune")]
+        prune: bool,
 
-    fn cat(&self, name_only: bool) -> Result<(), anyhow::Error> {
-        match self.kind {
-            ObjectType::Blob => {
-                print!("{}", str::from_utf8(&self.content)?)
-            }
-            ObjectType::Tree => {
-                // TODO: perf
-                let mut i = 0;
-                loop {
-                    let mut mode = Vec::with_capacity(6);
-                    while i < self.content.len() {
-                        let c = self.content[i];
-                        i += 1;
-                        if c == b' ' {
-                            break;
-                        }
-                        mode.push(c);
-                    }
-                    let mode = format!("{:0>6}", str::from_utf8(&mode)?);
-
-                    let mut name = Vec::new();
-                    while i < self.content.len() {
-                        let c = self.content[i];
-                        i += 1;
-                        if c == b'\0' {
-                            break;
-                        }
-                        name.push(c);
-                    }
-                    let name = str::from_utf8(&name)?;
-
-                    let hash = &self.content[i..i + 20];
-                    let hash = hex::encode(hash);
-                    i += 20;
-                    let item = GitObject::new(&hash).context(format!("{name}, {hash}"))?;
-                    if !name_only {
-                        println!("{} {} {}\t{}", mode, item.kind, hash, name);
-                    } else {
-                        println!("{}", name);
-                    }
-                    if i >= self.content.len() {
-                        break;
-                    }
-                }
-            }
-            ObjectType::Commit => unimplemented!("commit cannot be printed"),
-        }
-        Ok(())
-    }
-}
+        /// Do not prune, whatever the config says
+        #[arg(long)]
+        no_prune: bool,
 
-#[derive(Default)]
-struct BaseRef {
-    content: Vec<u8>,
-    otype: u8,
-}
+        /// Allow updates that are not fast-forwards
+        #[arg(short, long)]
+        force: bool,
 
-impl BaseRef {
-    fn new(content: &[u8], otype: u8) -> Self {
-        BaseRef {
-            content: content.to_vec(),
-            otype,
-        }
-    }
-}
+        /// Fetch every tag
+        #[arg(short, long, conflicts_with = "no_tags")]
+        tags: bool,
 
-fn decode_size(buf: &[u8], i: &mut usize, offset_mode: bool) -> usize {
-    let mut size = buf[*i] as usize & (if !offset_mode { 0x0f } else { 0x7f });
-    let mut shift = 4;
-    if offset_mode {
-        shift = 7;
-    }
-    while buf[*i] & 0x80 != 0 {
-        let b = buf[*i + 1] as usize;
-        size |= (b & 0x7F) << shift;
-        *i += 1;
-        shift += 7;
-    }
-    *i += 1;
-    size
-}
+        /// Fetch no tags besides those the refspecs name
+        #[arg(short, long)]
+        no_tags: bool,
 
-fn store_idx(
-    idx: &mut HashMap<String, BaseRef>,
-    otype: u8,
-    size: usize,
-    data: &Vec<u8>,
-    dir: &Path,
-) {
-    let header = match otype {
-        1 => "commit ",
-        2 => "tree ",
-        3 => "blob ",
-        _ => {
-            unimplemented!("we don't know how to deal with other types: {}", otype);
-        }
-    };
-    let mut obj = header.as_bytes().to_vec();
-    let size = Vec::from(size.to_string());
-    obj.extend_from_slice(&size);
-    obj.push(b'\0');
-    obj.extend_from_slice(&data);
-    let mut hasher = Sha1::new();
-    hasher.update(&obj);
-    let obj_hash = hasher.finalize();
-    let obj_hash = format!("{:x}", obj_hash);
-    eprintln!("{obj_hash}");
-
-    idx.insert(obj_hash.clone(), BaseRef::new(&data, otype));
-
-    let _ = write_object(dir, &obj_hash, &obj);
+        /// Allow updating the branch HEAD points at
+        #[arg(short, long)]
+        update_head_ok: bool,
+
+        /// Cut history off this many commits below each fetched tip
+        #[arg(long, conflicts_with_all = ["deepen", "unshallow"])]
+        depth: Option<u32>,
+
+        /// Fetch this many more commits of history below the current
+        /// shallow boundary
+        #[arg(long, conflicts_with = "unshallow")]
+        deepen: Option<u32>,
+
+        /// Cut history off at commits older than this date
+        #[arg(long, value_name = "date")]
+        shallow_since: Option<String>,
+
+        /// Leave out the history of this remote branch or tag
+        #[arg(long, value_name = "ref")]
+        shallow_exclude: Vec<String>,
+
+        /// Fetch the rest of history, making a shallow repository complete
+        #[arg(long)]
+        unshallow: bool,
+    },
+    /// Update remote refs along with the objects they need
+    Push {
+        /// A configured remote or a URL; defaults to the current branch's
+        /// push remote, or the one it fetches from
+        remote: Option<String>,
+
+        /// What to push where, as [+]<src>[:<dst>]; :<dst> deletes dst
+        refspecs: Vec<String>,
+
+        /// Do not report progress or updated refs
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Report progress even when stderr is not a terminal
+        #[arg(long)]
+        progress: bool,
+
+        /// Allow updates that are not fast-forwards
+        #[arg(short, long)]
+        force: bool,
+
+        /// Allow updates that are not fast-forwards of remote refs that
+        /// still hold what their remote-tracking ref (or <expect>) does
+        #[arg(
+            long,
+            value_name = "refname[:expect]",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = ""
+        )]
+        force_with_lease: Vec<String>,
+
+        /// Delete the named remote refs
+        #[arg(short, long)]
+        delete: bool,
+
+        /// Update either all refs or none
+        #[arg(long)]
+        atomic: bool,
+
+        /// Push every tag
+        #[arg(long)]
+        tags: bool,
+    },
+    /// Write a pack of the objects named on stdin
+    PackObjects {
+        /// Write <base-name>-<checksum>.pack and .idx, printing the checksum
+        #[arg(required_unless_present = "stdout", conflicts_with = "stdout")]
+        base_name: Option<PathBuf>,
+
+        /// Write the pack to stdout instead
+        #[arg(long)]
+        stdout: bool,
+
+        /// Read revisions (<rev>, ^<rev> and --not) rather than object ids
+        /// and list the objects between them as rev-list --objects does
+        #[arg(long)]
+        revs: bool,
+
+        /// How many objects to try as the delta base of each (pack.window)
+        #[arg(long)]
+        window: Option<usize>,
+
+        /// The longest delta chain to write (pack.depth)
+        #[arg(long)]
+        depth: Option<usize>,
+
+        /// Name delta bases by offset rather than by object id
+        #[arg(long)]
+        delta_base_offset: bool,
+
+        /// Compute every delta rather than copying those in existing packs
+        #[arg(long)]
+        no_reuse_delta: bool,
+
+        /// Do not report progress
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Report progress even when stderr is not a terminal
+        #[arg(long)]
+        progress: bool,
+    },
+    /// Verify the connectivity and validity of the objects in the repository
+    Fsck {
+        /// Only check that reachable objects exist, not their content
+        #[arg(long)]
+        connectivity_only: bool,
+
+        /// Print unreachable objects rather than only dangling ones
+        #[arg(long)]
+        unreachable: bool,
+
+        /// Write dangling objects into .git/lost-found/commit/ or .git/lost-found/other/
+        #[arg(long)]
+        lost_found: bool,
+    },
 }
 
-fn init_git_repo(path: &Path) -> Result<(), anyhow::Error> {
-    fs::create_dir_all(path.join(".git"))?;
-    fs::create_dir_all(path.join(".git/objects"))?;
-    fs::create_dir_all(path.join(".git/refs"))?;
-    fs::write(path.join(".git/HEAD"), "ref: refs/heads/main\n")?;
-    Ok(())
+fn main() {
+    let cli = Cli::parse();
+    if let Err(err) = run(&cli) {
+        eprintln!("fatal: {err:#}");
+        std::process::exit(error::exit_code(&err));
+    }
 }
 
-fn set_head_to_ref(path: &Path, head: &str) -> Result<(), anyhow::Error> {
-    fs::create_dir_all(path.join(".git/refs/heads")).context("create .git/refs/heads")?;
-    fs::write(path.join(".git/refs/heads/main"), head)?;
+/// Apply the global options that tune how objects are read and written.
+fn configure(cli: &Cli, repo: &mut Repository) -> Result<(), anyhow::Error> {
+    if let Some(level) = cli.compression {
+        let level = odb::compression_level(level).context("--compression")?;
+        repo.odb_mut().set_compression(level);
+    }
+    repo.odb_mut().set_verify(!cli.no_verify_objects);
     Ok(())
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    // You can use print statements as follows for debugging, they'll be visible when running tests.
-    eprintln!("Logs from your program will appear here!");
-
-    let cli = Cli::parse();
+fn run(cli: &Cli) -> Result<(), anyhow::Error> {
+    for dir in &cli.directories {
+        // like git, `-C ""` is a no-op
+        if !dir.as_os_str().is_empty() {
+            std::env::set_current_dir(dir).context(format!("cannot change to {:?}", dir))?;
+        }
+    }
+    let mut discover = DiscoverOptions::from_env();
+    if let Some(dir) = &cli.git_dir {
+        discover.git_dir = Some(dir.clone());
+    }
+    if let Some(dir) = &cli.work_tree {
+        discover.work_tree = Some(dir.clone());
+    }
+    let open = || -> Result<Repository, anyhow::Error> {
+        let mut repo = Repository::discover_with(Path::new("."), &discover)?;
+        configure(cli, &mut repo)?;
+        Ok(repo)
+    };
     match &cli.command {
-        Commands::Init => {
-            init_git_repo(Path::new("."))?;
-            println!("Initialized git directory")
+        Commands::Init {
+            directory,
+            bare,
+            initial_branch,
+            template,
+            object_format,
+            shared,
+            quiet,
+        } => {
+            let object_format = match object_format {
+                Some(format) => Some(
+                    ObjectFormat::parse(format)
+                        .ok_or_else(|| anyhow::anyhow!("unknown hash algorithm '{format}'"))?,
+                ),
+                None => None,
+            };
+            let opts = InitOptions {
+                bare: *bare,
+                initial_branch: initial_branch.clone(),
+                template: template.clone(),
+                object_format,
+                shared: match shared {
+                    Some(shared) => SharedRepository::parse(shared)?,
+                    None => SharedRepository::Umask,
+                },
+            };
+            let dir = directory.as_deref().unwrap_or(Path::new("."));
+            let reinit = Repository::open(dir).is_ok();
+            let mut repo = Repository::init_with(dir, &opts)?;
+            configure(cli, &mut repo)?;
+            if reinit && initial_branch.is_some() {
+                eprintln!(
+                    "warning: re-init: ignored --initial-branch={}",
+                    initial_branch.as_deref().unwrap_or_default()
+                );
+            }
+            if !*quiet {
+                println!(
+                    "{} {}Git repository in {}/",
+                    if reinit {
+                        "Reinitialized existing"
+                    } else {
+                        "Initialized empty"
+                    },
+                    if opts.shared != SharedRepository::Umask {
+                        "shared "
+                    } else {
+                        ""
+                    },
+                    repo.git_dir().display()
+                );
+            }
         }
         Commands::CatFile {
             pretty_print,
-            object,
+            show_type,
+            show_size,
+            exists,
+            batch,
+            batch_check,
+            batch_command,
+            buffer,
+            args,
         } => {
-            // use std::env;
-            // println!("cwd = {}", env::current_dir()?.display());
-            if !*pretty_print {
-                anyhow::bail!("we only support pretty_print (-p) now");
+            let batch_mode = if let Some(format) = batch {
+                Some((BatchMode::Contents, format))
+            } else if let Some(format) = batch_check {
+                Some((BatchMode::Check, format))
+            } else {
+                batch_command
+                    .as_ref()
+                    .map(|format| (BatchMode::Command, format))
+            };
+            if let Some((mode, format)) = batch_mode {
+                if !args.is_empty() {
+                    anyhow::bail!("batch modes take no arguments");
+                }
+                let out = std::io::BufWriter::new(std::io::stdout().lock());
+                return cat_file::batch(
+                    &open()?,
+                    mode,
+                    format,
+                    *buffer,
+                    std::io::stdin().lock(),
+                    out,
+                );
+            }
+            if *buffer {
+                anyhow::bail!("--buffer requires a batch mode");
+            }
+            let repo = open()?;
+
+            if !(*pretty_print || *show_type || *show_size || *exists) {
+                let [kind, object] = args.as_slice() else {
+                    anyhow::bail!("usage: cat-file <type> <object>");
+                };
+                let kind = ObjectType::parse(kind)
+                    .ok_or_else(|| anyhow::anyhow!("invalid object type \"{kind}\""))?;
+                let hash = repo
+                    .resolve(object)?
+                    .ok_or_else(|| Error::BadObjectName(object.to_string()))?;
+                let obj = repo.odb().open(&hash)?;
+                let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+                if obj.kind == kind {
+                    obj.copy_to(&mut out)?;
+                } else {
+                    out.write_all(&repo.odb().peel(obj.read_all()?, kind)?.content)?;
+                }
+                out.flush()?;
+                return Ok(());
+            }
+            let [object] = args.as_slice() else {
+                anyhow::bail!("usage: cat-file (-p | -t | -s | -e) <object>");
+            };
+            let hash = repo.resolve(object)?;
+            if *exists {
+                // a name that is not even well formed is an error, as in git
+                let Some(hash) = hash else {
+                    if object.len() == repo.odb().format().hex_len()
+                        && object.bytes().all(|c| c.is_ascii_hexdigit())
+                    {
+                        std::process::exit(1);
+                    }
+                    return Err(Error::BadObjectName(object.to_string()).into());
+                };
+                let found = repo.odb().header(&hash).is_ok();
+                std::process::exit(if found { 0 } else { 1 });
+            }
+            let hash = hash.ok_or_else(|| Error::BadObjectName(object.to_string()))?;
+            if *show_type {
+                println!("{}", repo.odb().header(&hash)?.0);
+            } else if *show_size {
+                println!("{}", repo.odb().header(&hash)?.1);
+            } else {
+                let obj = repo.odb().open(&hash)?;
+                if obj.kind == ObjectType::Blob {
+                    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+                    obj.copy_to(&mut out)?;
+                    out.flush()?;
+                } else {
+                    let mut out = std::io::stdout().lock();
+                    cat_file::pretty_print(&obj.read_all()?, repo.odb().format(), &mut out)?
+                }
             }
-            let obj = GitObject::new(object)?;
-            obj.cat(false)?
         }
         Commands::HashObject {
             write_object,
-            filename,
+            object_type,
+            stdin,
+            stdin_paths,
+            literally,
+            path,
+            no_filters,
+            files,
         } => {
-            let hash = calc_blob_hash(Path::new(filename), *write_object)?;
-            println!("{}", hash);
+            let repo = match open() {
+                Ok(repo) => Some(repo),
+                // only writing needs a repository
+                Err(_) if !*write_object => None,
+                Err(e) => return Err(e),
+            };
+            let standalone;
+            let odb = match &repo {
+                Some(repo) => repo.odb(),
+                None => {
+                    standalone = ObjectDatabase::new(
+                        ".git/objects",
+                        ObjectFormat::default(),
+                        &Config::default(),
+                    )?;
+                    &standalone
+                }
+            };
+            let opts = HashObjectOptions {
+                object_type: object_type.clone(),
+                write: *write_object,
+                literally: *literally,
+                no_filters: *no_filters,
+            };
+            let hasher = ObjectHasher::new(repo.as_ref(), odb, &opts)?;
+
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            if *stdin {
+                let mut data = Vec::new();
+                std::io::stdin().lock().read_to_end(&mut data)?;
+                writeln!(out, "{}", hasher.hash(data, path.as_deref())?)?;
+            }
+            if *stdin_paths {
+                for file in std::io::stdin().lock().lines() {
+                    let file = file?;
+                    writeln!(out, "{}", hasher.hash_file(&file, &file)?)?;
+                    // callers may be waiting on each answer before sending the next path
+                    out.flush()?;
+                }
+            }
+            for file in files {
+                let filter_path = path.as_deref().unwrap_or(file);
+                writeln!(out, "{}", hasher.hash_file(file, filter_path)?)?;
+            }
+            out.flush()?;
         }
-        Commands::LsTree { name_only, object } => {
-            let obj = GitObject::new(object)?;
-            obj.cat(*name_only)?;
+        Commands::LsTree {
+            recursive,
+            show_trees,
+            trees_only,
+            long,
+            name_only,
+            object_only,
+            full_name,
+            full_tree,
+            nul,
+            format,
+            object,
+            paths,
+        } => {
+            let format = match format {
+                Some(format) => format.as_str(),
+                None if *long => {
+                    "%(objectmode) %(objecttype) %(objectname) %(objectsize:padded)%x09%(path)"
+                }
+                None if *name_only => "%(path)",
+                None if *object_only => "%(objectname)",
+                None => "%(objectmode) %(objecttype) %(objectname)%x09%(path)",
+            };
+            let repo = open()?;
+            // the directory the listing is limited to, relative to the top
+            let prefix = match repo.work_tree_path(Path::new(".")) {
+                Some(prefix) if !*full_tree => prefix,
+                _ => String::new(),
+            };
+            let mut specs = Vec::new();
+            for p in paths {
+                let spec = if prefix.is_empty() {
+                    p.clone()
+                } else {
+                    let mut spec = repo
+                        .work_tree_path(Path::new(p))
+                        .ok_or_else(|| anyhow::anyhow!("{p}: '{p}' is outside repository"))?;
+                    if p.ends_with('/') && !spec.is_empty() {
+                        spec.push('/');
+                    }
+                    spec
+                };
+                specs.push(spec.into_bytes());
+            }
+            if specs.is_empty() && !prefix.is_empty() {
+                specs.push(format!("{prefix}/").into_bytes());
+            }
+            let opts = LsTreeOptions {
+                recursive: *recursive,
+                // -r -d only makes sense if the trees being recursed are shown
+                show_trees: *show_trees || (*recursive && *trees_only),
+                trees_only: *trees_only,
+                nul: *nul,
+                format: parse_format(format, LsTreeAtom::parse)?,
+                paths: specs,
+                prefix: if *full_name {
+                    Vec::new()
+                } else {
+                    prefix.into_bytes()
+                },
+            };
+            let hash = repo
+                .resolve(object)?
+                .ok_or_else(|| Error::BadObjectName(object.to_string()))?;
+            let odb = repo.odb();
+            let tree = odb.peel(odb.read(&hash)?, ObjectType::Tree)?;
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            ls_tree::ls_tree(odb, &tree, &opts, &mut out)?;
+            out.flush()?;
         }
         Commands::WriteTree => {
-            // SKIP: read all files/directories(recursively) where .git exists, now we just assume
-            // the command must be executed at where .git exactly exists.
-            //
-            // sort the entries
-            //
-            // calc hashes and write to the object file.
-            //
-            let hash = dir_hash(Path::new("."), true, true)?;
+            let repo = open()?;
+            let work_tree = repo
+                .work_tree()
+                .ok_or_else(|| anyhow::anyhow!("this operation must be run in a work tree"))?;
+            let hash = worktree::write_tree(repo.odb(), work_tree)?;
             println!("{hash}")
         }
         Commands::CommitTree {
@@ -302,474 +762,318 @@ fn main() -> Result<(), anyhow::Error> {
             writeln!(commit, "parent {}", parent,)?;
             writeln!(
                 commit,
-                "author {} <cabbageparadise@gmail.com> {} {}",
-                "chaosnyaruko", now, "+0800",
+                "author chaosnyaruko <cabbageparadise@gmail.com> {} +0800",
+                now,
             )?;
             writeln!(
                 commit,
-                "committer {} <cabbageparadise@gmail.com> {} {}",
-                "chaosnyaruko", now, "+0800",
+                "committer chaosnyaruko <cabbageparadise@gmail.com> {} +0800",
+                now,
             )?;
             writeln!(commit, "\n{message}")?;
 
-            let mut data = b"commit ".to_vec();
-            let mut size = Vec::from(commit.len().to_string());
-            data.append(&mut size);
-            data.push(b'\0');
-            data.append(&mut commit);
-            let mut hasher = Sha1::new();
-            hasher.update(&data);
-            let commit_hash = hasher.finalize();
-            let commit_hash = format!("{:x}", commit_hash);
-            write_object(&std::path::absolute(".")?, &commit_hash, &data)?;
+            let commit_hash = open()?.odb().write("commit", &commit)?;
             println!("{}", commit_hash);
         }
-        Commands::Clone { git_url, dir } => {
-            let dir = std::path::absolute(dir).context("absolute path for dir")?;
-            if std::fs::exists(&dir).context("exist")? {
-                anyhow::bail!(
-                    "destination path '{dir:?}' already exists and is not an empty directory."
-                );
-            }
-            init_git_repo(&dir).context("create .git in git clone")?;
-
-            let info_git_url = git_url.to_owned() + "/info/refs?service=git-upload-pack";
-            // TODO: rewrite it in an "await" way
-            let mut resp = reqwest::blocking::get(&info_git_url)?;
-
-            let status = resp.status();
-            assert!(status == 200 || status == 304);
-            if status == 304 {
-                anyhow::bail!("not got a valid service response");
+        Commands::Fsck {
+            connectivity_only,
+            unreachable,
+            lost_found,
+        } => {
+            let opts = fsck::FsckOptions {
+                connectivity_only: *connectivity_only,
+                unreachable: *unreachable,
+                lost_found: *lost_found,
+            };
+            let errors = fsck::fsck(&open()?, &opts)?;
+            if errors != 0 {
+                std::process::exit(errors);
             }
-
-            let mut body = Vec::new();
-            resp.copy_to(&mut body)?;
-            let mut offset = 0;
-
-            let mut head = String::new();
-            while offset < body.len() {
-                let line = read_pkt_line(&body, &mut offset)?;
-                let mut s = line.split(|c| *c == b' ' || *c == b'\0');
-                if let Some(h) = s.next() {
-                    if let Some(pointer) = s.next() {
-                        let pointer = str::from_utf8(pointer)?;
-                        if pointer == "HEAD" {
-                            head = String::from_utf8(h.to_vec())?;
-                            break;
-                        }
-                    }
+        }
+        Commands::PackObjects {
+            base_name,
+            stdout: _,
+            revs,
+            window,
+            depth,
+            delta_base_offset,
+            no_reuse_delta,
+            quiet,
+            progress,
+        } => {
+            let repo = open()?;
+            let odb = repo.odb();
+            let input = std::io::stdin().lock();
+            let objects = if *revs {
+                revwalk::read_revs(&repo, input)?
+            } else {
+                revwalk::read_object_list(&repo, input)?
+            };
+            let defaults = PackWriteOptions::new(odb);
+            let opts = PackWriteOptions {
+                window: window.unwrap_or(defaults.window),
+                depth: depth.unwrap_or(defaults.depth).min(4095),
+                ofs_delta: *delta_base_offset,
+                reuse_delta: !no_reuse_delta,
+                progress: !quiet && (*progress || std::io::stderr().is_terminal()),
+            };
+            // clap makes --stdout and a base name exclusive, and one of them
+            // required
+            match base_name {
+                Some(base_name) => {
+                    println!(
+                        "{}",
+                        pack::write_pack_files(odb, &objects, base_name, &opts)?
+                    );
                 }
-            }
-            eprintln!("head: {head}");
-            assert_eq!(head.len(), 40);
-            set_head_to_ref(&dir, &head)?;
-            let pack_git_url = git_url.to_owned() + "/git-upload-pack";
-            let want = format!("want {head}\n");
-            let want = create_pkt_line(want.as_bytes());
-            let flush = create_pkt_line(b"");
-            let done = create_pkt_line(b"done\n");
-
-            let mut body = Vec::with_capacity(want.len() + flush.len() + done.len());
-            body.extend_from_slice(&want);
-            body.extend_from_slice(&flush);
-            body.extend_from_slice(&done);
-
-            use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                CONTENT_TYPE,
-                HeaderValue::from_static("application/x-git-upload-pack-request"),
-            );
-            let client = reqwest::blocking::Client::new();
-            let mut resp = client
-                .post(&pack_git_url)
-                .headers(headers)
-                .body(body.clone())
-                .send()?;
-            eprintln!("cloning...");
-
-            let mut ori_buf = Vec::new();
-            resp.copy_to(&mut ori_buf).context("write to stdout")?;
-            let mut offset = 0;
-            set_head_to_ref(&dir, &head)?;
-            let nak = read_pkt_line(&ori_buf, &mut offset)?;
-            eprintln!("{}", str::from_utf8(nak)?);
-
-            let sig = &ori_buf[offset..offset + 4];
-            eprintln!("{sig:?}");
-            let version = &ori_buf[offset + 4..offset + 8];
-            eprintln!("{version:?}");
-            let object_num = &ori_buf[offset + 8..offset + 12];
-            let object_num = u32::from_be_bytes(object_num.try_into()?);
-            eprintln!("{object_num:?}");
-            offset += 12;
-
-            let mut buf = &ori_buf[offset..];
-
-            let mut idx = collections::HashMap::<String, BaseRef>::new();
-            for k in 0..object_num {
-                let mut i = 0;
-                let otype = (buf[i] >> 4) & 0x07;
-                let size = decode_size(&buf, &mut i, false);
-                eprintln!("k:{k} type: {}, size: {}", otype, size);
-                match otype {
-                    1 | 2 | 3 | 4 => {
-                        let mut z = ZlibDecoder::new(&buf[i..]);
-                        let mut data = Vec::new();
-                        let read_size = z
-                            .read_to_end(&mut data)
-                            .context("decompress a normal object")?;
-                        let inb = z.total_in();
-                        let out = z.total_out();
-                        assert_eq!(data.len(), size);
-                        assert_eq!(read_size, size);
-                        assert_eq!(read_size, out as usize);
-                        buf = &buf[i + inb as usize..];
-                        store_idx(&mut idx, otype, size, &mut data, &dir);
-                    }
-                    7 => {
-                        let base_ref = hex::encode(&buf[i..i + 20]);
-                        // NOTE: perf: Why the {} and copy?
-                        // https://stackoverflow.com/questions/47618823/cannot-borrow-as-mutable-because-it-is-also-borrowed-as-immutable
-                        // lexical lifetime https://stackoverflow.com/questions/50251487/what-are-non-lexical-lifetimes
-                        let base_content;
-                        let b_type;
-                        let base = idx.get(&base_ref);
-                        if base.is_none() {
-                            anyhow::bail!("base {} not found", base_ref);
-                        }
-                        let base = base.unwrap();
-                        base_content = base.content.clone();
-                        b_type = base.otype;
-                        i += 20;
-                        let mut z = ZlibDecoder::new(&buf[i..]);
-                        let mut data = Vec::new();
-                        z.read_to_end(&mut data).context("decompress ref delta")?;
-                        let inb = z.total_in();
-                        assert_eq!(data.len(), size);
-                        let mut j = 0;
-                        let src_size = decode_size(&data, &mut j, true);
-                        assert_eq!(src_size, base_content.len());
-                        let dst_size = decode_size(&data, &mut j, true);
-                        let mut new_dst = Vec::<u8>::with_capacity(dst_size);
-                        while j < data.len() {
-                            let ins = if data[j] & 0x80 != 0 { "COPY" } else { "ADD" };
-                            if ins == "COPY" {
-                                let size_to_copy = (data[j] >> 4) & 0b0111;
-                                let s1 = size_to_copy & 0b001 != 0;
-                                let s2 = size_to_copy & 0b010 != 0;
-                                let s3 = size_to_copy & 0b100 != 0;
-                                let offset_to_copy = (data[j]) & 0b1111;
-                                let of1 = offset_to_copy & 0b0001 != 0;
-                                let of2 = offset_to_copy & 0b0010 != 0;
-                                let of3 = offset_to_copy & 0b0100 != 0;
-                                let of4 = offset_to_copy & 0b1000 != 0;
-                                j += 1;
-                                let mut start: usize = 0;
-                                if of1 {
-                                    start |= (data[j]) as usize;
-                                    j += 1;
-                                }
-                                if of2 {
-                                    start |= (data[j] as usize) << 8;
-                                    j += 1;
-                                }
-                                if of3 {
-                                    start |= (data[j] as usize) << 16;
-                                    j += 1;
-                                }
-                                if of4 {
-                                    start |= (data[j] as usize) << 24;
-                                    j += 1;
-                                }
-
-                                let mut size: usize = 0;
-                                if s1 {
-                                    size |= data[j] as usize;
-                                    j += 1;
-                                }
-                                if s2 {
-                                    size |= (data[j] as usize) << 8;
-                                    j += 1;
-                                }
-                                if s3 {
-                                    size |= (data[j] as usize) << 16;
-                                    j += 1;
-                                }
-                                new_dst.extend_from_slice(&base_content[start..start + size]);
-                            } else {
-                                // if ins == "ADD"
-                                let add_size: usize = (data[j] as usize) & 0x7F;
-                                j += 1;
-                                let added = &data[j..j + add_size];
-                                j += add_size;
-                                new_dst.extend_from_slice(added);
-                            }
-                        }
-                        assert_eq!(j, data.len());
-                        assert_eq!(new_dst.len(), dst_size);
-                        store_idx(&mut idx, b_type, dst_size, &mut new_dst, &dir);
-                        buf = &buf[i + inb as usize..];
-                    }
-                    6 => {
-                        unimplemented!("{}", otype);
-                    }
-                    unknown => {
-                        unreachable!("unknown object type {unknown}")
-                    }
+                None => {
+                    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+                    pack::write_pack(odb, &objects, &mut out, &opts)?;
                 }
             }
-            let mut hasher = Sha1::new();
-            hasher.update(&ori_buf[8..ori_buf.len() - 20]);
-            let hash = hasher.finalize();
-            let hash = format!("{hash:x}");
-            assert_eq!(buf.len(), 20);
-            let expected_hash = hex::encode(buf);
-            assert_eq!(hash, expected_hash);
-            assert_eq!(idx.len(), object_num as usize);
-
-            let tree = tree_from_commit(&idx, &head)?;
-            checkout_files_by_tree(&idx, &tree, Path::new(&dir))?;
         }
-    }
-
-    Ok(())
-}
-
-fn tree_from_commit(idx: &HashMap<String, BaseRef>, head: &str) -> Result<String, anyhow::Error> {
-    let commit = idx.get(head).unwrap();
-    if commit.otype != 1 {
-        return Err(anyhow::anyhow!("HEAD should be a commit"));
-    }
-    let mut lines = commit.content.split(|c| *c == b'\n');
-    let tree = lines.next().unwrap();
-    assert_eq!(str::from_utf8(&tree[..5])?, "tree ");
-    let tree = str::from_utf8(&tree[5..])?;
-    Ok(tree.to_string())
-}
-
-fn checkout_files_by_tree(
-    idx: &HashMap<String, BaseRef>,
-    root_hash: &str,
-    path: &Path,
-) -> Result<(), anyhow::Error> {
-    let obj = idx.get(root_hash).unwrap();
-    match obj.otype {
-        3 => {
-            fs::write(path, &obj.content)?;
+        Commands::Fetch {
+            remote,
+            refspecs,
+            quiet,
+            progress,
+            prune,
+            no_prune,
+            force,
+            tags,
+            no_tags,
+            update_head_ok,
+            depth,
+            deepen,
+            shallow_since,
+            shallow_exclude,
+            unshallow,
+        } => {
+            let repo = open()?;
+            if *depth == Some(0) {
+                anyhow::bail!("depth 0 is not a positive number");
+            }
+            if *unshallow && !repo.is_shallow() {
+                anyhow::bail!("--unshallow on a complete repository does not make sense");
+            }
+            let deepen = Deepen {
+                depth: match (depth, deepen) {
+                    _ if *unshallow => Some(Deepen::INFINITE),
+                    (Some(depth), _) | (_, Some(depth)) => Some(*depth),
+                    (None, None) => None,
+                },
+                since: shallow_since
+                    .as_deref()
+                    .map(transport::parse_date)
+                    .transpose()?,
+                not: shallow_exclude.clone(),
+                relative: deepen.is_some(),
+            };
+            let name = match remote {
+                Some(name) => name.clone(),
+                None => remote::default_remote(&repo)?,
+            };
+            let remote = Remote::get(repo.config(), &name)?;
+            let refspecs = refspecs
+                .iter()
+                .map(|spec| Refspec::parse(spec))
+                .collect::<Result<Vec<_>, _>>()?;
+            let tags = if *tags {
+                TagMode::All
+            } else if *no_tags {
+                TagMode::None
+            } else {
+                remote.tags.unwrap_or_default()
+            };
+            let opts = FetchOptions {
+                progress: !quiet && (*progress || std::io::stderr().is_terminal()),
+                tags,
+                prune: *prune || (!no_prune && remote.prune.unwrap_or(false)),
+                force: *force,
+                update_head_ok: *update_head_ok,
+                deepen,
+                filter: remote.filter,
+            };
+            let updates = remote::fetch(&repo, &remote, &refspecs, &opts)?;
+            if !quiet {
+                remote::print_updates(&remote.url, &updates);
+            }
+            let rejected = updates
+                .iter()
+                .any(|u| matches!(u.status, UpdateStatus::Rejected(_)));
+            if rejected {
+                eprintln!("error: some local refs could not be updated");
+                std::process::exit(1);
+            }
         }
-        2 => {
-            // TODO: refactor(duplicate code with LsTree)
-            std::fs::create_dir_all(path).context(format!("create {:?}", path))?;
-            let mut i = 0;
-            loop {
-                let mut mode = Vec::with_capacity(6);
-                while i < obj.content.len() {
-                    let c = obj.content[i];
-                    i += 1;
-                    if c == b' ' {
-                        break;
+        Commands::Push {
+            remote,
+            refspecs,
+            quiet,
+            progress,
+            force,
+            force_with_lease,
+            delete,
+            atomic,
+            tags,
+        } => {
+            let repo = open()?;
+            let name = match remote {
+                Some(name) => name.clone(),
+                None => remote::default_push_remote(&repo)?,
+            };
+            let remote = Remote::get(repo.config(), &name)?;
+            if *delete && refspecs.is_empty() {
+                anyhow::bail!("--delete doesn't make sense without any refs");
+            }
+            let refspecs = refspecs
+                .iter()
+                .map(|spec| {
+                    if !delete {
+                        Refspec::parse_push(spec)
+                    } else if spec.contains(':') {
+                        anyhow::bail!("--delete only accepts plain target ref names")
+                    } else {
+                        Refspec::parse_push(&format!(":{spec}"))
                     }
-                    mode.push(c);
-                }
-                // TODO: set the right permission for checked-out files.
-                let mode = format!("{:0>6}", str::from_utf8(&mode)?);
-
-                let mut name = Vec::new();
-                while i < obj.content.len() {
-                    let c = obj.content[i];
-                    i += 1;
-                    if c == b'\0' {
-                        break;
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let leases = force_with_lease
+                .iter()
+                .map(|lease| match lease.split_once(':') {
+                    _ if lease.is_empty() => Lease {
+                        name: None,
+                        expect: None,
+                    },
+                    Some((name, expect)) => Lease {
+                        name: Some(name.to_string()),
+                        expect: Some(expect.to_string()),
+                    },
+                    None => Lease {
+                        name: Some(lease.clone()),
+                        expect: None,
+                    },
+                })
+                .collect();
+            let opts = PushOptions {
+                progress: !quiet && (*progress || std::io::stderr().is_terminal()),
+                force: *force,
+                leases,
+                atomic: *atomic,
+                tags: *tags,
+            };
+            let updates = remote::push(&repo, &remote, &refspecs, &opts)?;
+            let failed = updates.iter().any(|u| !u.status.is_ok());
+            if !quiet || failed {
+                remote::print_push_updates(&remote.url, &updates);
+            }
+            if failed {
+                eprintln!("error: failed to push some refs to '{}'", remote.url);
+                let head = repo.symbolic_ref("HEAD")?;
+                if let Some(advice) = remote::push_advice(&updates, head.as_deref()) {
+                    for line in advice.lines() {
+                        eprintln!("hint: {line}");
                     }
-                    name.push(c);
-                }
-                let name = str::from_utf8(&name)?;
-
-                let hash = &obj.content[i..i + 20];
-                let hash = hex::encode(hash);
-                i += 20;
-                eprintln!("{name}, {mode}, {hash}");
-                checkout_files_by_tree(idx, &hash, &path.join(name))?;
-                if i >= obj.content.len() {
-                    break;
                 }
+                std::process::exit(1);
+            }
+            let pushed = updates.iter().any(|u| u.status != PushStatus::UpToDate);
+            if !quiet && !pushed {
+                eprintln!("Everything up-to-date");
             }
-            assert_eq!(i, obj.content.len());
-        }
-        bad => {
-            return Err(anyhow::anyhow!("we don't know how to checkout {bad}"));
-        }
-    }
-    Ok(())
-}
-
-fn create_pkt_line(s: &[u8]) -> Vec<u8> {
-    let len = if s.len() == 0 { 0 } else { s.len() + 4 };
-    let len = format!("{len:04x}");
-    let mut res = len.bytes().collect::<Vec<u8>>();
-    res.extend(s);
-    return res;
-}
-
-fn read_pkt_line<'a>(buf: &'a [u8], offset: &mut usize) -> Result<&'a [u8], anyhow::Error> {
-    let len = &buf[*offset..*offset + 4];
-    *offset += 4;
-    let len = usize::from_str_radix(str::from_utf8(len)?, 16)?;
-    if len == 0 {
-        // for "0000"
-        return Ok(b"");
-    }
-    let res = &buf[*offset..*offset + len - 4];
-    *offset += len - 4;
-    Ok(res)
-}
-
-struct Objects(Vec<Object>);
-// TODO: combine it with GitObject
-#[derive(Debug)]
-struct Object {
-    kind: ObjectType,
-    hash: String,
-    path: PathBuf,
-    mode: &'static str,
-}
-
-impl std::fmt::Display for Objects {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for item in &self.0 {
-            write!(f, "{}\n", item)?; // uses MyStruct::fmt
         }
-        write!(f, "")
-    }
-}
-
-impl std::fmt::Display for Object {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{:0>6} {} {:?} {}",
-            self.mode,
-            self.kind,
-            self.path.file_name().unwrap(),
-            self.hash
-        )
-    }
-}
-
-fn dir_hash(dir: &Path, print: bool, write: bool) -> Result<String, anyhow::Error> {
-    let mut objs = Objects(Vec::new());
-    if dir.is_dir() {
-        for entry in std::fs::read_dir(dir)? {
-            let entry = entry?;
-            let mut obj = Object {
-                hash: String::new(),
-                path: entry.path(),
-                kind: ObjectType::Blob,
-                mode: "000000",
+        Commands::Clone {
+            git_url,
+            dir,
+            quiet,
+            progress,
+            branch,
+            single_branch,
+            no_tags,
+            bare,
+            mirror,
+            no_single_branch,
+            depth,
+            shallow_since,
+            shallow_exclude,
+            filter,
+        } => {
+            let bare = *bare || *mirror;
+            let filter = filter.as_deref().map(ObjectFilter::parse).transpose()?;
+            if *depth == Some(0) {
+                anyhow::bail!("depth 0 is not a positive number");
+            }
+            let deepen = Deepen {
+                depth: *depth,
+                since: shallow_since
+                    .as_deref()
+                    .map(transport::parse_date)
+                    .transpose()?,
+                not: shallow_exclude.clone(),
+                relative: false,
+            };
+            let dir = match dir {
+                Some(dir) => dir.clone(),
+                None => remote::clone_dir(git_url, bare)?,
             };
-            let path = entry.path();
-            // ignore the ".git" directory
-            if path.is_dir()
-                && path.file_name().unwrap().to_str().unwrap().cmp(".git")
-                    == std::cmp::Ordering::Equal
-            {
-                continue;
+            let (exists, empty) = match fs::read_dir(&dir) {
+                Ok(mut entries) => (true, entries.next().is_none()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (false, true),
+                Err(e) => return Err(e).context(format!("read {}", dir.display())),
+            };
+            if !empty {
+                anyhow::bail!(
+                    "destination path '{}' already exists and is not an empty directory.",
+                    dir.display()
+                );
             }
-            if path.is_dir() {
-                obj.hash = dir_hash(&path, false, true)?;
-                obj.kind = ObjectType::Tree;
-            } else {
-                obj.kind = ObjectType::Blob;
-                obj.hash = calc_blob_hash(&path, true)?;
+            if !quiet {
+                let bare = if bare { "bare repository " } else { "" };
+                eprintln!("Cloning into {bare}'{}'...", dir.display());
+            }
+            let clone_opts = remote::CloneOptions {
+                branch: branch.clone(),
+                // a shallow clone is of one branch unless asked otherwise
+                single_branch: *single_branch || (deepen.is_set() && !no_single_branch),
+                mirror: *mirror,
+            };
+            let clone = || -> Result<(), anyhow::Error> {
+                let advertisement = transport::ls_refs(git_url, clone_opts.ref_prefixes())?;
+                let opts = InitOptions {
+                    bare,
+                    object_format: Some(advertisement.object_format()?),
+                    ..InitOptions::default()
+                };
+                let mut repo =
+                    Repository::init_with(&dir, &opts).context("create .git in git clone")?;
+                configure(cli, &mut repo)?;
+                let fetch = FetchOptions {
+                    progress: !quiet && (*progress || std::io::stderr().is_terminal()),
+                    tags: if *no_tags {
+                        TagMode::None
+                    } else {
+                        TagMode::default()
+                    },
+                    deepen: deepen.clone(),
+                    filter,
+                    ..FetchOptions::default()
+                };
+                remote::clone(&repo, git_url, &advertisement, &clone_opts, &fetch)
+            };
+            // like git, do not leave a half-made clone behind
+            if let Err(e) = clone() {
+                if exists {
+                    for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                        let path = entry.path();
+                        let _ = fs::remove_dir_all(&path).or_else(|_| fs::remove_file(&path));
+                    }
+                } else {
+                    let _ = fs::remove_dir_all(&dir);
+                }
+                return Err(e);
             }
-            objs.0.push(obj);
         }
     }
-    objs.0.sort_by(|p1, p2| {
-        let oa = p1.path.file_name().expect("oa should be Some");
-        let ob = p2.path.file_name().expect("ob should be Some");
-        oa.cmp(ob)
-    });
-
-    let mut buf = Vec::new();
-    for obj in &mut objs.0 {
-        let mode = if obj.path.is_dir() {
-            "40000"
-        } else if obj.path.is_symlink() {
-            "120000"
-        } else if obj.path.is_file() {
-            "100644"
-        } else {
-            // TODO: executable
-            "100755"
-        };
-        obj.mode = mode;
-        buf.extend_from_slice(obj.mode.as_bytes());
-        buf.extend_from_slice(" ".as_bytes());
-        buf.extend_from_slice(
-            obj.path
-                .file_name()
-                .expect("write file name")
-                .to_str()
-                .expect("osstr to str")
-                .as_bytes(),
-        );
-        buf.extend_from_slice("\0".as_bytes());
-        buf.extend_from_slice(hex::decode(obj.hash.clone()).unwrap().as_slice());
-    }
-    if print {
-        eprintln!("buf len {:?}/{}\n{}", dir, buf.len(), objs);
-    }
 
-    let mut data = vec![b't', b'r', b'e', b'e', b' '];
-    let mut size = Vec::from(buf.len().to_string());
-    data.append(&mut size);
-    data.push(b'\0');
-    data.append(&mut buf);
-    let mut hasher = Sha1::new();
-    hasher.update(&data);
-    let tree_hash = hasher.finalize();
-    let tree_hash = format!("{:x}", tree_hash);
-    if write {
-        write_object(&std::path::absolute(".")?, &tree_hash, &data)
-            .context("write to tree object")?;
-    }
-    Ok(tree_hash)
-}
-
-fn calc_blob_hash(filename: &Path, write: bool) -> Result<String, anyhow::Error> {
-    let mut hasher = Sha1::new();
-    let mut file = std::fs::read(filename).context("read file err")?;
-    let mut size = Vec::from(file.len().to_string());
-    let mut data = vec![b'b', b'l', b'o', b'b', b' '];
-    data.append(&mut size);
-    data.push(b'\0');
-    data.append(&mut file);
-    hasher.update(&data);
-    let blob_hash = hasher.finalize();
-    let blob_hash = format!("{:x}", blob_hash);
-    if write {
-        write_object(&std::path::absolute(".")?, &blob_hash, &data)
-            .context("write to blob object")?;
-    }
-    Ok(blob_hash)
-}
-
-fn write_object(root: &Path, hash: &String, data: &[u8]) -> Result<(), anyhow::Error> {
-    let prefix = &hash[..2];
-    let path = &hash[2..];
-    let path = PathBuf::from(root.join(".git/objects"))
-        .join(prefix)
-        .join(path);
-    let f = if std::fs::exists(&path)? {
-        std::fs::File::open(&path).context(format!("open file {:?}", path))?
-    } else {
-        let prefix = PathBuf::from(root.join(".git/objects")).join(prefix);
-        std::fs::create_dir_all(&prefix)?;
-        std::fs::File::create(&path).context(format!("create file {:?}", path))?
-    };
-    let mut e = ZlibEncoder::new(f, Compression::fast());
-    e.write_all(&data).context("write object file error")
+    Ok(())
 }
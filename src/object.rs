@@ -0,0 +1,487 @@
+//! Git objects: their types, the loose object header, and parsing and
+//! validating the content of trees, commits and tags.
+
+use std::io::BufRead;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    Blob,
+    Tree,
+    Commit,
+    Tag,
+}
+
+impl ObjectType {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "blob" => Some(ObjectType::Blob),
+            "tree" => Some(ObjectType::Tree),
+            "commit" => Some(ObjectType::Commit),
+            "tag" => Some(ObjectType::Tag),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ObjectType::Blob => "blob",
+            ObjectType::Tree => "tree",
+            ObjectType::Commit => "commit",
+            ObjectType::Tag => "tag",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The hash function a repository names its objects with
+/// (`extensions.objectFormat`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObjectFormat {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl ObjectFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sha1" => Some(ObjectFormat::Sha1),
+            "sha256" => Some(ObjectFormat::Sha256),
+            _ => None,
+        }
+    }
+
+    /// The length of an object id in bytes.
+    pub fn raw_len(self) -> usize {
+        match self {
+            ObjectFormat::Sha1 => 20,
+            ObjectFormat::Sha256 => 32,
+        }
+    }
+
+    /// The length of an object id in hex digits.
+    pub fn hex_len(self) -> usize {
+        2 * self.raw_len()
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            ObjectFormat::Sha1 => Hasher::Sha1(Box::new(sha1_checked::Sha1::new())),
+            ObjectFormat::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    /// The hash of `data`, as used for pack and index checksums.
+    pub fn digest(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// The id of `data` stored as an object of type `kind`. `kind` is not
+    /// checked, so `hash-object --literally` can use it.
+    pub fn hash_object(self, kind: &str, data: &[u8]) -> Result<String, Error> {
+        let mut hasher = self.hasher();
+        hasher.update(format!("{kind} {}\0", data.len()).as_bytes());
+        hasher.update(data);
+        hasher.finalize_hex()
+    }
+
+    /// Whether `s` is a full object id in lowercase hex.
+    pub fn is_hex_id(self, s: &[u8]) -> bool {
+        s.len() == self.hex_len() && s.iter().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+    }
+
+    /// The all-zero id git uses for "no object".
+    pub fn null_id(self) -> String {
+        "0".repeat(self.hex_len())
+    }
+}
+
+/// A running hash in one of the object formats.
+///
+/// SHA-1 is computed with collision detection (SHA-1DC, as git uses), so
+/// content crafted for a SHAttered-style attack is refused rather than
+/// given the id of an innocent object.
+#[derive(Clone)]
+pub enum Hasher {
+    // the collision detection state is large
+    Sha1(Box<sha1_checked::Sha1>),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    /// The hash of everything fed in, or an error if it is part of a SHA-1
+    /// collision.
+    pub fn finalize(self) -> Result<Vec<u8>, Error> {
+        match self {
+            Hasher::Sha1(h) => {
+                let result = (*h).try_finalize();
+                if result.has_collision() {
+                    return Err(Error::Sha1Collision {
+                        hash: hex::encode(result.hash()),
+                    });
+                }
+                Ok(result.hash().to_vec())
+            }
+            Hasher::Sha256(h) => Ok(h.finalize().to_vec()),
+        }
+    }
+
+    pub fn finalize_hex(self) -> Result<String, Error> {
+        self.finalize().map(hex::encode)
+    }
+}
+
+impl std::fmt::Display for ObjectFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ObjectFormat::Sha1 => "sha1",
+            ObjectFormat::Sha256 => "sha256",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// An object read fully into memory.
+pub struct GitObject {
+    pub kind: ObjectType,
+    pub content: Vec<u8>,
+}
+
+/// Parse the `<type> <size>\0` header of a loose object.
+pub fn read_object_header(reader: &mut impl BufRead) -> Result<(ObjectType, usize), anyhow::Error> {
+    let mut buf = Vec::new();
+    reader.read_until(b' ', &mut buf)?;
+    if buf.pop() != Some(b' ') {
+        anyhow::bail!("missing object type");
+    }
+    let kind = std::str::from_utf8(&buf).context("parse object type")?;
+    let kind =
+        ObjectType::parse(kind).ok_or_else(|| anyhow::anyhow!("unsupport object type {kind}"))?;
+    buf.clear();
+    reader.read_until(b'\0', &mut buf)?;
+    if buf.pop() != Some(b'\0') {
+        anyhow::bail!("missing object size");
+    }
+    let size_str = std::str::from_utf8(&buf).context("convert size")?;
+    let size = size_str
+        .parse::<usize>()
+        .context(format!("num: {:?}", &size_str))?;
+    Ok((kind, size))
+}
+
+pub struct TreeEntry {
+    pub mode: u32,
+    pub name: Vec<u8>,
+    pub hash: String,
+}
+
+impl TreeEntry {
+    /// The type of the object an entry points at, derived from its mode so the
+    /// object itself (which may not even be present, e.g. for submodules) is
+    /// never opened.
+    pub fn kind(&self) -> ObjectType {
+        match self.mode & 0o170000 {
+            0o040000 => ObjectType::Tree,
+            0o160000 => ObjectType::Commit,
+            _ => ObjectType::Blob,
+        }
+    }
+}
+
+/// The order of entries in a tree: by name, with directories sorting as if
+/// their name ended in '/'.
+pub fn tree_entry_cmp(a: &[u8], a_is_tree: bool, b: &[u8], b_is_tree: bool) -> std::cmp::Ordering {
+    let common = a.len().min(b.len());
+    match a[..common].cmp(&b[..common]) {
+        std::cmp::Ordering::Equal => {}
+        unequal => return unequal,
+    }
+    let next = |name: &[u8], is_tree: bool| {
+        name.get(common)
+            .copied()
+            .or(is_tree.then_some(b'/'))
+            .unwrap_or(0)
+    };
+    next(a, a_is_tree).cmp(&next(b, b_is_tree))
+}
+
+pub fn parse_tree(content: &[u8], format: ObjectFormat) -> Result<Vec<TreeEntry>, anyhow::Error> {
+    let len = format.raw_len();
+    let mut entries = Vec::new();
+    let mut rest = content;
+    while !rest.is_empty() {
+        let sp = rest
+            .iter()
+            .position(|c| *c == b' ')
+            .ok_or_else(|| anyhow::anyhow!("tree entry without mode"))?;
+        let mode = u32::from_str_radix(std::str::from_utf8(&rest[..sp])?, 8)
+            .context("parse tree entry mode")?;
+        rest = &rest[sp + 1..];
+        let nul = rest
+            .iter()
+            .position(|c| *c == b'\0')
+            .ok_or_else(|| anyhow::anyhow!("tree entry without name"))?;
+        let name = rest[..nul].to_vec();
+        rest = &rest[nul + 1..];
+        if rest.len() < len {
+            anyhow::bail!("truncated tree entry {:?}", String::from_utf8_lossy(&name));
+        }
+        let hash = hex::encode(&rest[..len]);
+        rest = &rest[len..];
+        entries.push(TreeEntry { mode, name, hash });
+    }
+    Ok(entries)
+}
+
+/// The value of the first `<field> <value>` header line of a commit or tag.
+pub fn header_field<'a>(content: &'a [u8], field: &str) -> Option<&'a [u8]> {
+    content
+        .split(|c| *c == b'\n')
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix(field.as_bytes())?.strip_prefix(b" "))
+}
+
+/// Check a `Name <email> <timestamp> <tz>` line as used by commits and tags.
+fn validate_ident(ident: &[u8]) -> Result<(), anyhow::Error> {
+    // names and emails need not be UTF-8: an `encoding` header or an old
+    // commit may use another charset
+    let shown = String::from_utf8_lossy(ident);
+    let find =
+        |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).position(|w| w == needle);
+    let email =
+        find(ident, b" <").ok_or_else(|| anyhow::anyhow!("missing email in ident '{shown}'"))?;
+    let rest = &ident[email + 2..];
+    let date = find(rest, b"> ")
+        .map(|end| &rest[end + 2..])
+        .ok_or_else(|| anyhow::anyhow!("bad email in ident '{shown}'"))?;
+    let space = date
+        .iter()
+        .position(|c| *c == b' ')
+        .ok_or_else(|| anyhow::anyhow!("missing timezone in ident '{shown}'"))?;
+    let (timestamp, tz) = (&date[..space], &date[space + 1..]);
+    let timestamp_ok = !timestamp.is_empty() && timestamp.iter().all(u8::is_ascii_digit);
+    let tz_ok =
+        tz.len() == 5 && (tz[0] == b'+' || tz[0] == b'-') && tz[1..].iter().all(u8::is_ascii_digit);
+    if !timestamp_ok || !tz_ok {
+        anyhow::bail!("bad date in ident '{shown}'");
+    }
+    Ok(())
+}
+
+/// Check that `data` is a well-formed payload for an object of type `kind`.
+pub fn validate_object(
+    kind: ObjectType,
+    data: &[u8],
+    format: ObjectFormat,
+) -> Result<(), anyhow::Error> {
+    let is_hex_id = |id: &[u8]| format.is_hex_id(id);
+    let headers = || {
+        let end = data
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .map_or(data.len(), |i| i + 1);
+        data[..end]
+            .split(|c| *c == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| match l.iter().position(|c| *c == b' ') {
+                Some(i) => (&l[..i], &l[i + 1..]),
+                None => (l, &b""[..]),
+            })
+    };
+    match kind {
+        ObjectType::Blob => {}
+        ObjectType::Tree => {
+            for entry in parse_tree(data, format)? {
+                if entry.name.is_empty() || entry.name.contains(&b'/') {
+                    anyhow::bail!("invalid tree entry name {:?}", entry.name);
+                }
+            }
+        }
+        ObjectType::Commit => {
+            let mut headers = headers().peekable();
+            match headers.next() {
+                Some((b"tree", id)) if is_hex_id(id) => {}
+                _ => anyhow::bail!("commit does not start with a valid tree line"),
+            }
+            while let Some((b"parent", id)) = headers.peek() {
+                if !is_hex_id(id) {
+                    anyhow::bail!("invalid parent line in commit");
+                }
+                headers.next();
+            }
+            match headers.next() {
+                Some((b"author", ident)) => validate_ident(ident)?,
+                _ => anyhow::bail!("commit is missing an author line"),
+            }
+            match headers.next() {
+                Some((b"committer", ident)) => validate_ident(ident)?,
+                _ => anyhow::bail!("commit is missing a committer line"),
+            }
+        }
+        ObjectType::Tag => {
+            let mut headers = headers();
+            match headers.next() {
+                Some((b"object", id)) if is_hex_id(id) => {}
+                _ => anyhow::bail!("tag does not start with a valid object line"),
+            }
+            match headers.next() {
+                Some((b"type", t)) if ObjectType::parse(std::str::from_utf8(t)?).is_some() => {}
+                _ => anyhow::bail!("tag has an invalid type line"),
+            }
+            match headers.next() {
+                Some((b"tag", name)) if !name.is_empty() => {}
+                _ => anyhow::bail!("tag is missing its name"),
+            }
+            // very old tags have no tagger
+            if let Some((b"tagger", ident)) = headers.next() {
+                validate_ident(ident)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_objects_like_git() {
+        let sha1 = ObjectFormat::Sha1;
+        assert_eq!(
+            sha1.hash_object("blob", b"hello\n").unwrap(),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+        assert_eq!(
+            sha1.hash_object("tree", b"").unwrap(),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+        assert_eq!(sha1.null_id().len(), 40);
+    }
+
+    #[test]
+    fn hashes_sha256_objects_like_git() {
+        let sha256 = ObjectFormat::Sha256;
+        assert_eq!(
+            sha256.hash_object("blob", b"hello\n").unwrap(),
+            "2cf8d83d9ee29543b34a87727421fdecb7e3f3a183d337639025de576db9ebb4"
+        );
+        assert_eq!(
+            sha256.hash_object("tree", b"").unwrap(),
+            "6ef19b41225c5369f1c104d45d8d85efa9b057b53b14b4b9b939dd74decc5321"
+        );
+        assert_eq!((sha256.raw_len(), sha256.hex_len()), (32, 64));
+        assert!(sha256.is_hex_id(sha256.null_id().as_bytes()));
+        assert!(!sha256.is_hex_id(b"4b825dc642cb6eb9a060e54bf8d69288fbee4904"));
+        assert_eq!(ObjectFormat::parse("sha256"), Some(sha256));
+        assert_eq!(ObjectFormat::parse("md5"), None);
+    }
+
+    #[test]
+    fn parses_sha256_trees() {
+        let mut tree = b"100644 a\0".to_vec();
+        tree.extend([0xab; 32]);
+        tree.extend(b"40000 d\0");
+        tree.extend([0xcd; 32]);
+        let entries = parse_tree(&tree, ObjectFormat::Sha256).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].hash, "ab".repeat(32));
+        assert_eq!(entries[1].kind(), ObjectType::Tree);
+        // read as SHA-1 ids, the second entry is cut short
+        assert!(parse_tree(&tree, ObjectFormat::Sha1).is_err());
+    }
+
+    #[test]
+    fn validates_object_payloads() {
+        let validate_object = |kind, data: &[u8]| validate_object(kind, data, ObjectFormat::Sha1);
+        let tree = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let ident = "A U Thor <author@example.com> 1700000000 +0100";
+        let commit =
+            format!("tree {tree}\nparent {tree}\nauthor {ident}\ncommitter {ident}\n\nmsg\n");
+        assert!(validate_object(ObjectType::Commit, commit.as_bytes()).is_ok());
+        for bad in [
+            format!("tree {tree}\nauthor {ident}\n\nmsg\n"),
+            format!("parent {tree}\ntree {tree}\nauthor {ident}\ncommitter {ident}\n"),
+            format!("tree {tree}\nauthor A <a> x +0100\ncommitter {ident}\n"),
+            format!("tree {tree}\nauthor A <a> 1 0100\ncommitter {ident}\n"),
+        ] {
+            assert!(
+                validate_object(ObjectType::Commit, bad.as_bytes()).is_err(),
+                "{bad}"
+            );
+        }
+
+        // very old tags have no tagger
+        let tag = format!("object {tree}\ntype tree\ntag v1\n\nmsg\n");
+        assert!(validate_object(ObjectType::Tag, tag.as_bytes()).is_ok());
+        let tag = format!("object {tree}\ntype tre\ntag v1\n");
+        assert!(validate_object(ObjectType::Tag, tag.as_bytes()).is_err());
+
+        let mut tree = b"100644 a/b\0".to_vec();
+        tree.extend([1; 20]);
+        assert!(validate_object(ObjectType::Tree, &tree).is_err());
+        assert!(validate_object(ObjectType::Tree, &tree[..20]).is_err());
+        assert!(validate_object(ObjectType::Blob, b"\xff").is_ok());
+
+        // ids are as long as the object format says
+        let sha1_tree = ObjectFormat::Sha1.hash_object("tree", b"").unwrap();
+        let commit = format!("tree {sha1_tree}\nauthor {ident}\ncommitter {ident}\n");
+        let sha256 = ObjectFormat::Sha256;
+        assert!(super::validate_object(ObjectType::Commit, commit.as_bytes(), sha256).is_err());
+        let commit = commit.replace(&sha1_tree, &sha256.hash_object("tree", b"").unwrap());
+        assert!(super::validate_object(ObjectType::Commit, commit.as_bytes(), sha256).is_ok());
+    }
+
+    /// The first of the SHAmbles chosen-prefix collision pair.
+    const SHAMBLES: [&str; 16] = [
+        "99040d047fe81780012000ff4b65792069732070617274206f66206120636f6c6c6973696f6e2120",
+        "49742773206120747261702179c61af0afcc054515d9274e7307624b1dc7fb23988bb8de8b575dba",
+        "7b9eab31c1674b6d974378a827732ff5851c76a2e60772b5a47ce1eac40bb993c12d8c70e24a4f8d",
+        "5fcdedc1b32c9cf19e31af2429759d42e4dfdb31719f587623ee552939b6dcdc459fca53553b70f8",
+        "7ede30a247ea3af6c759a2f20b320d760db64ff479084fd3ccb3cdd48362d96a9c430617caff6c36",
+        "c637e53fde28417f626fec54ed7943a46e5f5730f2bb38fb1df6e0090010d00e24ad78bf92641993",
+        "608e8d158a789f34c46fe1e6027f35a4cbfb827076c50eca0e8b7cca69bb2c2b790259f9bf9570dd",
+        "8d4437a3115faff7c3cac09ad25266055c27104755178eaeff825a2caa2acfb5de64ce7641dc59a5",
+        "41a9fc9c756756e2e23dc713c8c24c9790aa6b0e38a7f55f14452a1ca2850ddd9562fd9a18ad4249",
+        "6aa97008f74672f68ef461eb88b09933d626b4f918749cc027fddd6c425fc4216835d0134d15285b",
+        "ab2cb784a4f7cbb4fb514d4bf0f6237cf00a9e9f132b9a066e6fd17f6c42987478586ff651af9674",
+        "7fb426b9872b9a88e4063f59bb334cc00650f83a80c42751b71974d300fc2819a2e8f1e32c1b51cb",
+        "18e6bfc4db9baef675d4aaf5b1574a047f8f6dd2ec153a93412293974d928f88ced9363cfef97ce2",
+        "e742bf34c96b8ef3875676fea5cca8e5f7dea0bab2413d4de00ee71ee01f162bdb6d1eafd925e6ae",
+        "baae6a354ef17cf205a404fbdb12fc454d41fdd95cf2459664a2ad032d1da60a73264075d7f1e0d6",
+        "c1403ae7a0d861df3fe5707188dd5e07d1589b9f8b6630553f8fc352b3e0c27da80bddba4c64020d",
+    ];
+
+    #[test]
+    fn refuses_sha1_collisions() {
+        let data = hex::decode(SHAMBLES.concat()).unwrap();
+        assert!(matches!(
+            ObjectFormat::Sha1.digest(&data),
+            Err(Error::Sha1Collision { .. })
+        ));
+        let mut hasher = ObjectFormat::Sha1.hasher();
+        for chunk in data.chunks(100) {
+            hasher.update(chunk);
+        }
+        assert!(hasher.finalize_hex().is_err());
+        // only SHA-1 is open to the attack
+        assert!(ObjectFormat::Sha256.digest(&data).is_ok());
+        assert!(ObjectFormat::Sha1.digest(&data[..320]).is_ok());
+    }
+}
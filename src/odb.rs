@@ -0,0 +1,1164 @@
+//! The object database: reading and writing loose objects under
+//! `.git/objects`, and reading objects from the packs in `objects/pack`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::object::{
+    header_field, read_object_header, GitObject, Hasher, ObjectFormat, ObjectType,
+};
+use crate::pack::{self, DeltaBaseCache, EntryKind, ObjectData, Pack};
+
+/// Repository settings that control how objects are written and read.
+struct ObjectSettings {
+    fsync: FsyncComponents,
+    delta_base_cache_limit: usize,
+    loose_compression: Compression,
+    pack_compression: Compression,
+    /// 0 means one per CPU.
+    pack_threads: usize,
+    /// How many objects are tried as delta bases for each one packed.
+    pack_window: usize,
+    /// The longest delta chain written into a pack.
+    pack_depth: usize,
+}
+
+/// Map a git compression level (-1 meaning "zlib default") to a zlib setting.
+pub fn compression_level(level: i32) -> Result<Compression, anyhow::Error> {
+    match level {
+        -1 => Ok(Compression::default()),
+        0..=9 => Ok(Compression::new(level as u32)),
+        _ => anyhow::bail!("bad zlib compression level {level}"),
+    }
+}
+
+fn config_compression(config: &Config, key: &str) -> Result<Option<Compression>, anyhow::Error> {
+    let Some(value) = config.get(key) else {
+        return Ok(None);
+    };
+    let level = value
+        .parse::<i32>()
+        .context(format!("bad numeric config value '{value}' for '{key}'"))?;
+    compression_level(level).context(key.to_string()).map(Some)
+}
+
+/// The `core.fsync` components that concern the object database.
+struct FsyncComponents {
+    loose_objects: bool,
+    packs: bool,
+    /// Pack indexes.
+    pack_metadata: bool,
+}
+
+impl Default for FsyncComponents {
+    /// git's default syncs packs, their metadata and refs, but not loose
+    /// objects.
+    fn default() -> Self {
+        FsyncComponents {
+            loose_objects: false,
+            packs: true,
+            pack_metadata: true,
+        }
+    }
+}
+
+/// Parse a `core.fsync` component list on top of git's default.
+fn parse_fsync(value: &str) -> FsyncComponents {
+    let mut fsync = FsyncComponents::default();
+    for component in value.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+        let (negative, name) = match component.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, component),
+        };
+        let (loose, packs, metadata) = match name {
+            "none" => {
+                fsync = FsyncComponents {
+                    loose_objects: false,
+                    packs: false,
+                    pack_metadata: false,
+                };
+                continue;
+            }
+            "loose-object" => (true, false, false),
+            "pack" => (false, true, false),
+            "pack-metadata" | "derived-metadata" => (false, false, true),
+            "objects" | "committed" | "added" => (true, true, false),
+            "all" => (true, true, true),
+            "commit-graph" | "index" | "reference" => continue,
+            unknown => {
+                eprintln!("warning: ignoring unknown core.fsync component '{unknown}'");
+                continue;
+            }
+        };
+        let set = |flag: &mut bool, included: bool| {
+            if included {
+                *flag = !negative;
+            }
+        };
+        set(&mut fsync.loose_objects, loose);
+        set(&mut fsync.packs, packs);
+        set(&mut fsync.pack_metadata, metadata);
+    }
+    fsync
+}
+
+/// Parse an integer config value with an optional `k`, `m` or `g` suffix.
+fn config_size(config: &Config, key: &str) -> Result<Option<usize>, anyhow::Error> {
+    let Some(value) = config.get(key) else {
+        return Ok(None);
+    };
+    let lower = value.to_ascii_lowercase();
+    let (number, unit) = match lower.as_bytes().last() {
+        Some(b'k') => (&lower[..lower.len() - 1], 1 << 10),
+        Some(b'm') => (&lower[..lower.len() - 1], 1 << 20),
+        Some(b'g') => (&lower[..lower.len() - 1], 1 << 30),
+        _ => (&lower[..], 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("bad numeric config value '{value}' for '{key}'"))
+}
+
+impl ObjectSettings {
+    fn from_config(config: &Config) -> Result<Self, anyhow::Error> {
+        let mut fsync = FsyncComponents::default();
+        if let Some(components) = config.get("core.fsync") {
+            fsync = parse_fsync(components);
+        } else if let Some(loose) = config.get_bool("core.fsyncObjectFiles")? {
+            fsync.loose_objects = loose;
+        }
+        // core.compression is the fallback for both; loose objects otherwise
+        // favour speed and packs use the zlib default, as in git
+        let core = config_compression(config, "core.compression")?;
+        let loose = config_compression(config, "core.looseCompression")?;
+        let pack = config_compression(config, "pack.compression")?;
+        Ok(ObjectSettings {
+            fsync,
+            delta_base_cache_limit: config_size(config, "core.deltaBaseCacheLimit")?
+                .unwrap_or(DeltaBaseCache::DEFAULT_LIMIT),
+            loose_compression: loose.or(core).unwrap_or(Compression::fast()),
+            pack_compression: pack.or(core).unwrap_or_default(),
+            pack_threads: config_size(config, "pack.threads")?.unwrap_or(0),
+            pack_window: config_size(config, "pack.window")?.unwrap_or(10),
+            // as in git, longer chains are not allowed
+            pack_depth: config_size(config, "pack.depth")?.unwrap_or(50).min(4095),
+        })
+    }
+}
+
+/// The packs in `objects/pack`, as last scanned.
+struct PackList {
+    /// When the pack directory was last modified at the time of the scan.
+    mtime: Option<SystemTime>,
+    packs: Vec<Pack>,
+}
+
+/// The objects directory of a repository, with the settings its config gives
+/// for writing objects.
+pub struct ObjectDatabase {
+    dir: PathBuf,
+    format: ObjectFormat,
+    settings: ObjectSettings,
+    verify: bool,
+    /// Scanned on first use, and again when an object is not found and the
+    /// pack directory changed since.
+    packs: RwLock<Option<Arc<PackList>>>,
+    /// The commits of a shallow repository whose parents are missing.
+    shallow: RwLock<HashSet<String>>,
+    /// The URL of the remote a partial clone fetches missing objects from.
+    promisor: RwLock<Option<String>>,
+}
+
+impl ObjectDatabase {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        format: ObjectFormat,
+        config: &Config,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(ObjectDatabase {
+            dir: dir.into(),
+            format,
+            settings: ObjectSettings::from_config(config)?,
+            verify: true,
+            packs: RwLock::new(None),
+            shallow: RwLock::new(HashSet::new()),
+            promisor: RwLock::new(None),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The hash function objects are named with.
+    pub fn format(&self) -> ObjectFormat {
+        self.format
+    }
+
+    /// Whether `oid` is a shallow commit, one whose parents are not in the
+    /// repository and which history walks treat as a root.
+    pub fn is_shallow(&self, oid: &str) -> bool {
+        self.shallow.read().unwrap().contains(oid)
+    }
+
+    /// The shallow commits, sorted.
+    pub fn shallow(&self) -> Vec<String> {
+        let mut commits = self
+            .shallow
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        commits.sort();
+        commits
+    }
+
+    /// Replace the set of shallow commits; see [`crate::shallow`] for where
+    /// they are kept.
+    pub fn set_shallow(&self, commits: impl IntoIterator<Item = String>) {
+        *self.shallow.write().unwrap() = commits.into_iter().collect();
+    }
+
+    /// Fetch objects that are missing from the remote at `url` when they are
+    /// opened, as a partial clone does; see [`ObjectDatabase::fetch_missing`].
+    pub fn set_promisor(&self, url: Option<String>) {
+        *self.promisor.write().unwrap() = url;
+    }
+
+    /// Fetch those of `oids` that are missing from the promisor remote, in
+    /// one request. Nothing happens in a repository that is not a partial
+    /// clone.
+    pub fn fetch_missing(&self, oids: &[String]) -> Result<(), anyhow::Error> {
+        let Some(url) = self.promisor.read().unwrap().clone() else {
+            return Ok(());
+        };
+        let mut missing = oids
+            .iter()
+            .filter(|oid| !self.contains(oid))
+            .cloned()
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing.dedup();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        crate::transport::fetch_objects(self, &url, &missing)
+    }
+
+    /// Whether objects are checked against their name when read; on by default.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Use `level` for every object written, whatever the config says.
+    pub fn set_compression(&mut self, level: Compression) {
+        self.settings.loose_compression = level;
+        self.settings.pack_compression = level;
+    }
+
+    /// How many bytes of resolved delta bases a pack keeps in memory
+    /// (`core.deltaBaseCacheLimit`).
+    pub fn delta_base_cache_limit(&self) -> usize {
+        self.settings.delta_base_cache_limit
+    }
+
+    /// Whether new packs and their indexes are flushed to disk before being
+    /// moved into place (`core.fsync`).
+    pub fn fsync_packs(&self) -> bool {
+        self.settings.fsync.packs || self.settings.fsync.pack_metadata
+    }
+
+    /// The zlib level for objects written into packs (`pack.compression`).
+    pub fn pack_compression(&self) -> Compression {
+        self.settings.pack_compression
+    }
+
+    /// How many threads hash objects and resolve deltas when a pack is
+    /// indexed (`pack.threads`, one per CPU when unset or 0).
+    pub fn pack_threads(&self) -> usize {
+        match self.settings.pack_threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    /// How many objects before each one are tried as its delta base when
+    /// writing a pack (`pack.window`).
+    pub fn pack_window(&self) -> usize {
+        self.settings.pack_window
+    }
+
+    /// The longest delta chain written into a pack (`pack.depth`).
+    pub fn pack_depth(&self) -> usize {
+        self.settings.pack_depth
+    }
+
+    fn pack_dir_mtime(&self) -> Option<SystemTime> {
+        fs::metadata(self.dir.join("pack"))
+            .and_then(|m| m.modified())
+            .ok()
+    }
+
+    fn scan_packs(&self) -> Arc<PackList> {
+        let mtime = self.pack_dir_mtime();
+        let mut packs = Vec::new();
+        for idx in pack::index_files(&self.dir).unwrap_or_default() {
+            match Pack::open(&idx, self.format, self.settings.delta_base_cache_limit) {
+                Ok(pack) => packs.push(pack),
+                Err(e) => eprintln!("warning: ignoring pack {idx:?}: {e:#}"),
+            }
+        }
+        let list = Arc::new(PackList { mtime, packs });
+        *self.packs.write().unwrap() = Some(list.clone());
+        list
+    }
+
+    /// The pack holding `oid`, and the offset of its entry there.
+    fn find_packed(&self, oid: &str) -> Option<(Arc<PackList>, usize, u64)> {
+        let find = |list: Arc<PackList>| {
+            let (i, entry) = list
+                .packs
+                .iter()
+                .enumerate()
+                .find_map(|(i, pack)| Some((i, pack.index.find(oid)?)))?;
+            let offset = entry.offset;
+            Some((list, i, offset))
+        };
+        let current = self.packs.read().unwrap().clone();
+        match current {
+            Some(list) => {
+                let mtime = list.mtime;
+                find(list).or_else(|| {
+                    // a pack may have arrived since the last scan
+                    (self.pack_dir_mtime() != mtime)
+                        .then(|| find(self.scan_packs()))
+                        .flatten()
+                })
+            }
+            None => find(self.scan_packs()),
+        }
+    }
+
+    /// Where the loose object `oid` is stored.
+    pub fn object_path(&self, oid: &str) -> PathBuf {
+        let (fanout, rest) = oid.split_at_checked(2).unwrap_or((oid, ""));
+        self.dir.join(fanout).join(rest)
+    }
+
+    pub fn contains(&self, oid: &str) -> bool {
+        self.format.is_hex_id(oid.as_bytes())
+            && (self.object_path(oid).is_file() || self.find_packed(oid).is_some())
+    }
+
+    /// Open an object, loose or packed. A partial clone first fetches it
+    /// from the promisor remote if it is missing.
+    pub fn open(&self, oid: &str) -> Result<ObjectReader, Error> {
+        self.or_fetch(oid, || self.open_local(oid))
+    }
+
+    /// Run `local`, and if `oid` is missing but a promisor remote may have
+    /// it, fetch it and run `local` again.
+    fn or_fetch<T>(&self, oid: &str, local: impl Fn() -> Result<T, Error>) -> Result<T, Error> {
+        let promised = self.promisor.read().unwrap().is_some();
+        match local() {
+            Err(Error::MissingObject { .. })
+                if promised && self.format.is_hex_id(oid.as_bytes()) =>
+            {
+                if let Err(e) = self.fetch_missing(&[oid.to_string()]) {
+                    eprintln!("warning: could not fetch {oid} from promisor remote: {e:#}");
+                }
+                local()
+            }
+            result => result,
+        }
+    }
+
+    fn open_local(&self, oid: &str) -> Result<ObjectReader, Error> {
+        match ObjectReader::open(self.object_path(oid), oid, self.format, self.verify) {
+            Err(Error::MissingObject { .. }) => {}
+            result => return result,
+        }
+        let Some((list, i, offset)) = self.find_packed(oid) else {
+            return Err(Error::MissingObject {
+                oid: oid.to_string(),
+            });
+        };
+        let pack = &list.packs[i];
+        let corrupt = |e: anyhow::Error| Error::CorruptObject {
+            oid: oid.to_string(),
+            path: pack.path.clone(),
+            reason: format!("{e:#}"),
+        };
+        // whole objects are inflated as they are read, like loose ones
+        let reader = match pack.stream_at(offset).map_err(corrupt)? {
+            Some((kind, size, reader)) => ObjectReader::packed(
+                pack.path.clone(),
+                oid,
+                kind,
+                size,
+                Source::Streamed(reader),
+                self.format,
+                self.verify,
+            ),
+            None => {
+                let (kind, data) = pack.object_at(offset).map_err(corrupt)?;
+                ObjectReader::packed(
+                    pack.path.clone(),
+                    oid,
+                    kind,
+                    data.len(),
+                    Source::Packed(std::io::Cursor::new(data)),
+                    self.format,
+                    self.verify,
+                )
+            }
+        };
+        Ok(reader)
+    }
+
+    /// How many bytes the object takes on disk: the loose object file, or
+    /// its entry in a pack.
+    pub fn disk_size(&self, oid: &str) -> Result<u64, anyhow::Error> {
+        if let Ok(meta) = fs::metadata(self.object_path(oid)) {
+            return Ok(meta.len());
+        }
+        let (list, i, offset) = self.find_packed(oid).ok_or_else(|| Error::MissingObject {
+            oid: oid.to_string(),
+        })?;
+        Ok(list.packs[i].raw_entry(offset)?.len() as u64)
+    }
+
+    /// The object a packed object is stored as a delta against, if it is.
+    pub fn delta_base(&self, oid: &str) -> Result<Option<String>, anyhow::Error> {
+        if self.object_path(oid).is_file() {
+            return Ok(None);
+        }
+        let Some((list, i, offset)) = self.find_packed(oid) else {
+            return Ok(None);
+        };
+        let pack = &list.packs[i];
+        Ok(match pack.entry_header(offset)?.kind {
+            EntryKind::Object(_) => None,
+            EntryKind::RefDelta(base) => Some(base),
+            EntryKind::OfsDelta(base) => pack.oid_at(base).map(str::to_string),
+        })
+    }
+
+    /// The delta a packed object is stored as, for copying into another
+    /// pack as it is: the object it is against, the size the delta inflates
+    /// to, and its compressed data. `None` if the object is not stored as a
+    /// delta.
+    pub fn packed_delta(
+        &self,
+        oid: &str,
+    ) -> Result<Option<(String, usize, Vec<u8>)>, anyhow::Error> {
+        if self.object_path(oid).is_file() {
+            return Ok(None);
+        }
+        let Some((list, i, offset)) = self.find_packed(oid) else {
+            return Ok(None);
+        };
+        let pack = &list.packs[i];
+        let header = pack.entry_header(offset)?;
+        if let EntryKind::Object(_) = header.kind {
+            return Ok(None);
+        }
+        let Some(base) = self.delta_base(oid)? else {
+            return Ok(None);
+        };
+        let mut raw = pack.raw_entry(offset)?;
+        raw.drain(..usize::try_from(header.data_offset - offset)?);
+        Ok(Some((base, header.size, raw)))
+    }
+
+    pub fn read(&self, oid: &str) -> Result<GitObject, Error> {
+        self.open(oid)?.read_all()
+    }
+
+    /// Read only the type and size of an object, without inflating its content
+    /// or, for a packed object, applying its deltas.
+    pub fn header(&self, oid: &str) -> Result<(ObjectType, usize), Error> {
+        self.or_fetch(oid, || self.header_local(oid))
+    }
+
+    fn header_local(&self, oid: &str) -> Result<(ObjectType, usize), Error> {
+        match ObjectReader::open(self.object_path(oid), oid, self.format, self.verify) {
+            Err(Error::MissingObject { .. }) => {}
+            result => return result.map(|reader| (reader.kind, reader.size)),
+        }
+        let Some((list, i, offset)) = self.find_packed(oid) else {
+            return Err(Error::MissingObject {
+                oid: oid.to_string(),
+            });
+        };
+        let pack = &list.packs[i];
+        pack.object_header(offset)
+            .map_err(|e| Error::CorruptObject {
+                oid: oid.to_string(),
+                path: pack.path.clone(),
+                reason: format!("{e:#}"),
+            })
+    }
+
+    /// Follow tags and commits until an object of `kind` is reached, as
+    /// `git cat-file <type> <object>` does.
+    pub fn peel(&self, obj: GitObject, kind: ObjectType) -> Result<GitObject, anyhow::Error> {
+        let mut obj = obj;
+        loop {
+            if obj.kind == kind {
+                return Ok(obj);
+            }
+            let field = match (obj.kind, kind) {
+                (ObjectType::Tag, _) => "object",
+                (ObjectType::Commit, ObjectType::Tree) => "tree",
+                _ => anyhow::bail!("object is a {}, not a {}", obj.kind, kind),
+            };
+            let next = header_field(&obj.content, field)
+                .ok_or_else(|| anyhow::anyhow!("{} has no {} header", obj.kind, field))?;
+            let next = std::str::from_utf8(next)?.to_string();
+            obj = self.read(&next)?;
+        }
+    }
+
+    /// The full id of the only object whose id starts with `prefix` (at least
+    /// four hex digits). Errors when more than one object matches.
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<Option<String>, anyhow::Error> {
+        let hex_len = self.format.hex_len();
+        if !(4..=hex_len).contains(&prefix.len()) || !prefix.bytes().all(|c| c.is_ascii_hexdigit())
+        {
+            return Ok(None);
+        }
+        let prefix = prefix.to_ascii_lowercase();
+        if prefix.len() == hex_len {
+            // a partial clone may fetch it when it is opened
+            let promised = self.promisor.read().unwrap().is_some();
+            return Ok((promised || self.contains(&prefix)).then_some(prefix));
+        }
+        let dir = self.dir.join(&prefix[..2]);
+        let mut found = None;
+        let mut add = |oid: String| {
+            if found.as_ref().is_some_and(|found| *found != oid) {
+                anyhow::bail!("short object ID {prefix} is ambiguous");
+            }
+            found = Some(oid);
+            Ok(())
+        };
+        if dir.is_dir() {
+            for entry in fs::read_dir(&dir)? {
+                let file = entry?.file_name();
+                let Some(file) = file.to_str() else {
+                    continue;
+                };
+                if file.starts_with(&prefix[2..]) {
+                    add(format!("{}{}", &prefix[..2], file))?;
+                }
+            }
+        }
+        // rescan, in case packs arrived
+        let current = self.packs.read().unwrap().clone();
+        let list = match current {
+            Some(list) if list.mtime == self.pack_dir_mtime() => list,
+            _ => self.scan_packs(),
+        };
+        for pack in &list.packs {
+            let entries = &pack.index.entries;
+            let start = entries.partition_point(|e| e.oid.as_str() < prefix.as_str());
+            for entry in entries[start..]
+                .iter()
+                .take_while(|e| e.oid.starts_with(&prefix))
+            {
+                add(entry.oid.clone())?;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Store `data` as an object of type `kind`, returning its id. `kind` is
+    /// not checked, so `hash-object --literally` can use it.
+    pub fn write(&self, kind: &str, data: &[u8]) -> Result<String, anyhow::Error> {
+        let hash = self.format.hash_object(kind, data)?;
+        if self.object_path(&hash).try_exists()? || self.find_packed(&hash).is_some() {
+            return Ok(hash);
+        }
+        let (tmp, f) = TempFile::create(&self.dir, "tmp_obj_")?;
+        let mut e = ZlibEncoder::new(f, self.settings.loose_compression);
+        e.write_all(format!("{kind} {}\0", data.len()).as_bytes())
+            .context("write object file error")?;
+        e.write_all(data).context("write object file error")?;
+        let f = e.finish().context("write object file error")?;
+        self.persist_object(tmp, f, &hash)?;
+        Ok(hash)
+    }
+
+    /// Hash, and optionally write, an object whose `size` bytes of content come
+    /// from `r`. Content flows through the hash and zlib in one pass with a fixed
+    /// buffer, so arbitrarily large blobs never have to fit in memory.
+    pub fn stream_object(
+        &self,
+        kind: &str,
+        size: u64,
+        r: &mut impl Read,
+        write: bool,
+    ) -> Result<String, anyhow::Error> {
+        let header = format!("{kind} {size}\0");
+        let mut hasher = self.format.hasher();
+        hasher.update(header.as_bytes());
+        let mut out = if write {
+            let (tmp, f) = TempFile::create(&self.dir, "tmp_obj_")?;
+            let mut e =
+                ZlibEncoder::new(std::io::BufWriter::new(f), self.settings.loose_compression);
+            e.write_all(header.as_bytes())?;
+            Some((tmp, e))
+        } else {
+            None
+        };
+
+        let mut buf = vec![0; 64 * 1024];
+        let mut total = 0u64;
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            hasher.update(&buf[..n]);
+            if let Some((_, e)) = &mut out {
+                e.write_all(&buf[..n])?;
+            }
+            total += n as u64;
+        }
+        if total != size {
+            anyhow::bail!("expected {size} bytes but read {total}, did the file change?");
+        }
+
+        let hash = hasher.finalize_hex()?;
+        if let Some((tmp, e)) = out {
+            let f = e.finish()?.into_inner().map_err(|e| e.into_error())?;
+            self.persist_object(tmp, f, &hash)?;
+        }
+        Ok(hash)
+    }
+
+    /// Move the loose object written to `tmp` through `f` into place as
+    /// `hash`, flushing it first if the config asks for it.
+    fn persist_object(&self, tmp: TempFile, f: fs::File, hash: &str) -> Result<(), anyhow::Error> {
+        let path = self.object_path(hash);
+        if path.try_exists()? {
+            return Ok(());
+        }
+        if self.settings.fsync.loose_objects {
+            f.sync_all().context(format!("fsync {:?}", tmp.path()))?;
+        }
+        drop(f);
+        tmp.persist(&path)
+    }
+}
+
+/// Where the content of an [`ObjectReader`] comes from.
+enum Source {
+    /// Inflated from a loose object file as it is read.
+    Loose(std::io::BufReader<flate2::bufread::ZlibDecoder<std::io::BufReader<fs::File>>>),
+    /// Inflated from a whole object in a pack as it is read.
+    Streamed(pack::EntryReader),
+    /// Resolved from a pack's deltas.
+    Packed(std::io::Cursor<ObjectData>),
+}
+
+/// An object whose header has been read but whose content has not. Loose
+/// objects and whole packed ones are still being inflated from disk, so
+/// large blobs never have to fit in memory; deltified packed ones have been
+/// resolved already.
+///
+/// Content read through it is hashed on the way; `finish` then checks that
+/// the object was complete, had nothing after its content and (unless
+/// disabled) hashes to its name.
+pub struct ObjectReader {
+    name: String,
+    path: PathBuf,
+    pub size: usize,
+    pub kind: ObjectType,
+    source: Source,
+    remaining: u64,
+    hasher: Option<Hasher>,
+}
+
+impl ObjectReader {
+    fn open(
+        path: PathBuf,
+        object: &str,
+        format: ObjectFormat,
+        verify: bool,
+    ) -> Result<Self, Error> {
+        // names also come from inside other objects, so they cannot be trusted
+        if !format.is_hex_id(object.as_bytes()) {
+            return Err(Error::BadObjectName(object.to_string()));
+        }
+        let f = match std::fs::File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::MissingObject {
+                    oid: object.to_string(),
+                })
+            }
+            Err(source) => return Err(Error::Io { path, source }),
+        };
+        let z = flate2::bufread::ZlibDecoder::new(std::io::BufReader::new(f));
+        let mut reader = std::io::BufReader::new(z);
+        let (kind, size) = match read_object_header(&mut reader) {
+            Ok(header) => header,
+            Err(e) => {
+                return Err(Error::CorruptObject {
+                    oid: object.to_string(),
+                    path,
+                    reason: format!("{e:#}"),
+                })
+            }
+        };
+        Ok(ObjectReader {
+            name: object.to_string(),
+            path,
+            size,
+            kind,
+            source: Source::Loose(reader),
+            remaining: size as u64,
+            hasher: Self::hasher(kind, size, format, verify),
+        })
+    }
+
+    /// A reader for an object of `size` bytes read from the pack at `path`
+    /// through `source`.
+    fn packed(
+        path: PathBuf,
+        object: &str,
+        kind: ObjectType,
+        size: usize,
+        source: Source,
+        format: ObjectFormat,
+        verify: bool,
+    ) -> Self {
+        ObjectReader {
+            name: object.to_string(),
+            path,
+            size,
+            kind,
+            source,
+            remaining: size as u64,
+            hasher: Self::hasher(kind, size, format, verify),
+        }
+    }
+
+    fn hasher(kind: ObjectType, size: usize, format: ObjectFormat, verify: bool) -> Option<Hasher> {
+        verify.then(|| {
+            let mut hasher = format.hasher();
+            hasher.update(format!("{kind} {size}\0").as_bytes());
+            hasher
+        })
+    }
+
+    fn corrupt(&self, reason: impl Into<String>) -> Error {
+        Error::CorruptObject {
+            oid: self.name.clone(),
+            path: self.path.clone(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Read the next chunk of content into `buf`, returning 0 once all of it
+    /// has been read.
+    pub fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let max = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = loop {
+            let read = match &mut self.source {
+                Source::Loose(reader) => reader.read(&mut buf[..max]),
+                Source::Streamed(reader) => reader.read(&mut buf[..max]),
+                Source::Packed(data) => data.read(&mut buf[..max]),
+            };
+            match read {
+                Ok(n) => break n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                // flate2 reports a damaged stream as invalid input or data
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::InvalidInput
+                            | std::io::ErrorKind::InvalidData
+                            | std::io::ErrorKind::UnexpectedEof
+                    ) =>
+                {
+                    return Err(self.corrupt(format!("zlib: {e}")))
+                }
+                Err(source) => {
+                    return Err(Error::Io {
+                        path: self.path.clone(),
+                        source,
+                    })
+                }
+            }
+        };
+        if n == 0 {
+            return Err(self.corrupt(format!(
+                "truncated: {} of {} bytes missing",
+                self.remaining, self.size
+            )));
+        }
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    /// Check the end of the object once all its content has been read.
+    pub fn finish(mut self) -> Result<(), Error> {
+        if self.remaining != 0 {
+            return Err(self.corrupt("object content was not read to the end"));
+        }
+        if let Source::Loose(reader) = &mut self.source {
+            let mut extra = [0u8; 1];
+            let trailing = match reader.read(&mut extra) {
+                Ok(n) => n > 0,
+                Err(e) => return Err(self.corrupt(format!("zlib: {e}"))),
+            };
+            // bytes after the end of the zlib stream are garbage too
+            let trailing = trailing
+                || !reader
+                    .get_mut()
+                    .get_mut()
+                    .fill_buf()
+                    .map(|b| b.is_empty())
+                    .unwrap_or(true);
+            if trailing {
+                return Err(self.corrupt("garbage at end of loose object"));
+            }
+        }
+        if let Some(hasher) = self.hasher.take() {
+            let actual = hasher.finalize_hex()?;
+            if actual != self.name {
+                return Err(Error::HashMismatch {
+                    oid: self.name,
+                    path: self.path,
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_all(mut self) -> Result<GitObject, Error> {
+        let mut content = vec![0; self.size];
+        let mut filled = 0;
+        while filled < content.len() {
+            filled += self.read_chunk(&mut content[filled..])?;
+        }
+        let kind = self.kind;
+        self.finish()?;
+        Ok(GitObject { kind, content })
+    }
+
+    /// Stream the whole content into `w`, verifying it on the way.
+    pub fn copy_to(mut self, w: &mut impl Write) -> Result<(), anyhow::Error> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = self.read_chunk(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            w.write_all(&buf[..n])?;
+        }
+        self.finish()?;
+        Ok(())
+    }
+}
+
+/// A file being written under a temporary name, such as a loose object or a
+/// pack. The file is removed on drop unless `persist` moved it into place.
+pub struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Create a file named `<prefix><unique suffix>` in `dir`.
+    pub fn create(dir: &Path, prefix: &str) -> Result<(Self, fs::File), anyhow::Error> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        fs::create_dir_all(dir)?;
+        loop {
+            let name = format!(
+                "{prefix}{}_{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = dir.join(name);
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(f) => {
+                    let tmp = TempFile {
+                        path,
+                        persisted: false,
+                    };
+                    return Ok((tmp, f));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).context(format!("create {:?}", path)),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Make the file read-only and move it to `path`. A file already there
+    /// is left alone, since objects and packs are named by their content.
+    /// Any handle on the file must be closed (and flushed, if wanted) first.
+    pub fn persist(mut self, path: &Path) -> Result<(), anyhow::Error> {
+        if path.try_exists()? {
+            return Ok(());
+        }
+        let mut perms = fs::metadata(&self.path)?.permissions();
+        perms.set_readonly(true);
+        fs::set_permissions(&self.path, perms)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(&self.path, path).context(format!("rename to {:?}", path))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An object database in a directory of its own, removed on drop.
+    struct TempOdb(ObjectDatabase);
+
+    impl TempOdb {
+        fn new(name: &str) -> Self {
+            Self::with_format(name, ObjectFormat::Sha1)
+        }
+
+        fn with_format(name: &str, format: ObjectFormat) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "codecrafters-git-odb-{}-{name}",
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            TempOdb(ObjectDatabase::new(dir, format, &Config::default()).unwrap())
+        }
+
+        /// Store `data`, deflated and followed by `garbage`, as the loose
+        /// object `oid`, whatever it hashes to.
+        fn store_raw(&self, oid: &str, data: &[u8], garbage: &[u8]) {
+            let path = self.0.object_path(oid);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let _ = fs::remove_file(&path);
+            let mut e = ZlibEncoder::new(fs::File::create(&path).unwrap(), Compression::fast());
+            e.write_all(data).unwrap();
+            e.finish().unwrap().write_all(garbage).unwrap();
+        }
+    }
+
+    impl Drop for TempOdb {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.dir());
+        }
+    }
+
+    const HELLO: &str = "ce013625030ba8dba906f756967f9e9ca394464a";
+
+    #[test]
+    fn streams_binary_blobs() {
+        let odb = TempOdb::new("binary");
+        let content: Vec<u8> = (0..=255u8).cycle().take(100_000).collect();
+        let oid = odb.0.write("blob", &content).unwrap();
+        let obj = odb.0.open(&oid).unwrap();
+        assert_eq!((obj.kind, obj.size), (ObjectType::Blob, content.len()));
+        let mut out = Vec::new();
+        obj.copy_to(&mut out).unwrap();
+        assert_eq!(out, content);
+    }
+
+    #[test]
+    fn verifies_objects_on_read() {
+        let mut odb = TempOdb::new("verify");
+        odb.store_raw(HELLO, b"blob 6\0hello\n", b"");
+        assert_eq!(odb.0.read(HELLO).unwrap().content, b"hello\n");
+
+        odb.store_raw(HELLO, b"blob 6\0HELLO\n", b"");
+        let err = odb.0.read(HELLO).err().unwrap();
+        assert!(
+            matches!(&err, Error::HashMismatch { actual, .. } if actual != HELLO),
+            "{err}"
+        );
+        odb.0.set_verify(false);
+        assert_eq!(odb.0.read(HELLO).unwrap().content, b"HELLO\n");
+        odb.0.set_verify(true);
+
+        for (data, garbage) in [
+            (&b"blob 6\0hello\n"[..], &b"junk"[..]),
+            (b"blob 6\0hello\nmore", b""),
+            (b"blob 6\0hel", b""),
+            (b"blob 6 hello\n", b""),
+        ] {
+            odb.store_raw(HELLO, data, garbage);
+            let err = odb.0.read(HELLO).err().unwrap();
+            assert!(matches!(err, Error::CorruptObject { .. }), "{err}");
+        }
+        assert!(matches!(
+            odb.0.read(&HELLO.replace('c', "d")),
+            Err(Error::MissingObject { .. })
+        ));
+    }
+
+    #[test]
+    fn streams_blobs_into_the_object_store() {
+        let odb = TempOdb::new("stream");
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let oid = odb
+            .0
+            .stream_object("blob", content.len() as u64, &mut &content[..], true)
+            .unwrap();
+        assert_eq!(
+            oid,
+            ObjectFormat::Sha1.hash_object("blob", &content).unwrap()
+        );
+        assert_eq!(odb.0.read(&oid).unwrap().content, content);
+
+        // a size that does not match what is read is an error, and leaves
+        // nothing behind
+        let err = odb.0.stream_object("blob", 10, &mut &b"short"[..], true);
+        assert!(err.is_err());
+        let files = fs::read_dir(odb.0.dir())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().is_file())
+            .count();
+        assert_eq!(files, 0);
+    }
+
+    #[test]
+    fn sha256_objects() {
+        let odb = TempOdb::with_format("sha256", ObjectFormat::Sha256);
+        let oid = odb.0.write("blob", b"hello\n").unwrap();
+        assert_eq!(
+            oid,
+            "2cf8d83d9ee29543b34a87727421fdecb7e3f3a183d337639025de576db9ebb4"
+        );
+        assert_eq!(odb.0.read(&oid).unwrap().content, b"hello\n");
+        let streamed = odb
+            .0
+            .stream_object("blob", 6, &mut &b"hello\n"[..], false)
+            .unwrap();
+        assert_eq!(streamed, oid);
+        // a SHA-1 name is no name at all here
+        assert!(matches!(odb.0.read(HELLO), Err(Error::BadObjectName(_))));
+    }
+
+    #[test]
+    fn written_objects_are_never_clobbered() {
+        let odb = TempOdb::new("clobber");
+        let oid = odb.0.write("blob", b"hello\n").unwrap();
+        assert_eq!(oid, HELLO);
+        let path = odb.0.object_path(HELLO);
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+
+        // whatever is already there is trusted to be the same object
+        odb.store_raw(HELLO, b"blob 6\0hello\n", b"junk");
+        let stored = fs::read(&path).unwrap();
+        odb.0.write("blob", b"hello\n").unwrap();
+        odb.0
+            .stream_object("blob", 6, &mut &b"hello\n"[..], true)
+            .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), stored);
+        assert_eq!(fs::read_dir(odb.0.dir()).unwrap().count(), 1);
+    }
+
+    fn fsync(value: &str) -> (bool, bool, bool) {
+        let fsync = parse_fsync(value);
+        (fsync.loose_objects, fsync.packs, fsync.pack_metadata)
+    }
+
+    #[test]
+    fn fsync_components_add_to_the_default() {
+        assert_eq!(fsync(""), (false, true, true));
+        assert_eq!(fsync("loose-object"), (true, true, true));
+        assert_eq!(fsync("-pack"), (false, false, true));
+        assert_eq!(fsync("reference, index"), (false, true, true));
+    }
+
+    #[test]
+    fn fsync_none_clears_and_all_sets_everything() {
+        assert_eq!(fsync("none"), (false, false, false));
+        assert_eq!(fsync("none,pack"), (false, true, false));
+        assert_eq!(fsync("all"), (true, true, true));
+        assert_eq!(fsync("all,-derived-metadata"), (true, true, false));
+        assert_eq!(fsync("objects,-loose-object"), (false, true, true));
+    }
+
+    #[test]
+    fn compression_levels() {
+        assert_eq!(compression_level(-1).unwrap(), Compression::default());
+        assert_eq!(compression_level(0).unwrap(), Compression::none());
+        assert_eq!(compression_level(9).unwrap(), Compression::best());
+        assert!(compression_level(10).is_err());
+        assert!(compression_level(-2).is_err());
+    }
+
+    #[test]
+    fn compression_levels_from_config() {
+        let git_dir = std::env::temp_dir().join(format!(
+            "codecrafters-git-odb-{}-compression",
+            std::process::id()
+        ));
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(
+            git_dir.join("config"),
+            "[core]\n\tcompression = 3\n\tlooseCompression = -1\n[pack]\n\tcompression = x\n",
+        )
+        .unwrap();
+        let config = Config::load(&git_dir).unwrap();
+        fs::remove_dir_all(&git_dir).unwrap();
+        assert_eq!(
+            config_compression(&config, "core.compression").unwrap(),
+            Some(Compression::new(3))
+        );
+        assert_eq!(
+            config_compression(&config, "core.looseCompression").unwrap(),
+            Some(Compression::default())
+        );
+        assert!(config_compression(&config, "pack.compression").is_err());
+        assert_eq!(config_compression(&config, "pack.depth").unwrap(), None);
+    }
+}
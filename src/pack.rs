@@ -0,0 +1,1992 @@
+//! Reading packfiles and their `.idx` indexes, indexing packs received
+//! from a remote, and writing packs, with deltas computed by
+//! [`delta`](crate::delta) or reused from existing packs.
+//!
+//! See gitformat-pack(5): a pack is a `PACK` header followed by zlib-deflated
+//! entries, each either a whole object or a delta against another entry, and
+//! a trailing hash of everything before it. Object ids and checksums use the
+//! repository's object format, so both are 20 bytes (SHA-1) or 32 (SHA-256).
+//!
+//! Packs are read from disk an entry at a time rather than loaded whole, so
+//! their size is not limited by memory.
+
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use anyhow::Context;
+use flate2::write::ZlibEncoder;
+
+use crate::delta::DeltaIndex;
+use crate::error::Error;
+use crate::object::{Hasher, ObjectFormat, ObjectType};
+use crate::odb::{ObjectDatabase, TempFile};
+use crate::progress::Progress;
+use crate::revwalk::ListedObject;
+
+pub const OBJ_OFS_DELTA: u8 = 6;
+pub const OBJ_REF_DELTA: u8 = 7;
+
+impl ObjectType {
+    /// The object type for a type number from a pack entry header.
+    pub fn from_pack_type(t: u8) -> Option<Self> {
+        match t {
+            1 => Some(ObjectType::Commit),
+            2 => Some(ObjectType::Tree),
+            3 => Some(ObjectType::Blob),
+            4 => Some(ObjectType::Tag),
+            _ => None,
+        }
+    }
+
+    /// The type number pack entry headers use for this type.
+    pub fn pack_type(self) -> u8 {
+        match self {
+            ObjectType::Commit => 1,
+            ObjectType::Tree => 2,
+            ObjectType::Blob => 3,
+            ObjectType::Tag => 4,
+        }
+    }
+}
+
+/// What a pack entry stores.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    Object(ObjectType),
+    /// A delta against the entry at this absolute offset in the same pack.
+    OfsDelta(u64),
+    /// A delta against the object with this id.
+    RefDelta(String),
+}
+
+/// The header of a pack entry: its kind, the inflated size of its data, and
+/// where that zlib data starts.
+pub struct EntryHeader {
+    pub kind: EntryKind,
+    pub size: usize,
+    pub data_offset: u64,
+}
+
+fn next_byte(r: &mut impl Read) -> Result<u8, Error> {
+    let mut c = [0];
+    match r.read_exact(&mut c) {
+        Ok(()) => Ok(c[0]),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(Error::CorruptPack(
+            "pack entry header runs past the end of the pack".to_string(),
+        )),
+        Err(e) => Err(Error::CorruptPack(format!("read pack entry header: {e}"))),
+    }
+}
+
+/// Encode the header of an entry of type number `type_num` whose data
+/// inflates to `size` bytes: the type and the size in a little-endian
+/// base-128 number. Delta entries follow it with their base.
+pub fn encode_entry_header(type_num: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![(type_num << 4) | (size & 0x0f) as u8];
+    let mut size = size >> 4;
+    while size != 0 {
+        *header.last_mut().expect("header is not empty") |= 0x80;
+        header.push((size & 0x7f) as u8);
+        size >>= 7;
+    }
+    header
+}
+
+/// Parse the header of the entry at `offset`, reading it from `r`, which is
+/// positioned there.
+pub fn read_entry_header(
+    r: &mut impl Read,
+    offset: u64,
+    format: ObjectFormat,
+) -> Result<EntryHeader, Error> {
+    let mut i = offset;
+    let mut c = next_byte(r)?;
+    i += 1;
+    let type_num = (c >> 4) & 0x07;
+    let mut size = (c & 0x0f) as usize;
+    let mut shift = 4;
+    while c & 0x80 != 0 {
+        c = next_byte(r)?;
+        i += 1;
+        if shift > 57 {
+            return Err(Error::CorruptPack(format!(
+                "pack entry size at {offset} overflows"
+            )));
+        }
+        size |= ((c & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+    let kind = match type_num {
+        OBJ_OFS_DELTA => {
+            // a big-endian base-128 number where each continuation adds one,
+            // so that every offset has exactly one encoding
+            let mut c = next_byte(r)?;
+            i += 1;
+            let mut rel = (c & 0x7f) as u64;
+            while c & 0x80 != 0 {
+                c = next_byte(r)?;
+                i += 1;
+                rel = rel
+                    .checked_add(1)
+                    .and_then(|r| r.checked_mul(128))
+                    .ok_or_else(|| {
+                        Error::CorruptPack(format!("delta base offset at {offset} overflows"))
+                    })?
+                    | (c & 0x7f) as u64;
+            }
+            let base = offset
+                .checked_sub(rel)
+                .filter(|_| rel != 0)
+                .ok_or_else(|| {
+                    Error::CorruptPack(format!("delta base offset at {offset} out of bounds"))
+                })?;
+            EntryKind::OfsDelta(base)
+        }
+        OBJ_REF_DELTA => {
+            let mut base = vec![0; format.raw_len()];
+            r.read_exact(&mut base)
+                .map_err(|_| Error::CorruptPack(format!("truncated ref delta at {offset}")))?;
+            i += base.len() as u64;
+            EntryKind::RefDelta(hex::encode(base))
+        }
+        t => EntryKind::Object(ObjectType::from_pack_type(t).ok_or_else(|| {
+            Error::CorruptPack(format!("unknown pack entry type {t} at {offset}"))
+        })?),
+    };
+    Ok(EntryHeader {
+        kind,
+        size,
+        data_offset: i,
+    })
+}
+
+/// Inflate the zlib stream at the start of `r`, which must produce exactly
+/// `size` bytes, handing the output to `sink` a chunk at a time. Only the
+/// bytes of the stream are consumed from `r`.
+pub fn inflate_into(
+    r: impl BufRead,
+    size: usize,
+    mut sink: impl FnMut(&[u8]),
+) -> Result<(), Error> {
+    let mut z = flate2::bufread::ZlibDecoder::new(r);
+    let mut buf = vec![0; (64 * 1024).min(size + 1)];
+    let mut total = 0;
+    loop {
+        let n = match z.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::CorruptPack(format!("inflate pack entry: {e}"))),
+        };
+        total += n;
+        if total > size {
+            break;
+        }
+        sink(&buf[..n]);
+    }
+    if total != size {
+        return Err(Error::CorruptPack(format!(
+            "pack entry inflates to {}{total} bytes, expected {size}",
+            if total > size { "more than " } else { "" }
+        )));
+    }
+    Ok(())
+}
+
+/// Inflate the zlib stream at the start of `r` into memory; see
+/// [`inflate_into`].
+pub fn inflate(r: impl BufRead, size: usize) -> Result<Vec<u8>, Error> {
+    // the size comes from the pack, so do not trust it for the allocation
+    let mut out = Vec::with_capacity(size.min(1 << 20));
+    inflate_into(r, size, |chunk| out.extend_from_slice(chunk))?;
+    Ok(out)
+}
+
+/// Read a delta header size: little-endian base-128.
+fn delta_size(delta: &[u8], i: &mut usize) -> Result<usize, Error> {
+    let mut size = 0usize;
+    let mut shift = 0;
+    loop {
+        let c = *delta
+            .get(*i)
+            .ok_or_else(|| Error::CorruptPack("truncated delta header".to_string()))?;
+        *i += 1;
+        if shift > 57 {
+            return Err(Error::CorruptPack("delta size overflows".to_string()));
+        }
+        size |= ((c & 0x7f) as usize) << shift;
+        shift += 7;
+        if c & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Apply a git delta to `base`, see "Deltified representation" in
+/// gitformat-pack(5).
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut i = 0;
+    let src_size = delta_size(delta, &mut i)?;
+    if src_size != base.len() {
+        return Err(Error::CorruptPack(format!(
+            "delta expects a {src_size} byte base, got {} bytes",
+            base.len()
+        )));
+    }
+    let dst_size = delta_size(delta, &mut i)?;
+    let mut out = Vec::with_capacity(dst_size);
+    while i < delta.len() {
+        let op = delta[i];
+        i += 1;
+        if op & 0x80 != 0 {
+            // copy from base: bits 0-3 select offset bytes, bits 4-6 size bytes
+            let mut arg = |bit: u8, shift: u32| -> Result<usize, Error> {
+                if op & bit == 0 {
+                    return Ok(0);
+                }
+                let b = *delta.get(i).ok_or_else(|| {
+                    Error::CorruptPack("truncated delta copy instruction".to_string())
+                })?;
+                i += 1;
+                Ok((b as usize) << shift)
+            };
+            let start = arg(0x01, 0)? | arg(0x02, 8)? | arg(0x04, 16)? | arg(0x08, 24)?;
+            let mut size = arg(0x10, 0)? | arg(0x20, 8)? | arg(0x40, 16)?;
+            if size == 0 {
+                size = 0x10000;
+            }
+            let chunk = start
+                .checked_add(size)
+                .and_then(|end| base.get(start..end))
+                .ok_or_else(|| {
+                    Error::CorruptPack("delta copies past the end of its base".to_string())
+                })?;
+            out.extend_from_slice(chunk);
+        } else if op != 0 {
+            let chunk = delta.get(i..i + op as usize).ok_or_else(|| {
+                Error::CorruptPack("truncated delta insert instruction".to_string())
+            })?;
+            out.extend_from_slice(chunk);
+            i += op as usize;
+        } else {
+            return Err(Error::CorruptPack("unexpected delta opcode 0".to_string()));
+        }
+    }
+    if out.len() != dst_size {
+        return Err(Error::CorruptPack(format!(
+            "delta produced {} bytes, expected {dst_size}",
+            out.len()
+        )));
+    }
+    Ok(out)
+}
+
+pub struct IndexEntry {
+    pub oid: String,
+    pub offset: u64,
+    /// CRC32 of the raw entry in the pack (version 2 indexes only).
+    pub crc32: Option<u32>,
+}
+
+/// A parsed `.idx` file, entries sorted by object id.
+pub struct PackIndex {
+    pub entries: Vec<IndexEntry>,
+    /// The trailer of the pack this index describes.
+    pub pack_checksum: Vec<u8>,
+    /// Whether the index's own trailing checksum matched its content.
+    pub checksum_ok: bool,
+}
+
+fn be32(buf: &[u8], at: usize) -> Result<u32, Error> {
+    let b = buf
+        .get(at..at + 4)
+        .ok_or_else(|| Error::CorruptPack("truncated pack index".to_string()))?;
+    let mut be = [0; 4];
+    be.copy_from_slice(b);
+    Ok(u32::from_be_bytes(be))
+}
+
+impl PackIndex {
+    pub fn open(path: &Path, format: ObjectFormat) -> Result<Self, anyhow::Error> {
+        let buf = std::fs::read(path).context(format!("read {:?}", path))?;
+        Self::parse(&buf, format).context(format!("parse {:?}", path))
+    }
+
+    pub fn parse(buf: &[u8], format: ObjectFormat) -> Result<Self, Error> {
+        let len = format.raw_len();
+        if buf.len() < 2 * len {
+            return Err(Error::CorruptPack("pack index too short".to_string()));
+        }
+        let (body, trailer) = buf.split_at(buf.len() - len);
+        let checksum_ok = format.digest(body)? == trailer;
+        let pack_checksum = body[body.len() - len..].to_vec();
+
+        let (version, fanout_at) = if buf.starts_with(b"\xfftOc") {
+            (be32(buf, 4)?, 8)
+        } else {
+            (1, 0)
+        };
+        // version 1 predates SHA-256
+        if version != 2 && (version != 1 || format != ObjectFormat::Sha1) {
+            return Err(Error::CorruptPack(format!(
+                "unsupported pack index version {version}"
+            )));
+        }
+        let count = be32(buf, fanout_at + 255 * 4)? as usize;
+        let table = fanout_at + 256 * 4;
+        let mut entries = Vec::with_capacity(count);
+        if version == 1 {
+            for n in 0..count {
+                let at = table + n * 24;
+                let oid = buf
+                    .get(at + 4..at + 24)
+                    .ok_or_else(|| Error::CorruptPack("truncated pack index".to_string()))?;
+                entries.push(IndexEntry {
+                    oid: hex::encode(oid),
+                    offset: be32(buf, at)? as u64,
+                    crc32: None,
+                });
+            }
+        } else {
+            let crcs = table + count * len;
+            let offsets = crcs + count * 4;
+            let large = offsets + count * 4;
+            for n in 0..count {
+                let oid = buf
+                    .get(table + n * len..table + (n + 1) * len)
+                    .ok_or_else(|| Error::CorruptPack("truncated pack index".to_string()))?;
+                let off = be32(buf, offsets + n * 4)?;
+                let offset = if off & 0x8000_0000 != 0 {
+                    let at = large + (off & 0x7fff_ffff) as usize * 8;
+                    let b = buf.get(at..at + 8).ok_or_else(|| {
+                        Error::CorruptPack("truncated large offset table".to_string())
+                    })?;
+                    let mut be = [0; 8];
+                    be.copy_from_slice(b);
+                    u64::from_be_bytes(be)
+                } else {
+                    off as u64
+                };
+                entries.push(IndexEntry {
+                    oid: hex::encode(oid),
+                    offset,
+                    crc32: Some(be32(buf, crcs + n * 4)?),
+                });
+            }
+        }
+        if entries.windows(2).any(|w| w[0].oid >= w[1].oid) {
+            return Err(Error::CorruptPack(
+                "pack index entries are not sorted".to_string(),
+            ));
+        }
+        Ok(PackIndex {
+            entries,
+            pack_checksum,
+            checksum_ok,
+        })
+    }
+
+    pub fn find(&self, oid: &str) -> Option<&IndexEntry> {
+        self.entries
+            .binary_search_by(|e| e.oid.as_str().cmp(oid))
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+}
+
+/// Encode an index (version 2) for a pack with the trailer `pack_checksum`
+/// holding `entries`, which must all have a CRC.
+pub fn encode_index(
+    entries: &mut [IndexEntry],
+    pack_checksum: &[u8],
+    format: ObjectFormat,
+) -> Result<Vec<u8>, Error> {
+    entries.sort_by(|a, b| a.oid.cmp(&b.oid));
+    let mut buf = b"\xfftOc".to_vec();
+    buf.extend_from_slice(&2u32.to_be_bytes());
+    let mut fanout = [0u32; 256];
+    for entry in entries.iter() {
+        let first = u8::from_str_radix(&entry.oid[..2], 16)
+            .map_err(|_| Error::BadObjectName(entry.oid.clone()))?;
+        fanout[first as usize] += 1;
+    }
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        buf.extend_from_slice(&total.to_be_bytes());
+    }
+    for entry in entries.iter() {
+        let oid = hex::decode(&entry.oid).map_err(|_| Error::BadObjectName(entry.oid.clone()))?;
+        buf.extend_from_slice(&oid);
+    }
+    for entry in entries.iter() {
+        let crc = entry
+            .crc32
+            .ok_or_else(|| Error::CorruptPack(format!("no CRC for {}", entry.oid)))?;
+        buf.extend_from_slice(&crc.to_be_bytes());
+    }
+    // offsets that do not fit in 31 bits go to a table of 64-bit ones
+    let mut large = Vec::new();
+    for entry in entries.iter() {
+        let offset = match u32::try_from(entry.offset) {
+            Ok(offset) if offset & 0x8000_0000 == 0 => offset,
+            _ => {
+                large.push(entry.offset);
+                0x8000_0000 | (large.len() as u32 - 1)
+            }
+        };
+        buf.extend_from_slice(&offset.to_be_bytes());
+    }
+    for offset in large {
+        buf.extend_from_slice(&offset.to_be_bytes());
+    }
+    buf.extend_from_slice(pack_checksum);
+    let checksum = format.digest(&buf)?;
+    buf.extend_from_slice(&checksum);
+    Ok(buf)
+}
+
+/// Resolved content shared between the delta base cache and readers.
+pub type ObjectData = Arc<[u8]>;
+
+/// Resolved delta bases by pack offset, bounded in size: once they take more
+/// than the limit (`core.deltaBaseCacheLimit`), the least recently used ones
+/// are dropped.
+pub struct DeltaBaseCache {
+    limit: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<u64, (ObjectType, ObjectData, u64)>,
+    /// Offsets by the tick they were last used at.
+    recency: BTreeMap<u64, u64>,
+}
+
+impl DeltaBaseCache {
+    /// git's default limit.
+    pub const DEFAULT_LIMIT: usize = 96 << 20;
+
+    pub fn new(limit: usize) -> Self {
+        DeltaBaseCache {
+            limit,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, offset: u64) -> Option<(ObjectType, ObjectData)> {
+        let (kind, data, used_at) = self.entries.get_mut(&offset)?;
+        self.tick += 1;
+        self.recency.remove(used_at);
+        *used_at = self.tick;
+        self.recency.insert(self.tick, offset);
+        Some((*kind, data.clone()))
+    }
+
+    pub fn insert(&mut self, offset: u64, kind: ObjectType, data: ObjectData) {
+        if data.len() > self.limit || self.get(offset).is_some() {
+            return;
+        }
+        self.tick += 1;
+        self.used += data.len();
+        self.entries.insert(offset, (kind, data, self.tick));
+        self.recency.insert(self.tick, offset);
+        while self.used > self.limit {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((_, data, _)) = self.entries.remove(&oldest) {
+                self.used -= data.len();
+            }
+        }
+    }
+}
+
+/// Read into `buf` from `offset` in `file` without moving a shared cursor, so
+/// one handle can serve several readers.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    #[cfg(unix)]
+    return std::os::unix::fs::FileExt::read_at(file, buf, offset);
+    #[cfg(windows)]
+    return std::os::windows::fs::FileExt::seek_read(file, buf, offset);
+}
+
+/// Reads `file` (a `File` or a reference to one) from `pos` up to `end`.
+struct FileSection<F> {
+    file: F,
+    pos: u64,
+    end: u64,
+}
+
+impl<F: Borrow<File>> Read for FileSection<F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = usize::try_from(self.end.saturating_sub(self.pos)).unwrap_or(usize::MAX);
+        let max = buf.len().min(left);
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = read_at(self.file.borrow(), &mut buf[..max], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// The content of a whole object in a pack, inflated as it is read.
+pub struct EntryReader(flate2::bufread::ZlibDecoder<BufReader<FileSection<File>>>);
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+/// Random access to the entries of a pack file.
+struct PackFile {
+    file: File,
+    format: ObjectFormat,
+    /// The size of the file, trailer included.
+    size: u64,
+}
+
+impl PackFile {
+    /// Where the entries end and the trailer starts.
+    fn body_end(&self) -> u64 {
+        self.size - self.format.raw_len() as u64
+    }
+
+    fn section(&self, from: u64, to: u64) -> FileSection<&File> {
+        FileSection {
+            file: &self.file,
+            pos: from,
+            end: to,
+        }
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        self.section(offset, self.size)
+            .read_exact(buf)
+            .map_err(|e| Error::CorruptPack(format!("read pack at {offset}: {e}")))
+    }
+
+    fn header(&self, offset: u64) -> Result<EntryHeader, Error> {
+        if offset < 12 || offset >= self.body_end() {
+            return Err(Error::CorruptPack(format!(
+                "pack entry at {offset} out of bounds"
+            )));
+        }
+        read_entry_header(
+            &mut BufReader::new(self.section(offset, self.body_end())),
+            offset,
+            self.format,
+        )
+    }
+
+    /// The inflated data of an entry: the object, or the delta for a delta
+    /// entry.
+    fn data(&self, header: &EntryHeader) -> Result<Vec<u8>, Error> {
+        let section = self.section(header.data_offset, self.body_end());
+        inflate(BufReader::new(section), header.size)
+    }
+
+    /// The size of the object a delta entry produces, read from the start
+    /// of the delta without inflating the rest of it.
+    fn delta_result_size(&self, header: &EntryHeader) -> Result<usize, Error> {
+        let section = self.section(header.data_offset, self.body_end());
+        let z = flate2::bufread::ZlibDecoder::new(BufReader::new(section));
+        // two sizes of at most ten bytes each start every delta
+        let mut start = Vec::new();
+        z.take(20)
+            .read_to_end(&mut start)
+            .map_err(|e| Error::CorruptPack(format!("inflate pack entry: {e}")))?;
+        let mut i = 0;
+        delta_size(&start, &mut i)?;
+        delta_size(&start, &mut i)
+    }
+
+    /// The type and size of the object at `offset`, from entry headers alone:
+    /// the size of a delta's result is at the start of the delta, and its
+    /// type is that of the whole object its chain ends at. Returns `None` if
+    /// `find` does not know a base.
+    fn object_header(
+        &self,
+        offset: u64,
+        find: impl Fn(&str) -> Option<u64>,
+    ) -> Result<Option<(ObjectType, usize)>, Error> {
+        let header = self.header(offset)?;
+        let mut next = match &header.kind {
+            EntryKind::Object(kind) => return Ok(Some((*kind, header.size))),
+            EntryKind::OfsDelta(base) => Some(*base),
+            EntryKind::RefDelta(base) => find(base),
+        };
+        let size = self.delta_result_size(&header)?;
+        for _ in 0..10_000 {
+            let Some(at) = next else {
+                return Ok(None);
+            };
+            next = match self.header(at)?.kind {
+                EntryKind::Object(kind) => return Ok(Some((kind, size))),
+                EntryKind::OfsDelta(base) => Some(base),
+                EntryKind::RefDelta(base) => find(&base),
+            };
+        }
+        Err(Error::CorruptPack(format!(
+            "delta chain at {offset} is too long or cyclic"
+        )))
+    }
+
+    /// The length of the entry at `offset`: header plus compressed data.
+    fn entry_len(&self, offset: u64) -> Result<u64, Error> {
+        let header = self.header(offset)?;
+        let section = self.section(header.data_offset, self.body_end());
+        let mut z = flate2::bufread::ZlibDecoder::new(BufReader::new(section));
+        let mut left = header.size;
+        let mut buf = vec![0; (64 * 1024).min(header.size + 1)];
+        loop {
+            match z.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) if n <= left => left -= n,
+                Ok(_) => left = usize::MAX,
+                Err(e) => return Err(Error::CorruptPack(format!("inflate pack entry: {e}"))),
+            }
+            if left == usize::MAX {
+                break;
+            }
+        }
+        if left != 0 {
+            return Err(Error::CorruptPack(format!(
+                "pack entry at {offset} does not inflate to {} bytes",
+                header.size
+            )));
+        }
+        Ok(header.data_offset + z.total_in() - offset)
+    }
+
+    /// Resolve the entry at `offset` down its delta chain, finding the
+    /// entries named by ref deltas with `find`. Bases met on the way are put
+    /// in `cache`. Returns `None` if `find` does not know a base.
+    fn resolve(
+        &self,
+        offset: u64,
+        cache: &Mutex<DeltaBaseCache>,
+        find: impl Fn(&str) -> Option<u64>,
+    ) -> Result<Option<(ObjectType, ObjectData)>, anyhow::Error> {
+        // walk down the delta chain iteratively so long chains cannot
+        // overflow the stack
+        let mut chain = Vec::new();
+        let mut at = offset;
+        let (kind, mut data) = loop {
+            if let Some(hit) = cache.lock().unwrap().get(at) {
+                break hit;
+            }
+            if chain.len() > 10_000 {
+                return Err(Error::CorruptPack(format!(
+                    "delta chain at {offset} is too long or cyclic"
+                ))
+                .into());
+            }
+            let header = self.header(at)?;
+            let data = self.data(&header).context(format!("entry at {at}"))?;
+            match header.kind {
+                EntryKind::Object(kind) => break (kind, ObjectData::from(data)),
+                EntryKind::OfsDelta(base) => {
+                    chain.push((at, data));
+                    at = base;
+                }
+                EntryKind::RefDelta(base) => {
+                    chain.push((at, data));
+                    let Some(base) = find(&base) else {
+                        return Ok(None);
+                    };
+                    at = base;
+                }
+            }
+        };
+        while let Some((delta_at, delta)) = chain.pop() {
+            cache.lock().unwrap().insert(at, kind, data.clone());
+            let result = apply_delta(&data, &delta).context(format!("delta at {delta_at}"))?;
+            data = ObjectData::from(result);
+            at = delta_at;
+        }
+        Ok(Some((kind, data)))
+    }
+}
+
+/// A pack on disk together with its index.
+pub struct Pack {
+    pub path: PathBuf,
+    pub format: ObjectFormat,
+    pub index: PackIndex,
+    file: PackFile,
+    count: u32,
+    trailer: Vec<u8>,
+    cache: Mutex<DeltaBaseCache>,
+    /// Index entry numbers sorted by offset, built on first use.
+    by_offset: OnceLock<Vec<usize>>,
+}
+
+impl Pack {
+    /// Open `<name>.pack` next to the given `.idx` file, caching up to
+    /// `cache_limit` bytes of delta bases.
+    pub fn open(
+        idx_path: &Path,
+        format: ObjectFormat,
+        cache_limit: usize,
+    ) -> Result<Self, anyhow::Error> {
+        let index = PackIndex::open(idx_path, format)?;
+        let path = idx_path.with_extension("pack");
+        let file = File::open(&path).context(format!("open {:?}", path))?;
+        let size = file.metadata().context(format!("stat {:?}", path))?.len();
+        let file = PackFile { file, format, size };
+        let not_a_pack = || Error::CorruptPack(format!("{:?} is not a pack", path));
+        if size < 12 + format.raw_len() as u64 {
+            return Err(not_a_pack().into());
+        }
+        let mut header = [0; 12];
+        file.read_exact_at(&mut header, 0)?;
+        if &header[..4] != b"PACK" {
+            return Err(not_a_pack().into());
+        }
+        let version = u32::from_be_bytes(header[4..8].try_into()?);
+        if version != 2 && version != 3 {
+            return Err(Error::CorruptPack(format!(
+                "{:?}: unsupported pack version {version}",
+                path
+            ))
+            .into());
+        }
+        let mut trailer = vec![0; format.raw_len()];
+        file.read_exact_at(&mut trailer, file.body_end())?;
+        Ok(Pack {
+            path,
+            format,
+            index,
+            file,
+            count: u32::from_be_bytes(header[8..12].try_into()?),
+            trailer,
+            cache: Mutex::new(DeltaBaseCache::new(cache_limit)),
+            by_offset: OnceLock::new(),
+        })
+    }
+
+    /// The number of objects the pack header claims to contain.
+    pub fn object_count(&self) -> u32 {
+        self.count
+    }
+
+    /// The checksum stored at the end of the pack.
+    pub fn trailer(&self) -> &[u8] {
+        &self.trailer
+    }
+
+    /// Whether the pack's trailer is the hash of the rest of it.
+    pub fn checksum_ok(&self) -> Result<bool, anyhow::Error> {
+        let mut hasher = self.format.hasher();
+        let mut section = self.file.section(0, self.file.body_end());
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = section
+                .read(&mut buf)
+                .context(format!("read {:?}", self.path))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize()? == self.trailer)
+    }
+
+    /// The header of the entry at `offset`.
+    pub fn entry_header(&self, offset: u64) -> Result<EntryHeader, Error> {
+        self.file.header(offset)
+    }
+
+    /// The id of the object whose entry is at `offset`.
+    pub fn oid_at(&self, offset: u64) -> Option<&str> {
+        let entries = &self.index.entries;
+        let by_offset = self.by_offset.get_or_init(|| {
+            let mut order = (0..entries.len()).collect::<Vec<_>>();
+            order.sort_by_key(|&i| entries[i].offset);
+            order
+        });
+        let i = by_offset
+            .binary_search_by_key(&offset, |&i| entries[i].offset)
+            .ok()?;
+        Some(&entries[by_offset[i]].oid)
+    }
+
+    /// The raw bytes of the entry at `offset`: header plus compressed data.
+    pub fn raw_entry(&self, offset: u64) -> Result<Vec<u8>, anyhow::Error> {
+        let len = self.file.entry_len(offset)?;
+        let mut raw = vec![0; usize::try_from(len)?];
+        self.file.read_exact_at(&mut raw, offset)?;
+        Ok(raw)
+    }
+
+    /// The type and size of the object at `offset`, without inflating it or
+    /// applying any deltas.
+    pub fn object_header(&self, offset: u64) -> Result<(ObjectType, usize), anyhow::Error> {
+        let found = self
+            .file
+            .object_header(offset, |oid| self.index.find(oid).map(|entry| entry.offset))?;
+        found.ok_or_else(|| {
+            Error::CorruptPack(format!(
+                "a delta base of the entry at {offset} is not in the pack"
+            ))
+            .into()
+        })
+    }
+
+    /// A reader inflating the object at `offset` straight from the pack, if
+    /// it is stored whole rather than as a delta: its type, its size and the
+    /// reader.
+    pub fn stream_at(
+        &self,
+        offset: u64,
+    ) -> Result<Option<(ObjectType, usize, EntryReader)>, anyhow::Error> {
+        let header = self.file.header(offset)?;
+        let EntryKind::Object(kind) = header.kind else {
+            return Ok(None);
+        };
+        let section = FileSection {
+            file: self.file.file.try_clone().context("reopen pack")?,
+            pos: header.data_offset,
+            end: self.file.body_end(),
+        };
+        let reader = flate2::bufread::ZlibDecoder::new(BufReader::new(section));
+        Ok(Some((kind, header.size, EntryReader(reader))))
+    }
+
+    /// Read and fully resolve the object at `offset`.
+    pub fn object_at(&self, offset: u64) -> Result<(ObjectType, ObjectData), anyhow::Error> {
+        let found = self.file.resolve(offset, &self.cache, |oid| {
+            self.index.find(oid).map(|entry| entry.offset)
+        })?;
+        found.ok_or_else(|| {
+            Error::CorruptPack(format!(
+                "a delta base of the entry at {offset} is not in the pack"
+            ))
+            .into()
+        })
+    }
+}
+
+/// All `.idx` files in the repository's pack directory.
+pub fn index_files(objects_dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let dir = objects_dir.join("pack");
+    let mut files = Vec::new();
+    if dir.is_dir() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "idx") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Mark the pack `name` (its checksum) in `odb` as coming from a promisor
+/// remote, with a `.promisor` file listing the refs fetched along with it:
+/// the objects it points at but lacks are the remote's to provide.
+pub fn write_promisor(
+    odb: &ObjectDatabase,
+    name: &str,
+    refs: &[(String, String)],
+) -> Result<(), anyhow::Error> {
+    let path = odb.dir().join("pack").join(format!("pack-{name}.promisor"));
+    let content = refs
+        .iter()
+        .map(|(oid, name)| format!("{oid} {name}\n"))
+        .collect::<String>();
+    std::fs::write(&path, content).context(format!("write {:?}", path))
+}
+
+/// How to write a pack.
+#[derive(Debug, Clone)]
+pub struct PackWriteOptions {
+    /// How many objects before each one, in the order similar objects are
+    /// sorted in, to try as its delta base; 0 turns delta compression off.
+    pub window: usize,
+    /// The longest delta chain to write.
+    pub depth: usize,
+    /// Name delta bases by their offset in the pack (OFS_DELTA) rather than
+    /// by id (REF_DELTA).
+    pub ofs_delta: bool,
+    /// Copy deltas that are already in packs rather than computing new
+    /// ones, when their base is packed too.
+    pub reuse_delta: bool,
+    /// Show progress on stderr.
+    pub progress: bool,
+}
+
+impl PackWriteOptions {
+    /// The window and depth `odb`'s config gives (`pack.window`,
+    /// `pack.depth`), reusing deltas and naming bases by offset.
+    pub fn new(odb: &ObjectDatabase) -> Self {
+        PackWriteOptions {
+            window: odb.pack_window(),
+            depth: odb.pack_depth(),
+            ofs_delta: true,
+            reuse_delta: true,
+            progress: false,
+        }
+    }
+}
+
+/// A pack as written: its checksum, and the index entries of its objects.
+pub struct WrittenPack {
+    pub checksum: Vec<u8>,
+    pub entries: Vec<IndexEntry>,
+}
+
+/// How an object is stored in the pack being written.
+enum Delta {
+    /// Copied as it is from another pack: the size it inflates to, and its
+    /// compressed data.
+    Reused(usize, Vec<u8>),
+    Computed(Vec<u8>),
+}
+
+/// An object to write into a pack.
+struct PackEntry {
+    oid: String,
+    kind: ObjectType,
+    size: usize,
+    name_hash: u32,
+    /// The entry the object is a delta against, and the delta.
+    delta: Option<(usize, Delta)>,
+    depth: usize,
+}
+
+/// Objects smaller than this are not worth a delta.
+const MIN_DELTA_SIZE: usize = 50;
+
+/// git's hash of the path an object was found at, which sorts objects whose
+/// paths end alike, and so are likely similar, next to each other.
+fn name_hash(path: &[u8]) -> u32 {
+    path.iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0u32, |hash, &c| (hash >> 2).wrapping_add((c as u32) << 24))
+}
+
+/// Find deltas for `entries`, as git's pack-objects does: objects are
+/// sorted by type, path hash and decreasing size, and each is tried against
+/// the `window` objects before it, keeping the smallest delta that does not
+/// make its chain longer than `depth`.
+fn find_deltas(
+    odb: &ObjectDatabase,
+    entries: &mut [PackEntry],
+    opts: &PackWriteOptions,
+) -> Result<(), anyhow::Error> {
+    let mut order = (0..entries.len())
+        .filter(|&i| entries[i].delta.is_none() && entries[i].size >= MIN_DELTA_SIZE)
+        .filter(|&i| u32::try_from(entries[i].size).is_ok())
+        .collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (&entries[a], &entries[b]);
+        (a.kind.pack_type(), a.name_hash, b.size).cmp(&(b.kind.pack_type(), b.name_hash, a.size))
+    });
+    let mut meter = Progress::new(
+        "Compressing objects",
+        Some(order.len() as u64),
+        opts.progress && !order.is_empty(),
+    );
+    let hash_len = odb.format().raw_len();
+    let mut window: VecDeque<(usize, DeltaIndex)> = VecDeque::new();
+    for i in order {
+        let target = odb.read(&entries[i].oid)?.content;
+        // the nearest objects are the likeliest to be alike
+        for (base, index) in window.iter().rev() {
+            let (base_entry, entry) = (&entries[*base], &entries[i]);
+            if base_entry.kind != entry.kind || base_entry.depth >= opts.depth {
+                continue;
+            }
+            let (max_size, depth) = match &entry.delta {
+                Some((_, Delta::Computed(delta))) => (delta.len(), entry.depth),
+                _ => ((entry.size / 2).saturating_sub(hash_len), 1),
+            };
+            // deeper bases have to save more to be worth it
+            let max_size = max_size * (opts.depth - base_entry.depth) / (opts.depth - depth + 1);
+            let size_diff = base_entry.size.saturating_sub(entry.size);
+            if max_size == 0 || size_diff >= max_size || entry.size < base_entry.size / 32 {
+                continue;
+            }
+            let Some(delta) = crate::delta::create_delta(index, &target, max_size) else {
+                continue;
+            };
+            let better = match &entry.delta {
+                Some((_, Delta::Computed(best))) => {
+                    delta.len() < best.len()
+                        || (delta.len() == best.len() && base_entry.depth + 1 < entry.depth)
+                }
+                _ => true,
+            };
+            if better {
+                let depth = base_entry.depth + 1;
+                entries[i].delta = Some((*base, Delta::Computed(delta)));
+                entries[i].depth = depth;
+            }
+        }
+        if opts.window > 0 {
+            if window.len() == opts.window {
+                window.pop_front();
+            }
+            window.push_back((i, DeltaIndex::new(target)));
+        }
+        meter.inc();
+    }
+    meter.finish();
+    Ok(())
+}
+
+/// Set the depth of every entry's delta chain, cutting chains deeper than
+/// `max_depth`, or looping, by writing the object they got too deep at
+/// whole. Chains of deltas reused from different packs can join up into
+/// both.
+fn limit_depth(entries: &mut [PackEntry], max_depth: usize) {
+    let mut known = vec![false; entries.len()];
+    for first in 0..entries.len() {
+        // the deltas walked through, each with its base
+        let mut chain = Vec::new();
+        let mut at = first;
+        while !known[at] {
+            let Some((base, _)) = &entries[at].delta else {
+                break;
+            };
+            if chain.iter().any(|&(i, _)| i == at) {
+                entries[at].delta = None;
+                break;
+            }
+            chain.push((at, *base));
+            at = *base;
+        }
+        if !known[at] {
+            entries[at].depth = 0;
+            known[at] = true;
+        }
+        while let Some((i, base)) = chain.pop() {
+            if known[i] {
+                continue;
+            }
+            let mut depth = entries[base].depth + 1;
+            if depth > max_depth {
+                entries[i].delta = None;
+                depth = 0;
+            }
+            entries[i].depth = depth;
+            known[i] = true;
+        }
+    }
+}
+
+/// Everything written to `inner`, hashed on the way for the pack trailer.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encode the distance back to the base of an OFS_DELTA entry: big-endian
+/// base-128, with one added to each byte but the last so that every value
+/// has a single encoding.
+fn encode_ofs(mut distance: u64) -> Vec<u8> {
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance != 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+/// Write a pack holding `objects` from `odb` to `out`, in the order given
+/// except that delta bases come before their deltas. Deltas are reused from
+/// the packs in `odb` and looked for among the objects as `opts` says.
+pub fn write_pack(
+    odb: &ObjectDatabase,
+    objects: &[ListedObject],
+    out: impl Write,
+    opts: &PackWriteOptions,
+) -> Result<WrittenPack, anyhow::Error> {
+    let mut entries = Vec::with_capacity(objects.len());
+    let mut numbers = HashMap::new();
+    for object in objects {
+        if numbers.contains_key(&object.oid) {
+            continue;
+        }
+        numbers.insert(object.oid.clone(), entries.len());
+        let (kind, size) = odb.header(&object.oid)?;
+        entries.push(PackEntry {
+            oid: object.oid.clone(),
+            kind,
+            size,
+            name_hash: name_hash(&object.path),
+            delta: None,
+            depth: 0,
+        });
+    }
+
+    if opts.reuse_delta && opts.depth > 0 {
+        for entry in entries.iter_mut() {
+            if let Some((base, size, data)) = odb.packed_delta(&entry.oid)? {
+                if let Some(&base) = numbers.get(&base) {
+                    entry.delta = Some((base, Delta::Reused(size, data)));
+                }
+            }
+        }
+        limit_depth(&mut entries, opts.depth);
+    }
+    if opts.window > 0 && opts.depth > 0 {
+        find_deltas(odb, &mut entries, opts)?;
+        // reused deltas can have a base that has just become a delta too
+        limit_depth(&mut entries, opts.depth);
+    }
+    let reused = entries
+        .iter()
+        .filter(|e| matches!(e.delta, Some((_, Delta::Reused(..)))))
+        .count();
+
+    // objects listed twice are only written once
+    let count = u32::try_from(entries.len())
+        .map_err(|_| Error::CorruptPack("too many objects for one pack".to_string()))?;
+    let mut out = HashingWriter {
+        inner: out,
+        hasher: odb.format().hasher(),
+        written: 0,
+    };
+    let mut meter = Progress::new(
+        "Writing objects",
+        Some(entries.len() as u64),
+        opts.progress && !entries.is_empty(),
+    );
+    out.write_all(b"PACK").context("write pack")?;
+    out.write_all(&2u32.to_be_bytes()).context("write pack")?;
+    out.write_all(&count.to_be_bytes()).context("write pack")?;
+    let mut offsets: Vec<Option<u64>> = vec![None; entries.len()];
+    let mut index_entries = Vec::with_capacity(entries.len());
+    let mut deltas = 0;
+    for first in 0..entries.len() {
+        // bases go first, down to the first one already written
+        let mut chain = vec![first];
+        while let Some((base, _)) = &entries[*chain.last().expect("chain is not empty")].delta {
+            if offsets[*base].is_some() {
+                break;
+            }
+            chain.push(*base);
+        }
+        while let Some(i) = chain.pop() {
+            if offsets[i].is_some() {
+                continue;
+            }
+            let entry = &entries[i];
+            let offset = out.written;
+            let raw = match &entry.delta {
+                Some((base, delta)) => {
+                    let size = match delta {
+                        Delta::Reused(size, _) => *size,
+                        Delta::Computed(delta) => delta.len(),
+                    };
+                    let base_offset = offsets[*base].expect("delta base is written first");
+                    let mut raw;
+                    if opts.ofs_delta {
+                        raw = encode_entry_header(OBJ_OFS_DELTA, size);
+                        raw.extend_from_slice(&encode_ofs(offset - base_offset));
+                    } else {
+                        raw = encode_entry_header(OBJ_REF_DELTA, size);
+                        let base = &entries[*base].oid;
+                        raw.extend_from_slice(
+                            &hex::decode(base).map_err(|_| Error::BadObjectName(base.clone()))?,
+                        );
+                    }
+                    deltas += 1;
+                    match delta {
+                        Delta::Reused(_, data) => {
+                            raw.extend_from_slice(data);
+                            raw
+                        }
+                        Delta::Computed(delta) => {
+                            let mut z = ZlibEncoder::new(raw, odb.pack_compression());
+                            z.write_all(delta).context("compress delta")?;
+                            z.finish().context("compress delta")?
+                        }
+                    }
+                }
+                None => {
+                    let object = odb.read(&entry.oid)?;
+                    let header = encode_entry_header(object.kind.pack_type(), object.content.len());
+                    let mut z = ZlibEncoder::new(header, odb.pack_compression());
+                    z.write_all(&object.content).context("compress object")?;
+                    z.finish().context("compress object")?
+                }
+            };
+            out.write_all(&raw).context("write pack")?;
+            let mut crc = flate2::Crc::new();
+            crc.update(&raw);
+            index_entries.push(IndexEntry {
+                oid: entry.oid.clone(),
+                offset,
+                crc32: Some(crc.sum()),
+            });
+            offsets[i] = Some(offset);
+            meter.inc();
+            meter.set_bytes(out.written);
+        }
+    }
+    meter.finish();
+    let HashingWriter {
+        mut inner, hasher, ..
+    } = out;
+    let checksum = hasher.finalize()?;
+    inner.write_all(&checksum).context("write pack")?;
+    inner.flush().context("write pack")?;
+    if opts.progress {
+        eprintln!(
+            "Total {} (delta {deltas}), reused {reused} (delta {reused}), pack-reused 0",
+            entries.len()
+        );
+    }
+    Ok(WrittenPack {
+        checksum,
+        entries: index_entries,
+    })
+}
+
+/// Write a pack of `objects` as `write_pack` does to
+/// `<base_name>-<checksum>.pack`, along with its index in
+/// `<base_name>-<checksum>.idx`, returning the hex checksum.
+pub fn write_pack_files(
+    odb: &ObjectDatabase,
+    objects: &[ListedObject],
+    base_name: &Path,
+    opts: &PackWriteOptions,
+) -> Result<String, anyhow::Error> {
+    let dir = match base_name.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (tmp_pack, file) = TempFile::create(dir, "tmp_pack_")?;
+    let mut out = BufWriter::new(file);
+    let mut written = write_pack(odb, objects, &mut out, opts)?;
+    out.into_inner().context("write pack")?;
+    let idx = encode_index(&mut written.entries, &written.checksum, odb.format())?;
+    let (tmp_idx, mut file) = TempFile::create(dir, "tmp_idx_")?;
+    file.write_all(&idx).context("write pack index")?;
+    drop(file);
+
+    // the index goes last, as for received packs
+    let name = hex::encode(&written.checksum);
+    let path = |ext: &str| {
+        let mut path = base_name.as_os_str().to_owned();
+        path.push(format!("-{name}.{ext}"));
+        PathBuf::from(path)
+    };
+    tmp_pack.persist(&path("pack"))?;
+    tmp_idx.persist(&path("idx"))?;
+    Ok(name)
+}
+
+/// The pack being received: everything consumed from `input` is copied to
+/// the pack file and hashed on the way, and the CRC of the current entry
+/// kept for the index.
+struct PackStream<R> {
+    input: BufReader<R>,
+    out: BufWriter<File>,
+    write_error: Option<std::io::Error>,
+    hasher: Hasher,
+    crc: flate2::Crc,
+    offset: u64,
+    progress: Progress,
+}
+
+impl<R: Read> PackStream<R> {
+    /// Fail if copying to the pack file failed.
+    fn check_write(&mut self) -> Result<(), anyhow::Error> {
+        match self.write_error.take() {
+            Some(e) => Err(e).context("write pack"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Read for PackStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for PackStream<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.input.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let data = &self.input.buffer()[..amt];
+        if self.write_error.is_none() {
+            if let Err(e) = self.out.write_all(data) {
+                self.write_error = Some(e);
+            }
+        }
+        self.hasher.update(data);
+        self.crc.update(data);
+        self.offset += amt as u64;
+        self.input.consume(amt);
+        self.progress.set_bytes(self.offset);
+    }
+}
+
+/// Whole objects up to this size are hashed on worker threads while a pack
+/// is received.
+const WORKER_HASH_LIMIT: usize = 1 << 20;
+
+/// An entry of a pack being indexed.
+struct ReceivedEntry {
+    offset: u64,
+    crc32: u32,
+    /// Known once the entry is hashed; deltas are only hashed once resolved.
+    oid: Option<String>,
+}
+
+/// Store the pack read from `input` (as received from a server) in `odb`'s
+/// pack directory along with an index for it, returning the hex checksum
+/// that names it.
+///
+/// The pack is written to disk as it arrives and whole objects are hashed on
+/// the way; deltas are then resolved from the file with a bounded cache of
+/// bases, so neither the pack nor its objects have to fit in memory. A thin
+/// pack, with deltas against objects `odb` already has, is completed by
+/// appending those bases to it. With `progress`, meters are shown on stderr.
+pub fn index_pack(
+    odb: &ObjectDatabase,
+    input: impl Read,
+    progress: bool,
+) -> Result<String, anyhow::Error> {
+    let format = odb.format();
+    let pack_dir = odb.dir().join("pack");
+    let (tmp_pack, file) = TempFile::create(&pack_dir, "tmp_pack_")?;
+    let mut stream = PackStream {
+        input: BufReader::with_capacity(64 * 1024, input),
+        out: BufWriter::new(file),
+        write_error: None,
+        hasher: format.hasher(),
+        crc: flate2::Crc::new(),
+        offset: 0,
+        progress: Progress::new("Receiving objects", None, progress),
+    };
+
+    let mut header = [0; 12];
+    if stream.read_exact(&mut header).is_err() || &header[..4] != b"PACK" {
+        return Err(Error::Protocol("upload-pack did not send a pack".to_string()).into());
+    }
+    let version = u32::from_be_bytes(header[4..8].try_into()?);
+    if version != 2 && version != 3 {
+        return Err(Error::CorruptPack(format!("unsupported pack version {version}")).into());
+    }
+    let count = u32::from_be_bytes(header[8..12].try_into()?);
+    stream.progress.set_total(count.into());
+
+    // small whole objects are hashed on worker threads while the rest of
+    // the pack arrives; big ones are hashed as they stream past, so that
+    // they are never held in memory
+    let threads = odb.pack_threads();
+    let (jobs, queue) = mpsc::sync_channel::<HashJob>(threads * 4);
+    let queue = Mutex::new(queue);
+    let mut entries = Vec::with_capacity(count.min(1 << 20) as usize);
+    thread::scope(|scope| -> Result<(), anyhow::Error> {
+        // dropped on the way out, error or not, which stops the workers
+        let jobs = jobs;
+        let workers = (0..threads)
+            .map(|_| scope.spawn(|| hash_worker(format, &queue)))
+            .collect::<Vec<_>>();
+        for i in 0..count as usize {
+            let offset = stream.offset;
+            stream.crc.reset();
+            let header = read_entry_header(&mut stream, offset, format)?;
+            let oid = match header.kind {
+                EntryKind::Object(kind) if header.size <= WORKER_HASH_LIMIT => {
+                    let data = inflate(&mut stream, header.size)?;
+                    // the workers take jobs until the queue is closed
+                    jobs.send((i, kind, data))
+                        .map_err(|_| anyhow::anyhow!("hash workers stopped early"))?;
+                    None
+                }
+                EntryKind::Object(kind) => {
+                    let mut hasher = format.hasher();
+                    hasher.update(format!("{kind} {}\0", header.size).as_bytes());
+                    inflate_into(&mut stream, header.size, |chunk| hasher.update(chunk))?;
+                    Some(hasher.finalize_hex()?)
+                }
+                _ => {
+                    inflate_into(&mut stream, header.size, |_| {})?;
+                    None
+                }
+            };
+            stream.check_write()?;
+            entries.push(ReceivedEntry {
+                offset,
+                crc32: stream.crc.sum(),
+                oid,
+            });
+            stream.progress.inc();
+        }
+        drop(jobs);
+        for worker in workers {
+            let hashed = worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("hash worker panicked"))??;
+            for (i, oid) in hashed {
+                entries[i].oid = Some(oid);
+            }
+        }
+        Ok(())
+    })?;
+    let body_end = stream.offset;
+    let mut checksum = std::mem::replace(&mut stream.hasher, format.hasher()).finalize()?;
+    let mut trailer = vec![0; format.raw_len()];
+    stream
+        .read_exact(&mut trailer)
+        .map_err(|_| Error::CorruptPack("pack is missing its checksum".to_string()))?;
+    if trailer != checksum {
+        return Err(Error::CorruptPack("pack checksum mismatch".to_string()).into());
+    }
+    if !stream.input.fill_buf()?.is_empty() {
+        return Err(Error::CorruptPack("garbage after the pack".to_string()).into());
+    }
+    stream.check_write()?;
+    let PackStream {
+        out,
+        progress: receiving,
+        ..
+    } = stream;
+    receiving.finish();
+    // synced below, once the pack is complete
+    drop(out.into_inner().map_err(|e| e.into_error())?);
+
+    let mut pack = PackFile {
+        file: File::options()
+            .read(true)
+            .write(true)
+            .open(tmp_pack.path())
+            .context("reopen pack")?,
+        format,
+        size: body_end + format.raw_len() as u64,
+    };
+    let deltas = entries.iter().filter(|entry| entry.oid.is_none()).count();
+    let resolving = Mutex::new(Progress::new(
+        "Resolving deltas",
+        Some(deltas as u64),
+        progress,
+    ));
+    let mut unresolved = resolve_deltas(odb, &pack, &mut entries, &resolving)?;
+    if !unresolved.is_empty() {
+        // a thin pack: its deltas may have bases the server knew we have,
+        // which are added to the pack to make it whole
+        checksum = complete_thin_pack(odb, &mut pack, &mut entries, &unresolved)?;
+        unresolved = resolve_deltas(odb, &pack, &mut entries, &resolving)?;
+    }
+    if !unresolved.is_empty() {
+        return Err(Error::CorruptPack(format!(
+            "{} deltas have a base that is neither in the pack nor in the repository",
+            unresolved.len()
+        ))
+        .into());
+    }
+    resolving.into_inner().expect("progress poisoned").finish();
+    if odb.fsync_packs() {
+        pack.file.sync_all().context("fsync pack")?;
+    }
+    drop(pack);
+
+    let mut index = entries
+        .into_iter()
+        .map(|entry| IndexEntry {
+            oid: entry.oid.expect("every entry is resolved"),
+            offset: entry.offset,
+            crc32: Some(entry.crc32),
+        })
+        .collect::<Vec<_>>();
+    // an index finds one entry per object id, so a pack holding an object
+    // twice cannot be indexed
+    index.sort_by(|a, b| a.oid.cmp(&b.oid));
+    if let Some(pair) = index.windows(2).find(|pair| pair[0].oid == pair[1].oid) {
+        return Err(Error::CorruptPack(format!(
+            "the same object {} appears twice in the pack",
+            pair[0].oid
+        ))
+        .into());
+    }
+    let idx = encode_index(&mut index, &checksum, format)?;
+    let (tmp_idx, mut file) = TempFile::create(&pack_dir, "tmp_idx_")?;
+    file.write_all(&idx).context("write pack index")?;
+    if odb.fsync_packs() {
+        file.sync_all().context("fsync pack index")?;
+    }
+    drop(file);
+
+    // the index goes last: a pack is only used once its index is there
+    let name = hex::encode(&checksum);
+    let base = pack_dir.join(format!("pack-{name}"));
+    tmp_pack.persist(&base.with_extension("pack"))?;
+    tmp_idx.persist(&base.with_extension("idx"))?;
+    Ok(name)
+}
+
+/// Resolve the deltas among `entries` that are not hashed yet against the
+/// received pack file and hash them, returning those left over because
+/// their base is not in the pack.
+fn resolve_deltas(
+    odb: &ObjectDatabase,
+    pack: &PackFile,
+    entries: &mut [ReceivedEntry],
+    progress: &Mutex<Progress>,
+) -> Result<Vec<usize>, anyhow::Error> {
+    let format = odb.format();
+    let threads = odb.pack_threads();
+    let cache = Mutex::new(DeltaBaseCache::new(odb.delta_base_cache_limit()));
+    let mut by_oid = entries
+        .iter()
+        .filter_map(|entry| Some((entry.oid.clone()?, entry.offset)))
+        .collect::<HashMap<_, _>>();
+    let mut pending = (0..entries.len())
+        .filter(|i| entries[*i].oid.is_none())
+        .collect::<Vec<_>>();
+    // ref deltas may come before their base, so resolve in rounds until
+    // nothing is left or a round makes no progress
+    while !pending.is_empty() {
+        // workers take the next pending entry until there is none left or
+        // one of them fails; bases they share come from the cache
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let resolve_some = || -> Result<Vec<(usize, Option<String>)>, anyhow::Error> {
+            let mut resolved = Vec::new();
+            while !failed.load(Ordering::Relaxed) {
+                let Some(&i) = pending.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    break;
+                };
+                let result = pack
+                    .resolve(entries[i].offset, &cache, |oid| by_oid.get(oid).copied())
+                    .and_then(|object| match object {
+                        Some((kind, data)) => {
+                            Ok(Some(format.hash_object(&kind.to_string(), &data)?))
+                        }
+                        None => Ok(None),
+                    });
+                match result {
+                    Ok(oid) => {
+                        if oid.is_some() {
+                            progress.lock().expect("progress poisoned").inc();
+                        }
+                        resolved.push((i, oid));
+                    }
+                    Err(e) => {
+                        failed.store(true, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                }
+            }
+            Ok(resolved)
+        };
+        let rounds = thread::scope(|scope| {
+            let workers = (0..threads.min(pending.len()))
+                .map(|_| scope.spawn(resolve_some))
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("delta worker panicked")))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        let mut deferred = Vec::new();
+        for (i, oid) in rounds.into_iter().flatten() {
+            match oid {
+                Some(oid) => {
+                    by_oid.insert(oid.clone(), entries[i].offset);
+                    entries[i].oid = Some(oid);
+                }
+                None => deferred.push(i),
+            }
+        }
+        deferred.sort_unstable();
+        if deferred.len() == pending.len() {
+            return Ok(deferred);
+        }
+        pending = deferred;
+    }
+    Ok(Vec::new())
+}
+
+/// Append to `pack` the objects from `odb` that the `unresolved` ref deltas
+/// are against, as whole objects, and rewrite its header and trailer to
+/// match, as `git index-pack --fix-thin` does. Returns the new checksum.
+fn complete_thin_pack(
+    odb: &ObjectDatabase,
+    pack: &mut PackFile,
+    entries: &mut Vec<ReceivedEntry>,
+    unresolved: &[usize],
+) -> Result<Vec<u8>, anyhow::Error> {
+    let format = odb.format();
+    let mut bases = Vec::new();
+    for &i in unresolved {
+        if let EntryKind::RefDelta(base) = pack.header(entries[i].offset)?.kind {
+            if !bases.contains(&base) && odb.contains(&base) {
+                bases.push(base);
+            }
+        }
+    }
+    if bases.is_empty() {
+        return Err(Error::CorruptPack(format!(
+            "{} deltas have a base that is neither in the pack nor in the repository",
+            unresolved.len()
+        ))
+        .into());
+    }
+
+    // the new entries go where the trailer was
+    let mut offset = pack.body_end();
+    let mut file = &pack.file;
+    file.seek(SeekFrom::Start(offset)).context("seek in pack")?;
+    let mut out = BufWriter::new(file);
+    for base in bases {
+        let object = odb.read(&base)?;
+        let mut entry = encode_entry_header(object.kind.pack_type(), object.content.len());
+        let mut z = ZlibEncoder::new(entry, odb.pack_compression());
+        z.write_all(&object.content).context("compress object")?;
+        entry = z.finish().context("compress object")?;
+        out.write_all(&entry).context("write pack")?;
+        let mut crc = flate2::Crc::new();
+        crc.update(&entry);
+        entries.push(ReceivedEntry {
+            offset,
+            crc32: crc.sum(),
+            oid: Some(base),
+        });
+        offset += entry.len() as u64;
+    }
+    out.flush().context("write pack")?;
+    drop(out);
+
+    let count = u32::try_from(entries.len())
+        .map_err(|_| Error::CorruptPack("too many objects for one pack".to_string()))?;
+    file.seek(SeekFrom::Start(8)).context("seek in pack")?;
+    file.write_all(&count.to_be_bytes()).context("write pack")?;
+    let mut hasher = format.hasher();
+    let mut body = BufReader::new(pack.section(0, offset));
+    loop {
+        let chunk = body.fill_buf().context("read pack")?;
+        if chunk.is_empty() {
+            break;
+        }
+        hasher.update(chunk);
+        let n = chunk.len();
+        body.consume(n);
+    }
+    let checksum = hasher.finalize()?;
+    file.seek(SeekFrom::Start(offset)).context("seek in pack")?;
+    file.write_all(&checksum).context("write pack")?;
+    pack.size = offset + checksum.len() as u64;
+    Ok(checksum)
+}
+
+/// A whole object received, queued for hashing: its entry number, type and
+/// data.
+type HashJob = (usize, ObjectType, Vec<u8>);
+
+/// Hash the objects from `queue` until it is closed, returning their entry
+/// numbers and ids.
+fn hash_worker(
+    format: ObjectFormat,
+    queue: &Mutex<Receiver<HashJob>>,
+) -> Result<Vec<(usize, String)>, Error> {
+    let mut hashed = Vec::new();
+    let mut error = None;
+    loop {
+        let job = queue.lock().expect("hash queue poisoned").recv();
+        let Ok((i, kind, data)) = job else {
+            break;
+        };
+        // after a failure, keep taking jobs so that the sender never blocks
+        if error.is_none() {
+            match format.hash_object(&kind.to_string(), &data) {
+                Ok(oid) => hashed.push((i, oid)),
+                Err(e) => error = Some(e),
+            }
+        }
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(hashed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::repository::{InitOptions, Repository};
+    use std::fs;
+
+    /// An object database using `pack.threads` worker threads, in a
+    /// directory of its own removed on drop.
+    struct ThreadedOdb(ObjectDatabase);
+
+    impl ThreadedOdb {
+        fn new(name: &str, threads: usize) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "codecrafters-git-pack-{}-{name}",
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join("config"),
+                format!("[pack]\n\tthreads = {threads}\n"),
+            )
+            .unwrap();
+            let config = Config::load(&dir).unwrap();
+            let odb = ObjectDatabase::new(dir.join("objects"), ObjectFormat::Sha1, &config);
+            ThreadedOdb(odb.unwrap())
+        }
+    }
+
+    impl Drop for ThreadedOdb {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.dir().parent().unwrap());
+        }
+    }
+
+    /// A new bare repository in a directory of its own, removed on drop.
+    struct TempRepo(Repository);
+
+    impl TempRepo {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "codecrafters-git-pack-{}-{name}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            let opts = InitOptions {
+                bare: true,
+                ..Default::default()
+            };
+            TempRepo(Repository::init_with(&dir, &opts).unwrap())
+        }
+    }
+
+    impl Drop for TempRepo {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.0.git_dir());
+        }
+    }
+
+    fn size_varint(mut size: usize, out: &mut Vec<u8>) {
+        while size >= 0x80 {
+            out.push(size as u8 | 0x80);
+            size >>= 7;
+        }
+        out.push(size as u8);
+    }
+
+    /// Append a pack entry of type `kind`, its header giving the size of
+    /// `data` before `prefix` (a delta's base) is put in front of it.
+    fn push_entry(pack: &mut Vec<u8>, kind: u8, prefix: &[u8], data: &[u8]) {
+        let mut size = data.len();
+        let mut byte = kind << 4 | (size & 0x0f) as u8;
+        size >>= 4;
+        while size > 0 {
+            pack.push(byte | 0x80);
+            byte = (size & 0x7f) as u8;
+            size >>= 7;
+        }
+        pack.push(byte);
+        pack.extend_from_slice(prefix);
+        let mut encoder = flate2::write::ZlibEncoder::new(pack, flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap();
+    }
+
+    /// A pack of a few blobs, each followed by ref deltas appending a line
+    /// to it, some of them against an earlier delta. Returns the pack and
+    /// the ids and contents of its objects.
+    fn delta_pack() -> (Vec<u8>, Vec<(String, Vec<u8>)>) {
+        let mut objects = Vec::new();
+        let mut entries = Vec::new();
+        for i in 0..8 {
+            let base: Vec<u8> = (0..100)
+                .flat_map(|line| format!("blob {i} line {line}\n").into_bytes())
+                .collect();
+            entries.push((3, Vec::new(), base.clone()));
+            let mut bases = vec![base];
+            for d in 0..6 {
+                // every other delta builds on the one before it
+                let base = bases[if d % 2 == 1 { d } else { 0 }].clone();
+                let tail = format!("delta {d}\n").into_bytes();
+                let mut delta = Vec::new();
+                size_varint(base.len(), &mut delta);
+                size_varint(base.len() + tail.len(), &mut delta);
+                // copy the whole base, then insert the tail
+                delta.extend_from_slice(&[0xb0, base.len() as u8, (base.len() >> 8) as u8]);
+                delta.push(tail.len() as u8);
+                delta.extend_from_slice(&tail);
+                let base_oid = ObjectFormat::Sha1.hash_object("blob", &base).unwrap();
+                entries.push((7, hex::decode(base_oid).unwrap(), delta));
+                bases.push([base, tail].concat());
+            }
+            for blob in bases {
+                let oid = ObjectFormat::Sha1.hash_object("blob", &blob).unwrap();
+                objects.push((oid, blob));
+            }
+        }
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for (kind, prefix, data) in entries {
+            push_entry(&mut pack, kind, &prefix, &data);
+        }
+        let checksum = ObjectFormat::Sha1.digest(&pack).unwrap();
+        pack.extend_from_slice(&checksum);
+        (pack, objects)
+    }
+
+    #[test]
+    fn indexes_the_same_with_any_number_of_threads() {
+        let (pack, objects) = delta_pack();
+        let mut indexes = Vec::new();
+        for threads in [1, 4] {
+            let odb = ThreadedOdb::new(&format!("threads-{threads}"), threads);
+            assert_eq!(odb.0.pack_threads(), threads);
+            let name = index_pack(&odb.0, pack.as_slice(), false).unwrap();
+            assert_eq!(name, hex::encode(&pack[pack.len() - 20..]));
+            for (oid, content) in &objects {
+                assert_eq!(&odb.0.read(oid).unwrap().content, content);
+            }
+            let idx = odb.0.dir().join(format!("pack/pack-{name}.idx"));
+            indexes.push(fs::read(idx).unwrap());
+        }
+        assert_eq!(indexes[0], indexes[1]);
+    }
+
+    #[test]
+    fn refuses_corrupt_packs() {
+        let (mut pack, _) = delta_pack();
+        let odb = ThreadedOdb::new("corrupt", 4);
+        let last = pack.len() - 1;
+        pack[last] ^= 1;
+        assert!(index_pack(&odb.0, pack.as_slice(), false).is_err());
+        assert!(index_pack(&odb.0, &b"PACK\0\0\0\x07"[..], false).is_err());
+    }
+
+    #[test]
+    fn refuses_packs_holding_an_object_twice() {
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&3u32.to_be_bytes());
+        for data in [&b"twice\n"[..], b"once\n", b"twice\n"] {
+            push_entry(&mut pack, 3, &[], data);
+        }
+        let checksum = ObjectFormat::Sha1.digest(&pack).unwrap();
+        pack.extend_from_slice(&checksum);
+
+        let odb = ThreadedOdb::new("duplicate", 1);
+        let err = index_pack(&odb.0, pack.as_slice(), false).unwrap_err();
+        let twice = ObjectFormat::Sha1.hash_object("blob", b"twice\n").unwrap();
+        assert!(err.to_string().contains(&twice), "{err}");
+        // nothing is left behind for the repository to find
+        let pack_dir = odb.0.dir().join("pack");
+        let names = fs::read_dir(&pack_dir)
+            .map(|dir| dir.flatten().map(|e| e.file_name()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        assert!(
+            !names
+                .iter()
+                .any(|name| name.to_string_lossy().ends_with(".idx")),
+            "{names:?}"
+        );
+    }
+
+    #[test]
+    fn encodes_base_offsets() {
+        assert_eq!(encode_ofs(0), [0x00]);
+        assert_eq!(encode_ofs(127), [0x7f]);
+        assert_eq!(encode_ofs(128), [0x80, 0x00]);
+        assert_eq!(encode_ofs(16_511), [0xff, 0x7f]);
+        assert_eq!(encode_ofs(16_512), [0x80, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn written_pack_indexes_back() {
+        let source = TempRepo::new("source");
+        let odb = source.0.odb();
+        let text = (0..200).map(|i| format!("line {i}\n")).collect::<String>();
+        let mut objects = Vec::new();
+        for i in 0..5 {
+            let content = text.replace(&format!("line {}\n", i * 40), "changed\n");
+            let oid = odb.write("blob", content.as_bytes()).unwrap();
+            objects.push(ListedObject {
+                oid,
+                kind: ObjectType::Blob,
+                path: b"file.txt".to_vec(),
+            });
+        }
+        // listing an object twice must not change the pack's object count
+        objects.push(ListedObject {
+            oid: objects[2].oid.clone(),
+            kind: ObjectType::Blob,
+            path: b"file.txt".to_vec(),
+        });
+
+        for ofs_delta in [true, false] {
+            let opts = PackWriteOptions {
+                ofs_delta,
+                ..PackWriteOptions::new(odb)
+            };
+            let mut pack = Vec::new();
+            let written = write_pack(odb, &objects, &mut pack, &opts).unwrap();
+            assert_eq!(written.entries.len(), 5);
+            assert_eq!(&pack[8..12], 5u32.to_be_bytes());
+
+            let target = TempRepo::new(&format!("target-{ofs_delta}"));
+            let name = index_pack(target.0.odb(), pack.as_slice(), false).unwrap();
+            assert_eq!(name, hex::encode(&written.checksum));
+            for object in &objects[..5] {
+                assert_eq!(
+                    target.0.odb().read(&object.oid).unwrap().content,
+                    odb.read(&object.oid).unwrap().content
+                );
+                // similar blobs are stored as deltas against each other
+                let base = target.0.odb().delta_base(&object.oid).unwrap();
+                assert!(base.is_some() || object.oid == written.entries[0].oid);
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Progress meters on stderr, drawn the way git draws them:
+//! `Receiving objects:  42% (420/1000), 1.20 MiB | 512 KiB/s`.
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// How often a meter is redrawn at most.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Progress {
+    title: String,
+    total: Option<u64>,
+    count: u64,
+    /// Bytes transferred so far, shown with the throughput.
+    bytes: Option<u64>,
+    start: Instant,
+    last_draw: Option<Instant>,
+    last_len: usize,
+    enabled: bool,
+}
+
+impl Progress {
+    /// A meter counting up to `total`, when it is known. A meter that is not
+    /// `enabled` draws nothing, so callers need not check themselves.
+    pub fn new(title: &str, total: Option<u64>, enabled: bool) -> Self {
+        Progress {
+            title: title.to_string(),
+            total,
+            count: 0,
+            bytes: None,
+            start: Instant::now(),
+            last_draw: None,
+            last_len: 0,
+            enabled,
+        }
+    }
+
+    /// Set the total, once it is known.
+    pub fn set_total(&mut self, total: u64) {
+        self.total = Some(total);
+    }
+
+    pub fn set(&mut self, count: u64) {
+        self.count = count;
+        self.draw(false);
+    }
+
+    pub fn inc(&mut self) {
+        self.set(self.count + 1);
+    }
+
+    /// Also show `bytes` transferred so far, with the throughput.
+    pub fn set_bytes(&mut self, bytes: u64) {
+        self.bytes = Some(bytes);
+        self.draw(false);
+    }
+
+    /// Draw the final state, followed by ", done.".
+    pub fn finish(mut self) {
+        self.draw(true);
+    }
+
+    fn draw(&mut self, done: bool) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        if !done && self.last_draw.is_some_and(|t| now - t < INTERVAL) {
+            return;
+        }
+        self.last_draw = Some(now);
+
+        let mut line = format!("{}: ", self.title);
+        match self.total {
+            Some(total) if total > 0 => {
+                let percent = self.count * 100 / total;
+                line += &format!("{percent:3}% ({}/{total})", self.count);
+            }
+            _ => line += &self.count.to_string(),
+        }
+        if let Some(bytes) = self.bytes {
+            line += &format!(", {}", human_size(bytes));
+            let secs = (now - self.start).as_secs_f64();
+            if secs > 0.0 {
+                let rate = (bytes as f64 / secs) as u64;
+                line += &format!(" | {}/s", human_size(rate));
+            }
+        }
+        if done {
+            line += ", done.";
+        }
+        let len = line.len();
+        // blank out what is left of a longer previous line
+        line += &" ".repeat(self.last_len.saturating_sub(len));
+        self.last_len = len;
+        line += if done { "\n" } else { "\r" };
+        let _ = std::io::stderr().write_all(line.as_bytes());
+    }
+}
+
+/// A size in bytes, as git prints it: `512 bytes`, `1.50 KiB`, `3.25 MiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [(&str, u64); 3] = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+    for (unit, size) in UNITS {
+        if bytes >= size {
+            let hundredths = bytes % size * 100 / size;
+            return format!("{}.{hundredths:02} {unit}", bytes / size);
+        }
+    }
+    if bytes == 1 {
+        "1 byte".to_string()
+    } else {
+        format!("{bytes} bytes")
+    }
+}
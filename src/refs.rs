@@ -0,0 +1,292 @@
+//! Refs: loose files under `.git/refs` and the `packed-refs` file.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::error::Error;
+
+/// How many symbolic refs are followed before giving up, as in git.
+const SYMREF_MAXDEPTH: usize = 5;
+
+/// Resolve a ref name the way git's rev-parse does, trying `<name>`,
+/// `refs/<name>`, `refs/tags/<name>`, `refs/heads/<name>`,
+/// `refs/remotes/<name>` and `refs/remotes/<name>/HEAD` in turn and following
+/// symbolic refs, up to [`SYMREF_MAXDEPTH`] of them.
+pub fn resolve(git_dir: &Path, name: &str) -> Result<Option<String>, anyhow::Error> {
+    resolve_at_depth(git_dir, name, 0)
+}
+
+fn resolve_at_depth(
+    git_dir: &Path,
+    name: &str,
+    depth: usize,
+) -> Result<Option<String>, anyhow::Error> {
+    if name.is_empty() || name.split('/').any(|c| c.is_empty() || c == "..") {
+        return Ok(None);
+    }
+    let candidates = [
+        name.to_string(),
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+        format!("refs/remotes/{name}/HEAD"),
+    ];
+    let packed = fs::read_to_string(git_dir.join("packed-refs")).unwrap_or_default();
+    for candidate in &candidates {
+        let path = git_dir.join(candidate);
+        if path.is_file() {
+            let content = fs::read_to_string(&path).context(format!("read {:?}", path))?;
+            let content = content.trim();
+            if let Some(target) = content.strip_prefix("ref: ") {
+                if depth == SYMREF_MAXDEPTH {
+                    anyhow::bail!("{candidate}: symbolic ref nesting too deep");
+                }
+                return resolve_at_depth(git_dir, target, depth + 1);
+            }
+            return Ok(Some(content.to_string()));
+        }
+        let packed_ref = packed.lines().find_map(|line| {
+            let (hash, refname) = line.split_once(' ')?;
+            (refname == candidate).then_some(hash)
+        });
+        if let Some(hash) = packed_ref {
+            return Ok(Some(hash.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether `name` is a well-formed ref name, following the rules of
+/// `git check-ref-format`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "@"
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name
+            .bytes()
+            .any(|c| c < 0x20 || c == 0x7f || b" ~^:?*[\\".contains(&c))
+        && !name
+            .split('/')
+            .any(|c| c.is_empty() || c.starts_with('.') || c.ends_with(".lock"))
+}
+
+/// Point the ref `name` (e.g. `refs/heads/main`) at `oid`. The new value is
+/// written to `<ref>.lock` and renamed into place, as git does, so concurrent
+/// updates fail instead of interleaving. `HEAD` may be named too, which
+/// detaches it.
+pub fn update(git_dir: &Path, name: &str, oid: &str) -> Result<(), anyhow::Error> {
+    write(git_dir, name, &format!("{oid}\n"))
+}
+
+/// Make `name` (`HEAD`, or a ref such as `refs/remotes/origin/HEAD`) a
+/// symbolic ref pointing at the ref `target`.
+pub fn set_symbolic(git_dir: &Path, name: &str, target: &str) -> Result<(), anyhow::Error> {
+    if !target.starts_with("refs/") || !is_valid_name(target) {
+        return Err(Error::RefConflict {
+            name: name.to_string(),
+            reason: format!("'{target}' is not a valid ref name"),
+        }
+        .into());
+    }
+    write(git_dir, name, &format!("ref: {target}\n"))
+}
+
+fn write(git_dir: &Path, name: &str, content: &str) -> Result<(), anyhow::Error> {
+    let conflict = |reason: String| Error::RefConflict {
+        name: name.to_string(),
+        reason,
+    };
+    if !(name == "HEAD" || name.starts_with("refs/")) || !is_valid_name(name) {
+        return Err(conflict("not a valid ref name".to_string()).into());
+    }
+    let path = git_dir.join(name);
+    if path.is_dir() {
+        return Err(conflict("a directory is in the way".to_string()).into());
+    }
+    // refs/heads/a and refs/heads/a/b cannot both exist
+    let mut prefix = String::new();
+    for component in name.split('/') {
+        if !prefix.is_empty() && prefix != "refs" && git_dir.join(&prefix).is_file() {
+            return Err(conflict(format!("{prefix} exists")).into());
+        }
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(component);
+    }
+    let parent = path.parent().unwrap_or(git_dir);
+    fs::create_dir_all(parent).context(format!("create {:?}", parent))?;
+    let lock = path.with_file_name(format!(
+        "{}.lock",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let mut f = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+    {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(conflict(format!("{:?} exists, is another git running?", lock)).into())
+        }
+        Err(source) => return Err(Error::Io { path: lock, source }.into()),
+    };
+    let written = f
+        .write_all(content.as_bytes())
+        .and_then(|_| fs::rename(&lock, &path));
+    if let Err(source) = written {
+        let _ = fs::remove_file(&lock);
+        return Err(Error::Io { path, source }.into());
+    }
+    Ok(())
+}
+
+/// The ref the symbolic ref `name` (such as `HEAD`) points at, or `None`
+/// when `name` is missing or holds an object id.
+pub fn symbolic_target(git_dir: &Path, name: &str) -> Result<Option<String>, anyhow::Error> {
+    let path = git_dir.join(name);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => return Err(Error::Io { path, source }.into()),
+    };
+    Ok(content.trim_end().strip_prefix("ref: ").map(str::to_string))
+}
+
+/// Delete the ref `name`, both its loose file and its `packed-refs` entry.
+/// Deleting a ref that does not exist is not an error.
+pub fn delete(git_dir: &Path, name: &str) -> Result<(), anyhow::Error> {
+    let path = git_dir.join(name);
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(source) => return Err(Error::Io { path, source }.into()),
+    }
+    let packed_path = git_dir.join("packed-refs");
+    let Ok(packed) = fs::read_to_string(&packed_path) else {
+        return Ok(());
+    };
+    // drop the entry along with the peeled value that may follow it
+    let mut kept = String::new();
+    let mut dropping = false;
+    let mut found = false;
+    for line in packed.lines() {
+        if line.starts_with('^') && dropping {
+            continue;
+        }
+        dropping = line.split_once(' ').is_some_and(|(_, n)| n == name);
+        found |= dropping;
+        if !dropping {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    if !found {
+        return Ok(());
+    }
+    // rewritten under a lock, as a ref is
+    let lock = git_dir.join("packed-refs.lock");
+    let mut f = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+    {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(Error::RefConflict {
+                name: name.to_string(),
+                reason: format!("{:?} exists, is another git running?", lock),
+            }
+            .into())
+        }
+        Err(source) => return Err(Error::Io { path: lock, source }.into()),
+    };
+    let written = f
+        .write_all(kept.as_bytes())
+        .and_then(|_| fs::rename(&lock, &packed_path));
+    if let Err(source) = written {
+        let _ = fs::remove_file(&lock);
+        return Err(Error::Io {
+            path: packed_path,
+            source,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Every ref in the repository with the value it holds: an object id, or
+/// `ref: <target>` for symbolic refs.
+pub fn all(git_dir: &Path) -> Result<Vec<(String, String)>, anyhow::Error> {
+    fn walk(dir: &Path, name: &str, refs: &mut Vec<(String, String)>) -> Result<(), anyhow::Error> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let child = format!("{name}/{}", entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &child, refs)?;
+            } else if !child.ends_with(".lock") {
+                let value = std::fs::read_to_string(entry.path())?;
+                refs.push((child, value.trim().to_string()));
+            }
+        }
+        Ok(())
+    }
+    let mut refs = Vec::new();
+    let refs_dir = git_dir.join("refs");
+    if refs_dir.is_dir() {
+        walk(&refs_dir, "refs", &mut refs)?;
+    }
+    let packed = std::fs::read_to_string(git_dir.join("packed-refs")).unwrap_or_default();
+    for line in packed.lines() {
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        if let Some((oid, name)) = line.split_once(' ') {
+            // loose refs take precedence over packed ones
+            if !refs.iter().any(|(n, _)| n == name) {
+                refs.push((name.to_string(), oid.to_string()));
+            }
+        }
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbolic_refs_nest_up_to_a_limit() {
+        let dir = std::env::temp_dir().join(format!(
+            "codecrafters-git-refs-{}-symref-depth",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let oid = "a".repeat(40);
+        update(&dir, "refs/heads/r0", &oid).unwrap();
+        for i in 1..=SYMREF_MAXDEPTH + 1 {
+            set_symbolic(
+                &dir,
+                &format!("refs/heads/r{i}"),
+                &format!("refs/heads/r{}", i - 1),
+            )
+            .unwrap();
+        }
+        let resolve = |i: usize| resolve(&dir, &format!("r{i}"));
+        assert_eq!(resolve(SYMREF_MAXDEPTH).unwrap(), Some(oid));
+        let err = resolve(SYMREF_MAXDEPTH + 1).unwrap_err();
+        assert!(err.to_string().contains("symbolic ref nesting too deep"));
+
+        // a symbolic ref pointing at itself is no different
+        set_symbolic(&dir, "refs/heads/loop", "refs/heads/loop").unwrap();
+        assert!(resolve_at_depth(&dir, "loop", 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,2072 @@
+//! Remotes as the config describes them, the refspecs that map their refs
+//! to local ones, and fetching from and pushing to them.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::config::{self, Config};
+use crate::error::Error;
+use crate::object::{parse_tree, ObjectType};
+use crate::odb::ObjectDatabase;
+use crate::pack;
+use crate::progress::Progress;
+use crate::repository::Repository;
+use crate::transport::{
+    self, Advertisement, Deepen, FetchOptions, FetchResponse, ObjectFilter, PushCommand,
+    PushOptions, RemoteRef, TagMode,
+};
+use crate::{revwalk, worktree};
+
+/// A refspec, `[+]<src>[:<dst>]`: which remote refs to fetch and which local
+/// refs to store them in, or, pushing, which local refs to send and which
+/// remote refs to update. Either side may hold a single `*`, on both sides
+/// at once, to map many refs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    /// Update `dst` even when it is not a fast-forward.
+    pub force: bool,
+    pub src: String,
+    pub dst: Option<String>,
+}
+
+impl Refspec {
+    pub fn parse(spec: &str) -> Result<Self, anyhow::Error> {
+        let (force, rest) = match spec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (src, dst) = match rest.split_once(':') {
+            Some((src, "")) => (src, None),
+            Some((src, dst)) => (src, Some(dst)),
+            None => (rest, None),
+        };
+        let stars = |s: &str| s.matches('*').count();
+        let valid = !src.is_empty()
+            && stars(src) <= 1
+            && dst.map_or(stars(src) == 0, |dst| stars(dst) == stars(src));
+        if !valid {
+            anyhow::bail!("invalid refspec '{spec}'");
+        }
+        Ok(Refspec {
+            force,
+            src: src.to_string(),
+            dst: dst.map(str::to_string),
+        })
+    }
+
+    /// Parse a refspec to push, which may also be `:<dst>`, deleting `dst`,
+    /// or `:`, pushing every branch that both sides have.
+    pub fn parse_push(spec: &str) -> Result<Self, anyhow::Error> {
+        let force = spec.starts_with('+');
+        match spec.trim_start_matches('+').strip_prefix(':') {
+            Some(dst) if !dst.contains('*') && !dst.contains(':') => Ok(Refspec {
+                force,
+                src: String::new(),
+                dst: (!dst.is_empty()).then(|| dst.to_string()),
+            }),
+            _ => Self::parse(spec),
+        }
+    }
+
+    pub fn is_pattern(&self) -> bool {
+        self.src.contains('*')
+    }
+
+    /// What to ask `ls-refs` for so that every ref the source may match is
+    /// listed.
+    fn prefixes(&self) -> Vec<String> {
+        match self.src.split_once('*') {
+            Some((prefix, _)) => vec![prefix.to_string()],
+            None => expand_name(&self.src),
+        }
+    }
+
+    /// The advertised refs the source names: those matching a pattern, or
+    /// the one an exact name resolves to.
+    fn matches<'a>(&self, advertisement: &'a Advertisement) -> Vec<&'a RemoteRef> {
+        if self.is_pattern() {
+            return advertisement
+                .refs
+                .iter()
+                .filter(|r| match_pattern(&self.src, "", &r.name).is_some())
+                .collect();
+        }
+        expand_name(&self.src)
+            .iter()
+            .find_map(|name| advertisement.find(name))
+            .into_iter()
+            .collect()
+    }
+
+    /// The local ref the remote ref `name` is stored in, if the source
+    /// matches it and there is a destination.
+    pub fn map(&self, name: &str) -> Option<String> {
+        let dst = self.dst.as_deref()?;
+        if self.is_pattern() {
+            return match_pattern(&self.src, dst, name);
+        }
+        expand_name(&self.src)
+            .iter()
+            .any(|n| n == name)
+            .then(|| local_ref_name(dst))
+    }
+}
+
+/// The full names a short ref name may stand for, in the order git tries
+/// them.
+fn expand_name(name: &str) -> Vec<String> {
+    if name.starts_with("refs/") || name == "HEAD" {
+        return vec![name.to_string()];
+    }
+    vec![
+        name.to_string(),
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+        format!("refs/remotes/{name}/HEAD"),
+    ]
+}
+
+/// The local ref an exact destination names: a branch unless it says
+/// otherwise.
+fn local_ref_name(dst: &str) -> String {
+    if dst.starts_with("refs/") {
+        dst.to_string()
+    } else if ["heads/", "tags/", "remotes/"]
+        .iter()
+        .any(|p| dst.starts_with(p))
+    {
+        format!("refs/{dst}")
+    } else {
+        format!("refs/heads/{dst}")
+    }
+}
+
+/// Match `name` against the pattern `from` and put what its `*` matched in
+/// place of the `*` of `to`.
+fn match_pattern(from: &str, to: &str, name: &str) -> Option<String> {
+    let (prefix, suffix) = from.split_once('*')?;
+    let middle = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    if middle.is_empty() {
+        return None;
+    }
+    Some(to.replacen('*', middle, 1))
+}
+
+/// A remote repository, as `remote.<name>.*` configures it.
+pub struct Remote {
+    pub name: String,
+    pub url: String,
+    /// The refspecs fetched when none are given (`remote.<name>.fetch`).
+    pub fetch: Vec<Refspec>,
+    /// The refspecs pushed when none are given (`remote.<name>.push`).
+    pub push: Vec<Refspec>,
+    /// Whether fetching prunes by default (`remote.<name>.prune`, else
+    /// `fetch.prune`).
+    pub prune: Option<bool>,
+    /// `remote.<name>.tagOpt`: `--tags` or `--no-tags`.
+    pub tags: Option<TagMode>,
+    /// Whether the remote provides the objects a partial clone lacks
+    /// (`remote.<name>.promisor`, or `extensions.partialClone` naming it).
+    pub promisor: bool,
+    /// The filter fetches from a promisor remote use
+    /// (`remote.<name>.partialCloneFilter`).
+    pub filter: Option<ObjectFilter>,
+}
+
+impl Remote {
+    /// The remote called `name`, or an unnamed one when `name` is a URL.
+    pub fn get(config: &Config, name: &str) -> Result<Self, anyhow::Error> {
+        let key = |var: &str| format!("remote.{name}.{var}");
+        let url = match config.get(&key("url")) {
+            Some(url) => url.to_string(),
+            None if name.contains("://") => {
+                return Ok(Remote {
+                    name: name.to_string(),
+                    url: name.trim_end_matches('/').to_string(),
+                    fetch: Vec::new(),
+                    push: Vec::new(),
+                    prune: config.get_bool("fetch.prune")?,
+                    tags: None,
+                    promisor: false,
+                    filter: None,
+                })
+            }
+            None => anyhow::bail!("'{name}' does not appear to be a git repository"),
+        };
+        let fetch = config
+            .get_all(&key("fetch"))
+            .map(Refspec::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .context(key("fetch"))?;
+        let push = config
+            .get_all(&key("push"))
+            .map(Refspec::parse_push)
+            .collect::<Result<Vec<_>, _>>()
+            .context(key("push"))?;
+        let prune = match config.get_bool(&key("prune"))? {
+            Some(prune) => Some(prune),
+            None => config.get_bool("fetch.prune")?,
+        };
+        let tags = match config.get(&key("tagOpt")) {
+            Some("--tags") => Some(TagMode::All),
+            Some("--no-tags") => Some(TagMode::None),
+            _ => None,
+        };
+        let promisor = config.get_bool(&key("promisor"))?.unwrap_or(false)
+            || config.get("extensions.partialClone") == Some(name);
+        let filter = match config.get(&key("partialCloneFilter")) {
+            Some(spec) if promisor => {
+                Some(ObjectFilter::parse(spec).context(key("partialCloneFilter"))?)
+            }
+            _ => None,
+        };
+        Ok(Remote {
+            name: name.to_string(),
+            url: url.trim_end_matches('/').to_string(),
+            fetch,
+            push,
+            prune,
+            tags,
+            promisor,
+            filter,
+        })
+    }
+}
+
+/// The branch `HEAD` points at, as a short name.
+fn current_branch(repo: &Repository) -> Result<Option<String>, anyhow::Error> {
+    Ok(repo
+        .symbolic_ref("HEAD")?
+        .and_then(|target| Some(target.strip_prefix("refs/heads/")?.to_string())))
+}
+
+/// The remote to use when none is named: the current branch's
+/// `branch.<name>.remote`, or `origin`.
+pub fn default_remote(repo: &Repository) -> Result<String, anyhow::Error> {
+    let configured = current_branch(repo)?
+        .and_then(|branch| repo.config().get(&format!("branch.{branch}.remote")))
+        .map(str::to_string);
+    Ok(configured.unwrap_or_else(|| "origin".to_string()))
+}
+
+/// The remote to push to when none is named: the current branch's
+/// `branch.<name>.pushRemote`, else `remote.pushDefault`, else the one
+/// fetched from.
+pub fn default_push_remote(repo: &Repository) -> Result<String, anyhow::Error> {
+    let config = repo.config();
+    let configured = current_branch(repo)?
+        .and_then(|branch| config.get(&format!("branch.{branch}.pushRemote")))
+        .or_else(|| config.get("remote.pushDefault"))
+        .map(str::to_string);
+    match configured {
+        Some(name) => Ok(name),
+        None => default_remote(repo),
+    }
+}
+
+/// What happened to a local ref in a fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateStatus {
+    New,
+    UpToDate,
+    FastForward,
+    /// Updated although the old value is not an ancestor of the new one.
+    Forced,
+    /// Left alone, for the reason given.
+    Rejected(&'static str),
+    /// Pruned, its remote ref being gone.
+    Deleted,
+    /// Fetched without a local ref to store it in: only listed in
+    /// `FETCH_HEAD`.
+    FetchHead,
+}
+
+/// A local ref a fetch created, updated, left alone or deleted, or a
+/// remote ref it only recorded in `FETCH_HEAD`.
+#[derive(Debug, Clone)]
+pub struct RefUpdate {
+    /// The remote ref it follows; `None` for pruned refs.
+    pub remote: Option<String>,
+    pub local: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    pub status: UpdateStatus,
+}
+
+/// A remote ref to fetch, and where it goes.
+struct Mapping<'a> {
+    remote: &'a RemoteRef,
+    local: Option<String>,
+    force: bool,
+    /// Marked for merging in `FETCH_HEAD`.
+    for_merge: bool,
+}
+
+/// Fetch `refspecs` from `remote`, or its configured refspecs when none are
+/// given, and update the local refs they map to.
+///
+/// As in git, refspecs given here without a destination still update the
+/// remote-tracking refs the configured refspecs map them to, and every
+/// fetched ref is listed in `FETCH_HEAD`. Updates that are not
+/// fast-forwards are rejected unless forced; the caller can tell from the
+/// returned [`RefUpdate`]s.
+pub fn fetch(
+    repo: &Repository,
+    remote: &Remote,
+    refspecs: &[Refspec],
+    opts: &FetchOptions,
+) -> Result<Vec<RefUpdate>, anyhow::Error> {
+    let mut prefixes = specs_to_fetch(remote, refspecs)?
+        .iter()
+        .flat_map(Refspec::prefixes)
+        .collect::<Vec<_>>();
+    if opts.tags != TagMode::None {
+        prefixes.push("refs/tags/".to_string());
+    }
+    let prefixes = prefixes.iter().map(String::as_str).collect::<Vec<_>>();
+    let advertisement = transport::ls_refs(&remote.url, &prefixes)?;
+    fetch_refs(repo, remote, refspecs, &advertisement, opts, true)
+}
+
+/// The refspecs a fetch uses: those given, or else the remote's.
+fn specs_to_fetch(remote: &Remote, refspecs: &[Refspec]) -> Result<Vec<Refspec>, anyhow::Error> {
+    Ok(match (refspecs.is_empty(), remote.fetch.is_empty()) {
+        (false, _) => refspecs.to_vec(),
+        (true, false) => remote.fetch.clone(),
+        // an unconfigured remote has nothing but its HEAD to offer
+        (true, true) => vec![Refspec::parse("HEAD")?],
+    })
+}
+
+/// [`fetch`], given what the remote advertised, writing `FETCH_HEAD` only
+/// if `fetch_head` is set.
+fn fetch_refs(
+    repo: &Repository,
+    remote: &Remote,
+    refspecs: &[Refspec],
+    advertisement: &Advertisement,
+    opts: &FetchOptions,
+    fetch_head: bool,
+) -> Result<Vec<RefUpdate>, anyhow::Error> {
+    let odb = repo.odb();
+    let from_command_line = !refspecs.is_empty();
+    let specs = specs_to_fetch(remote, refspecs)?;
+    let tag_spec = Refspec::parse("refs/tags/*:refs/tags/*")?;
+    let remote_format = advertisement.object_format()?;
+    if remote_format != odb.format() {
+        anyhow::bail!(
+            "the remote uses {remote_format} but the repository uses {}",
+            odb.format()
+        );
+    }
+
+    let merge = match current_branch(repo)? {
+        Some(branch)
+            if repo.config().get(&format!("branch.{branch}.remote")) == Some(&remote.name) =>
+        {
+            repo.config()
+                .get(&format!("branch.{branch}.merge"))
+                .map(str::to_string)
+        }
+        _ => None,
+    };
+    let mut mappings: Vec<Mapping> = Vec::new();
+    for spec in &specs {
+        let matched = spec.matches(advertisement);
+        if matched.is_empty() && !spec.is_pattern() {
+            anyhow::bail!("couldn't find remote ref {}", spec.src);
+        }
+        for remote_ref in matched {
+            let mut local = spec.map(&remote_ref.name);
+            if local.is_none() && from_command_line {
+                local = remote.fetch.iter().find_map(|s| s.map(&remote_ref.name));
+            }
+            mappings.push(Mapping {
+                remote: remote_ref,
+                local,
+                force: spec.force || opts.force,
+                for_merge: from_command_line || merge.as_deref() == Some(&remote_ref.name),
+            });
+        }
+    }
+    if opts.tags == TagMode::All {
+        for remote_ref in tag_spec.matches(advertisement) {
+            mappings.push(Mapping {
+                remote: remote_ref,
+                local: tag_spec.map(&remote_ref.name),
+                force: opts.force,
+                for_merge: false,
+            });
+        }
+    }
+    // a local ref is only updated once, by the first refspec mapping to it
+    let mut taken = Vec::new();
+    for mapping in &mut mappings {
+        if let Some(local) = &mapping.local {
+            if taken.contains(local) {
+                mapping.local = None;
+            } else {
+                taken.push(local.clone());
+            }
+        }
+    }
+
+    if !opts.update_head_ok && repo.work_tree().is_some() {
+        if let Some(head) = repo.symbolic_ref("HEAD")? {
+            let clobbered = mappings.iter().any(|m| {
+                m.local.as_deref() == Some(&head)
+                    && repo.resolve_ref(&head).ok().flatten().as_deref() != Some(&m.remote.oid)
+            });
+            if clobbered {
+                anyhow::bail!(
+                    "refusing to fetch into branch '{head}' checked out at '{}'",
+                    repo.work_tree().unwrap_or(repo.git_dir()).display()
+                );
+            }
+        }
+    }
+
+    let haves = repo
+        .refs()?
+        .into_iter()
+        .filter(|(_, value)| !value.starts_with("ref: "))
+        .map(|(_, oid)| oid)
+        .collect::<Vec<_>>();
+    // deepening wants even what is here, to fetch more of its history
+    let mut wants = Vec::new();
+    for mapping in &mappings {
+        let oid = &mapping.remote.oid;
+        if (opts.deepen.is_set() || !odb.contains(oid)) && !wants.contains(oid) {
+            wants.push(oid.clone());
+        }
+    }
+    if !wants.is_empty() {
+        let response =
+            transport::fetch_pack(odb, &remote.url, advertisement, &wants, &haves, opts)?;
+        update_shallow(repo, &response)?;
+        if remote.promisor {
+            let refs = mappings
+                .iter()
+                .map(|m| (m.remote.oid.clone(), m.remote.name.clone()))
+                .collect::<Vec<_>>();
+            pack::write_promisor(odb, &response.pack, &refs)?;
+        }
+    }
+
+    // tags pointing into what is now here come along, fetched separately
+    // when the remote did not include them
+    let mut followed = Vec::new();
+    if opts.tags == TagMode::Follow {
+        for remote_ref in &advertisement.refs {
+            let target = remote_ref.peeled.as_ref().unwrap_or(&remote_ref.oid);
+            if remote_ref.name.starts_with("refs/tags/")
+                && !mappings.iter().any(|m| m.remote.name == remote_ref.name)
+                && repo.resolve_ref(&remote_ref.name)?.is_none()
+                && odb.contains(target)
+            {
+                followed.push(remote_ref);
+            }
+        }
+        let missing = followed
+            .iter()
+            .filter(|r| !odb.contains(&r.oid))
+            .map(|r| r.oid.clone())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            let mut haves = haves.clone();
+            haves.extend(wants);
+            let opts = FetchOptions {
+                tags: TagMode::None,
+                deepen: Deepen::default(),
+                ..opts.clone()
+            };
+            let response =
+                transport::fetch_pack(odb, &remote.url, advertisement, &missing, &haves, &opts)?;
+            update_shallow(repo, &response)?;
+            if remote.promisor {
+                pack::write_promisor(odb, &response.pack, &[])?;
+            }
+        }
+        followed.retain(|r| odb.contains(&r.oid));
+    }
+
+    mappings.extend(followed.into_iter().map(|remote_ref| Mapping {
+        remote: remote_ref,
+        local: Some(remote_ref.name.clone()),
+        force: false,
+        for_merge: false,
+    }));
+
+    // pruned first, so that a pruned ref cannot block a new one
+    let mut updates = Vec::new();
+    if opts.prune {
+        for spec in specs.iter().filter(|s| s.is_pattern()) {
+            let Some(dst) = &spec.dst else {
+                continue;
+            };
+            // symbolic refs such as origin/HEAD follow the remote's own
+            // refs and are not pruned
+            let refs = repo.refs()?.into_iter();
+            for (local, old) in refs.filter(|(_, value)| !value.starts_with("ref: ")) {
+                let Some(name) = match_pattern(dst, &spec.src, &local) else {
+                    continue;
+                };
+                let mapped = mappings.iter().any(|m| m.local.as_ref() == Some(&local));
+                if mapped || advertisement.find(&name).is_some() {
+                    continue;
+                }
+                repo.delete_ref(&local)?;
+                updates.push(RefUpdate {
+                    remote: None,
+                    local,
+                    old: Some(old),
+                    new: None,
+                    status: UpdateStatus::Deleted,
+                });
+            }
+        }
+    }
+
+    for mapping in &mappings {
+        updates.push(match &mapping.local {
+            Some(local) => update_ref(repo, mapping.remote, local, mapping.force)?,
+            None => RefUpdate {
+                remote: Some(mapping.remote.name.clone()),
+                local: "FETCH_HEAD".to_string(),
+                old: None,
+                new: Some(mapping.remote.oid.clone()),
+                status: UpdateStatus::FetchHead,
+            },
+        });
+    }
+
+    if fetch_head {
+        write_fetch_head(repo, &remote.url, &mappings)?;
+    }
+    Ok(updates)
+}
+
+/// Point `local` at what `remote_ref` holds, if that is allowed.
+fn update_ref(
+    repo: &Repository,
+    remote_ref: &RemoteRef,
+    local: &str,
+    force: bool,
+) -> Result<RefUpdate, anyhow::Error> {
+    let old = repo.resolve_ref(local)?;
+    let new = &remote_ref.oid;
+    // tags are not expected to move, even forward
+    let tag = local.starts_with("refs/tags/");
+    let status = match &old {
+        None => UpdateStatus::New,
+        Some(old) if old == new => UpdateStatus::UpToDate,
+        Some(old) if !tag && is_fast_forward(repo, old, new)? => UpdateStatus::FastForward,
+        Some(_) if force => UpdateStatus::Forced,
+        Some(_) if tag => UpdateStatus::Rejected("would clobber existing tag"),
+        Some(_) => UpdateStatus::Rejected("non-fast-forward"),
+    };
+    if matches!(
+        status,
+        UpdateStatus::New | UpdateStatus::FastForward | UpdateStatus::Forced
+    ) {
+        repo.update_ref(local, new)?;
+    }
+    Ok(RefUpdate {
+        remote: Some(remote_ref.name.clone()),
+        local: local.to_string(),
+        old,
+        new: Some(new.clone()),
+        status,
+    })
+}
+
+/// Record where history is now cut off, after a fetch that moved it.
+fn update_shallow(repo: &Repository, response: &FetchResponse) -> Result<(), anyhow::Error> {
+    if response.shallow.is_empty() && response.unshallow.is_empty() {
+        return Ok(());
+    }
+    let mut commits = repo.odb().shallow();
+    commits.retain(|c| !response.unshallow.contains(c));
+    commits.extend(response.shallow.iter().cloned());
+    repo.set_shallow(&commits)
+}
+
+/// Whether moving a ref from `old` to `new` keeps what `old` reached: both
+/// are commits, and `old` is an ancestor of `new`.
+fn is_fast_forward(repo: &Repository, old: &str, new: &str) -> Result<bool, anyhow::Error> {
+    let odb = repo.odb();
+    if !odb.contains(old) {
+        return Ok(false);
+    }
+    let (Some(old), Some(new)) = (
+        revwalk::peel_to_commit(odb, old)?,
+        revwalk::peel_to_commit(odb, new)?,
+    ) else {
+        return Ok(false);
+    };
+    revwalk::is_ancestor(odb, &old, &new)
+}
+
+/// Record the fetched refs in `FETCH_HEAD`, the ones to merge first marked
+/// as such, in the format `git pull` reads.
+fn write_fetch_head(
+    repo: &Repository,
+    url: &str,
+    mappings: &[Mapping],
+) -> Result<(), anyhow::Error> {
+    let url = display_url(url);
+    let mut content = String::new();
+    for for_merge in [true, false] {
+        for mapping in mappings.iter().filter(|m| m.for_merge == for_merge) {
+            let name = &mapping.remote.name;
+            let (kind, what) = if name == "HEAD" {
+                ("", "")
+            } else if let Some(branch) = name.strip_prefix("refs/heads/") {
+                ("branch ", branch)
+            } else if let Some(tag) = name.strip_prefix("refs/tags/") {
+                ("tag ", tag)
+            } else if let Some(branch) = name.strip_prefix("refs/remotes/") {
+                ("remote-tracking branch ", branch)
+            } else {
+                ("", name.as_str())
+            };
+            let merge = if for_merge { "" } else { "not-for-merge" };
+            let what = if what.is_empty() {
+                String::new()
+            } else {
+                format!("'{what}' of ")
+            };
+            writeln!(
+                content,
+                "{}\t{merge}\t{kind}{what}{url}",
+                mapping.remote.oid
+            )?;
+        }
+    }
+    let path = repo.git_dir().join("FETCH_HEAD");
+    fs::write(&path, content).map_err(|source| Error::Io { path, source })?;
+    Ok(())
+}
+
+/// How to clone.
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    /// The branch, or tag, to check out instead of the one the remote's
+    /// `HEAD` points at.
+    pub branch: Option<String>,
+    /// Only fetch that one branch, and the tags pointing into it.
+    pub single_branch: bool,
+    /// Copy every ref as it is, into a bare repository.
+    pub mirror: bool,
+}
+
+impl CloneOptions {
+    /// What to ask `ls-refs` for before cloning.
+    pub fn ref_prefixes(&self) -> &'static [&'static str] {
+        if self.mirror {
+            &[]
+        } else {
+            &["HEAD", "refs/heads/", "refs/tags/"]
+        }
+    }
+}
+
+/// The branch the remote's `HEAD` points at. Servers that do not say are
+/// guessed at from the branches holding the same commit, `master` first,
+/// as git does.
+fn remote_head(advertisement: &Advertisement) -> Option<&RemoteRef> {
+    let head = advertisement.find("HEAD")?;
+    if let Some(target) = &head.symref_target {
+        return advertisement.find(target);
+    }
+    let mut branches = advertisement
+        .refs
+        .iter()
+        .filter(|r| r.name.starts_with("refs/heads/") && r.oid == head.oid);
+    advertisement
+        .find("refs/heads/master")
+        .filter(|r| r.oid == head.oid)
+        .or_else(|| branches.next())
+}
+
+/// Where a clone keeps the remote's ref `name`: branches go under
+/// `refs/remotes/origin/` unless the clone is bare.
+fn clone_tracking_ref(name: &str, bare: bool) -> String {
+    match name.strip_prefix("refs/heads/") {
+        Some(branch) if !bare => format!("refs/remotes/origin/{branch}"),
+        _ => name.to_string(),
+    }
+}
+
+/// The refspec a clone fetches with and the tags it brings along, given the
+/// ref it checks out and the tag mode asked for.
+fn clone_refspec(
+    opts: &CloneOptions,
+    checkout: Option<&RemoteRef>,
+    bare: bool,
+    tags: TagMode,
+) -> (String, TagMode) {
+    let spec = if opts.mirror {
+        "+refs/*:refs/*".to_string()
+    } else {
+        match checkout {
+            Some(r) if opts.single_branch => {
+                format!("+{}:{}", r.name, clone_tracking_ref(&r.name, bare))
+            }
+            _ if bare => "+refs/heads/*:refs/heads/*".to_string(),
+            _ => "+refs/heads/*:refs/remotes/origin/*".to_string(),
+        }
+    };
+    let tags = if opts.mirror || tags == TagMode::None {
+        TagMode::None
+    } else if opts.single_branch {
+        TagMode::Follow
+    } else {
+        TagMode::All
+    };
+    (spec, tags)
+}
+
+/// The directory `git clone <url>` creates: the last part of the URL,
+/// without `.git` unless the clone is bare.
+pub fn clone_dir(url: &str, bare: bool) -> Result<PathBuf, anyhow::Error> {
+    let url = url.trim_end_matches('/');
+    let name = url.rsplit(['/', ':']).next().unwrap_or_default();
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    if name.is_empty() {
+        anyhow::bail!("could not guess a directory name from '{url}'; please specify one");
+    }
+    Ok(PathBuf::from(if bare {
+        format!("{name}.git")
+    } else {
+        name.to_string()
+    }))
+}
+
+/// Fill the freshly initialized `repo` from `url` as `git clone` does: set
+/// up the `origin` remote, fetch its branches and tags, and check out its
+/// `HEAD` (or the branch `opts` names) into the work tree, if there is one.
+/// `advertisement` is what [`transport::ls_refs`] returned for
+/// [`CloneOptions::ref_prefixes`].
+pub fn clone(
+    repo: &Repository,
+    url: &str,
+    advertisement: &Advertisement,
+    opts: &CloneOptions,
+    fetch_opts: &FetchOptions,
+) -> Result<(), anyhow::Error> {
+    let bare = repo.work_tree().is_none();
+    let checkout = match &opts.branch {
+        Some(branch) => Some(
+            [
+                format!("refs/heads/{branch}"),
+                format!("refs/tags/{branch}"),
+            ]
+            .iter()
+            .find_map(|name| advertisement.find(name))
+            .ok_or_else(|| {
+                anyhow::anyhow!("Remote branch {branch} not found in upstream origin")
+            })?,
+        ),
+        None => remote_head(advertisement),
+    };
+    let (spec, tags) = clone_refspec(opts, checkout, bare, fetch_opts.tags);
+
+    // a bare clone maps branches onto branches, and so does not fetch them
+    // again by default
+    let mut vars = vec![("url", url)];
+    if fetch_opts.tags == TagMode::None {
+        vars.push(("tagOpt", "--no-tags"));
+    }
+    if opts.mirror || !bare {
+        vars.push(("fetch", &spec));
+    }
+    if opts.mirror {
+        vars.push(("mirror", "true"));
+    }
+    let filter = fetch_opts.filter.map(|filter| filter.to_string());
+    if let Some(filter) = &filter {
+        vars.push(("promisor", "true"));
+        vars.push(("partialclonefilter", filter));
+    }
+    let config_path = repo.git_dir().join("config");
+    config::append_section(&config_path, "remote", Some("origin"), &vars)?;
+    if filter.is_some() {
+        // older versions of git would not know to fetch what is missing
+        config::set(&config_path, "core.repositoryformatversion", "1")?;
+        config::set(&config_path, "extensions.partialclone", "origin")?;
+        repo.odb()
+            .set_promisor(Some(url.trim_end_matches('/').to_string()));
+    }
+
+    let remote = Remote {
+        name: "origin".to_string(),
+        url: url.trim_end_matches('/').to_string(),
+        fetch: vec![Refspec::parse(&spec)?],
+        push: Vec::new(),
+        prune: None,
+        tags: Some(tags),
+        promisor: fetch_opts.filter.is_some(),
+        filter: fetch_opts.filter,
+    };
+    let fetch_opts = FetchOptions {
+        tags,
+        update_head_ok: true,
+        ..fetch_opts.clone()
+    };
+    fetch_refs(repo, &remote, &[], advertisement, &fetch_opts, false)?;
+
+    let Some(checkout) = checkout else {
+        eprintln!("warning: You appear to have cloned an empty repository.");
+        return Ok(());
+    };
+    if !opts.mirror && !bare {
+        if let Some(head) = remote_head(advertisement) {
+            let head = clone_tracking_ref(&head.name, bare);
+            if repo.resolve_ref(&head)?.is_some() {
+                repo.set_symbolic_ref("refs/remotes/origin/HEAD", &head)?;
+            }
+        }
+    }
+    let commit = match checkout.name.strip_prefix("refs/heads/") {
+        Some(branch) => {
+            if !bare {
+                repo.update_ref(&checkout.name, &checkout.oid)?;
+                config::append_section(
+                    &config_path,
+                    "branch",
+                    Some(branch),
+                    &[("remote", "origin"), ("merge", &checkout.name)],
+                )?;
+            }
+            repo.set_symbolic_ref("HEAD", &checkout.name)?;
+            revwalk::peel_to_commit(repo.odb(), &checkout.oid)?
+        }
+        // a tag is checked out on a detached HEAD
+        None => {
+            let commit = revwalk::peel_to_commit(repo.odb(), &checkout.oid)?;
+            let commit = commit
+                .ok_or_else(|| anyhow::anyhow!("{} does not point at a commit", checkout.name))?;
+            repo.update_ref("HEAD", &commit)?;
+            Some(commit)
+        }
+    };
+
+    let Some(work_tree) = repo.work_tree() else {
+        return Ok(());
+    };
+    let commit =
+        commit.ok_or_else(|| anyhow::anyhow!("{} does not point at a commit", checkout.name))?;
+    let tree = revwalk::read_commit(repo.odb(), &commit)?.tree;
+    // what a partial clone left out is fetched in one go rather than as
+    // each file is written
+    repo.odb()
+        .fetch_missing(&missing_blobs(repo.odb(), &tree)?)?;
+    worktree::checkout_tree(repo.odb(), &tree, work_tree, fetch_opts.progress)
+}
+
+/// The blobs `tree` and its subtrees point at that are not in `odb`.
+fn missing_blobs(odb: &ObjectDatabase, tree: &str) -> Result<Vec<String>, anyhow::Error> {
+    let mut missing = Vec::new();
+    let mut trees = vec![tree.to_string()];
+    while let Some(tree) = trees.pop() {
+        let object = odb.read(&tree)?;
+        for entry in parse_tree(&object.content, odb.format())? {
+            match entry.kind() {
+                ObjectType::Tree => trees.push(entry.hash),
+                ObjectType::Blob if !odb.contains(&entry.hash) => missing.push(entry.hash),
+                _ => {}
+            }
+        }
+    }
+    Ok(missing)
+}
+
+/// What happened to a remote ref in a push.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushStatus {
+    New,
+    UpToDate,
+    FastForward,
+    /// Updated although the old value is not an ancestor of the new one.
+    Forced,
+    Deleted,
+    /// Not sent, for the reason given.
+    Rejected(&'static str),
+    /// Refused by the remote, for the reason it gave.
+    RemoteRejected(String),
+    /// Sent, but the remote did not say whether it made the update.
+    NoReport,
+}
+
+impl PushStatus {
+    /// Whether the remote ref now holds what was pushed.
+    pub fn is_ok(&self) -> bool {
+        matches!(
+            self,
+            PushStatus::New
+                | PushStatus::UpToDate
+                | PushStatus::FastForward
+                | PushStatus::Forced
+                | PushStatus::Deleted
+        )
+    }
+}
+
+/// A remote ref a push updated, or tried to.
+#[derive(Debug, Clone)]
+pub struct PushUpdate {
+    /// What was pushed, as given or as the local ref it resolved to; `None`
+    /// for deletions.
+    pub src: Option<String>,
+    pub dst: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    pub status: PushStatus,
+}
+
+/// The refspecs a push uses when none are given: the remote's, or else
+/// those `push.default` picks, which by default push the current branch to
+/// its upstream branch of the same name.
+fn specs_to_push(repo: &Repository, remote: &Remote) -> Result<Vec<Refspec>, anyhow::Error> {
+    if !remote.push.is_empty() {
+        return Ok(remote.push.clone());
+    }
+    let config = repo.config();
+    let mode = config.get("push.default").unwrap_or("simple");
+    match mode {
+        "nothing" => anyhow::bail!(
+            "You didn't specify any refspecs to push, and push.default is \"nothing\"."
+        ),
+        "matching" => return Ok(vec![Refspec::parse_push(":")?]),
+        "current" | "simple" | "upstream" => {}
+        _ => anyhow::bail!("bad push.default value '{mode}'"),
+    }
+    let Some(branch) = current_branch(repo)? else {
+        anyhow::bail!(
+            "You are not currently on a branch.\n\
+             To push the history leading to the current (detached HEAD)\n\
+             state now, use\n\
+             \n    git push {} HEAD:<name-of-remote-branch>\n",
+            remote.name
+        );
+    };
+    let local = format!("refs/heads/{branch}");
+    let fetch_remote = config
+        .get(&format!("branch.{branch}.remote"))
+        .unwrap_or("origin");
+    // pushing somewhere other than where the branch comes from pushes it
+    // under its own name
+    if mode == "current" || (mode == "simple" && fetch_remote != remote.name) {
+        return Ok(vec![Refspec::parse(&format!("{local}:{local}"))?]);
+    }
+    let upstream = config
+        .get(&format!("branch.{branch}.merge"))
+        .filter(|_| fetch_remote == remote.name);
+    let Some(upstream) = upstream else {
+        anyhow::bail!(
+            "The current branch {branch} has no upstream branch.\n\
+             To push the current branch and set the remote as upstream, use\n\
+             \n    git push --set-upstream {} {branch}\n\
+             \n\
+             To have this happen automatically for branches without a tracking\n\
+             upstream, see 'push.autoSetupRemote' in 'git help config'.\n",
+            remote.name
+        );
+    };
+    if mode == "simple" && upstream != local {
+        anyhow::bail!(
+            "The upstream branch of your current branch does not match\n\
+             the name of your current branch.  To push to the upstream branch\n\
+             on the remote, use\n\
+             \n    git push {0} HEAD:{1}\n\
+             \n\
+             To push to the branch of the same name on the remote, use\n\
+             \n    git push {0} HEAD\n\
+             \n\
+             To choose either option permanently, see push.default in 'git help config'.\n\
+             \n\
+             To avoid automatically configuring an upstream branch when its name\n\
+             won't match the local branch, see option 'simple' of branch.autoSetupMerge\n\
+             in 'git help config'.\n",
+            remote.name,
+            short_name(upstream)
+        );
+    }
+    Ok(vec![Refspec::parse(&format!("{local}:{upstream}"))?])
+}
+
+/// A local ref, or other object, to push, and the remote ref it goes to.
+struct PushMapping {
+    src: Option<String>,
+    new: Option<String>,
+    dst: String,
+    force: bool,
+}
+
+/// The full name of the local ref `name` stands for, and what it holds.
+/// `HEAD` stands for the branch it points at, if there is one.
+fn find_local_ref(
+    repo: &Repository,
+    local_refs: &[(String, String)],
+    name: &str,
+) -> Result<Option<(String, String)>, anyhow::Error> {
+    if name == "HEAD" {
+        let target = repo.symbolic_ref("HEAD")?;
+        let oid = repo.resolve_ref("HEAD")?;
+        return Ok(oid.map(|oid| (target.unwrap_or_else(|| "HEAD".to_string()), oid)));
+    }
+    for candidate in expand_name(name) {
+        if local_refs.iter().any(|(n, _)| *n == candidate) {
+            if let Some(oid) = repo.resolve_ref(&candidate)? {
+                return Ok(Some((candidate, oid)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Match `refspecs` against the local refs and the refs the remote
+/// advertised, giving what to push where. Updates to refs the remote has
+/// come first, in the order it listed them, then new refs.
+///
+/// Every refspec that matches nothing is reported on stderr before failing,
+/// as git does.
+fn match_push_refs(
+    repo: &Repository,
+    url: &str,
+    refspecs: &[Refspec],
+    advertisement: &Advertisement,
+    force: bool,
+) -> Result<Vec<PushMapping>, anyhow::Error> {
+    let local_refs = repo
+        .refs()?
+        .into_iter()
+        .filter(|(_, value)| !value.starts_with("ref: "))
+        .collect::<Vec<_>>();
+    let mut errors = 0;
+    let mut mappings = Vec::new();
+    for spec in refspecs {
+        match match_push_refspec(repo, &local_refs, spec, advertisement, force) {
+            Ok(matched) => {
+                for mapping in matched {
+                    if !mappings.iter().any(|m: &PushMapping| m.dst == mapping.dst) {
+                        mappings.push(mapping);
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                errors += 1;
+            }
+        }
+    }
+    if errors > 0 {
+        anyhow::bail!("failed to push some refs to '{url}'");
+    }
+    let position = |dst: &str| {
+        advertisement
+            .refs
+            .iter()
+            .position(|r| r.name == dst)
+            .unwrap_or(usize::MAX)
+    };
+    mappings.sort_by_key(|m| position(&m.dst));
+    Ok(mappings)
+}
+
+/// What the one refspec `spec` pushes where.
+fn match_push_refspec(
+    repo: &Repository,
+    local_refs: &[(String, String)],
+    spec: &Refspec,
+    advertisement: &Advertisement,
+    force: bool,
+) -> Result<Vec<PushMapping>, anyhow::Error> {
+    let remote_ref = |dst: &str| {
+        expand_name(dst)
+            .into_iter()
+            .find(|name| advertisement.find(name).is_some())
+    };
+    let force = force || spec.force;
+    let mut mappings = Vec::new();
+    if spec.src.is_empty() {
+        match &spec.dst {
+            // `:` pushes the branches both sides have
+            None => {
+                for (name, oid) in local_refs {
+                    if name.starts_with("refs/heads/") && advertisement.find(name).is_some() {
+                        mappings.push(PushMapping {
+                            src: Some(name.clone()),
+                            new: Some(oid.clone()),
+                            dst: name.clone(),
+                            force,
+                        });
+                    }
+                }
+            }
+            Some(dst) => {
+                let dst = remote_ref(dst).ok_or_else(|| {
+                    anyhow::anyhow!("unable to delete '{dst}': remote ref does not exist")
+                })?;
+                mappings.push(PushMapping {
+                    src: None,
+                    new: None,
+                    dst,
+                    force,
+                });
+            }
+        }
+        return Ok(mappings);
+    }
+    if spec.is_pattern() {
+        let dst = spec.dst.as_deref().unwrap_or(&spec.src);
+        for (name, oid) in local_refs {
+            if let Some(dst) = match_pattern(&spec.src, dst, name) {
+                mappings.push(PushMapping {
+                    src: Some(name.clone()),
+                    new: Some(oid.clone()),
+                    dst,
+                    force,
+                });
+            }
+        }
+        return Ok(mappings);
+    }
+    let (src, new) = match find_local_ref(repo, local_refs, &spec.src)? {
+        Some((name, oid)) => (name, oid),
+        None => match repo.resolve(&spec.src)? {
+            Some(oid) if spec.dst.is_some() => (spec.src.clone(), oid),
+            _ => anyhow::bail!("src refspec {} does not match any", spec.src),
+        },
+    };
+    let dst = match spec.dst.as_deref() {
+        Some(dst) if dst.starts_with("refs/") => dst.to_string(),
+        Some(dst) => match remote_ref(dst) {
+            Some(name) => name,
+            None if src.starts_with("refs/heads/") => format!("refs/heads/{dst}"),
+            None if src.starts_with("refs/tags/") => format!("refs/tags/{dst}"),
+            None => anyhow::bail!(
+                "The destination you provided is not a full refname (i.e.,\n\
+                 starting with \"refs/\"). Could not guess one for '{dst}'."
+            ),
+        },
+        None if src.starts_with("refs/") => src.clone(),
+        None => anyhow::bail!("src refspec {} does not match any", spec.src),
+    };
+    mappings.push(PushMapping {
+        src: Some(src),
+        new: Some(new),
+        dst,
+        force,
+    });
+    Ok(mappings)
+}
+
+/// The remote-tracking ref `remote` keeps its ref `name` in, if any.
+fn tracking_ref(remote: &Remote, name: &str) -> Option<String> {
+    remote.fetch.iter().find_map(|spec| spec.map(name))
+}
+
+/// What a push lease expects the remote ref `dst` to hold, if a lease
+/// applies to it: `Some(None)` when it expects the ref not to exist.
+fn lease_for(
+    repo: &Repository,
+    remote: &Remote,
+    opts: &PushOptions,
+    dst: &str,
+) -> Result<Option<Option<String>>, anyhow::Error> {
+    // a lease naming the ref wins over one for every ref
+    let lease = opts
+        .leases
+        .iter()
+        .rev()
+        .find(|l| {
+            l.name
+                .as_deref()
+                .is_some_and(|name| expand_name(name).iter().any(|n| n == dst))
+        })
+        .or_else(|| opts.leases.iter().rev().find(|l| l.name.is_none()));
+    let Some(lease) = lease else {
+        return Ok(None);
+    };
+    let expect = match lease.expect.as_deref() {
+        Some("") => None,
+        Some(expect) => Some(
+            repo.resolve(expect)?
+                .ok_or_else(|| anyhow::anyhow!("cannot parse expected object name '{expect}'"))?,
+        ),
+        None => match tracking_ref(remote, dst) {
+            Some(tracking) => repo.resolve_ref(&tracking)?,
+            None => None,
+        },
+    };
+    Ok(Some(expect))
+}
+
+/// Whether the update of a remote ref from `old` to `new` has to be forced,
+/// and why: git's checks, made before anything is sent.
+fn push_rejection(
+    repo: &Repository,
+    dst: &str,
+    old: &str,
+    new: &str,
+) -> Result<Option<&'static str>, anyhow::Error> {
+    let odb = repo.odb();
+    if dst.starts_with("refs/tags/") {
+        return Ok(Some("already exists"));
+    }
+    if !odb.contains(old) {
+        return Ok(Some("fetch first"));
+    }
+    let (Some(old), Some(new)) = (
+        revwalk::peel_to_commit(odb, old)?,
+        revwalk::peel_to_commit(odb, new)?,
+    ) else {
+        return Ok(Some("needs force"));
+    };
+    if revwalk::is_ancestor(odb, &old, &new)? {
+        Ok(None)
+    } else {
+        Ok(Some("non-fast-forward"))
+    }
+}
+
+/// Whether a push sends the update to the remote.
+fn to_send(update: &PushUpdate) -> bool {
+    matches!(
+        update.status,
+        PushStatus::New | PushStatus::FastForward | PushStatus::Forced | PushStatus::Deleted
+    )
+}
+
+/// What a push does with each of `mappings`, given what the remote's refs
+/// hold: the updates git rejects before anything is sent are marked so,
+/// and so is every update of an atomic push if any one is rejected.
+fn plan_push(
+    repo: &Repository,
+    remote: &Remote,
+    advertisement: &Advertisement,
+    mappings: Vec<PushMapping>,
+    opts: &PushOptions,
+) -> Result<Vec<PushUpdate>, anyhow::Error> {
+    let mut updates = Vec::new();
+    for mapping in mappings {
+        let old = advertisement.find(&mapping.dst).map(|r| r.oid.clone());
+        let status = match (&old, &mapping.new) {
+            (_, None) if advertisement.capability("delete-refs").is_none() => {
+                PushStatus::Rejected("remote does not support deleting refs")
+            }
+            (Some(old), Some(new)) if old == new => PushStatus::UpToDate,
+            _ => {
+                let mut force = mapping.force;
+                let mut rejection = None;
+                match lease_for(repo, remote, opts, &mapping.dst)? {
+                    Some(expect) if expect != old => rejection = Some("stale info"),
+                    Some(_) => force = true,
+                    None => {
+                        if let (Some(old), Some(new)) = (&old, &mapping.new) {
+                            rejection = push_rejection(repo, &mapping.dst, old, new)?;
+                        }
+                    }
+                }
+                match (rejection, &old, &mapping.new) {
+                    (Some(reason), _, _) if !force => PushStatus::Rejected(reason),
+                    (_, _, None) => PushStatus::Deleted,
+                    (_, None, _) => PushStatus::New,
+                    (Some(_), _, _) => PushStatus::Forced,
+                    // a forced update is only shown as one if it was needed
+                    (None, Some(old), Some(new)) if force && !is_fast_forward(repo, old, new)? => {
+                        PushStatus::Forced
+                    }
+                    (None, _, _) => PushStatus::FastForward,
+                }
+            }
+        };
+        updates.push(PushUpdate {
+            src: mapping.src,
+            dst: mapping.dst,
+            old,
+            new: mapping.new,
+            status,
+        });
+    }
+
+    let rejected = updates
+        .iter()
+        .any(|u| matches!(u.status, PushStatus::Rejected(_)));
+    if opts.atomic && rejected {
+        for update in updates.iter_mut().filter(|u| to_send(u)) {
+            update.status = PushStatus::Rejected("atomic push failed");
+        }
+    }
+    Ok(updates)
+}
+
+/// Push `refspecs` to `remote`, or what [`specs_to_push`] picks when none
+/// are given, and update the remote-tracking refs of what was pushed.
+///
+/// Updates that are not fast-forwards, or that a lease does not allow, are
+/// rejected before anything is sent, unless forced; so are all updates of
+/// an atomic push if any one is. The returned [`PushUpdate`]s say what
+/// happened to each remote ref.
+pub fn push(
+    repo: &Repository,
+    remote: &Remote,
+    refspecs: &[Refspec],
+    opts: &PushOptions,
+) -> Result<Vec<PushUpdate>, anyhow::Error> {
+    let odb = repo.odb();
+    let advertisement = transport::receive_pack_refs(&remote.url)?;
+    let mut specs = if refspecs.is_empty() && !opts.tags {
+        specs_to_push(repo, remote)?
+    } else {
+        refspecs.to_vec()
+    };
+    if opts.tags {
+        specs.push(Refspec::parse("refs/tags/*:refs/tags/*")?);
+    }
+    let mappings = match_push_refs(repo, &remote.url, &specs, &advertisement, opts.force)?;
+
+    let mut updates = plan_push(repo, remote, &advertisement, mappings, opts)?;
+    let null = odb.format().null_id();
+    let commands = updates
+        .iter()
+        .filter(|u| to_send(u))
+        .map(|u| PushCommand {
+            name: u.dst.clone(),
+            old: u.old.clone().unwrap_or_else(|| null.clone()),
+            new: u.new.clone().unwrap_or_else(|| null.clone()),
+        })
+        .collect::<Vec<_>>();
+    if commands.is_empty() {
+        return Ok(updates);
+    }
+
+    // the remote has whatever its refs reach that we know of
+    let tips = commands
+        .iter()
+        .filter(|c| c.new != null)
+        .map(|c| c.new.clone())
+        .collect::<Vec<_>>();
+    let exclude = advertisement
+        .refs
+        .iter()
+        .map(|r| r.oid.clone())
+        .collect::<Vec<_>>();
+    let objects = revwalk::list_objects(odb, &tips, &exclude)?;
+    if opts.progress && !objects.is_empty() {
+        let mut meter = Progress::new("Enumerating objects", None, true);
+        meter.set(objects.len() as u64);
+        meter.finish();
+    }
+    let reports =
+        transport::send_pack(odb, &remote.url, &advertisement, &commands, &objects, opts)?;
+    for update in updates.iter_mut().filter(|u| to_send(u)) {
+        match reports.iter().find(|(name, _)| *name == update.dst) {
+            Some((_, None)) => {}
+            Some((_, Some(reason))) => update.status = PushStatus::RemoteRejected(reason.clone()),
+            None => update.status = PushStatus::NoReport,
+        }
+    }
+
+    for update in updates.iter().filter(|u| u.status.is_ok()) {
+        let Some(tracking) = tracking_ref(remote, &update.dst) else {
+            continue;
+        };
+        match &update.new {
+            Some(new) => repo.update_ref(&tracking, new)?,
+            None => repo.delete_ref(&tracking)?,
+        }
+    }
+    Ok(updates)
+}
+
+/// `url` the way git shows it, without a trailing `.git`.
+pub fn display_url(url: &str) -> &str {
+    let url = url.trim_end_matches('/');
+    url.strip_suffix(".git").unwrap_or(url)
+}
+
+/// A ref name without the `refs/heads/`, `refs/tags/` or `refs/remotes/`
+/// it starts with.
+fn short_name(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+/// Report `updates` on stderr the way git does, under a `From <url>` line.
+/// Refs that were already up to date are left out.
+pub fn print_updates(url: &str, updates: &[RefUpdate]) {
+    let shown = updates
+        .iter()
+        .filter(|u| u.status != UpdateStatus::UpToDate)
+        .collect::<Vec<_>>();
+    if shown.is_empty() {
+        return;
+    }
+    let abbrev = |oid: &Option<String>| {
+        oid.as_deref()
+            .map(|oid| oid[..7.min(oid.len())].to_string())
+            .unwrap_or_default()
+    };
+    fn remote_name(update: &RefUpdate) -> &str {
+        update.remote.as_deref().map_or("(none)", short_name)
+    }
+    let width = shown
+        .iter()
+        .map(|u| remote_name(u).len())
+        .max()
+        .unwrap_or(0)
+        .max(10);
+    let mut out = format!("From {}\n", display_url(url));
+    for update in shown {
+        let remote = update.remote.as_deref().unwrap_or_default();
+        let (code, summary, reason) = match update.status {
+            UpdateStatus::New if remote.starts_with("refs/tags/") => {
+                ('*', "[new tag]".to_string(), None)
+            }
+            UpdateStatus::New if remote.starts_with("refs/heads/") => {
+                ('*', "[new branch]".to_string(), None)
+            }
+            UpdateStatus::New => ('*', "[new ref]".to_string(), None),
+            UpdateStatus::FastForward => (
+                ' ',
+                format!("{}..{}", abbrev(&update.old), abbrev(&update.new)),
+                None,
+            ),
+            UpdateStatus::Forced if update.local.starts_with("refs/tags/") => {
+                ('t', "[tag update]".to_string(), None)
+            }
+            UpdateStatus::Forced => (
+                '+',
+                format!("{}...{}", abbrev(&update.old), abbrev(&update.new)),
+                Some("forced update"),
+            ),
+            UpdateStatus::Rejected(reason) => ('!', "[rejected]".to_string(), Some(reason)),
+            UpdateStatus::Deleted => ('-', "[deleted]".to_string(), None),
+            UpdateStatus::FetchHead if remote.starts_with("refs/tags/") => {
+                ('*', "tag".to_string(), None)
+            }
+            UpdateStatus::FetchHead if remote.starts_with("refs/remotes/") => {
+                ('*', "remote-tracking branch".to_string(), None)
+            }
+            UpdateStatus::FetchHead => ('*', "branch".to_string(), None),
+            UpdateStatus::UpToDate => continue,
+        };
+        let _ = write!(
+            out,
+            " {code} {summary:<17} {:<width$} -> {}",
+            remote_name(update),
+            short_name(&update.local)
+        );
+        if let Some(reason) = reason {
+            let _ = write!(out, "  ({reason})");
+        }
+        out.push('\n');
+    }
+    eprint!("{out}");
+}
+
+/// The advice git gives after a push that `updates` were rejected in, if
+/// any, as lines to prefix with `hint: `. `head` is the branch `HEAD`
+/// points at, whose being behind gets its own advice.
+pub fn push_advice(updates: &[PushUpdate], head: Option<&str>) -> Option<&'static str> {
+    let rejected = |reason: &'static str| {
+        updates
+            .iter()
+            .filter(|u| u.status == PushStatus::Rejected(reason))
+            .map(|u| Some(u.dst.as_str()) == head)
+            .collect::<Vec<_>>()
+    };
+    let non_ff = rejected("non-fast-forward");
+    if non_ff.contains(&true) {
+        Some(
+            "Updates were rejected because the tip of your current branch is behind\n\
+             its remote counterpart. Integrate the remote changes (e.g.\n\
+             'git pull ...') before pushing again.\n\
+             See the 'Note about fast-forwards' in 'git push --help' for details.",
+        )
+    } else if !non_ff.is_empty() {
+        Some(
+            "Updates were rejected because a pushed branch tip is behind its remote\n\
+             counterpart. Check out this branch and integrate the remote changes\n\
+             (e.g. 'git pull ...') before pushing again.\n\
+             See the 'Note about fast-forwards' in 'git push --help' for details.",
+        )
+    } else if !rejected("already exists").is_empty() {
+        Some("Updates were rejected because the tag already exists in the remote.")
+    } else if !rejected("fetch first").is_empty() {
+        Some(
+            "Updates were rejected because the remote contains work that you do\n\
+             not have locally. This is usually caused by another repository pushing\n\
+             to the same ref. You may want to first integrate the remote changes\n\
+             (e.g., 'git pull ...') before pushing again.\n\
+             See the 'Note about fast-forwards' in 'git push --help' for details.",
+        )
+    } else if !rejected("needs force").is_empty() {
+        Some(
+            "You cannot update a remote ref that points at a non-commit object,\n\
+             or update a remote ref to make it point at a non-commit object,\n\
+             without using the '--force' option.",
+        )
+    } else {
+        None
+    }
+}
+
+/// Report `updates` on stderr the way git does, under a `To <url>` line:
+/// the refs that were updated, then those that were not. Refs that were
+/// already up to date are left out.
+pub fn print_push_updates(url: &str, updates: &[PushUpdate]) {
+    let abbrev = |oid: &Option<String>| {
+        oid.as_deref()
+            .map(|oid| oid[..7.min(oid.len())].to_string())
+            .unwrap_or_default()
+    };
+    let (updated, failed): (Vec<_>, Vec<_>) = updates
+        .iter()
+        .filter(|u| u.status != PushStatus::UpToDate)
+        .partition(|u| u.status.is_ok());
+    if updated.is_empty() && failed.is_empty() {
+        return;
+    }
+    let mut out = format!("To {url}\n");
+    for update in updated.into_iter().chain(failed) {
+        let (code, summary, reason) = match &update.status {
+            PushStatus::New if update.dst.starts_with("refs/tags/") => {
+                ('*', "[new tag]".to_string(), None)
+            }
+            PushStatus::New if update.dst.starts_with("refs/heads/") => {
+                ('*', "[new branch]".to_string(), None)
+            }
+            PushStatus::New => ('*', "[new reference]".to_string(), None),
+            PushStatus::FastForward => (
+                ' ',
+                format!("{}..{}", abbrev(&update.old), abbrev(&update.new)),
+                None,
+            ),
+            PushStatus::Forced => (
+                '+',
+                format!("{}...{}", abbrev(&update.old), abbrev(&update.new)),
+                Some("forced update"),
+            ),
+            PushStatus::Deleted => ('-', "[deleted]".to_string(), None),
+            PushStatus::Rejected(reason) => ('!', "[rejected]".to_string(), Some(*reason)),
+            PushStatus::RemoteRejected(reason) => {
+                ('!', "[remote rejected]".to_string(), Some(reason.as_str()))
+            }
+            PushStatus::NoReport => (
+                '!',
+                "[remote failure]".to_string(),
+                Some("remote failed to report status"),
+            ),
+            PushStatus::UpToDate => continue,
+        };
+        let _ = write!(out, " {code} {summary:<17} ");
+        match &update.src {
+            Some(src) => {
+                let _ = write!(out, "{} -> {}", short_name(src), short_name(&update.dst));
+            }
+            None => out.push_str(short_name(&update.dst)),
+        }
+        if let Some(reason) = reason {
+            let _ = write!(out, " ({reason})");
+        }
+        out.push('\n');
+    }
+    eprint!("{out}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::InitOptions;
+    use crate::transport::{Lease, ProtocolVersion};
+
+    /// An advertisement of `refs`, given as name and the character their
+    /// made-up object id repeats.
+    fn advertise(refs: &[(&str, char)]) -> Advertisement {
+        Advertisement {
+            version: ProtocolVersion::V0,
+            refs: refs
+                .iter()
+                .map(|&(name, oid)| RemoteRef {
+                    name: name.to_string(),
+                    oid: oid.to_string().repeat(40),
+                    symref_target: None,
+                    peeled: None,
+                })
+                .collect(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// A bare repository with an `origin` remote, removed on drop, holding
+    /// commits `a`, then `b` on top of it and `d` beside it.
+    struct PushRepo {
+        repo: Repository,
+        remote: Remote,
+        a: String,
+        b: String,
+        d: String,
+    }
+
+    impl PushRepo {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "codecrafters-git-remote-{}-{name}",
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            let opts = InitOptions {
+                bare: true,
+                ..Default::default()
+            };
+            let repo = Repository::init_with(&dir, &opts).unwrap();
+            config::append_section(
+                &dir.join("config"),
+                "remote",
+                Some("origin"),
+                &[
+                    ("url", "https://example.com/repo.git"),
+                    ("fetch", "+refs/heads/*:refs/remotes/origin/*"),
+                ],
+            )
+            .unwrap();
+            let remote = Remote::get(&Config::load(&dir).unwrap(), "origin").unwrap();
+            let tree = repo.odb().write("tree", b"").unwrap();
+            let commit = |parent: Option<&str>, message: &str| {
+                let mut data = format!("tree {tree}\n");
+                if let Some(parent) = parent {
+                    data += &format!("parent {parent}\n");
+                }
+                let ident = "A U Thor <author@example.com> 1000 +0000";
+                data += &format!("author {ident}\ncommitter {ident}\n\n{message}\n");
+                repo.odb().write("commit", data.as_bytes()).unwrap()
+            };
+            let a = commit(None, "a");
+            let b = commit(Some(&a), "b");
+            let d = commit(Some(&a), "d");
+            PushRepo {
+                repo,
+                remote,
+                a,
+                b,
+                d,
+            }
+        }
+    }
+
+    impl Drop for PushRepo {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.repo.git_dir());
+        }
+    }
+
+    #[test]
+    fn parses_refspecs() {
+        let spec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert!(spec.force);
+        assert_eq!(spec.src, "refs/heads/*");
+        assert_eq!(spec.dst.as_deref(), Some("refs/remotes/origin/*"));
+        assert!(spec.is_pattern());
+
+        let spec = Refspec::parse("main").unwrap();
+        assert!(!spec.force);
+        assert_eq!(spec.dst, None);
+        // an empty destination is the same as none
+        assert_eq!(Refspec::parse("main:").unwrap(), spec);
+
+        for bad in ["", ":dst", "refs/heads/*", "a*:b", "a:b*", "a*b*:c*", "+"] {
+            assert!(Refspec::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_push_refspecs() {
+        let delete = Refspec::parse_push(":refs/heads/topic").unwrap();
+        assert_eq!(delete.src, "");
+        assert_eq!(delete.dst.as_deref(), Some("refs/heads/topic"));
+
+        let matching = Refspec::parse_push("+:").unwrap();
+        assert!(matching.force);
+        assert_eq!((matching.src.as_str(), matching.dst), ("", None));
+
+        assert!(Refspec::parse_push(":refs/heads/*").is_err());
+        assert_eq!(
+            Refspec::parse_push("main:topic").unwrap(),
+            Refspec::parse("main:topic").unwrap()
+        );
+    }
+
+    #[test]
+    fn maps_remote_refs() {
+        let spec = Refspec::parse("refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert_eq!(
+            spec.map("refs/heads/feature/x").as_deref(),
+            Some("refs/remotes/origin/feature/x")
+        );
+        assert_eq!(spec.map("refs/tags/v1"), None);
+        // `*` matches at least one character
+        assert_eq!(spec.map("refs/heads/"), None);
+
+        let spec = Refspec::parse("refs/*/v1:refs/kept/*").unwrap();
+        assert_eq!(spec.map("refs/tags/v1").as_deref(), Some("refs/kept/tags"));
+
+        // short names expand as git would look them up
+        let spec = Refspec::parse("main:topic").unwrap();
+        assert_eq!(
+            spec.map("refs/heads/main").as_deref(),
+            Some("refs/heads/topic")
+        );
+        assert_eq!(spec.map("refs/heads/other"), None);
+        let spec = Refspec::parse("v1:tags/v1").unwrap();
+        assert_eq!(spec.map("refs/tags/v1").as_deref(), Some("refs/tags/v1"));
+
+        assert_eq!(Refspec::parse("main").unwrap().map("refs/heads/main"), None);
+    }
+
+    #[test]
+    fn remote_head_prefers_the_symref_then_master() {
+        let mut advertisement = advertise(&[
+            ("HEAD", 'a'),
+            ("refs/heads/develop", 'a'),
+            ("refs/heads/main", 'a'),
+            ("refs/heads/master", 'a'),
+        ]);
+        let head =
+            |advertisement: &Advertisement| remote_head(advertisement).map(|r| r.name.clone());
+        assert_eq!(head(&advertisement).as_deref(), Some("refs/heads/master"));
+
+        advertisement.refs[0].symref_target = Some("refs/heads/main".to_string());
+        assert_eq!(head(&advertisement).as_deref(), Some("refs/heads/main"));
+
+        // otherwise the first branch at the same commit
+        let advertisement = advertise(&[
+            ("HEAD", 'a'),
+            ("refs/heads/develop", 'b'),
+            ("refs/heads/main", 'a'),
+            ("refs/heads/master", 'b'),
+            ("refs/heads/topic", 'a'),
+        ]);
+        assert_eq!(head(&advertisement).as_deref(), Some("refs/heads/main"));
+
+        let advertisement = advertise(&[("HEAD", 'a'), ("refs/heads/main", 'b')]);
+        assert_eq!(head(&advertisement), None);
+        assert_eq!(head(&advertise(&[("refs/heads/main", 'b')])), None);
+    }
+
+    #[test]
+    fn clone_dirs_come_from_the_url() {
+        let dir = |url, bare| clone_dir(url, bare).unwrap();
+        assert_eq!(
+            dir("https://example.com/git/repo.git", false),
+            PathBuf::from("repo")
+        );
+        assert_eq!(
+            dir("https://example.com/git/repo/", false),
+            PathBuf::from("repo")
+        );
+        assert_eq!(
+            dir("https://example.com/repo", true),
+            PathBuf::from("repo.git")
+        );
+        assert_eq!(dir("host:repo.git", true), PathBuf::from("repo.git"));
+        assert!(clone_dir("https://example.com/.git", false).is_err());
+    }
+
+    #[test]
+    fn clone_refspecs_and_tags() {
+        let advertisement = advertise(&[("refs/heads/main", 'a')]);
+        let main = advertisement.find("refs/heads/main");
+        let spec = |opts: &CloneOptions, bare, tags| clone_refspec(opts, main, bare, tags);
+        let plain = CloneOptions::default();
+        assert_eq!(
+            spec(&plain, false, TagMode::Follow),
+            (
+                "+refs/heads/*:refs/remotes/origin/*".to_string(),
+                TagMode::All
+            )
+        );
+        assert_eq!(
+            spec(&plain, true, TagMode::Follow),
+            ("+refs/heads/*:refs/heads/*".to_string(), TagMode::All)
+        );
+        assert_eq!(spec(&plain, false, TagMode::None).1, TagMode::None);
+
+        let mirror = CloneOptions {
+            mirror: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            spec(&mirror, true, TagMode::Follow),
+            ("+refs/*:refs/*".to_string(), TagMode::None)
+        );
+
+        let single = CloneOptions {
+            single_branch: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            spec(&single, false, TagMode::Follow),
+            (
+                "+refs/heads/main:refs/remotes/origin/main".to_string(),
+                TagMode::Follow
+            )
+        );
+        assert_eq!(
+            spec(&single, true, TagMode::Follow),
+            (
+                "+refs/heads/main:refs/heads/main".to_string(),
+                TagMode::Follow
+            )
+        );
+        assert_eq!(spec(&single, false, TagMode::None).1, TagMode::None);
+        // a single-branch clone of an empty repository has no branch to
+        // narrow down to
+        assert_eq!(
+            clone_refspec(&single, None, false, TagMode::Follow).0,
+            "+refs/heads/*:refs/remotes/origin/*"
+        );
+    }
+
+    #[test]
+    fn unshallow_lines_leave_the_shallow_list() {
+        let dir = std::env::temp_dir().join(format!(
+            "codecrafters-git-remote-{}-shallow",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let opts = InitOptions {
+            bare: true,
+            ..Default::default()
+        };
+        let repo = Repository::init_with(&dir, &opts).unwrap();
+        let oid = |c: char| c.to_string().repeat(40);
+        repo.set_shallow(&[oid('a'), oid('b')]).unwrap();
+
+        let mut response = FetchResponse {
+            pack: String::new(),
+            shallow: vec![oid('c')],
+            unshallow: vec![oid('a')],
+            wanted_refs: Vec::new(),
+        };
+        update_shallow(&repo, &response).unwrap();
+        assert_eq!(repo.odb().shallow(), [oid('b'), oid('c')]);
+        let file = std::fs::read_to_string(dir.join("shallow")).unwrap();
+        assert_eq!(file, format!("{}\n{}\n", oid('b'), oid('c')));
+
+        // deepening all the way leaves nothing shallow
+        response.shallow.clear();
+        response.unshallow = vec![oid('b'), oid('c')];
+        update_shallow(&repo, &response).unwrap();
+        assert!(!repo.is_shallow());
+        assert!(!dir.join("shallow").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn push_rejections() {
+        let t = PushRepo::new("push-rejection");
+        let rejection =
+            |dst: &str, old: &str, new: &str| push_rejection(&t.repo, dst, old, new).unwrap();
+        assert_eq!(rejection("refs/heads/main", &t.a, &t.b), None);
+        assert_eq!(
+            rejection("refs/heads/main", &t.b, &t.d),
+            Some("non-fast-forward")
+        );
+        // what the remote has is not known here
+        let unknown = "e".repeat(40);
+        assert_eq!(
+            rejection("refs/heads/main", &unknown, &t.b),
+            Some("fetch first")
+        );
+        assert_eq!(
+            rejection("refs/tags/v1", &t.a, &t.b),
+            Some("already exists")
+        );
+        let blob = t.repo.odb().write("blob", b"hello\n").unwrap();
+        assert_eq!(
+            rejection("refs/heads/main", &blob, &t.b),
+            Some("needs force")
+        );
+    }
+
+    #[test]
+    fn push_leases() {
+        let t = PushRepo::new("push-lease");
+        t.repo
+            .update_ref("refs/remotes/origin/topic", &t.a)
+            .unwrap();
+        let lease = |name: Option<&str>, expect: Option<&str>| Lease {
+            name: name.map(str::to_string),
+            expect: expect.map(str::to_string),
+        };
+        let lease_for = |leases: Vec<Lease>, dst: &str| {
+            let opts = PushOptions {
+                leases,
+                ..Default::default()
+            };
+            lease_for(&t.repo, &t.remote, &opts, dst)
+        };
+
+        // an empty expectation is for the ref not to exist
+        let leases = vec![lease(Some("main"), Some(""))];
+        assert_eq!(
+            lease_for(leases.clone(), "refs/heads/main").unwrap(),
+            Some(None)
+        );
+        assert_eq!(lease_for(leases, "refs/heads/topic").unwrap(), None);
+
+        // with no expectation, the remote-tracking ref says what to expect
+        let leases = vec![lease(None, None)];
+        assert_eq!(
+            lease_for(leases.clone(), "refs/heads/topic").unwrap(),
+            Some(Some(t.a.clone()))
+        );
+        assert_eq!(
+            lease_for(leases.clone(), "refs/heads/main").unwrap(),
+            Some(None)
+        );
+
+        // a lease naming the ref wins over one for every ref
+        let leases = vec![lease(Some("topic"), Some(&t.b)), lease(None, None)];
+        assert_eq!(
+            lease_for(leases, "refs/heads/topic").unwrap(),
+            Some(Some(t.b.clone()))
+        );
+        assert!(lease_for(vec![lease(None, Some("nowhere"))], "refs/heads/main").is_err());
+    }
+
+    #[test]
+    fn plans_pushes() {
+        let t = PushRepo::new("push-plan");
+        let unknown = "e".repeat(40);
+        let advertisement = Advertisement {
+            version: ProtocolVersion::V0,
+            refs: [
+                ("refs/heads/ff", &t.a),
+                ("refs/heads/diverged", &t.b),
+                ("refs/heads/forced", &t.b),
+                ("refs/heads/unknown", &unknown),
+                ("refs/tags/v1", &t.a),
+                ("refs/heads/same", &t.b),
+                ("refs/heads/stale", &t.a),
+                ("refs/heads/leased", &t.b),
+                ("refs/heads/gone", &t.a),
+            ]
+            .into_iter()
+            .map(|(name, oid)| RemoteRef {
+                name: name.to_string(),
+                oid: oid.clone(),
+                symref_target: None,
+                peeled: None,
+            })
+            .collect(),
+            capabilities: Vec::new(),
+        };
+        let mappings = || {
+            [
+                ("refs/heads/ff", Some(&t.b), false),
+                ("refs/heads/diverged", Some(&t.d), false),
+                ("refs/heads/forced", Some(&t.d), true),
+                ("refs/heads/unknown", Some(&t.b), false),
+                ("refs/tags/v1", Some(&t.b), false),
+                ("refs/heads/same", Some(&t.b), false),
+                ("refs/heads/new", Some(&t.b), false),
+                ("refs/heads/stale", Some(&t.d), false),
+                ("refs/heads/leased", Some(&t.d), false),
+                ("refs/heads/gone", None, false),
+            ]
+            .into_iter()
+            .map(|(dst, new, force)| PushMapping {
+                src: new.map(|_| "refs/heads/main".to_string()),
+                new: new.cloned(),
+                dst: dst.to_string(),
+                force,
+            })
+            .collect::<Vec<_>>()
+        };
+        let mut opts = PushOptions {
+            leases: vec![
+                Lease {
+                    name: Some("stale".to_string()),
+                    expect: Some(t.b.clone()),
+                },
+                Lease {
+                    name: Some("leased".to_string()),
+                    expect: Some(t.b.clone()),
+                },
+            ],
+            ..Default::default()
+        };
+        let plan = |opts: &PushOptions| {
+            plan_push(&t.repo, &t.remote, &advertisement, mappings(), opts)
+                .unwrap()
+                .into_iter()
+                .map(|u| u.status)
+                .collect::<Vec<_>>()
+        };
+        let cannot_delete = PushStatus::Rejected("remote does not support deleting refs");
+        assert_eq!(
+            plan(&opts),
+            [
+                PushStatus::FastForward,
+                PushStatus::Rejected("non-fast-forward"),
+                PushStatus::Forced,
+                PushStatus::Rejected("fetch first"),
+                PushStatus::Rejected("already exists"),
+                PushStatus::UpToDate,
+                PushStatus::New,
+                PushStatus::Rejected("stale info"),
+                PushStatus::Forced,
+                cannot_delete.clone(),
+            ]
+        );
+
+        // one rejection turns away every update of an atomic push
+        opts.atomic = true;
+        let failed = PushStatus::Rejected("atomic push failed");
+        assert_eq!(
+            plan(&opts),
+            [
+                failed.clone(),
+                PushStatus::Rejected("non-fast-forward"),
+                failed.clone(),
+                PushStatus::Rejected("fetch first"),
+                PushStatus::Rejected("already exists"),
+                PushStatus::UpToDate,
+                failed.clone(),
+                PushStatus::Rejected("stale info"),
+                failed,
+                cannot_delete,
+            ]
+        );
+        // and without any, the push goes ahead
+        let mut accepted = mappings();
+        accepted.retain(|m| {
+            ["refs/heads/ff", "refs/heads/same", "refs/heads/new"].contains(&m.dst.as_str())
+        });
+        let statuses = plan_push(&t.repo, &t.remote, &advertisement, accepted, &opts)
+            .unwrap()
+            .into_iter()
+            .map(|u| u.status)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                PushStatus::FastForward,
+                PushStatus::UpToDate,
+                PushStatus::New
+            ]
+        );
+    }
+}
@@ -0,0 +1,763 @@
+//! A repository: its git directory, work tree, config and object database.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::index::Index;
+use crate::object::ObjectFormat;
+use crate::odb::ObjectDatabase;
+use crate::{refs, shallow};
+
+pub struct Repository {
+    git_dir: PathBuf,
+    work_tree: Option<PathBuf>,
+    config: Config,
+    odb: ObjectDatabase,
+}
+
+/// Where [`Repository::discover_with`] looks for a repository.
+#[derive(Default, Clone)]
+pub struct DiscoverOptions {
+    /// Use this git directory instead of searching for one.
+    pub git_dir: Option<PathBuf>,
+    /// Use this work tree instead of the one that comes with the git
+    /// directory.
+    pub work_tree: Option<PathBuf>,
+    /// Never search these directories or anything above them.
+    pub ceiling_dirs: Vec<PathBuf>,
+}
+
+impl DiscoverOptions {
+    /// Options from `GIT_DIR`, `GIT_WORK_TREE` and `GIT_CEILING_DIRECTORIES`.
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+        DiscoverOptions {
+            git_dir: var("GIT_DIR").map(PathBuf::from),
+            work_tree: var("GIT_WORK_TREE").map(PathBuf::from),
+            // git ignores relative entries
+            ceiling_dirs: var("GIT_CEILING_DIRECTORIES")
+                .map(|dirs| {
+                    std::env::split_paths(&dirs)
+                        .filter(|dir| dir.is_absolute())
+                        .map(|dir| normalize(&dir))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// How [`Repository::init_with`] sets up a repository.
+#[derive(Default, Clone)]
+pub struct InitOptions {
+    /// Make the directory itself the git directory, with no work tree.
+    pub bare: bool,
+    /// The branch `HEAD` points at, instead of `init.defaultBranch` or
+    /// `main`.
+    pub initial_branch: Option<String>,
+    /// Copy files from this directory into the new git directory instead of
+    /// from `GIT_TEMPLATE_DIR`, `init.templateDir` or the built-in template.
+    /// An empty path copies nothing.
+    pub template: Option<PathBuf>,
+    /// The hash function of a new repository, SHA-1 if not given. An
+    /// existing repository keeps its own, and re-initializing it with a
+    /// different one is an error.
+    pub object_format: Option<ObjectFormat>,
+    pub shared: SharedRepository,
+}
+
+/// Who may write to a repository besides its owner
+/// (`core.sharedRepository`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SharedRepository {
+    /// Permissions follow the umask.
+    #[default]
+    Umask,
+    /// The group may write.
+    Group,
+    /// The group may write and everybody may read.
+    Everybody,
+    /// Files get exactly these permission bits.
+    Mode(u32),
+}
+
+impl SharedRepository {
+    /// Parse a `--shared` or `core.sharedRepository` value.
+    pub fn parse(value: &str) -> Result<Self, anyhow::Error> {
+        Ok(match value {
+            "umask" | "false" | "no" | "off" | "0" => SharedRepository::Umask,
+            "group" | "true" | "yes" | "on" | "1" => SharedRepository::Group,
+            "all" | "world" | "everybody" | "2" => SharedRepository::Everybody,
+            _ => {
+                let mode = u32::from_str_radix(value, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or_else(|| anyhow::anyhow!("invalid shared repository value {value:?}"))?;
+                if mode & 0o600 != 0o600 {
+                    anyhow::bail!(
+                        "problem with shared repository mode {mode:03o}: the owner of files \
+                         must always have read and write permissions"
+                    );
+                }
+                SharedRepository::Mode(mode)
+            }
+        })
+    }
+
+    fn config_value(&self) -> String {
+        match self {
+            SharedRepository::Umask => "0".to_string(),
+            SharedRepository::Group => "1".to_string(),
+            SharedRepository::Everybody => "2".to_string(),
+            SharedRepository::Mode(mode) => format!("0{mode:o}"),
+        }
+    }
+}
+
+/// What `git init` puts in a repository when there is no template directory.
+const DEFAULT_TEMPLATE: [(&str, &str); 2] = [
+    (
+        "description",
+        "Unnamed repository; edit this file 'description' to name the repository.\n",
+    ),
+    (
+        "info/exclude",
+        "# git ls-files --others --exclude-from=.git/info/exclude\n\
+         # Lines that start with '#' are comments.\n\
+         # For a project mostly in C, the following would be a good set of\n\
+         # exclude patterns (uncomment them if you want to use them):\n\
+         # *.[oa]\n\
+         # *~\n",
+    ),
+];
+
+/// Copy the files of `template` into `git_dir`, keeping any that are
+/// already there. A missing template directory is not an error, as in git.
+fn copy_template(template: &Path, git_dir: &Path) -> Result<(), anyhow::Error> {
+    let Ok(entries) = fs::read_dir(template) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let entry = entry?;
+        let target = git_dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&target).context(format!("create {:?}", target))?;
+            copy_template(&entry.path(), &target)?;
+        } else if !target.exists() {
+            fs::copy(entry.path(), &target).context(format!("copy to {:?}", target))?;
+        }
+    }
+    Ok(())
+}
+
+/// Give the group (and for [`SharedRepository::Everybody`] everyone) access
+/// to everything under `path`, and make directories setgid so new files
+/// inherit their group.
+#[cfg(unix)]
+fn adjust_shared_perm(path: &Path, shared: SharedRepository) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tweak = match shared {
+        SharedRepository::Umask => return Ok(()),
+        SharedRepository::Group => 0o660,
+        SharedRepository::Everybody => 0o664,
+        SharedRepository::Mode(mode) => mode,
+    };
+    let meta = fs::symlink_metadata(path).context(format!("stat {:?}", path))?;
+    if meta.is_symlink() {
+        return Ok(());
+    }
+    let mut mode = meta.permissions().mode();
+    mode = match shared {
+        SharedRepository::Mode(_) => (mode & !0o777) | tweak,
+        _ => mode | tweak,
+    };
+    if meta.is_dir() {
+        // directories are searchable wherever they are readable
+        mode |= (mode & 0o444) >> 2 | 0o2000;
+        for entry in fs::read_dir(path).context(format!("read {:?}", path))? {
+            adjust_shared_perm(&entry?.path(), shared)?;
+        }
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).context(format!("chmod {:?}", path))
+}
+
+#[cfg(not(unix))]
+fn adjust_shared_perm(_path: &Path, _shared: SharedRepository) -> Result<(), anyhow::Error> {
+    Ok(())
+}
+
+/// The object format of a repository with `config`, refusing repository
+/// formats and extensions we do not understand, as git does.
+fn object_format(config: &Config) -> Result<ObjectFormat, anyhow::Error> {
+    let version = match config.get("core.repositoryFormatVersion") {
+        Some(v) => v
+            .parse::<u32>()
+            .map_err(|_| anyhow::anyhow!("bad core.repositoryFormatVersion {v:?}"))?,
+        None => 0,
+    };
+    match version {
+        // version 0 repositories predate extensions and ignore them
+        0 => return Ok(ObjectFormat::Sha1),
+        1 => {}
+        _ => anyhow::bail!("expected git repo version <= 1, found {version}"),
+    }
+    for key in config.keys() {
+        if let Some(extension) = key.strip_prefix("extensions.") {
+            if !matches!(
+                extension,
+                "noop" | "objectformat" | "preciousobjects" | "partialclone"
+            ) {
+                anyhow::bail!("unknown repository extension found: {extension}");
+            }
+        }
+    }
+    match config.get("extensions.objectFormat") {
+        Some(name) => ObjectFormat::parse(&name.to_ascii_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("unknown object format '{name}'")),
+        None => Ok(ObjectFormat::Sha1),
+    }
+}
+
+/// The URL of the remote a partial clone fetches missing objects from: the
+/// one `extensions.partialClone` names, or else the first with
+/// `remote.<name>.promisor` set.
+fn promisor_url(config: &Config) -> Option<String> {
+    let name = match config.get("extensions.partialClone") {
+        Some(name) => name.to_string(),
+        None => config.keys().find_map(|key| {
+            let name = key.strip_prefix("remote.")?.strip_suffix(".promisor")?;
+            config.get_bool(key).ok()??.then(|| name.to_string())
+        })?,
+    };
+    config
+        .get(&format!("remote.{name}.url"))
+        .map(str::to_string)
+}
+
+/// Whether `dir` looks like a git directory.
+fn is_git_dir(dir: &Path) -> bool {
+    dir.join("HEAD").is_file() && dir.join("objects").is_dir() && dir.join("refs").is_dir()
+}
+
+/// Follow a `.git` file of the form `gitdir: <path>`, as left behind by
+/// `git worktree` and submodules, to the git directory it names.
+fn read_gitfile(path: &Path) -> Result<PathBuf, anyhow::Error> {
+    let content = fs::read_to_string(path).context(format!("read {:?}", path))?;
+    let target = content
+        .strip_prefix("gitdir:")
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("invalid gitfile format: {:?}", path))?;
+    let git_dir = path.parent().unwrap_or(Path::new("")).join(target);
+    if !is_git_dir(&git_dir) {
+        anyhow::bail!("not a git repository: {:?}", git_dir);
+    }
+    Ok(normalize(&git_dir))
+}
+
+/// Resolve `.` and `..` in an absolute path without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl Repository {
+    /// Open the repository at `path`: a work tree with a `.git` directory, or
+    /// a git directory itself (in which case there is no work tree).
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let path = normalize(&std::path::absolute(path)?);
+        let dot_git = path.join(".git");
+        if is_git_dir(&dot_git) {
+            Self::from_parts(dot_git, None, Some(path))
+        } else if is_git_dir(&path) {
+            Self::from_parts(path, None, None)
+        } else {
+            anyhow::bail!("not a git repository: {:?}", path)
+        }
+    }
+
+    /// Open the repository containing `start`, looking in it and each of its
+    /// parents for a `.git` directory or file, or for a bare repository.
+    pub fn discover(start: &Path) -> Result<Self, anyhow::Error> {
+        Self::discover_with(start, &DiscoverOptions::default())
+    }
+
+    /// Like [`Repository::discover`], but honouring `opts`. A `git_dir` or
+    /// `work_tree` relative path is taken relative to `start`.
+    ///
+    /// With an explicit `git_dir` and no `work_tree`, the work tree is
+    /// `core.worktree` if set, none if `core.bare` is set, and `start`
+    /// otherwise.
+    pub fn discover_with(start: &Path, opts: &DiscoverOptions) -> Result<Self, anyhow::Error> {
+        let start = normalize(&std::path::absolute(start)?);
+        let work_tree = opts
+            .work_tree
+            .as_ref()
+            .map(|dir| normalize(&start.join(dir)));
+
+        if let Some(git_dir) = &opts.git_dir {
+            let git_dir = normalize(&start.join(git_dir));
+            let git_dir = if git_dir.is_file() {
+                read_gitfile(&git_dir)?
+            } else if is_git_dir(&git_dir) {
+                git_dir
+            } else {
+                anyhow::bail!("not a git repository: {:?}", git_dir)
+            };
+            return Self::from_parts(git_dir, work_tree, Some(start));
+        }
+
+        for dir in start.ancestors() {
+            if dir != start && opts.ceiling_dirs.iter().any(|ceiling| ceiling == dir) {
+                break;
+            }
+            let dot_git = dir.join(".git");
+            if dot_git.is_file() {
+                let git_dir = read_gitfile(&dot_git)?;
+                return Self::from_parts(git_dir, work_tree, Some(dir.to_path_buf()));
+            }
+            if is_git_dir(&dot_git) {
+                return Self::from_parts(dot_git, work_tree, Some(dir.to_path_buf()));
+            }
+            if is_git_dir(dir) {
+                return Self::from_parts(dir.to_path_buf(), work_tree, None);
+            }
+        }
+        anyhow::bail!(
+            "not a git repository (or any of the parent directories): {:?}",
+            start
+        )
+    }
+
+    /// Create an empty repository with its work tree at `dir`.
+    pub fn init(dir: &Path) -> Result<Self, anyhow::Error> {
+        Self::init_with(dir, &InitOptions::default())
+    }
+
+    /// Create a repository at `dir`, or just fill in what is missing if one
+    /// is already there: existing files, `HEAD` and the config are left
+    /// alone on re-initialization.
+    pub fn init_with(dir: &Path, opts: &InitOptions) -> Result<Self, anyhow::Error> {
+        let dir = normalize(&std::path::absolute(dir)?);
+        let git_dir = if opts.bare {
+            dir.clone()
+        } else {
+            dir.join(".git")
+        };
+        let reinit = is_git_dir(&git_dir);
+        let create = |path: &Path| {
+            fs::create_dir_all(path).map_err(|source| Error::Io {
+                path: path.to_path_buf(),
+                source,
+            })
+        };
+        let config = Config::load(&git_dir)?;
+        let branch = match &opts.initial_branch {
+            Some(branch) => branch.as_str(),
+            None => config.get("init.defaultBranch").unwrap_or("main"),
+        };
+        if !refs::is_valid_name(&format!("refs/heads/{branch}")) {
+            anyhow::bail!("invalid initial branch name: '{branch}'");
+        }
+        let format = opts.object_format.unwrap_or_default();
+        if reinit {
+            let existing = object_format(&config).context(format!("config in {:?}", git_dir))?;
+            if opts.object_format.is_some_and(|format| format != existing) {
+                anyhow::bail!("attempt to reinitialize repository with different hash");
+            }
+        }
+        create(&git_dir)?;
+
+        let template = match &opts.template {
+            Some(dir) => Some(dir.clone()),
+            None => std::env::var_os("GIT_TEMPLATE_DIR")
+                .map(PathBuf::from)
+                .or_else(|| config.get("init.templateDir").map(PathBuf::from)),
+        };
+        match template {
+            // an empty template directory means no template at all
+            Some(template) if template.as_os_str().is_empty() => {}
+            Some(template) => copy_template(&template, &git_dir)?,
+            None => {
+                for (name, content) in DEFAULT_TEMPLATE {
+                    let path = git_dir.join(name);
+                    create(path.parent().unwrap_or(&git_dir))?;
+                    if !path.exists() {
+                        fs::write(&path, content).context(format!("write {:?}", path))?;
+                    }
+                }
+                create(&git_dir.join("hooks"))?;
+            }
+        }
+        for subdir in ["objects/info", "objects/pack", "refs/heads", "refs/tags"] {
+            create(&git_dir.join(subdir))?;
+        }
+
+        if !reinit {
+            fs::write(git_dir.join("HEAD"), format!("ref: refs/heads/{branch}\n"))?;
+
+            // only repositories that need extensions claim format version 1
+            let version = match format {
+                ObjectFormat::Sha1 => 0,
+                _ => 1,
+            };
+            let mut core = format!(
+                "[core]\n\trepositoryformatversion = {version}\n\tfilemode = {}\n\tbare = {}\n",
+                cfg!(unix),
+                opts.bare
+            );
+            if opts.shared != SharedRepository::Umask {
+                core.push_str(&format!(
+                    "\tsharedrepository = {}\n[receive]\n\tdenyNonFastforwards = true\n",
+                    opts.shared.config_value()
+                ));
+            }
+            if format != ObjectFormat::Sha1 {
+                core.push_str(&format!("[extensions]\n\tobjectformat = {format}\n"));
+            }
+            // a template may have brought a config of its own, which our
+            // settings are appended to
+            let path = git_dir.join("config");
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .context(format!("open {:?}", path))?;
+            file.write_all(core.as_bytes())
+                .context(format!("write {:?}", path))?;
+        }
+        adjust_shared_perm(&git_dir, opts.shared)?;
+
+        let work_tree = (!opts.bare).then_some(dir);
+        Self::from_parts(git_dir, None, work_tree)
+    }
+
+    /// An explicitly given `work_tree` wins; otherwise the config can move the
+    /// work tree with `core.worktree` or drop it with `core.bare`, and without
+    /// either `found_work_tree` is used.
+    fn from_parts(
+        git_dir: PathBuf,
+        work_tree: Option<PathBuf>,
+        found_work_tree: Option<PathBuf>,
+    ) -> Result<Self, anyhow::Error> {
+        let config = Config::load(&git_dir)?;
+        let work_tree = match (work_tree, config.get("core.worktree")) {
+            (Some(dir), _) => Some(dir),
+            (None, Some(dir)) => Some(normalize(&git_dir.join(dir))),
+            (None, None)
+                if config
+                    .get_bool("core.bare")
+                    .context(format!("config in {:?}", git_dir))?
+                    .unwrap_or(false) =>
+            {
+                None
+            }
+            (None, None) => found_work_tree,
+        };
+        let format = object_format(&config).context(format!("config in {:?}", git_dir))?;
+        let odb = ObjectDatabase::new(git_dir.join("objects"), format, &config)
+            .context(format!("config in {:?}", git_dir))?;
+        odb.set_shallow(shallow::read(&git_dir)?);
+        odb.set_promisor(promisor_url(&config));
+        Ok(Repository {
+            git_dir,
+            work_tree,
+            config,
+            odb,
+        })
+    }
+
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    /// The top of the work tree, or `None` for a bare repository.
+    pub fn work_tree(&self) -> Option<&Path> {
+        self.work_tree.as_deref()
+    }
+
+    /// `path`, relative to the current directory, as a `/`-separated path
+    /// from the top of the work tree, the form tree entries and attribute
+    /// patterns use. `None` when there is no work tree or `path` is outside
+    /// of it.
+    pub fn work_tree_path(&self, path: &Path) -> Option<String> {
+        let path = normalize(&std::path::absolute(path).ok()?);
+        let relative = path.strip_prefix(self.work_tree.as_ref()?).ok()?;
+        let parts = relative
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<Vec<_>>>()?;
+        Some(parts.join("/"))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn odb(&self) -> &ObjectDatabase {
+        &self.odb
+    }
+
+    pub fn odb_mut(&mut self) -> &mut ObjectDatabase {
+        &mut self.odb
+    }
+
+    pub fn index(&self) -> Result<Index, anyhow::Error> {
+        Index::read(&self.git_dir.join("index"), self.odb.format())
+    }
+
+    /// Resolve a full or abbreviated object id, or a ref name, to a full
+    /// object id.
+    ///
+    /// Returns `Ok(None)` when nothing matches, and an error when an
+    /// abbreviated id is ambiguous.
+    pub fn resolve(&self, name: &str) -> Result<Option<String>, anyhow::Error> {
+        if let Some(oid) = self.odb.resolve_prefix(name)? {
+            return Ok(Some(oid));
+        }
+        self.resolve_ref(name)
+    }
+
+    /// See [`refs::resolve`].
+    pub fn resolve_ref(&self, name: &str) -> Result<Option<String>, anyhow::Error> {
+        refs::resolve(&self.git_dir, name)
+    }
+
+    /// See [`refs::update`].
+    pub fn update_ref(&self, name: &str, oid: &str) -> Result<(), anyhow::Error> {
+        refs::update(&self.git_dir, name, oid)
+    }
+
+    /// See [`refs::delete`].
+    pub fn delete_ref(&self, name: &str) -> Result<(), anyhow::Error> {
+        refs::delete(&self.git_dir, name)
+    }
+
+    /// See [`refs::set_symbolic`].
+    pub fn set_symbolic_ref(&self, name: &str, target: &str) -> Result<(), anyhow::Error> {
+        refs::set_symbolic(&self.git_dir, name, target)
+    }
+
+    /// See [`refs::symbolic_target`].
+    pub fn symbolic_ref(&self, name: &str) -> Result<Option<String>, anyhow::Error> {
+        refs::symbolic_target(&self.git_dir, name)
+    }
+
+    /// Whether the repository is a shallow clone.
+    pub fn is_shallow(&self) -> bool {
+        !self.odb.shallow().is_empty()
+    }
+
+    /// Make `commits` the shallow commits, in `.git/shallow` and for history
+    /// walks from now on.
+    pub fn set_shallow(&self, commits: &[String]) -> Result<(), anyhow::Error> {
+        shallow::write(&self.git_dir, commits)?;
+        self.odb.set_shallow(commits.iter().cloned());
+        Ok(())
+    }
+
+    /// See [`refs::all`].
+    pub fn refs(&self) -> Result<Vec<(String, String)>, anyhow::Error> {
+        refs::all(&self.git_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty path for a test to create a repository at.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "codecrafters-git-repository-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn discovery_stops_at_ceiling_directories() {
+        let top =
+            std::env::temp_dir().join(format!("codecrafters-git-discover-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&top);
+        Repository::init(&top).unwrap();
+        let sub = top.join("a").join("b");
+        fs::create_dir_all(&sub).unwrap();
+        let discover = |ceilings: &[&Path]| {
+            let opts = DiscoverOptions {
+                ceiling_dirs: ceilings.iter().map(|dir| dir.to_path_buf()).collect(),
+                ..Default::default()
+            };
+            Repository::discover_with(&sub, &opts).map(|repo| repo.git_dir().to_path_buf())
+        };
+
+        assert_eq!(discover(&[]).unwrap(), top.join(".git"));
+        // the search goes no higher than below a ceiling
+        assert!(discover(&[&top.join("a")]).is_err());
+        assert!(discover(&[&top]).is_err());
+        // but a ceiling that is where the search starts does not stop it
+        assert_eq!(discover(&[&sub]).unwrap(), top.join(".git"));
+        assert_eq!(
+            discover(&[&top.join("elsewhere"), Path::new("/nonexistent")]).unwrap(),
+            top.join(".git")
+        );
+        fs::remove_dir_all(&top).unwrap();
+    }
+
+    #[test]
+    fn init_fills_in_the_default_template() {
+        let dir = temp_dir("init");
+        let repo = Repository::init(&dir).unwrap();
+        let git_dir = dir.join(".git");
+        assert_eq!(repo.git_dir(), git_dir);
+        assert_eq!(
+            fs::read_to_string(git_dir.join("HEAD")).unwrap(),
+            "ref: refs/heads/main\n"
+        );
+        for path in ["description", "info/exclude"] {
+            assert!(git_dir.join(path).is_file(), "{path}");
+        }
+        for path in [
+            "hooks",
+            "objects/info",
+            "objects/pack",
+            "refs/heads",
+            "refs/tags",
+        ] {
+            assert!(git_dir.join(path).is_dir(), "{path}");
+        }
+        let config = Config::load(&git_dir).unwrap();
+        assert_eq!(config.get("core.repositoryformatversion"), Some("0"));
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(false));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn init_bare_with_branch_and_template() {
+        let dir = temp_dir("bare");
+        let template = temp_dir("template");
+        fs::create_dir_all(template.join("info")).unwrap();
+        fs::write(template.join("info/attributes"), "* -text\n").unwrap();
+        let opts = InitOptions {
+            bare: true,
+            initial_branch: Some("trunk".to_string()),
+            template: Some(template.clone()),
+            ..Default::default()
+        };
+        Repository::init_with(&dir, &opts).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("HEAD")).unwrap(),
+            "ref: refs/heads/trunk\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("info/attributes")).unwrap(),
+            "* -text\n"
+        );
+        // a template replaces the built-in one
+        assert!(!dir.join("description").exists());
+        let config = Config::load(&dir).unwrap();
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(true));
+
+        let opts = InitOptions {
+            initial_branch: Some("bad..name".to_string()),
+            ..Default::default()
+        };
+        assert!(Repository::init_with(&temp_dir("bad-branch"), &opts).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&template).unwrap();
+    }
+
+    #[test]
+    fn reinit_leaves_head_and_config_alone() {
+        let dir = temp_dir("reinit");
+        Repository::init(&dir).unwrap();
+        let git_dir = dir.join(".git");
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/topic\n").unwrap();
+        fs::remove_file(git_dir.join("description")).unwrap();
+        let config = fs::read_to_string(git_dir.join("config")).unwrap();
+
+        let opts = InitOptions {
+            initial_branch: Some("other".to_string()),
+            ..Default::default()
+        };
+        Repository::init_with(&dir, &opts).unwrap();
+        assert_eq!(
+            fs::read_to_string(git_dir.join("HEAD")).unwrap(),
+            "ref: refs/heads/topic\n"
+        );
+        assert_eq!(fs::read_to_string(git_dir.join("config")).unwrap(), config);
+        // but what is missing is filled in again
+        assert!(git_dir.join("description").is_file());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_shared_repository_values() {
+        let parse = |value| SharedRepository::parse(value).unwrap();
+        assert_eq!(parse("false"), SharedRepository::Umask);
+        assert_eq!(parse("group"), SharedRepository::Group);
+        assert_eq!(parse("true"), SharedRepository::Group);
+        assert_eq!(parse("all"), SharedRepository::Everybody);
+        assert_eq!(parse("0640"), SharedRepository::Mode(0o640));
+        assert_eq!(parse("0640").config_value(), "0640");
+        for bad in ["0440", "1777", "rw", ""] {
+            assert!(SharedRepository::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn sha256_repositories() {
+        let dir = temp_dir("sha256");
+        let opts = InitOptions {
+            object_format: Some(ObjectFormat::Sha256),
+            ..Default::default()
+        };
+        let repo = Repository::init_with(&dir, &opts).unwrap();
+        assert_eq!(repo.odb().format(), ObjectFormat::Sha256);
+        // re-initializing keeps the hash, and cannot change it
+        let reinit = Repository::init_with(&dir, &InitOptions::default()).unwrap();
+        assert_eq!(reinit.odb().format(), ObjectFormat::Sha256);
+        let sha1 = InitOptions {
+            object_format: Some(ObjectFormat::Sha1),
+            ..Default::default()
+        };
+        let Err(err) = Repository::init_with(&dir, &sha1) else {
+            panic!("re-initialized with a different hash");
+        };
+        assert_eq!(
+            err.to_string(),
+            "attempt to reinitialize repository with different hash"
+        );
+        let config = Config::load(repo.git_dir()).unwrap();
+        assert_eq!(config.get("core.repositoryformatversion"), Some("1"));
+        assert_eq!(config.get("extensions.objectformat"), Some("sha256"));
+        let git_dir = repo.git_dir().to_path_buf();
+        assert_eq!(
+            Repository::discover(&dir).unwrap().odb().format(),
+            ObjectFormat::Sha256
+        );
+
+        // extensions this does not know make the repository unusable
+        let mut config = fs::read_to_string(git_dir.join("config")).unwrap();
+        config.push_str("\tworktreeconfig = true\n");
+        fs::write(git_dir.join("config"), config).unwrap();
+        assert!(Repository::discover(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
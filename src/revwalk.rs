@@ -0,0 +1,345 @@
+//! Walking commit history: what a commit's parents are, what reaches
+//! what, and which objects one set of tips has that another lacks.
+
+use std::collections::{BinaryHeap, HashSet};
+use std::io::BufRead;
+
+use crate::error::Error;
+use crate::object::{header_field, parse_tree, ObjectFormat, ObjectType};
+use crate::odb::ObjectDatabase;
+use crate::repository::Repository;
+
+/// The parts of a commit that history walks need.
+pub struct Commit {
+    pub tree: String,
+    pub parents: Vec<String>,
+    /// The committer timestamp, in seconds since the epoch.
+    pub time: i64,
+}
+
+impl Commit {
+    /// Parse the headers of a commit, or `None` if they are malformed.
+    pub fn parse(content: &[u8], format: ObjectFormat) -> Option<Self> {
+        let mut lines = content.split(|c| *c == b'\n');
+        let tree = lines
+            .next()?
+            .strip_prefix(b"tree ")
+            .filter(|id| format.is_hex_id(id))?;
+        let mut parents = Vec::new();
+        for line in lines {
+            match line.strip_prefix(b"parent ") {
+                Some(id) if format.is_hex_id(id) => {
+                    parents.push(String::from_utf8_lossy(id).into_owned())
+                }
+                Some(_) => return None,
+                None => break,
+            }
+        }
+        // `Name <email> <timestamp> <tz>`; the name may contain anything
+        let committer = header_field(content, "committer")?;
+        let date = &committer[committer.iter().rposition(|c| *c == b'>')? + 1..];
+        let time = std::str::from_utf8(date)
+            .ok()?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()?;
+        Some(Commit {
+            tree: String::from_utf8_lossy(tree).into_owned(),
+            parents,
+            time,
+        })
+    }
+}
+
+/// Read and parse the commit `oid`. A shallow commit has no parents.
+pub fn read_commit(odb: &ObjectDatabase, oid: &str) -> Result<Commit, anyhow::Error> {
+    let object = odb.read(oid)?;
+    let corrupt = |reason: &str| Error::CorruptObject {
+        oid: oid.to_string(),
+        path: odb.object_path(oid),
+        reason: reason.to_string(),
+    };
+    if object.kind != ObjectType::Commit {
+        return Err(corrupt(&format!("expected a commit, found a {}", object.kind)).into());
+    }
+    let mut commit = Commit::parse(&object.content, odb.format())
+        .ok_or_else(|| corrupt("malformed commit headers"))?;
+    if odb.is_shallow(oid) {
+        commit.parents.clear();
+    }
+    Ok(commit)
+}
+
+/// The commit `oid` names, following tags, or `None` when it names some
+/// other kind of object.
+pub fn peel_to_commit(odb: &ObjectDatabase, oid: &str) -> Result<Option<String>, anyhow::Error> {
+    let mut oid = oid.to_string();
+    loop {
+        let (kind, _) = odb.header(&oid)?;
+        match kind {
+            ObjectType::Commit => return Ok(Some(oid)),
+            ObjectType::Tag => oid = peel_once(odb, &oid)?,
+            _ => return Ok(None),
+        }
+    }
+}
+
+/// Whether the commit `ancestor` can be reached from the commit
+/// `descendant` by following parents (a commit reaches itself).
+pub fn is_ancestor(
+    odb: &ObjectDatabase,
+    ancestor: &str,
+    descendant: &str,
+) -> Result<bool, anyhow::Error> {
+    let mut queue = BinaryHeap::new();
+    let mut seen = HashSet::new();
+    let commit = read_commit(odb, descendant)?;
+    queue.push((commit.time, descendant.to_string(), commit.parents));
+    seen.insert(descendant.to_string());
+    // newest first, so that the walk reaches the ancestor's generation from
+    // every side at about the same time
+    while let Some((_, oid, parents)) = queue.pop() {
+        if oid == ancestor {
+            return Ok(true);
+        }
+        for parent in parents {
+            if seen.insert(parent.clone()) {
+                let commit = read_commit(odb, &parent)?;
+                queue.push((commit.time, parent, commit.parents));
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// An object found by [`list_objects`].
+pub struct ListedObject {
+    pub oid: String,
+    pub kind: ObjectType,
+    /// The path a tree or blob was first found at, which packing uses to
+    /// place similar objects together; empty for commits, tags and root
+    /// trees.
+    pub path: Vec<u8>,
+}
+
+/// The objects reachable from `tips` but not from `exclude`, as `git
+/// rev-list --objects <tips> --not <exclude>` lists them: tags, then commits
+/// newest first, then the trees and blobs of those commits. Only excluded
+/// objects that are in `odb` are taken into account.
+pub fn list_objects(
+    odb: &ObjectDatabase,
+    tips: &[String],
+    exclude: &[String],
+) -> Result<Vec<ListedObject>, anyhow::Error> {
+    let mut listed = Vec::new();
+    let mut seen = HashSet::new();
+
+    // what the other side has: every commit reachable from the exclusions,
+    // and the trees of those next to what is sent
+    let mut excluded_commits = HashSet::new();
+    let mut excluded_trees = Vec::new();
+    let mut queue = Vec::new();
+    for oid in exclude.iter().filter(|oid| odb.contains(oid)) {
+        seen.insert(oid.clone());
+        match peel(odb, oid)? {
+            (ObjectType::Commit, commit) => queue.push(commit),
+            (ObjectType::Tree, tree) => excluded_trees.push(tree),
+            _ => {}
+        }
+    }
+    while let Some(oid) = queue.pop() {
+        if !odb.contains(&oid) || !excluded_commits.insert(oid.clone()) {
+            continue;
+        }
+        queue.extend(read_commit(odb, &oid)?.parents);
+    }
+
+    let mut commits = BinaryHeap::new();
+    let mut trees = Vec::new();
+    for tip in tips {
+        let mut oid = tip.clone();
+        loop {
+            let (kind, _) = odb.header(&oid)?;
+            if !seen.insert(oid.clone()) {
+                break;
+            }
+            match kind {
+                ObjectType::Tag => {
+                    listed.push(ListedObject {
+                        oid: oid.clone(),
+                        kind,
+                        path: Vec::new(),
+                    });
+                    oid = peel_once(odb, &oid)?;
+                }
+                ObjectType::Commit => {
+                    seen.remove(&oid);
+                    if !excluded_commits.contains(&oid) {
+                        commits.push((read_commit(odb, &oid)?.time, oid));
+                    }
+                    break;
+                }
+                ObjectType::Tree => {
+                    seen.remove(&oid);
+                    trees.push((oid, Vec::new()));
+                    break;
+                }
+                ObjectType::Blob => {
+                    listed.push(ListedObject {
+                        oid,
+                        kind,
+                        path: Vec::new(),
+                    });
+                    break;
+                }
+            }
+        }
+    }
+
+    // newest first, as the other side would walk them
+    while let Some((_, oid)) = commits.pop() {
+        if !seen.insert(oid.clone()) {
+            continue;
+        }
+        let commit = read_commit(odb, &oid)?;
+        for parent in commit.parents {
+            if excluded_commits.contains(&parent) {
+                excluded_trees.push(read_commit(odb, &parent)?.tree);
+            } else if !seen.contains(&parent) {
+                commits.push((read_commit(odb, &parent)?.time, parent));
+            }
+        }
+        trees.push((commit.tree, Vec::new()));
+        listed.push(ListedObject {
+            oid,
+            kind: ObjectType::Commit,
+            path: Vec::new(),
+        });
+    }
+
+    // everything in the trees the other side has is left out
+    while let Some(tree) = excluded_trees.pop() {
+        if !odb.contains(&tree) || !seen.insert(tree.clone()) {
+            continue;
+        }
+        for entry in parse_tree(&odb.read(&tree)?.content, odb.format())? {
+            match entry.kind() {
+                ObjectType::Tree => excluded_trees.push(entry.hash),
+                ObjectType::Blob => {
+                    seen.insert(entry.hash);
+                }
+                _ => {}
+            }
+        }
+    }
+    // trees are walked depth first, so that each lands next to its blobs
+    trees.reverse();
+    while let Some((tree, path)) = trees.pop() {
+        if !seen.insert(tree.clone()) {
+            continue;
+        }
+        let entries = parse_tree(&odb.read(&tree)?.content, odb.format())?;
+        listed.push(ListedObject {
+            oid: tree,
+            kind: ObjectType::Tree,
+            path: path.clone(),
+        });
+        for entry in entries.into_iter().rev() {
+            let mut child = path.clone();
+            if !child.is_empty() {
+                child.push(b'/');
+            }
+            child.extend_from_slice(&entry.name);
+            match entry.kind() {
+                ObjectType::Tree => trees.push((entry.hash, child)),
+                ObjectType::Blob if seen.insert(entry.hash.clone()) => listed.push(ListedObject {
+                    oid: entry.hash,
+                    kind: ObjectType::Blob,
+                    path: child,
+                }),
+                // gitlinks point into another repository
+                _ => {}
+            }
+        }
+    }
+    Ok(listed)
+}
+
+/// The object the tag `oid` points at.
+fn peel_once(odb: &ObjectDatabase, oid: &str) -> Result<String, anyhow::Error> {
+    let tag = odb.read(oid)?;
+    let target = header_field(&tag.content, "object")
+        .filter(|id| odb.format().is_hex_id(id))
+        .ok_or_else(|| Error::CorruptObject {
+            oid: oid.to_string(),
+            path: odb.object_path(oid),
+            reason: "tag does not start with an object header".to_string(),
+        })?;
+    Ok(String::from_utf8_lossy(target).into_owned())
+}
+
+/// The type of the first object `oid` reaches that is not a tag, and its id.
+fn peel(odb: &ObjectDatabase, oid: &str) -> Result<(ObjectType, String), anyhow::Error> {
+    let mut oid = oid.to_string();
+    loop {
+        match odb.header(&oid)?.0 {
+            ObjectType::Tag => oid = peel_once(odb, &oid)?,
+            kind => return Ok((kind, oid)),
+        }
+    }
+}
+
+fn resolve(repo: &Repository, rev: &str) -> Result<String, anyhow::Error> {
+    repo.resolve(rev)?
+        .ok_or_else(|| Error::BadObjectName(rev.to_string()).into())
+}
+
+/// The objects `pack-objects --revs` reads from `input`: one revision per
+/// line, those starting with `^` excluded, and `--not` flipping whether the
+/// ones after it are.
+pub fn read_revs(
+    repo: &Repository,
+    input: impl BufRead,
+) -> Result<Vec<ListedObject>, anyhow::Error> {
+    let (mut tips, mut exclude) = (Vec::new(), Vec::new());
+    let mut not = false;
+    for line in input.lines() {
+        let line = line?;
+        match line.as_str() {
+            "" => continue,
+            "--not" => not = !not,
+            _ => match line.strip_prefix('^') {
+                Some(rev) if !not => exclude.push(resolve(repo, rev)?),
+                Some(rev) => tips.push(resolve(repo, rev)?),
+                None if not => exclude.push(resolve(repo, &line)?),
+                None => tips.push(resolve(repo, &line)?),
+            },
+        }
+    }
+    list_objects(repo.odb(), &tips, &exclude)
+}
+
+/// The objects `pack-objects` reads from `input` without `--revs`: one per
+/// line, optionally followed by a space and the path it was found at.
+pub fn read_object_list(
+    repo: &Repository,
+    input: impl BufRead,
+) -> Result<Vec<ListedObject>, anyhow::Error> {
+    let mut objects = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let (oid, path) = line.split_once(' ').unwrap_or((&line, ""));
+        if oid.is_empty() {
+            continue;
+        }
+        let oid = resolve(repo, oid)?;
+        let (kind, _) = repo.odb().header(&oid)?;
+        objects.push(ListedObject {
+            oid,
+            kind,
+            path: path.as_bytes().to_vec(),
+        });
+    }
+    Ok(objects)
+}
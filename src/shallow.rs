@@ -0,0 +1,65 @@
+//! The `.git/shallow` file: the commits of a shallow clone whose parents
+//! were left out, one object id per line. History walks treat them as
+//! having no parents.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::Error;
+
+/// The shallow commits of the repository at `git_dir`, sorted; empty when it
+/// is not shallow.
+pub fn read(git_dir: &Path) -> Result<Vec<String>, anyhow::Error> {
+    let path = git_dir.join("shallow");
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(source) => return Err(Error::Io { path, source }.into()),
+    };
+    let mut commits = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    commits.sort();
+    commits.dedup();
+    Ok(commits)
+}
+
+/// Replace the shallow commits with `commits`, under `shallow.lock` as git
+/// does. No commits makes the repository complete again, removing the file.
+pub fn write(git_dir: &Path, commits: &[String]) -> Result<(), anyhow::Error> {
+    let path = git_dir.join("shallow");
+    let lock = git_dir.join("shallow.lock");
+    let mut f = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+    {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            anyhow::bail!("{:?} exists, is another git running?", lock)
+        }
+        Err(source) => return Err(Error::Io { path: lock, source }.into()),
+    };
+    let mut commits = commits.to_vec();
+    commits.sort();
+    commits.dedup();
+    let written = if commits.is_empty() {
+        fs::remove_file(&lock).and_then(|_| match fs::remove_file(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        })
+    } else {
+        f.write_all(commits.join("\n").as_bytes())
+            .and_then(|_| f.write_all(b"\n"))
+            .and_then(|_| fs::rename(&lock, &path))
+    };
+    if let Err(source) = written {
+        let _ = fs::remove_file(&lock);
+        return Err(Error::Io { path, source }.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,2099 @@
+//! Talking to a remote repository over git's smart HTTP protocol.
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::{BufReader, Read, Write};
+
+use anyhow::Context;
+
+use crate::error::Error;
+use crate::object::ObjectFormat;
+use crate::odb::ObjectDatabase;
+use crate::pack;
+use crate::revwalk;
+
+pub fn create_pkt_line(s: &[u8]) -> Vec<u8> {
+    let len = if s.is_empty() { 0 } else { s.len() + 4 };
+    let len = format!("{len:04x}");
+    let mut res = len.bytes().collect::<Vec<u8>>();
+    res.extend(s);
+    res
+}
+
+/// A delimiter packet, separating the sections of a protocol v2 request or
+/// response.
+pub const DELIM_PKT: &[u8] = b"0001";
+
+/// One packet of a pkt-line stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Packet<'a> {
+    /// `0000`
+    Flush,
+    /// `0001`, only in protocol v2
+    Delim,
+    /// `0002`, only in protocol v2
+    ResponseEnd,
+    Data(&'a [u8]),
+}
+
+/// Parse the four hex digits that start a packet. Lengths below 4 are the
+/// special packets, returned as is.
+fn parse_pkt_len(len: &[u8]) -> Result<usize, Error> {
+    let parsed = std::str::from_utf8(len)
+        .ok()
+        .filter(|l| l.bytes().all(|c| c.is_ascii_hexdigit()))
+        .and_then(|l| usize::from_str_radix(l, 16).ok())
+        .ok_or_else(|| {
+            Error::Protocol(format!(
+                "bad pkt-line length {:?}",
+                String::from_utf8_lossy(len)
+            ))
+        })?;
+    if parsed == 3 {
+        return Err(Error::Protocol(format!("bad pkt-line length {parsed}")));
+    }
+    Ok(parsed)
+}
+
+/// The special packet a length below 4 stands for.
+fn special_packet(len: usize) -> Option<Packet<'static>> {
+    match len {
+        0 => Some(Packet::Flush),
+        1 => Some(Packet::Delim),
+        2 => Some(Packet::ResponseEnd),
+        _ => None,
+    }
+}
+
+/// Read the packet at `offset`, advancing past it.
+pub fn read_packet<'a>(buf: &'a [u8], offset: &mut usize) -> Result<Packet<'a>, Error> {
+    let len = buf
+        .get(*offset..*offset + 4)
+        .ok_or_else(|| Error::Protocol("truncated pkt-line length".to_string()))?;
+    let len = parse_pkt_len(len)?;
+    *offset += 4;
+    if let Some(packet) = special_packet(len) {
+        return Ok(packet);
+    }
+    let res = buf
+        .get(*offset..*offset + len - 4)
+        .ok_or_else(|| Error::Protocol("truncated pkt-line".to_string()))?;
+    *offset += len - 4;
+    Ok(Packet::Data(res))
+}
+
+/// Read the pkt-line at `offset`, advancing past it. A flush packet
+/// (`0000`) reads as an empty line.
+pub fn read_pkt_line<'a>(buf: &'a [u8], offset: &mut usize) -> Result<&'a [u8], Error> {
+    match read_packet(buf, offset)? {
+        Packet::Flush => Ok(b""),
+        Packet::Data(line) => Ok(line),
+        packet => Err(Error::Protocol(format!("unexpected {packet:?} packet"))),
+    }
+}
+
+/// Reads packets one at a time from a stream, such as a response body.
+pub struct PacketReader<R> {
+    inner: BufReader<R>,
+    line: Vec<u8>,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(inner: R) -> Self {
+        PacketReader {
+            inner: BufReader::new(inner),
+            line: Vec::new(),
+        }
+    }
+
+    pub fn read_packet(&mut self) -> Result<Packet<'_>, anyhow::Error> {
+        let truncated = |e: std::io::Error| -> anyhow::Error {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                Error::Protocol("truncated pkt-line".to_string()).into()
+            } else {
+                anyhow::Error::new(e).context("read from remote")
+            }
+        };
+        let mut len = [0; 4];
+        self.inner.read_exact(&mut len).map_err(truncated)?;
+        let len = parse_pkt_len(&len)?;
+        if let Some(packet) = special_packet(len) {
+            return Ok(packet);
+        }
+        self.line.resize(len - 4, 0);
+        self.inner.read_exact(&mut self.line).map_err(truncated)?;
+        Ok(Packet::Data(&self.line))
+    }
+
+    /// The rest of the stream, for data that does not come in packets.
+    pub fn into_inner(self) -> BufReader<R> {
+        self.inner
+    }
+}
+
+/// The version of the wire protocol a remote answered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V0,
+    V1,
+    V2,
+}
+
+/// A ref as a remote advertises it.
+#[derive(Debug, Clone)]
+pub struct RemoteRef {
+    pub name: String,
+    pub oid: String,
+    /// What a symbolic ref points at, e.g. `refs/heads/main` for `HEAD`.
+    pub symref_target: Option<String>,
+    /// The object an annotated tag points at.
+    pub peeled: Option<String>,
+}
+
+/// What a remote's upload-pack advertises.
+pub struct Advertisement {
+    pub version: ProtocolVersion,
+    pub refs: Vec<RemoteRef>,
+    /// Capabilities, as `name` or `name=value`.
+    pub capabilities: Vec<String>,
+}
+
+impl Advertisement {
+    /// The value of the capability `name`: `Some("")` when it is advertised
+    /// without a value.
+    pub fn capability(&self, name: &str) -> Option<&str> {
+        self.capabilities
+            .iter()
+            .find_map(|c| match c.split_once('=') {
+                Some((n, value)) if n == name => Some(value),
+                None if c == name => Some(""),
+                _ => None,
+            })
+    }
+
+    /// The remote repository's object format; servers that do not say use
+    /// SHA-1.
+    pub fn object_format(&self) -> Result<ObjectFormat, Error> {
+        match self.capability("object-format") {
+            Some(name) => ObjectFormat::parse(name)
+                .ok_or_else(|| Error::Protocol(format!("unknown object format '{name}'"))),
+            None => Ok(ObjectFormat::Sha1),
+        }
+    }
+
+    /// The advertised ref called `name`.
+    pub fn find(&self, name: &str) -> Option<&RemoteRef> {
+        self.refs.iter().find(|r| r.name == name)
+    }
+}
+
+/// Send a request to the remote's `service` (`git-upload-pack` or
+/// `git-receive-pack`), returning the response to read the body from.
+fn post_service(
+    url: &str,
+    service: &str,
+    version: ProtocolVersion,
+    body: Vec<u8>,
+) -> Result<reqwest::blocking::Response, anyhow::Error> {
+    let pack_url = format!("{url}/{service}");
+    use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(&format!("application/x-{service}-request"))?,
+    );
+    headers.insert(
+        ACCEPT,
+        HeaderValue::from_str(&format!("application/x-{service}-result"))?,
+    );
+    if version == ProtocolVersion::V2 {
+        headers.insert("Git-Protocol", HeaderValue::from_static("version=2"));
+    }
+    let client = reqwest::blocking::Client::new();
+    let resp = client.post(&pack_url).headers(headers).body(body).send()?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(Error::Protocol(format!("{pack_url}: server answered {status}")).into());
+    }
+    Ok(resp)
+}
+
+/// Send a request to the remote's upload-pack.
+fn post_upload_pack(
+    url: &str,
+    version: ProtocolVersion,
+    body: Vec<u8>,
+) -> Result<reqwest::blocking::Response, anyhow::Error> {
+    post_service(url, "git-upload-pack", version, body)
+}
+
+/// What the remote's `service` first advertises, asking for protocol v2 if
+/// `v2` is set; see [`parse_info_refs`].
+fn get_info_refs(url: &str, service: &str, v2: bool) -> Result<Vec<u8>, anyhow::Error> {
+    let info_url = format!("{url}/info/refs?service={service}");
+    let client = reqwest::blocking::Client::new();
+    // TODO: rewrite it in an "await" way
+    let mut request = client.get(&info_url);
+    if v2 {
+        request = request.header("Git-Protocol", "version=2");
+    }
+    let mut resp = request.send()?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(Error::Protocol(format!("{info_url}: server answered {status}")).into());
+    }
+    let mut body = Vec::new();
+    resp.copy_to(&mut body)?;
+    Ok(body)
+}
+
+/// The refs and capabilities a remote advertises, limited to refs starting
+/// with one of `prefixes` (all refs when it is empty).
+///
+/// Protocol v2 is asked for, and the server filters the refs itself;
+/// servers that do not speak it answer in v0 (or v1), whose advertisement
+/// always lists every ref.
+pub fn ls_refs(url: &str, prefixes: &[&str]) -> Result<Advertisement, anyhow::Error> {
+    let body = get_info_refs(url, "git-upload-pack", true)?;
+    let mut advertisement = parse_info_refs(&body)?;
+    if advertisement.version == ProtocolVersion::V2 {
+        advertisement = ls_refs_v2(url, advertisement.capabilities, prefixes)?;
+    } else if !prefixes.is_empty() {
+        advertisement
+            .refs
+            .retain(|r| prefixes.iter().any(|p| r.name.starts_with(p)));
+    }
+    Ok(advertisement)
+}
+
+/// Parse the response to `info/refs`. A v2 server only lists its
+/// capabilities, so the advertisement it gives has no refs.
+fn parse_info_refs(body: &[u8]) -> Result<Advertisement, Error> {
+    let mut offset = 0;
+    // smart HTTP starts v0 and v1 responses with "# service=..." and a flush
+    if body.get(4) == Some(&b'#') {
+        read_pkt_line(body, &mut offset)?;
+        read_pkt_line(body, &mut offset)?;
+    }
+    let mut peek = offset;
+    match read_pkt_line(body, &mut peek)? {
+        b"version 2\n" | b"version 2" => Ok(Advertisement {
+            version: ProtocolVersion::V2,
+            refs: Vec::new(),
+            capabilities: read_v2_capabilities(body, peek)?,
+        }),
+        b"version 1\n" | b"version 1" => {
+            let mut advertisement = parse_v0_advertisement(body, peek)?;
+            advertisement.version = ProtocolVersion::V1;
+            Ok(advertisement)
+        }
+        _ => parse_v0_advertisement(body, offset),
+    }
+}
+
+/// The capabilities listed after `version 2`, up to the flush packet.
+fn read_v2_capabilities(body: &[u8], mut offset: usize) -> Result<Vec<String>, Error> {
+    let mut capabilities = Vec::new();
+    loop {
+        match read_packet(body, &mut offset)? {
+            Packet::Flush => return Ok(capabilities),
+            Packet::Data(line) => {
+                let line = line.strip_suffix(b"\n").unwrap_or(line);
+                capabilities.push(String::from_utf8_lossy(line).into_owned());
+            }
+            packet => {
+                return Err(Error::Protocol(format!(
+                    "unexpected {packet:?} packet in capability advertisement"
+                )))
+            }
+        }
+    }
+}
+
+/// The start of a protocol v2 command request: the command and the
+/// capabilities every request repeats, up to the delimiter before the
+/// command's arguments.
+fn v2_command(command: &str, advertisement: &Advertisement) -> Result<Vec<u8>, Error> {
+    let mut body = create_pkt_line(format!("command={command}\n").as_bytes());
+    if advertisement.capability("object-format").is_some() {
+        let line = format!("object-format={}\n", advertisement.object_format()?);
+        body.extend_from_slice(&create_pkt_line(line.as_bytes()));
+    }
+    body.extend_from_slice(DELIM_PKT);
+    Ok(body)
+}
+
+fn ls_refs_v2(
+    url: &str,
+    capabilities: Vec<String>,
+    prefixes: &[&str],
+) -> Result<Advertisement, anyhow::Error> {
+    let mut advertisement = Advertisement {
+        version: ProtocolVersion::V2,
+        refs: Vec::new(),
+        capabilities,
+    };
+    if advertisement.capability("ls-refs").is_none() {
+        return Err(Error::Protocol("server does not support ls-refs".to_string()).into());
+    }
+    let format = advertisement.object_format()?;
+    let mut body = v2_command("ls-refs", &advertisement)?;
+    body.extend_from_slice(&create_pkt_line(b"peel\n"));
+    body.extend_from_slice(&create_pkt_line(b"symrefs\n"));
+    for prefix in prefixes {
+        body.extend_from_slice(&create_pkt_line(
+            format!("ref-prefix {prefix}\n").as_bytes(),
+        ));
+    }
+    body.extend_from_slice(&create_pkt_line(b""));
+    let mut resp = post_upload_pack(url, ProtocolVersion::V2, body)?;
+    let mut buf = Vec::new();
+    resp.copy_to(&mut buf).context("read ls-refs response")?;
+    advertisement.refs = parse_ls_refs(&buf, format)?;
+    Ok(advertisement)
+}
+
+/// Parse the refs an `ls-refs` response lists, with their `symref-target`
+/// and `peeled` attributes.
+fn parse_ls_refs(resp: &[u8], format: ObjectFormat) -> Result<Vec<RemoteRef>, Error> {
+    let mut refs = Vec::new();
+    let mut offset = 0;
+    loop {
+        let line = match read_packet(resp, &mut offset)? {
+            Packet::Flush => break,
+            Packet::Data(line) => line,
+            packet => {
+                return Err(Error::Protocol(format!(
+                    "unexpected {packet:?} packet in ls-refs"
+                )))
+            }
+        };
+        let line = String::from_utf8_lossy(line.strip_suffix(b"\n").unwrap_or(line));
+        let mut fields = line.split(' ');
+        let (Some(oid), Some(name)) = (fields.next(), fields.next()) else {
+            return Err(Error::Protocol(format!("bad ls-refs line {line:?}")));
+        };
+        if !format.is_hex_id(oid.as_bytes()) {
+            return Err(Error::Protocol(format!("bad ls-refs line {line:?}")));
+        }
+        let mut remote_ref = RemoteRef {
+            name: name.to_string(),
+            oid: oid.to_string(),
+            symref_target: None,
+            peeled: None,
+        };
+        for attribute in fields {
+            if let Some(target) = attribute.strip_prefix("symref-target:") {
+                remote_ref.symref_target = Some(target.to_string());
+            } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                remote_ref.peeled = Some(peeled.to_string());
+            }
+        }
+        refs.push(remote_ref);
+    }
+    Ok(refs)
+}
+
+/// Parse a v0 ref advertisement starting at `offset`: one ref per line,
+/// with the capabilities after a NUL on the first.
+fn parse_v0_advertisement(body: &[u8], mut offset: usize) -> Result<Advertisement, Error> {
+    let mut lines = Vec::new();
+    let mut capabilities = None;
+    while offset < body.len() {
+        let line = read_pkt_line(body, &mut offset)?;
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        // the first ref carries the capabilities after a NUL
+        let mut parts = line.splitn(2, |c| *c == b'\0');
+        let line = String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+        if let Some(caps) = parts.next() {
+            capabilities.get_or_insert_with(|| {
+                String::from_utf8_lossy(caps)
+                    .split(' ')
+                    .filter(|c| !c.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            });
+        }
+        lines.push(line);
+    }
+
+    let mut advertisement = Advertisement {
+        version: ProtocolVersion::V0,
+        refs: Vec::new(),
+        capabilities: capabilities.unwrap_or_default(),
+    };
+    let format = advertisement.object_format()?;
+    for line in lines {
+        let (oid, name) = line
+            .split_once(' ')
+            .filter(|(oid, _)| format.is_hex_id(oid.as_bytes()))
+            .ok_or_else(|| Error::Protocol(format!("bad ref advertisement {line:?}")))?;
+        // an empty repository advertises its capabilities on a fake ref
+        if name == "capabilities^{}" {
+            continue;
+        }
+        // annotated tags are followed by the object they point at
+        if let Some(tag) = name.strip_suffix("^{}") {
+            if let Some(last) = advertisement.refs.last_mut().filter(|r| r.name == tag) {
+                last.peeled = Some(oid.to_string());
+            }
+            continue;
+        }
+        advertisement.refs.push(RemoteRef {
+            name: name.to_string(),
+            oid: oid.to_string(),
+            symref_target: None,
+            peeled: None,
+        });
+    }
+    // symbolic refs are only listed as capabilities
+    let symrefs = advertisement
+        .capabilities
+        .iter()
+        .filter_map(|c| c.strip_prefix("symref=")?.split_once(':'))
+        .map(|(name, target)| (name.to_string(), target.to_string()))
+        .collect::<Vec<_>>();
+    for (name, target) in symrefs {
+        if let Some(r) = advertisement.refs.iter_mut().find(|r| r.name == name) {
+            r.symref_target = Some(target);
+        }
+    }
+    Ok(advertisement)
+}
+
+/// Which tags a fetch brings along besides those its refspecs name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TagMode {
+    /// Tags pointing at objects the fetch brings in (`include-tag`).
+    #[default]
+    Follow,
+    /// Every tag, as with `--tags`.
+    All,
+    /// None, as with `--no-tags`.
+    None,
+}
+
+/// How far a shallow fetch reaches into history; nothing set asks for all
+/// of it.
+#[derive(Debug, Clone, Default)]
+pub struct Deepen {
+    /// Fetch this many commits of history from each wanted tip (`deepen`).
+    pub depth: Option<u32>,
+    /// Fetch only commits made after this time, in seconds since the epoch
+    /// (`deepen-since`).
+    pub since: Option<i64>,
+    /// Leave out the history of these remote refs (`deepen-not`).
+    pub not: Vec<String>,
+    /// Count `depth` from the current shallow commits rather than from the
+    /// tips (`deepen-relative`).
+    pub relative: bool,
+}
+
+impl Deepen {
+    /// A depth reaching every commit, which is how `--unshallow` asks for the
+    /// rest of history.
+    pub const INFINITE: u32 = 0x7fff_ffff;
+
+    /// Whether the fetch is to change where history is cut off.
+    pub fn is_set(&self) -> bool {
+        self.depth.is_some() || self.since.is_some() || !self.not.is_empty()
+    }
+}
+
+/// Parse a date the way `--shallow-since` takes one: seconds since the
+/// epoch (optionally after `@`), RFC 2822 or ISO 8601 with or without a time
+/// (local time unless a zone is given), or `<n> <unit>s ago`.
+pub fn parse_date(date: &str) -> Result<i64, anyhow::Error> {
+    use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+    let date = date.trim();
+    let seconds = date.strip_prefix('@').unwrap_or(date);
+    if let Ok(seconds) = seconds.parse() {
+        return Ok(seconds);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc2822(date)
+        .or(DateTime::parse_from_rfc3339(date))
+        .or(DateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S %z"))
+    {
+        return Ok(time.timestamp());
+    }
+    let local = |naive: NaiveDateTime| Local.from_local_datetime(&naive).earliest();
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Some(time) = NaiveDateTime::parse_from_str(date, format)
+            .ok()
+            .and_then(local)
+        {
+            return Ok(time.timestamp());
+        }
+    }
+    if let Some(time) = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|day| local(day.and_hms_opt(0, 0, 0)?))
+    {
+        return Ok(time.timestamp());
+    }
+    // "2 weeks ago", or "2.weeks.ago" as git also takes
+    let words = date.split([' ', '.']).collect::<Vec<_>>();
+    if let [count, unit, "ago"] = words[..] {
+        let unit = unit.strip_suffix('s').unwrap_or(unit);
+        let seconds = match unit {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            "month" => 30 * 24 * 60 * 60,
+            "year" => 365 * 24 * 60 * 60,
+            _ => 0,
+        };
+        if let (Ok(count), true) = (count.parse::<i64>(), seconds > 0) {
+            return Ok(Local::now().timestamp() - count * seconds);
+        }
+    }
+    anyhow::bail!("invalid date '{date}'")
+}
+
+/// Objects a partial clone leaves out, to be fetched when they are needed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFilter {
+    /// Every blob (`blob:none`).
+    BlobNone,
+    /// Blobs of this many bytes or more (`blob:limit=<n>`).
+    BlobLimit(u64),
+    /// Trees and blobs deeper than this below the root tree, so `tree:0`
+    /// leaves out every tree (`tree:<depth>`).
+    Tree(u64),
+}
+
+impl ObjectFilter {
+    /// Parse a filter spec, as `--filter` takes it. Blob limits may end in
+    /// `k`, `m` or `g`.
+    pub fn parse(spec: &str) -> Result<Self, anyhow::Error> {
+        let invalid = || anyhow::anyhow!("invalid filter-spec '{spec}'");
+        if spec == "blob:none" {
+            return Ok(ObjectFilter::BlobNone);
+        }
+        if let Some(limit) = spec.strip_prefix("blob:limit=") {
+            let (digits, unit) = match limit.char_indices().last() {
+                Some((i, c)) if c.is_ascii_alphabetic() => (&limit[..i], c),
+                _ => (limit, 'b'),
+            };
+            let scale = match unit.to_ascii_lowercase() {
+                'b' => 1,
+                'k' => 1 << 10,
+                'm' => 1 << 20,
+                'g' => 1 << 30,
+                _ => return Err(invalid()),
+            };
+            let limit = digits.parse::<u64>().map_err(|_| invalid())?;
+            let limit = limit.checked_mul(scale).ok_or_else(invalid)?;
+            return Ok(ObjectFilter::BlobLimit(limit));
+        }
+        if let Some(depth) = spec.strip_prefix("tree:") {
+            return Ok(ObjectFilter::Tree(depth.parse().map_err(|_| invalid())?));
+        }
+        Err(invalid())
+    }
+}
+
+impl std::fmt::Display for ObjectFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ObjectFilter::BlobNone => write!(f, "blob:none"),
+            ObjectFilter::BlobLimit(limit) => write!(f, "blob:limit={limit}"),
+            ObjectFilter::Tree(depth) => write!(f, "tree:{depth}"),
+        }
+    }
+}
+
+/// How to fetch.
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    /// Show progress on stderr: ours, and the remote's messages.
+    pub progress: bool,
+    pub tags: TagMode,
+    /// Delete remote-tracking refs whose remote ref is gone.
+    pub prune: bool,
+    /// Allow updates that are not fast-forwards for every refspec, as a
+    /// leading `+` does for one.
+    pub force: bool,
+    /// Allow updating the branch `HEAD` points at.
+    pub update_head_ok: bool,
+    pub deepen: Deepen,
+    /// Ask for a pack without the objects the filter leaves out, for a
+    /// partial clone.
+    pub filter: Option<ObjectFilter>,
+}
+
+/// What the remote sent back for a fetch.
+pub struct FetchResponse {
+    /// The checksum naming the pack the objects were stored in.
+    pub pack: String,
+    /// Commits the remote made shallow boundaries.
+    pub shallow: Vec<String>,
+    /// Commits that are no longer shallow boundaries.
+    pub unshallow: Vec<String>,
+    /// The refs asked for with `want-ref`, as `(object id, name)` pairs.
+    pub wanted_refs: Vec<(String, String)>,
+}
+
+/// After this many haves without a new one acknowledged, the remote is
+/// unlikely to have anything in common with what is left, so give up.
+const MAX_IN_VAIN: usize = 256;
+
+/// Picks the commits to offer the remote as `have`s, newest first from the
+/// local tips, and keeps track of which ones it acknowledged.
+///
+/// Over HTTP every round is a new request, so each round repeats the haves
+/// acknowledged so far before the new ones.
+struct Negotiator<'a> {
+    odb: &'a ObjectDatabase,
+    queue: BinaryHeap<(i64, String)>,
+    seen: HashSet<String>,
+    parents: HashMap<String, Vec<String>>,
+    /// Commits known to be common: acknowledged, or reachable from one that
+    /// was, which need not be offered.
+    common: HashSet<String>,
+    acked: Vec<String>,
+    batch: usize,
+    in_vain: usize,
+    /// The remote said it has enough to send a pack.
+    ready: bool,
+}
+
+impl<'a> Negotiator<'a> {
+    fn new(odb: &'a ObjectDatabase, tips: &[String]) -> Result<Self, anyhow::Error> {
+        let mut negotiator = Negotiator {
+            odb,
+            queue: BinaryHeap::new(),
+            seen: HashSet::new(),
+            parents: HashMap::new(),
+            common: HashSet::new(),
+            acked: Vec::new(),
+            batch: 16,
+            in_vain: 0,
+            ready: false,
+        };
+        for tip in tips {
+            if let Some(commit) = revwalk::peel_to_commit(odb, tip)? {
+                negotiator.push(commit)?;
+            }
+        }
+        Ok(negotiator)
+    }
+
+    fn push(&mut self, oid: String) -> Result<(), anyhow::Error> {
+        // history may be incomplete, so what is missing is skipped
+        if !self.seen.insert(oid.clone()) || !self.odb.contains(&oid) {
+            return Ok(());
+        }
+        let commit = revwalk::read_commit(self.odb, &oid)?;
+        self.parents.insert(oid.clone(), commit.parents);
+        self.queue.push((commit.time, oid));
+        Ok(())
+    }
+
+    /// The next commit worth offering.
+    fn next_have(&mut self) -> Result<Option<String>, anyhow::Error> {
+        while let Some((_, oid)) = self.queue.pop() {
+            let parents = self.parents.get(&oid).cloned().unwrap_or_default();
+            let common = self.common.contains(&oid);
+            for parent in parents {
+                if common {
+                    self.common.insert(parent.clone());
+                }
+                self.push(parent)?;
+            }
+            if !common {
+                return Ok(Some(oid));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The haves for the next round, or `None` when it is time to send
+    /// `done`.
+    fn next_round(&mut self) -> Result<Option<Vec<String>>, anyhow::Error> {
+        if self.ready || (!self.acked.is_empty() && self.in_vain > MAX_IN_VAIN) {
+            return Ok(None);
+        }
+        let mut haves = self.acked.clone();
+        let mut new = 0;
+        while new < self.batch {
+            let Some(have) = self.next_have()? else {
+                break;
+            };
+            haves.push(have);
+            new += 1;
+        }
+        if new == 0 {
+            return Ok(None);
+        }
+        self.in_vain += new;
+        self.batch = (self.batch * 2).min(1024);
+        Ok(Some(haves))
+    }
+
+    /// The remote has `oid`, and so everything it reaches.
+    fn ack(&mut self, oid: &str) {
+        if self.acked.iter().any(|a| a == oid) {
+            return;
+        }
+        self.acked.push(oid.to_string());
+        self.in_vain = 0;
+        self.common.insert(oid.to_string());
+        if let Some(parents) = self.parents.get(oid) {
+            self.common.extend(parents.iter().cloned());
+        }
+    }
+}
+
+/// The request lines telling the remote where local history is cut off and
+/// how far to deepen it, which read the same in every protocol version but
+/// for `deepen-relative`: v0 asks for it as a capability instead.
+fn shallow_request(odb: &ObjectDatabase, deepen: &Deepen, version: ProtocolVersion) -> Vec<u8> {
+    let mut lines = Vec::new();
+    for commit in odb.shallow() {
+        lines.push(format!("shallow {commit}\n"));
+    }
+    if let Some(depth) = deepen.depth {
+        lines.push(format!("deepen {depth}\n"));
+        if deepen.relative && version == ProtocolVersion::V2 {
+            lines.push("deepen-relative\n".to_string());
+        }
+    }
+    if let Some(since) = deepen.since {
+        lines.push(format!("deepen-since {since}\n"));
+    }
+    for name in &deepen.not {
+        lines.push(format!("deepen-not {name}\n"));
+    }
+    lines
+        .iter()
+        .flat_map(|line| create_pkt_line(line.as_bytes()))
+        .collect()
+}
+
+/// Check that the remote can do the shallow fetch asked for; `supports`
+/// says whether it has a feature, named as in the v0 capabilities.
+fn check_deepen(
+    odb: &ObjectDatabase,
+    deepen: &Deepen,
+    supports: impl Fn(&str) -> bool,
+) -> Result<(), Error> {
+    let unsupported = |what: &str| Err(Error::Protocol(format!("Server does not support {what}")));
+    if (deepen.is_set() || !odb.shallow().is_empty()) && !supports("shallow") {
+        return unsupported("shallow clients");
+    }
+    if deepen.since.is_some() && !supports("deepen-since") {
+        return unsupported("--shallow-since");
+    }
+    if !deepen.not.is_empty() && !supports("deepen-not") {
+        return unsupported("--shallow-exclude");
+    }
+    if deepen.relative && !supports("deepen-relative") {
+        return unsupported("--deepen");
+    }
+    Ok(())
+}
+
+/// Ask the remote for a pack containing `wants` and everything they reach,
+/// using the protocol version the remote advertised with, and store it in
+/// `odb` as it arrives.
+///
+/// `haves` are local commits (or tags) to negotiate from: the remote leaves
+/// out what they reach, and may send a thin pack with deltas against such
+/// objects, which is completed from `odb`.
+pub fn fetch_pack(
+    odb: &ObjectDatabase,
+    url: &str,
+    advertisement: &Advertisement,
+    wants: &[String],
+    haves: &[String],
+    opts: &FetchOptions,
+) -> Result<FetchResponse, anyhow::Error> {
+    match advertisement.version {
+        ProtocolVersion::V2 => fetch_pack_v2(odb, url, advertisement, wants, haves, opts),
+        ProtocolVersion::V0 | ProtocolVersion::V1 => {
+            fetch_pack_v0(odb, url, advertisement, wants, haves, opts)
+        }
+    }
+}
+
+/// Fetch the objects `oids` by id from the promisor remote at `url`, for a
+/// partial clone that found them missing. Nothing is negotiated, no tags
+/// are followed, and the trees fetched come without their blobs, which are
+/// fetched when they are needed in turn. The pack is marked as a promisor
+/// pack.
+pub fn fetch_objects(
+    odb: &ObjectDatabase,
+    url: &str,
+    oids: &[String],
+) -> Result<(), anyhow::Error> {
+    let advertisement = ls_refs(url, &["HEAD"])?;
+    let opts = FetchOptions {
+        tags: TagMode::None,
+        filter: Some(ObjectFilter::BlobNone),
+        ..FetchOptions::default()
+    };
+    let response = fetch_pack(odb, url, &advertisement, oids, &[], &opts)?;
+    pack::write_promisor(odb, &response.pack, &[])
+}
+
+/// The refs and capabilities the remote's receive-pack advertises. Pushes
+/// are always made in protocol v0, so all refs are listed.
+pub fn receive_pack_refs(url: &str) -> Result<Advertisement, anyhow::Error> {
+    let body = get_info_refs(url, "git-receive-pack", false)?;
+    Ok(parse_info_refs(&body)?)
+}
+
+/// The value a push lease expects a remote ref to have before it is
+/// overwritten (`--force-with-lease=<name>:<expect>`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    /// The remote ref it applies to; `None` for every ref pushed.
+    pub name: Option<String>,
+    /// The expected object id, empty if the ref must not exist; `None` to
+    /// expect what its remote-tracking ref holds.
+    pub expect: Option<String>,
+}
+
+/// How to push.
+#[derive(Debug, Clone, Default)]
+pub struct PushOptions {
+    /// Show progress on stderr: ours, and the remote's messages.
+    pub progress: bool,
+    /// Allow updates that are not fast-forwards for every refspec, as a
+    /// leading `+` does for one.
+    pub force: bool,
+    /// Allow overwriting the refs these name as long as they hold what the
+    /// lease expects.
+    pub leases: Vec<Lease>,
+    /// Update either all refs or none.
+    pub atomic: bool,
+    /// Push every tag besides the refspecs.
+    pub tags: bool,
+}
+
+/// A ref update a push asks the remote for. A null `old` creates the ref
+/// and a null `new` deletes it.
+#[derive(Debug, Clone)]
+pub struct PushCommand {
+    pub name: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// Send `commands` to the remote's receive-pack, along with a pack of
+/// `objects` unless every command is a deletion, and return what it
+/// reported for each ref: `None` if it was updated, or why not. A remote
+/// that does not report is taken to have made every update.
+pub fn send_pack(
+    odb: &ObjectDatabase,
+    url: &str,
+    advertisement: &Advertisement,
+    commands: &[PushCommand],
+    objects: &[revwalk::ListedObject],
+    opts: &PushOptions,
+) -> Result<Vec<(String, Option<String>)>, anyhow::Error> {
+    let has = |c: &str| advertisement.capability(c).is_some();
+    if advertisement.object_format()? != odb.format() {
+        anyhow::bail!("the receiving end does not support this repository's hash algorithm");
+    }
+    if opts.atomic && !has("atomic") {
+        anyhow::bail!("the receiving end does not support --atomic push");
+    }
+    let report_status = ["report-status-v2", "report-status"]
+        .into_iter()
+        .find(|c| has(c));
+    let side_band = has("side-band-64k");
+    let mut requested = report_status.into_iter().collect::<Vec<_>>();
+    if side_band {
+        requested.push("side-band-64k");
+    }
+    if !opts.progress && has("quiet") {
+        requested.push("quiet");
+    }
+    if opts.atomic {
+        requested.push("atomic");
+    }
+    let ofs_delta = has("ofs-delta");
+    if ofs_delta {
+        requested.push("ofs-delta");
+    }
+    let mut capabilities = String::from("\0");
+    capabilities += &requested.join(" ");
+    if has("object-format") {
+        capabilities += &format!(" object-format={}", odb.format());
+    }
+
+    let mut body = Vec::new();
+    for command in commands {
+        let line = format!(
+            "{} {} {}{capabilities}\n",
+            command.old, command.new, command.name
+        );
+        body.extend_from_slice(&create_pkt_line(line.as_bytes()));
+        capabilities.clear();
+    }
+    body.extend_from_slice(&create_pkt_line(b""));
+    let null = odb.format().null_id();
+    if commands.iter().any(|c| c.new != null) {
+        let pack_opts = pack::PackWriteOptions {
+            ofs_delta,
+            progress: opts.progress,
+            ..pack::PackWriteOptions::new(odb)
+        };
+        pack::write_pack(odb, objects, &mut body, &pack_opts)?;
+    }
+
+    let resp = post_service(url, "git-receive-pack", ProtocolVersion::V0, body)?;
+    if report_status.is_none() {
+        return Ok(commands.iter().map(|c| (c.name.clone(), None)).collect());
+    }
+    let mut reader = PacketReader::new(resp);
+    let mut report = Vec::new();
+    if side_band {
+        let mut side_band = SideBand::new(&mut reader);
+        let result = side_band.read_to_end(&mut report);
+        if let Some(e) = side_band.take_error() {
+            return Err(e);
+        }
+        result.context("read push report")?;
+    } else {
+        reader
+            .into_inner()
+            .read_to_end(&mut report)
+            .context("read push report")?;
+    }
+
+    Ok(parse_report_status(&report)?)
+}
+
+/// Parse a `report-status` (or `report-status-v2`) report: `None` for each
+/// ref the remote updated, or the reason it did not.
+fn parse_report_status(report: &[u8]) -> Result<Vec<(String, Option<String>)>, Error> {
+    let mut offset = 0;
+    let mut statuses = Vec::new();
+    loop {
+        let line = match read_packet(report, &mut offset)? {
+            Packet::Flush => break,
+            Packet::Data(line) => line,
+            packet => {
+                return Err(Error::Protocol(format!(
+                    "unexpected {packet:?} packet in push report"
+                )))
+            }
+        };
+        let line = String::from_utf8_lossy(line.strip_suffix(b"\n").unwrap_or(line));
+        if let Some(status) = line.strip_prefix("unpack ") {
+            if status != "ok" {
+                eprintln!("error: remote unpack failed: {status}");
+            }
+        } else if let Some(name) = line.strip_prefix("ok ") {
+            statuses.push((name.to_string(), None));
+        } else if let Some(rest) = line.strip_prefix("ng ") {
+            let (name, reason) = rest.split_once(' ').unwrap_or((rest, "failed"));
+            statuses.push((name.to_string(), Some(reason.to_string())));
+        } else if !line.starts_with("option ") {
+            // report-status-v2 follows an ok line with option lines when a
+            // hook changed what the update did, which are not needed here
+            return Err(Error::Protocol(format!("bad push report line {line:?}")));
+        }
+    }
+    Ok(statuses)
+}
+
+/// How a v0 batch of acknowledgments ended.
+#[derive(Debug, PartialEq, Eq)]
+enum AckEnd {
+    /// `NAK`: the end of a round, or after `done` when nothing was common.
+    Nak,
+    /// A bare `ACK <oid>`: the pack follows.
+    Ack,
+}
+
+/// Read v0 acknowledgments (`multi_ack_detailed` style) up to the line that
+/// ends them, passing the commits they name to `negotiator`.
+fn read_acks_v0(
+    reader: &mut PacketReader<impl Read>,
+    negotiator: &mut Negotiator,
+) -> Result<AckEnd, anyhow::Error> {
+    loop {
+        let line = match reader.read_packet()? {
+            Packet::Data(line) if line.starts_with(b"ERR ") => {
+                return Err(remote_error(&line[4..]))
+            }
+            Packet::Data(line) => String::from_utf8_lossy(line).trim_end().to_string(),
+            packet => {
+                return Err(Error::Protocol(format!("expected ACK or NAK, got {packet:?}")).into())
+            }
+        };
+        if line == "NAK" {
+            return Ok(AckEnd::Nak);
+        }
+        let Some(ack) = line.strip_prefix("ACK ") else {
+            return Err(Error::Protocol(format!("expected ACK or NAK, got {line:?}")).into());
+        };
+        match ack.split_once(' ') {
+            None => {
+                negotiator.ack(ack);
+                return Ok(AckEnd::Ack);
+            }
+            Some((oid, "common" | "continue")) => negotiator.ack(oid),
+            Some((oid, "ready")) => {
+                negotiator.ack(oid);
+                negotiator.ready = true;
+            }
+            Some(_) => {
+                return Err(Error::Protocol(format!("bad acknowledgment {line:?}")).into());
+            }
+        }
+    }
+}
+
+/// The capabilities a v0 request asks for on its first want line, of those
+/// `advertisement` lists; `negotiate` says whether haves will be sent.
+fn v0_capabilities(
+    odb: &ObjectDatabase,
+    advertisement: &Advertisement,
+    opts: &FetchOptions,
+    negotiate: bool,
+) -> Result<String, Error> {
+    let has = |c: &str| advertisement.capability(c).is_some();
+    let mut capabilities = String::new();
+    let side_band = ["side-band-64k", "side-band"].into_iter().find(|c| has(c));
+    let mut requested = side_band.into_iter().collect::<Vec<_>>();
+    if negotiate {
+        requested.push("multi_ack_detailed");
+        if has("no-done") {
+            requested.push("no-done");
+        }
+        if has("thin-pack") {
+            requested.push("thin-pack");
+        }
+    }
+    if has("ofs-delta") {
+        requested.push("ofs-delta");
+    }
+    if !opts.progress && has("no-progress") {
+        requested.push("no-progress");
+    }
+    if opts.tags == TagMode::Follow && has("include-tag") {
+        requested.push("include-tag");
+    }
+    if opts.deepen.is_set() || !odb.shallow().is_empty() {
+        requested.push("shallow");
+    }
+    if opts.deepen.since.is_some() {
+        requested.push("deepen-since");
+    }
+    if !opts.deepen.not.is_empty() {
+        requested.push("deepen-not");
+    }
+    if opts.deepen.relative {
+        requested.push("deepen-relative");
+    }
+    if opts.filter.is_some() && has("filter") {
+        requested.push("filter");
+    }
+    for capability in requested {
+        capabilities += &format!(" {capability}");
+    }
+    if has("object-format") {
+        capabilities += &format!(" object-format={}", advertisement.object_format()?);
+    }
+    Ok(capabilities)
+}
+
+fn fetch_pack_v0(
+    odb: &ObjectDatabase,
+    url: &str,
+    advertisement: &Advertisement,
+    wants: &[String],
+    haves: &[String],
+    opts: &FetchOptions,
+) -> Result<FetchResponse, anyhow::Error> {
+    let has = |c: &str| advertisement.capability(c).is_some();
+    check_deepen(odb, &opts.deepen, has)?;
+    // without multi_ack_detailed the server cannot say which haves it
+    // shares, so just ask for everything
+    let negotiate = !haves.is_empty() && has("multi_ack_detailed");
+    let no_done = negotiate && has("no-done");
+    let side_band = has("side-band-64k") || has("side-band");
+    let filter = opts.filter.filter(|_| has("filter"));
+    if opts.filter.is_some() && filter.is_none() {
+        eprintln!("warning: filtering not recognized by server, ignoring");
+    }
+
+    // capabilities go on the first want line
+    let mut capabilities = v0_capabilities(odb, advertisement, opts, negotiate)?;
+    let mut request = Vec::new();
+    for want in wants {
+        let line = format!("want {want}{capabilities}\n");
+        request.extend_from_slice(&create_pkt_line(line.as_bytes()));
+        capabilities.clear();
+    }
+    request.extend_from_slice(&shallow_request(odb, &opts.deepen, advertisement.version));
+    if let Some(filter) = filter {
+        request.extend_from_slice(&create_pkt_line(format!("filter {filter}\n").as_bytes()));
+    }
+    request.extend_from_slice(&create_pkt_line(b""));
+
+    let mut response = FetchResponse {
+        pack: String::new(),
+        shallow: Vec::new(),
+        unshallow: Vec::new(),
+        wanted_refs: Vec::new(),
+    };
+    let mut negotiator = Negotiator::new(odb, if negotiate { haves } else { &[] })?;
+    let mut reader = loop {
+        let round = negotiator.next_round()?;
+        let done = round.is_none();
+        let haves = round.unwrap_or_else(|| negotiator.acked.clone());
+        let mut body = request.clone();
+        for have in &haves {
+            body.extend_from_slice(&create_pkt_line(format!("have {have}\n").as_bytes()));
+        }
+        if done {
+            body.extend_from_slice(&create_pkt_line(b"done\n"));
+        } else {
+            body.extend_from_slice(&create_pkt_line(b""));
+        }
+        let resp = post_upload_pack(url, advertisement.version, body)?;
+        let mut reader = PacketReader::new(resp);
+        // every response to a deepening request starts with the new shallow
+        // boundaries, the same each time
+        if opts.deepen.is_set() {
+            read_shallow_list_v0(&mut reader, &mut response)?;
+        }
+        // after done, the pack follows either way
+        let end = read_acks_v0(&mut reader, &mut negotiator)?;
+        if done || end == AckEnd::Ack {
+            break reader;
+        }
+        // with no-done, a ready round ends with a bare ACK and the pack
+        if negotiator.ready && no_done {
+            if read_acks_v0(&mut reader, &mut negotiator)? != AckEnd::Ack {
+                return Err(Error::Protocol("expected ACK after ready".to_string()).into());
+            }
+            break reader;
+        }
+    };
+    response.pack = if side_band {
+        receive_side_band(odb, &mut reader, opts.progress)?
+    } else {
+        pack::index_pack(odb, reader.into_inner(), opts.progress)?
+    };
+    Ok(response)
+}
+
+/// Read the `shallow` and `unshallow` lines a v0 remote sends up to a flush
+/// into `response`, replacing what an earlier round's list said.
+fn read_shallow_list_v0(
+    reader: &mut PacketReader<impl Read>,
+    response: &mut FetchResponse,
+) -> Result<(), anyhow::Error> {
+    response.shallow.clear();
+    response.unshallow.clear();
+    loop {
+        let line = match reader.read_packet()? {
+            Packet::Flush => return Ok(()),
+            Packet::Data(line) if line.starts_with(b"ERR ") => {
+                return Err(remote_error(&line[4..]))
+            }
+            Packet::Data(line) => String::from_utf8_lossy(line).trim_end().to_string(),
+            packet => {
+                return Err(
+                    Error::Protocol(format!("expected shallow/unshallow, got {packet:?}")).into(),
+                )
+            }
+        };
+        if let Some(oid) = line.strip_prefix("shallow ") {
+            response.shallow.push(oid.to_string());
+        } else if let Some(oid) = line.strip_prefix("unshallow ") {
+            response.unshallow.push(oid.to_string());
+        } else {
+            return Err(
+                Error::Protocol(format!("expected shallow/unshallow, got {line:?}")).into(),
+            );
+        }
+    }
+}
+
+fn fetch_pack_v2(
+    odb: &ObjectDatabase,
+    url: &str,
+    advertisement: &Advertisement,
+    wants: &[String],
+    haves: &[String],
+    opts: &FetchOptions,
+) -> Result<FetchResponse, anyhow::Error> {
+    if advertisement.capability("fetch").is_none() {
+        return Err(Error::Protocol("server does not support fetch".to_string()).into());
+    }
+    let features = advertisement.capability("fetch").unwrap_or_default();
+    let shallow = features.split(' ').any(|f| f == "shallow");
+    // v2 servers take every deepening argument as part of "shallow"
+    check_deepen(odb, &opts.deepen, |_| shallow)?;
+    let mut request = v2_command("fetch", advertisement)?;
+    if !haves.is_empty() {
+        request.extend_from_slice(&create_pkt_line(b"thin-pack\n"));
+    }
+    request.extend_from_slice(&create_pkt_line(b"ofs-delta\n"));
+    if !opts.progress {
+        request.extend_from_slice(&create_pkt_line(b"no-progress\n"));
+    }
+    if opts.tags == TagMode::Follow {
+        request.extend_from_slice(&create_pkt_line(b"include-tag\n"));
+    }
+    for want in wants {
+        request.extend_from_slice(&create_pkt_line(format!("want {want}\n").as_bytes()));
+    }
+    request.extend_from_slice(&shallow_request(odb, &opts.deepen, ProtocolVersion::V2));
+    if let Some(filter) = opts.filter {
+        if features.split(' ').any(|f| f == "filter") {
+            request.extend_from_slice(&create_pkt_line(format!("filter {filter}\n").as_bytes()));
+        } else {
+            eprintln!("warning: filtering not recognized by server, ignoring");
+        }
+    }
+
+    let mut negotiator = Negotiator::new(odb, haves)?;
+    let mut response = FetchResponse {
+        pack: String::new(),
+        shallow: Vec::new(),
+        unshallow: Vec::new(),
+        wanted_refs: Vec::new(),
+    };
+    loop {
+        let round = negotiator.next_round()?;
+        let done = round.is_none();
+        let haves = round.unwrap_or_else(|| negotiator.acked.clone());
+        let mut body = request.clone();
+        for have in &haves {
+            body.extend_from_slice(&create_pkt_line(format!("have {have}\n").as_bytes()));
+        }
+        if done {
+            body.extend_from_slice(&create_pkt_line(b"done\n"));
+        }
+        body.extend_from_slice(&create_pkt_line(b""));
+        let resp = post_upload_pack(url, ProtocolVersion::V2, body)?;
+        let mut reader = PacketReader::new(resp);
+        if read_fetch_response_v2(odb, &mut reader, &mut negotiator, &mut response, opts)? {
+            return Ok(response);
+        }
+        if done {
+            return Err(Error::Protocol("remote sent no pack".to_string()).into());
+        }
+    }
+}
+
+/// Read the sections of a v2 fetch response into `response`, returning
+/// whether it carried the pack, or only acknowledgments for another round.
+fn read_fetch_response_v2(
+    odb: &ObjectDatabase,
+    reader: &mut PacketReader<impl Read>,
+    negotiator: &mut Negotiator,
+    response: &mut FetchResponse,
+    opts: &FetchOptions,
+) -> Result<bool, anyhow::Error> {
+    loop {
+        let header = match reader.read_packet()? {
+            Packet::Data(line) if line.starts_with(b"ERR ") => {
+                return Err(remote_error(&line[4..]))
+            }
+            Packet::Data(line) => line.strip_suffix(b"\n").unwrap_or(line).to_vec(),
+            packet => {
+                return Err(Error::Protocol(format!(
+                    "expected a fetch response section, got {packet:?}"
+                ))
+                .into())
+            }
+        };
+        if header == b"packfile" {
+            response.pack = receive_side_band(odb, reader, opts.progress)?;
+            return Ok(true);
+        }
+        // every other section is a list of lines ending in a delimiter, or
+        // a flush when the response ends there
+        let mut lines = Vec::new();
+        let end = loop {
+            match reader.read_packet()? {
+                Packet::Data(line) => {
+                    let line = line.strip_suffix(b"\n").unwrap_or(line);
+                    lines.push(String::from_utf8_lossy(line).into_owned());
+                }
+                Packet::Delim => break Packet::Delim,
+                _ => break Packet::Flush,
+            }
+        };
+        match &header[..] {
+            b"acknowledgments" => {
+                for line in lines {
+                    if let Some(oid) = line.strip_prefix("ACK ") {
+                        negotiator.ack(oid);
+                    } else if line == "ready" {
+                        negotiator.ready = true;
+                    }
+                }
+            }
+            b"shallow-info" => {
+                for line in lines {
+                    if let Some(oid) = line.strip_prefix("shallow ") {
+                        response.shallow.push(oid.to_string());
+                    } else if let Some(oid) = line.strip_prefix("unshallow ") {
+                        response.unshallow.push(oid.to_string());
+                    }
+                }
+            }
+            b"wanted-refs" => {
+                for line in lines {
+                    if let Some((oid, name)) = line.split_once(' ') {
+                        response
+                            .wanted_refs
+                            .push((oid.to_string(), name.to_string()));
+                    }
+                }
+            }
+            // packfile-uris are never asked for
+            _ => {}
+        }
+        if end != Packet::Delim {
+            return Ok(false);
+        }
+    }
+}
+
+/// Reads the pack data the remote sends on side-band band 1, up to the
+/// flush packet, passing its messages (band 2) on to stderr as they come.
+///
+/// Errors, including one the remote reports on band 3, end the stream with
+/// a bare I/O error; the error itself is kept for [`SideBand::take_error`].
+pub struct SideBand<'a, R> {
+    reader: &'a mut PacketReader<R>,
+    data: Vec<u8>,
+    pos: usize,
+    done: bool,
+    /// Messages may be split across packets anywhere.
+    line_start: bool,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a, R: Read> SideBand<'a, R> {
+    pub fn new(reader: &'a mut PacketReader<R>) -> Self {
+        SideBand {
+            reader,
+            data: Vec::new(),
+            pos: 0,
+            done: false,
+            line_start: true,
+            error: None,
+        }
+    }
+
+    /// The error that ended the stream, if one did.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+
+    fn next_packet(&mut self) -> Result<(), anyhow::Error> {
+        let data = match self.reader.read_packet()? {
+            Packet::Flush | Packet::ResponseEnd => {
+                self.done = true;
+                return Ok(());
+            }
+            Packet::Data(data) => data,
+            packet => return Err(Error::Protocol(format!("unexpected {packet:?} packet")).into()),
+        };
+        match data.split_first() {
+            Some((1, data)) => {
+                self.data.clear();
+                self.data.extend_from_slice(data);
+                self.pos = 0;
+            }
+            Some((2, message)) => remote_message(message, &mut self.line_start),
+            Some((3, message)) => return Err(remote_error(message)),
+            _ => return Err(Error::Protocol("bad side-band packet".to_string()).into()),
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for SideBand<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.data.len() {
+            if self.done {
+                return Ok(0);
+            }
+            if let Err(e) = self.next_packet() {
+                let message = e.to_string();
+                self.error = Some(e);
+                self.done = true;
+                return Err(std::io::Error::other(message));
+            }
+        }
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Index the pack the remote sends on side-band into `odb`, preferring the
+/// error the side-band stream ran into to the one indexing reports.
+fn receive_side_band(
+    odb: &ObjectDatabase,
+    reader: &mut PacketReader<impl Read>,
+    progress: bool,
+) -> Result<String, anyhow::Error> {
+    let mut side_band = SideBand::new(reader);
+    let result = pack::index_pack(odb, &mut side_band, progress);
+    match side_band.take_error() {
+        Some(e) => Err(e),
+        None => result,
+    }
+}
+
+/// An error the remote reported, in an `ERR` packet or on band 3.
+fn remote_error(message: &[u8]) -> anyhow::Error {
+    Error::Protocol(format!(
+        "remote error: {}",
+        String::from_utf8_lossy(message).trim_end()
+    ))
+    .into()
+}
+
+/// Show a message from the remote, prefixing each line (or `\r`-terminated
+/// progress update) as git does. `line_start` says whether the previous
+/// message ended a line.
+fn remote_message(message: &[u8], line_start: &mut bool) {
+    let mut stderr = std::io::stderr().lock();
+    for line in message.split_inclusive(|c| matches!(c, b'\r' | b'\n')) {
+        if *line_start {
+            let _ = stderr.write_all(b"remote: ");
+        }
+        let _ = stderr.write_all(line);
+        *line_start = line.ends_with(b"\r") || line.ends_with(b"\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{InitOptions, Repository};
+
+    /// A bare repository holding a line of `n` commits, oldest first, removed
+    /// on drop.
+    struct History {
+        repo: Repository,
+        commits: Vec<String>,
+    }
+
+    impl History {
+        fn new(name: &str, n: usize) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "codecrafters-git-transport-{}-{name}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            let opts = InitOptions {
+                bare: true,
+                ..Default::default()
+            };
+            let repo = Repository::init_with(&dir, &opts).unwrap();
+            let tree = repo.odb().write("tree", b"").unwrap();
+            let mut commits: Vec<String> = Vec::new();
+            for i in 0..n {
+                let mut data = format!("tree {tree}\n");
+                if let Some(parent) = commits.last() {
+                    data.push_str(&format!("parent {parent}\n"));
+                }
+                let ident = format!("A U Thor <author@example.com> {} +0000", 1000 + i);
+                data.push_str(&format!("author {ident}\ncommitter {ident}\n\n{i}\n"));
+                commits.push(repo.odb().write("commit", data.as_bytes()).unwrap());
+            }
+            History { repo, commits }
+        }
+
+        /// The commits `from` down to `to`, newest first.
+        fn range(&self, from: usize, to: usize) -> Vec<String> {
+            self.commits[to..=from].iter().rev().cloned().collect()
+        }
+    }
+
+    impl Drop for History {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.repo.git_dir());
+        }
+    }
+
+    /// `lines` as pkt-lines, an empty line standing for a flush packet.
+    fn pkt_lines(lines: &[&str]) -> Vec<u8> {
+        lines
+            .iter()
+            .flat_map(|line| create_pkt_line(line.as_bytes()))
+            .collect()
+    }
+
+    fn oid(c: char) -> String {
+        c.to_string().repeat(40)
+    }
+
+    #[test]
+    fn pkt_line_lengths() {
+        assert_eq!(parse_pkt_len(b"0000").unwrap(), 0);
+        assert_eq!(parse_pkt_len(b"0002").unwrap(), 2);
+        assert_eq!(parse_pkt_len(b"00ff").unwrap(), 255);
+        assert_eq!(parse_pkt_len(b"FFFF").unwrap(), 0xffff);
+        // 3 would be a packet too short to hold its own length
+        assert!(parse_pkt_len(b"0003").is_err());
+        assert!(parse_pkt_len(b"00g0").is_err());
+        assert!(parse_pkt_len(b"+0ff").is_err());
+    }
+
+    #[test]
+    fn reads_packets_in_turn() {
+        let buf = b"0009hello00000001000200050";
+        let mut offset = 0;
+        assert_eq!(
+            read_packet(buf, &mut offset).unwrap(),
+            Packet::Data(b"hello")
+        );
+        assert_eq!(read_packet(buf, &mut offset).unwrap(), Packet::Flush);
+        assert_eq!(read_packet(buf, &mut offset).unwrap(), Packet::Delim);
+        assert_eq!(read_packet(buf, &mut offset).unwrap(), Packet::ResponseEnd);
+        assert_eq!(read_packet(buf, &mut offset).unwrap(), Packet::Data(b"0"));
+        assert_eq!(offset, buf.len());
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let mut offset = 0;
+        assert!(matches!(
+            read_packet(b"00", &mut offset),
+            Err(Error::Protocol(_))
+        ));
+        let mut offset = 0;
+        assert!(matches!(
+            read_packet(b"000ahello", &mut offset),
+            Err(Error::Protocol(_))
+        ));
+        let mut reader = PacketReader::new(&b"000ahello"[..]);
+        assert!(reader.read_packet().is_err());
+        let mut reader = PacketReader::new(&b"0003"[..]);
+        assert!(reader.read_packet().is_err());
+    }
+
+    #[test]
+    fn packet_reader_streams() {
+        let mut reader = PacketReader::new(&b"0008abcd0000PACK"[..]);
+        assert_eq!(reader.read_packet().unwrap(), Packet::Data(b"abcd"));
+        assert_eq!(reader.read_packet().unwrap(), Packet::Flush);
+        let mut rest = Vec::new();
+        reader.into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"PACK");
+    }
+
+    #[test]
+    fn demultiplexes_side_band() {
+        let demultiplex_all = |packets: &[&[u8]]| {
+            let stream: Vec<u8> = packets.iter().flat_map(|p| create_pkt_line(p)).collect();
+            let mut reader = PacketReader::new(&stream[..]);
+            let mut side_band = SideBand::new(&mut reader);
+            let mut out = Vec::new();
+            match side_band.read_to_end(&mut out) {
+                Ok(_) => Ok(out),
+                Err(_) => Err(side_band.take_error().unwrap()),
+            }
+        };
+        let out = demultiplex_all(&[b"\x01PA", b"\x02Counting objects: 1\r", b"\x01CK", b""]);
+        assert_eq!(out.unwrap(), b"PACK");
+
+        let err = demultiplex_all(&[b"\x01PA", b"\x03upload-pack: not our ref\n"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "protocol error: remote error: upload-pack: not our ref"
+        );
+        assert!(demultiplex_all(&[b"\x04?", b""]).is_err());
+        // the stream must end with a flush
+        assert!(demultiplex_all(&[b"\x01PACK"]).is_err());
+    }
+
+    #[test]
+    fn empty_repository_advertisement() {
+        let body = pkt_lines(&[
+            "# service=git-upload-pack\n",
+            "",
+            &format!(
+                "{} capabilities^{{}}\0multi_ack side-band-64k object-format=sha1\n",
+                oid('0')
+            ),
+            "",
+        ]);
+        let advertisement = parse_info_refs(&body).unwrap();
+        assert_eq!(advertisement.version, ProtocolVersion::V0);
+        assert!(advertisement.refs.is_empty());
+        assert_eq!(advertisement.capability("side-band-64k"), Some(""));
+        assert_eq!(advertisement.capability("object-format"), Some("sha1"));
+        assert_eq!(advertisement.capability("ofs-delta"), None);
+    }
+
+    #[test]
+    fn v0_advertisement_with_peeled_tags_and_symrefs() {
+        let body = pkt_lines(&[
+            "# service=git-upload-pack\n",
+            "",
+            &format!(
+                "{} HEAD\0multi_ack symref=HEAD:refs/heads/main agent=git/2.39\n",
+                oid('a')
+            ),
+            &format!("{} refs/heads/main\n", oid('a')),
+            &format!("{} refs/tags/v1\n", oid('b')),
+            &format!("{} refs/tags/v1^{{}}\n", oid('c')),
+            &format!("{} refs/tags/v2\n", oid('d')),
+            "",
+        ]);
+        let advertisement = parse_info_refs(&body).unwrap();
+        let names: Vec<&str> = advertisement.refs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["HEAD", "refs/heads/main", "refs/tags/v1", "refs/tags/v2"]
+        );
+        let head = advertisement.find("HEAD").unwrap();
+        assert_eq!(head.symref_target.as_deref(), Some("refs/heads/main"));
+        assert_eq!(head.oid, oid('a'));
+        let v1 = advertisement.find("refs/tags/v1").unwrap();
+        assert_eq!(
+            (v1.oid.clone(), v1.peeled.clone()),
+            (oid('b'), Some(oid('c')))
+        );
+        assert_eq!(advertisement.find("refs/tags/v2").unwrap().peeled, None);
+        assert_eq!(advertisement.capability("agent"), Some("git/2.39"));
+
+        // v1 is v0 with a version line in front
+        let body = pkt_lines(&[
+            "version 1\n",
+            &format!("{} refs/heads/main\0ofs-delta\n", oid('a')),
+            "",
+        ]);
+        let advertisement = parse_info_refs(&body).unwrap();
+        assert_eq!(advertisement.version, ProtocolVersion::V1);
+        assert_eq!(advertisement.refs.len(), 1);
+    }
+
+    #[test]
+    fn v0_advertisement_ids_follow_the_object_format() {
+        let sha256 = "e".repeat(64);
+        let body = pkt_lines(&[
+            &format!("{sha256} refs/heads/main\0object-format=sha256\n"),
+            "",
+        ]);
+        let advertisement = parse_info_refs(&body).unwrap();
+        assert_eq!(advertisement.object_format().unwrap(), ObjectFormat::Sha256);
+        assert_eq!(advertisement.refs[0].oid, sha256);
+
+        let body = pkt_lines(&[&format!("{} refs/heads/main\0\n", oid('a')), "bad line\n"]);
+        assert!(matches!(parse_info_refs(&body), Err(Error::Protocol(_))));
+        let body = pkt_lines(&[&format!(
+            "{} refs/heads/main\0object-format=md5\n",
+            oid('a')
+        )]);
+        assert!(matches!(parse_info_refs(&body), Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn v2_capabilities() {
+        let body = pkt_lines(&[
+            "version 2\n",
+            "agent=git/2.39\n",
+            "ls-refs=unborn\n",
+            "fetch=shallow wait-for-done\n",
+            "object-format=sha256\n",
+            "",
+        ]);
+        let advertisement = parse_info_refs(&body).unwrap();
+        assert_eq!(advertisement.version, ProtocolVersion::V2);
+        assert!(advertisement.refs.is_empty());
+        assert_eq!(advertisement.capability("ls-refs"), Some("unborn"));
+        assert_eq!(
+            advertisement.capability("fetch"),
+            Some("shallow wait-for-done")
+        );
+        assert_eq!(advertisement.object_format().unwrap(), ObjectFormat::Sha256);
+
+        // the block must end with a flush
+        let body = pkt_lines(&["version 2\n", "ls-refs\n"]);
+        assert!(parse_info_refs(&body).is_err());
+    }
+
+    #[test]
+    fn ls_refs_attributes() {
+        let resp = pkt_lines(&[
+            &format!("{} HEAD symref-target:refs/heads/main\n", oid('a')),
+            &format!("{} refs/heads/main\n", oid('a')),
+            &format!("{} refs/tags/v1 peeled:{}\n", oid('b'), oid('c')),
+            "",
+        ]);
+        let refs = parse_ls_refs(&resp, ObjectFormat::Sha1).unwrap();
+        assert_eq!(refs.len(), 3);
+        assert_eq!(refs[0].name, "HEAD");
+        assert_eq!(refs[0].symref_target.as_deref(), Some("refs/heads/main"));
+        assert_eq!(
+            (refs[1].symref_target.clone(), refs[1].peeled.clone()),
+            (None, None)
+        );
+        assert_eq!(refs[2].oid, oid('b'));
+        assert_eq!(refs[2].peeled, Some(oid('c')));
+
+        for bad in [format!("{}\n", oid('a')), "abc HEAD\n".to_string()] {
+            let resp = pkt_lines(&[&bad, ""]);
+            assert!(parse_ls_refs(&resp, ObjectFormat::Sha1).is_err(), "{bad}");
+        }
+        let resp = pkt_lines(&[&format!("{} HEAD\n", oid('a')), ""]);
+        assert!(parse_ls_refs(&resp, ObjectFormat::Sha256).is_err());
+    }
+
+    #[test]
+    fn negotiation_rounds_double() {
+        let history = History::new("rounds", 60);
+        let tip = history.commits[59].clone();
+        let mut negotiator = Negotiator::new(history.repo.odb(), &[tip]).unwrap();
+        assert_eq!(
+            negotiator.next_round().unwrap(),
+            Some(history.range(59, 44))
+        );
+        assert_eq!(
+            negotiator.next_round().unwrap(),
+            Some(history.range(43, 12))
+        );
+        // what is left of history, however short
+        assert_eq!(negotiator.next_round().unwrap(), Some(history.range(11, 0)));
+        assert_eq!(negotiator.next_round().unwrap(), None);
+    }
+
+    #[test]
+    fn negotiation_repeats_acks_and_skips_common() {
+        let history = History::new("acks", 10);
+        let tip = history.commits[9].clone();
+        let mut negotiator = Negotiator::new(history.repo.odb(), &[tip]).unwrap();
+        negotiator.batch = 2;
+        assert_eq!(negotiator.next_round().unwrap(), Some(history.range(9, 8)));
+        negotiator.ack(&history.commits[9]);
+        negotiator.ack(&history.commits[9]);
+        negotiator.batch = 1;
+        let mut expected = vec![history.commits[9].clone()];
+        expected.extend(history.range(7, 7));
+        assert_eq!(negotiator.next_round().unwrap(), Some(expected));
+
+        // everything below an acknowledged commit is common, so nothing is
+        // left to offer
+        negotiator.ack(&history.commits[7]);
+        assert_eq!(negotiator.next_round().unwrap(), None);
+    }
+
+    #[test]
+    fn negotiation_stops_when_ready_or_in_vain() {
+        let history = History::new("stop", 4);
+        let tips = &history.commits[3..];
+        let mut negotiator = Negotiator::new(history.repo.odb(), tips).unwrap();
+        negotiator.ready = true;
+        assert_eq!(negotiator.next_round().unwrap(), None);
+
+        let mut negotiator = Negotiator::new(history.repo.odb(), tips).unwrap();
+        negotiator.batch = 1;
+        negotiator.next_round().unwrap();
+        // without any acknowledgement the walk goes on however long it takes
+        negotiator.in_vain = MAX_IN_VAIN + 1;
+        assert!(negotiator.next_round().unwrap().is_some());
+        negotiator.ack(&history.commits[3]);
+        negotiator.in_vain = MAX_IN_VAIN + 1;
+        assert_eq!(negotiator.next_round().unwrap(), None);
+    }
+
+    #[test]
+    fn shallow_requests() {
+        let history = History::new("shallow-request", 3);
+        let odb = history.repo.odb();
+        let deepen = Deepen {
+            depth: Some(2),
+            relative: true,
+            ..Default::default()
+        };
+        // v0 asks for deepen-relative as a capability instead
+        assert_eq!(
+            shallow_request(odb, &deepen, ProtocolVersion::V0),
+            pkt_lines(&["deepen 2\n"])
+        );
+        assert_eq!(
+            shallow_request(odb, &deepen, ProtocolVersion::V2),
+            pkt_lines(&["deepen 2\n", "deepen-relative\n"])
+        );
+
+        odb.set_shallow([history.commits[0].clone()]);
+        let deepen = Deepen {
+            since: Some(1001),
+            not: vec!["refs/heads/old".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            shallow_request(odb, &deepen, ProtocolVersion::V0),
+            pkt_lines(&[
+                &format!("shallow {}\n", history.commits[0]),
+                "deepen-since 1001\n",
+                "deepen-not refs/heads/old\n",
+            ])
+        );
+    }
+
+    #[test]
+    fn requests_deepen_relative_as_a_v0_capability() {
+        let history = History::new("shallow-capabilities", 1);
+        let advertisement = Advertisement {
+            version: ProtocolVersion::V0,
+            refs: Vec::new(),
+            capabilities: ["side-band-64k", "ofs-delta", "shallow", "deepen-relative"]
+                .map(str::to_string)
+                .to_vec(),
+        };
+        let opts = FetchOptions {
+            deepen: Deepen {
+                depth: Some(1),
+                relative: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let capabilities = v0_capabilities(history.repo.odb(), &advertisement, &opts, false);
+        assert_eq!(
+            capabilities.unwrap(),
+            " side-band-64k ofs-delta shallow deepen-relative"
+        );
+        let capabilities = v0_capabilities(
+            history.repo.odb(),
+            &advertisement,
+            &Default::default(),
+            false,
+        );
+        assert_eq!(capabilities.unwrap(), " side-band-64k ofs-delta");
+    }
+
+    #[test]
+    fn deepening_needs_server_support() {
+        let history = History::new("check-deepen", 2);
+        let odb = history.repo.odb();
+        let error = |deepen: &Deepen, supported: &[&str]| match check_deepen(odb, deepen, |c| {
+            supported.contains(&c)
+        }) {
+            Err(Error::Protocol(message)) => Some(message),
+            Err(e) => panic!("{e}"),
+            Ok(()) => None,
+        };
+        let depth = Deepen {
+            depth: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            error(&depth, &[]).as_deref(),
+            Some("Server does not support shallow clients")
+        );
+        assert_eq!(error(&depth, &["shallow"]), None);
+        assert_eq!(error(&Deepen::default(), &[]), None);
+
+        let all = ["shallow", "deepen-since", "deepen-not", "deepen-relative"];
+        for (deepen, needs, flag) in [
+            (
+                Deepen {
+                    since: Some(1000),
+                    ..Default::default()
+                },
+                "deepen-since",
+                "--shallow-since",
+            ),
+            (
+                Deepen {
+                    not: vec!["main".to_string()],
+                    ..Default::default()
+                },
+                "deepen-not",
+                "--shallow-exclude",
+            ),
+            (
+                Deepen {
+                    depth: Some(1),
+                    relative: true,
+                    ..Default::default()
+                },
+                "deepen-relative",
+                "--deepen",
+            ),
+        ] {
+            let without: Vec<&str> = all.iter().copied().filter(|c| *c != needs).collect();
+            assert_eq!(
+                error(&deepen, &without),
+                Some(format!("Server does not support {flag}"))
+            );
+            assert_eq!(error(&deepen, &all), None);
+        }
+
+        // a shallow repository cannot fetch from a server that knows nothing
+        // of shallow clients, even without deepening
+        odb.set_shallow([history.commits[1].clone()]);
+        assert!(error(&Deepen::default(), &[]).is_some());
+    }
+
+    #[test]
+    fn reads_v0_shallow_lists() {
+        let read = |lines: &[&str]| {
+            let stream = pkt_lines(lines);
+            let mut reader = PacketReader::new(&stream[..]);
+            let mut response = FetchResponse {
+                pack: String::new(),
+                shallow: vec![oid('f')],
+                unshallow: vec![oid('f')],
+                wanted_refs: Vec::new(),
+            };
+            read_shallow_list_v0(&mut reader, &mut response)
+                .map(|()| (response.shallow, response.unshallow))
+        };
+        // an earlier round's list is replaced
+        let (shallow, unshallow) = read(&[
+            &format!("shallow {}\n", oid('a')),
+            &format!("unshallow {}\n", oid('b')),
+            &format!("shallow {}\n", oid('c')),
+            "",
+        ])
+        .unwrap();
+        assert_eq!(shallow, [oid('a'), oid('c')]);
+        assert_eq!(unshallow, [oid('b')]);
+        assert_eq!(read(&[""]).unwrap(), (Vec::new(), Vec::new()));
+
+        let err = read(&["ERR shallow-exclude not found\n"]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("remote error: shallow-exclude not found"));
+        assert!(read(&["NAK\n", ""]).is_err());
+        assert!(read(&[&format!("shallow {}\n", oid('a'))]).is_err());
+    }
+    #[test]
+    fn parses_shallow_since_dates() {
+        assert_eq!(parse_date("1700000000").unwrap(), 1700000000);
+        assert_eq!(parse_date("@1700000000").unwrap(), 1700000000);
+        assert_eq!(
+            parse_date("Tue, 14 Nov 2023 22:13:20 +0000").unwrap(),
+            1700000000
+        );
+        assert_eq!(parse_date("2023-11-14T22:13:20Z").unwrap(), 1700000000);
+        assert_eq!(parse_date("2023-11-14 23:13:20 +0100").unwrap(), 1700000000);
+        let week_ago = chrono::Local::now().timestamp() - 7 * 24 * 60 * 60;
+        assert!((parse_date("1.week.ago").unwrap() - week_ago).abs() < 5);
+        assert!((parse_date("1 weeks ago").unwrap() - week_ago).abs() < 5);
+        for bad in ["", "yesterday", "2 fortnights ago", "2023-13-01"] {
+            assert!(parse_date(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_object_filters() {
+        let parse = |spec| ObjectFilter::parse(spec).unwrap();
+        assert_eq!(parse("blob:none"), ObjectFilter::BlobNone);
+        assert_eq!(parse("blob:limit=0"), ObjectFilter::BlobLimit(0));
+        assert_eq!(parse("blob:limit=100"), ObjectFilter::BlobLimit(100));
+        assert_eq!(parse("blob:limit=2k"), ObjectFilter::BlobLimit(2048));
+        assert_eq!(parse("blob:limit=1M"), ObjectFilter::BlobLimit(1 << 20));
+        assert_eq!(parse("blob:limit=3g"), ObjectFilter::BlobLimit(3 << 30));
+        assert_eq!(parse("tree:0"), ObjectFilter::Tree(0));
+        assert_eq!(parse("tree:3"), ObjectFilter::Tree(3));
+        for bad in [
+            "",
+            "blob:all",
+            "blob:limit=",
+            "blob:limit=k",
+            "blob:limit=1t",
+            "blob:limit=-1",
+            "blob:limit=99999999999g",
+            "tree:",
+            "tree:-1",
+            "sparse:oid=main",
+        ] {
+            assert!(ObjectFilter::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn object_filters_display_as_parsed() {
+        for spec in ["blob:none", "blob:limit=2048", "tree:1"] {
+            assert_eq!(ObjectFilter::parse(spec).unwrap().to_string(), spec);
+        }
+    }
+
+    #[test]
+    fn requests_filters_the_remote_supports() {
+        let history = History::new("filter-capabilities", 1);
+        let mut advertisement = Advertisement {
+            version: ProtocolVersion::V0,
+            refs: Vec::new(),
+            capabilities: vec!["ofs-delta".to_string()],
+        };
+        let opts = FetchOptions {
+            filter: Some(ObjectFilter::BlobNone),
+            ..Default::default()
+        };
+        let odb = history.repo.odb();
+        assert_eq!(
+            v0_capabilities(odb, &advertisement, &opts, false).unwrap(),
+            " ofs-delta"
+        );
+        advertisement.capabilities.push("filter".to_string());
+        assert_eq!(
+            v0_capabilities(odb, &advertisement, &opts, false).unwrap(),
+            " ofs-delta filter"
+        );
+    }
+
+    #[test]
+    fn parses_push_reports() {
+        let report = pkt_lines(&[
+            "unpack ok\n",
+            "ok refs/heads/main\n",
+            // report-status-v2 details what a hook changed
+            "option refname refs/heads/main\n",
+            &format!("option old-oid {}\n", oid('a')),
+            "ng refs/heads/topic non-fast-forward\n",
+            "ng refs/heads/locked\n",
+            "ng refs/tags/v1 hook declined the update\n",
+            "",
+        ]);
+        let statuses = parse_report_status(&report).unwrap();
+        let reason = |reason: &str| Some(reason.to_string());
+        assert_eq!(
+            statuses,
+            [
+                ("refs/heads/main".to_string(), None),
+                ("refs/heads/topic".to_string(), reason("non-fast-forward")),
+                ("refs/heads/locked".to_string(), reason("failed")),
+                (
+                    "refs/tags/v1".to_string(),
+                    reason("hook declined the update")
+                ),
+            ]
+        );
+
+        // a failed unpack is reported, but the ref statuses still count
+        let report = pkt_lines(&[
+            "unpack index-pack failed\n",
+            "ng refs/heads/main unpacker error\n",
+            "",
+        ]);
+        assert_eq!(parse_report_status(&report).unwrap().len(), 1);
+
+        for bad in [
+            &["unpack ok\n", "what refs/heads/main\n", ""][..],
+            &["ok refs/heads/main\n"],
+        ] {
+            assert!(parse_report_status(&pkt_lines(bad)).is_err(), "{bad:?}");
+        }
+    }
+}
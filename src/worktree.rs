@@ -0,0 +1,124 @@
+//! Moving content between the work tree and the object database.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::error::Error;
+use crate::object::{parse_tree, tree_entry_cmp, ObjectType};
+use crate::odb::ObjectDatabase;
+use crate::progress::Progress;
+
+/// Hash, and optionally write, the file at `path` as a blob.
+pub fn hash_file(odb: &ObjectDatabase, path: &Path, write: bool) -> Result<String, anyhow::Error> {
+    let mut file = fs::File::open(path).context(format!("open {:?}", path))?;
+    let size = file.metadata().context(format!("stat {:?}", path))?.len();
+    odb.stream_object("blob", size, &mut file, write)
+        .context(format!("hash {:?}", path))
+}
+
+/// Write a tree object (and every blob and subtree in it) for the directory
+/// `dir`, skipping `.git`, and return its id.
+pub fn write_tree(odb: &ObjectDatabase, dir: &Path) -> Result<String, anyhow::Error> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir).context(format!("read {:?}", dir))? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        // `.git` is a file rather than a directory in linked work trees
+        if name == ".git" {
+            continue;
+        }
+        let (mode, hash) = if path.is_dir() {
+            ("40000", write_tree(odb, &path)?)
+        } else if path.is_symlink() {
+            ("120000", hash_file(odb, &path, true)?)
+        } else if path.is_file() {
+            ("100644", hash_file(odb, &path, true)?)
+        } else {
+            // TODO: executable
+            ("100755", hash_file(odb, &path, true)?)
+        };
+        entries.push((mode, name, hash));
+    }
+    entries.sort_by(|a, b| {
+        tree_entry_cmp(
+            a.1.as_encoded_bytes(),
+            a.0 == "40000",
+            b.1.as_encoded_bytes(),
+            b.0 == "40000",
+        )
+    });
+
+    let mut buf = Vec::new();
+    for (mode, name, hash) in &entries {
+        buf.extend_from_slice(mode.as_bytes());
+        buf.push(b' ');
+        buf.extend_from_slice(name.as_encoded_bytes());
+        buf.push(b'\0');
+        buf.extend_from_slice(&hex::decode(hash)?);
+    }
+    odb.write("tree", &buf).context("write to tree object")
+}
+
+/// Write the files of the tree `tree` into the directory `path`. With
+/// `progress`, a meter is shown on stderr.
+pub fn checkout_tree(
+    odb: &ObjectDatabase,
+    tree: &str,
+    path: &Path,
+    progress: bool,
+) -> Result<(), anyhow::Error> {
+    let mut progress = Progress::new("Updating files", None, progress);
+    checkout(odb, tree, path, &mut progress)?;
+    progress.finish();
+    Ok(())
+}
+
+fn checkout(
+    odb: &ObjectDatabase,
+    tree: &str,
+    path: &Path,
+    progress: &mut Progress,
+) -> Result<(), anyhow::Error> {
+    let obj = odb.read(tree)?;
+    if obj.kind != ObjectType::Tree {
+        anyhow::bail!("{tree} is a {}, not a tree", obj.kind);
+    }
+    fs::create_dir_all(path).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    for entry in parse_tree(&obj.content, odb.format())? {
+        // TODO: set the right permission for checked-out files.
+        let name = std::str::from_utf8(&entry.name)?;
+        // a hostile tree must not write outside of the directory
+        if matches!(name, "" | "." | "..")
+            || name.contains('/')
+            || name.eq_ignore_ascii_case(".git")
+        {
+            return Err(Error::CorruptObject {
+                oid: tree.to_string(),
+                path: odb.object_path(tree),
+                reason: format!("unsafe tree entry name {name:?}"),
+            }
+            .into());
+        }
+        let target = path.join(name);
+        match entry.kind() {
+            // submodules are checked out as empty directories
+            ObjectType::Commit => fs::create_dir_all(&target)?,
+            ObjectType::Tree => checkout(odb, &entry.hash, &target, progress)?,
+            _ => {
+                let mut out = fs::File::create(&target).map_err(|source| Error::Io {
+                    path: target.clone(),
+                    source,
+                })?;
+                odb.open(&entry.hash)?.copy_to(&mut out)?;
+                progress.inc();
+            }
+        }
+    }
+    Ok(())
+}